@@ -0,0 +1,281 @@
+use actix_web::{get, post, web, HttpResponse, Responder, Scope};
+use serde::{Deserialize, Serialize};
+use mongodb::bson::Document;
+use mongodb::bson::oid::ObjectId;
+
+use crate::permission::{Authorized, AdminsManage, AdminsRead, ROLES};
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::db::{count_other_active_admins, find_user_id};
+use crate::audit::{summary, Audit};
+use crate::config::Config;
+use crate::password::PasswordPolicy;
+use crate::repository::{AccountUpdate, Store};
+
+#[derive(Serialize)]
+pub struct AdminEntry {
+    pub user_id: String,
+    pub username: String,
+    pub email: String,
+    pub created_at: String,
+    pub created_by: Option<String>,
+    pub staff_role: String,
+    pub disabled: bool,
+    /// Courses the admin is on the staff of, every course when null
+    pub course_ids: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct GetAdminListResponse {
+    pub admins: Vec<AdminEntry>,
+}
+
+#[derive(Serialize)]
+pub struct RoleEntry {
+    pub role: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct GetRolesResponse {
+    pub roles: Vec<RoleEntry>,
+}
+
+#[derive(Deserialize)]
+pub struct DisableAdminRequest {
+    pub username: String,
+    pub disabled: bool,
+}
+
+#[derive(Deserialize)]
+pub struct StaffRoleRequest {
+    pub username: String,
+    pub staff_role: String,
+}
+
+#[derive(Deserialize)]
+pub struct AdminCoursesRequest {
+    pub username: String,
+    /// Courses to restrict the admin to, null gives access to every course
+    pub course_ids: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAdminRequest {
+    pub username: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    pub username: String,
+    pub new_password: String,
+}
+
+async fn find_admin(db: &dyn Store, username: &str) -> ApiResult<ObjectId> {
+    find_user_id(db, "admins", username)
+        .await?
+        .ok_or_else(ApiError::new_not_found)
+}
+
+/// The fields of an admin account recorded in the audit log around a change.
+async fn admin_summary(db: &dyn Store, admin_id: &ObjectId) -> ApiResult<Option<Document>> {
+    let admin = db.find_account_in("admins", admin_id).await?;
+    Ok(admin.map(|admin| summary(&admin, &["username", "email", "staff_role", "disabled", "course_ids"])))
+}
+
+/// Refuse to take away the last admin that is still able to log in.
+async fn check_not_last_admin(db: &dyn Store, user_id: &ObjectId) -> ApiResult<()> {
+    if count_other_active_admins(db, user_id).await? == 0 {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Cannot remove the last active admin".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[get("/list")]
+async fn get_admin_list(
+    db: web::Data<dyn Store>,
+    _user: Authorized<AdminsRead>,
+) -> ApiResult<impl Responder> {
+    let admins = db.list_accounts("admins")
+        .await?
+        .into_iter()
+        .map(|admin| AdminEntry {
+            user_id: admin.id.to_hex(),
+            username: admin.username,
+            email: admin.email,
+            created_at: admin.created_at,
+            created_by: admin.created_by.map(|id| id.to_hex()),
+            staff_role: admin.staff_role.unwrap_or_else(|| ROLES[0].0.to_string()),
+            disabled: admin.disabled,
+            course_ids: admin.course_ids,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(GetAdminListResponse { admins }))
+}
+
+#[get("/roles")]
+async fn get_roles(
+    _user: Authorized<AdminsRead>,
+) -> ApiResult<impl Responder> {
+    let roles = ROLES
+        .iter()
+        .map(|(role, permissions)| RoleEntry {
+            role: role.to_string(),
+            permissions: permissions.iter().map(|p| p.to_string()).collect(),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(GetRolesResponse { roles }))
+}
+
+#[post("/disable")]
+async fn disable_admin(
+    db: web::Data<dyn Store>,
+    user: Authorized<AdminsManage>,
+    audit: Audit,
+    req: web::Json<DisableAdminRequest>,
+) -> ApiResult<impl Responder> {
+    let admin_id = find_admin(db.get_ref(), &req.username).await?;
+    if req.disabled {
+        check_not_last_admin(db.get_ref(), &admin_id).await?;
+    }
+
+    let before = admin_summary(db.get_ref(), &admin_id).await?;
+    let update = AccountUpdate { disabled: Some(req.disabled), ..Default::default() };
+    db.update_account("admins", &admin_id, &update).await?;
+    let after = admin_summary(db.get_ref(), &admin_id).await?;
+    audit
+        .record(db.get_ref(), &user.user_id, "admins.disable", &req.username, before, after)
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+#[post("/role")]
+async fn set_staff_role(
+    db: web::Data<dyn Store>,
+    user: Authorized<AdminsManage>,
+    audit: Audit,
+    req: web::Json<StaffRoleRequest>,
+) -> ApiResult<impl Responder> {
+    if !ROLES.iter().any(|(role, _)| *role == req.staff_role) {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Invalid staff role".to_string(),
+        ));
+    }
+    let admin_id = find_admin(db.get_ref(), &req.username).await?;
+    if req.staff_role != "super_admin" {
+        if admin_id == user.user_id {
+            return Err(ApiError::new(
+                ApiErrorType::InvalidRequest,
+                "Cannot demote yourself".to_string(),
+            ));
+        }
+        check_not_last_admin(db.get_ref(), &admin_id).await?;
+    }
+
+    let before = admin_summary(db.get_ref(), &admin_id).await?;
+    let update = AccountUpdate { staff_role: Some(req.staff_role.clone()), ..Default::default() };
+    db.update_account("admins", &admin_id, &update).await?;
+    let after = admin_summary(db.get_ref(), &admin_id).await?;
+    audit
+        .record(db.get_ref(), &user.user_id, "admins.role", &req.username, before, after)
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+#[post("/courses")]
+async fn set_admin_courses(
+    db: web::Data<dyn Store>,
+    config: web::Data<Config>,
+    user: Authorized<AdminsManage>,
+    audit: Audit,
+    req: web::Json<AdminCoursesRequest>,
+) -> ApiResult<impl Responder> {
+    let admin_id = find_admin(db.get_ref(), &req.username).await?;
+    let update = match &req.course_ids {
+        Some(course_ids) => {
+            if let Some(course_id) = course_ids.iter().find(|id| config.course(id).is_none()) {
+                return Err(ApiError::new(
+                    ApiErrorType::InvalidRequest,
+                    format!("Unknown course {}", course_id),
+                ));
+            }
+            AccountUpdate { course_ids: Some(Some(course_ids.clone())), ..Default::default() }
+        }
+        None => AccountUpdate { course_ids: Some(None), ..Default::default() },
+    };
+
+    let before = admin_summary(db.get_ref(), &admin_id).await?;
+    db.update_account("admins", &admin_id, &update).await?;
+    let after = admin_summary(db.get_ref(), &admin_id).await?;
+    audit
+        .record(db.get_ref(), &user.user_id, "admins.courses", &req.username, before, after)
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+#[post("/delete")]
+async fn delete_admin(
+    db: web::Data<dyn Store>,
+    user: Authorized<AdminsManage>,
+    audit: Audit,
+    req: web::Json<DeleteAdminRequest>,
+) -> ApiResult<impl Responder> {
+    let admin_id = find_admin(db.get_ref(), &req.username).await?;
+    check_not_last_admin(db.get_ref(), &admin_id).await?;
+
+    let before = admin_summary(db.get_ref(), &admin_id).await?;
+    db.purge_account("admins", &admin_id).await?;
+    audit
+        .record(db.get_ref(), &user.user_id, "admins.delete", &req.username, before, None)
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+#[post("/reset_password")]
+async fn reset_password(
+    db: web::Data<dyn Store>,
+    user: Authorized<AdminsManage>,
+    audit: Audit,
+    req: web::Json<ResetPasswordRequest>,
+    passwords: web::Data<PasswordPolicy>,
+) -> ApiResult<impl Responder> {
+    passwords.check(&req.new_password, &req.username)?;
+    let admin_id = find_admin(db.get_ref(), &req.username).await?;
+
+    let password_hash = passwords.hash(&req.new_password)?;
+
+    let before = admin_summary(db.get_ref(), &admin_id).await?;
+    // the new password is known to another admin, so it only works for changing itself
+    let update = AccountUpdate {
+        password: Some(password_hash),
+        must_change_password: Some(true),
+        ..Default::default()
+    };
+    db.update_account("admins", &admin_id, &update).await?;
+    db.revoke_sessions(&admin_id, None).await?;
+    let after = admin_summary(db.get_ref(), &admin_id).await?;
+    audit
+        .record(db.get_ref(), &user.user_id, "admins.reset_password", &req.username, before, after)
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+pub fn api_scope() -> Scope {
+    web::scope("/admins")
+        .service(get_admin_list)
+        .service(get_roles)
+        .service(disable_admin)
+        .service(set_admin_courses)
+        .service(set_staff_role)
+        .service(delete_admin)
+        .service(reset_password)
+}
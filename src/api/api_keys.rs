@@ -0,0 +1,156 @@
+use actix_web::{get, post, web, HttpResponse, Responder, Scope};
+use serde::{Deserialize, Serialize};
+use mongodb::bson::DateTime;
+use mongodb::bson::oid::ObjectId;
+
+use crate::api_key::{generate_key, hash_key, SCOPES};
+use crate::audit::{summary, Audit};
+use crate::permission::{Authorized, ApiKeysManage};
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::models::ApiKey;
+use crate::repository::Store;
+use crate::utils::format_datetime;
+
+#[derive(Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<String>,
+    /// Lifetime of the key, it never expires when omitted
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct CreateApiKeyResponse {
+    pub id: String,
+    /// The raw key, returned only once
+    pub key: String,
+}
+
+#[derive(Serialize)]
+pub struct ApiKeyEntry {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_by: String,
+    pub created_at: String,
+    pub expires_at: Option<String>,
+    pub last_used_at: Option<String>,
+    pub revoked: bool,
+}
+
+#[derive(Serialize)]
+pub struct GetApiKeyListResponse {
+    pub keys: Vec<ApiKeyEntry>,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeApiKeyRequest {
+    pub id: String,
+}
+
+#[post("/create")]
+async fn create_api_key(
+    db: web::Data<dyn Store>,
+    user: Authorized<ApiKeysManage>,
+    audit: Audit,
+    req: web::Json<CreateApiKeyRequest>,
+) -> ApiResult<impl Responder> {
+    if req.name.is_empty() || req.scopes.is_empty() {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Name and scopes are required".to_string(),
+        ));
+    }
+    if let Some(scope) = req.scopes.iter().find(|s| !SCOPES.contains(&s.as_str())) {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            format!("Unknown scope {}", scope),
+        ));
+    }
+
+    let key = generate_key();
+    let id = ObjectId::new();
+    let mut api_key = ApiKey {
+        id,
+        name: req.name.clone(),
+        key_hash: hash_key(&key),
+        scopes: req.scopes.clone(),
+        created_by: user.user_id,
+        created_at: chrono::Local::now().to_string(),
+        expires_at: None,
+        last_used_at: None,
+        revoked: false,
+    };
+    if let Some(days) = req.expires_in_days {
+        if days <= 0 {
+            return Err(ApiError::new(
+                ApiErrorType::InvalidRequest,
+                "Invalid expiration".to_string(),
+            ));
+        }
+        let expires_at = chrono::Utc::now() + chrono::Duration::days(days);
+        api_key.expires_at = Some(DateTime::from_millis(expires_at.timestamp_millis()));
+    }
+    db.insert_api_key(&api_key).await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "api_keys.create",
+            &id.to_hex(),
+            None,
+            Some(summary(&api_key, &["name", "scopes", "expires_at"])),
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().json(CreateApiKeyResponse { id: id.to_hex(), key }))
+}
+
+#[get("/list")]
+async fn get_api_key_list(
+    db: web::Data<dyn Store>,
+    _user: Authorized<ApiKeysManage>,
+) -> ApiResult<impl Responder> {
+    let keys = db
+        .list_api_keys()
+        .await?
+        .into_iter()
+        .map(|api_key| ApiKeyEntry {
+            id: api_key.id.to_hex(),
+            name: api_key.name,
+            scopes: api_key.scopes,
+            created_by: api_key.created_by.to_hex(),
+            created_at: api_key.created_at,
+            expires_at: api_key.expires_at.as_ref().map(format_datetime),
+            last_used_at: api_key.last_used_at.as_ref().map(format_datetime),
+            revoked: api_key.revoked,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(GetApiKeyListResponse { keys }))
+}
+
+#[post("/revoke")]
+async fn revoke_api_key(
+    db: web::Data<dyn Store>,
+    user: Authorized<ApiKeysManage>,
+    audit: Audit,
+    req: web::Json<RevokeApiKeyRequest>,
+) -> ApiResult<impl Responder> {
+    let id = ObjectId::parse_str(&req.id).map_err(|_| ApiError::new_not_found())?;
+    if !db.revoke_api_key(&id).await? {
+        return Err(ApiError::new_not_found());
+    }
+    audit
+        .record(db.get_ref(), &user.user_id, "api_keys.revoke", &req.id, None, None)
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+pub fn api_scope() -> Scope {
+    web::scope("/api_keys")
+        .service(create_api_key)
+        .service(get_api_key_list)
+        .service(revoke_api_key)
+}
@@ -0,0 +1,107 @@
+use actix_web::{get, web, HttpResponse, Responder, Scope};
+use serde::{Deserialize, Serialize};
+use mongodb::bson::{Bson, DateTime};
+
+use crate::permission::{Authorized, AuditRead};
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::db::find_user_id;
+use crate::repository::{AuditQuery, Store};
+use crate::utils::format_datetime;
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Deserialize)]
+pub struct GetAuditListQuery {
+    /// Username of the admin or student who acted
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    /// RFC 3339 timestamps bounding the time range
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct AuditEntry {
+    pub id: String,
+    pub at: String,
+    pub actor_id: String,
+    pub actor: Option<String>,
+    pub action: String,
+    pub target: String,
+    pub request_id: String,
+    pub ip: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+pub struct GetAuditListResponse {
+    pub entries: Vec<AuditEntry>,
+}
+
+fn parse_time(name: &str, value: &str) -> ApiResult<DateTime> {
+    let time = chrono::DateTime::parse_from_rfc3339(value).map_err(|_| ApiError::new(
+        ApiErrorType::InvalidRequest,
+        format!("Invalid {} timestamp", name),
+    ))?;
+    Ok(DateTime::from_millis(time.timestamp_millis()))
+}
+
+/// Newest entries first.
+#[get("/list")]
+async fn get_audit_list(
+    db: web::Data<dyn Store>,
+    _user: Authorized<AuditRead>,
+    query: web::Query<GetAuditListQuery>,
+) -> ApiResult<impl Responder> {
+    let mut filter = AuditQuery {
+        action: query.action.clone(),
+        limit: query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+        ..Default::default()
+    };
+    if let Some(actor) = &query.actor {
+        let actor_id = match find_user_id(db.get_ref(), "admins", actor).await? {
+            Some(actor_id) => Some(actor_id),
+            None => find_user_id(db.get_ref(), "users", actor).await?,
+        };
+        match actor_id {
+            Some(actor_id) => filter.actor_id = Some(actor_id),
+            // deleted or renamed accounts are still found by the username they had
+            None => filter.actor = Some(actor.clone()),
+        };
+    }
+    if let Some(since) = &query.since {
+        filter.since = Some(parse_time("since", since)?);
+    }
+    if let Some(until) = &query.until {
+        filter.until = Some(parse_time("until", until)?);
+    }
+
+    let summary = |summary: Option<_>| summary.map(|summary| Bson::Document(summary).into_relaxed_extjson());
+    let entries = db
+        .list_audit(&filter)
+        .await?
+        .into_iter()
+        .map(|entry| AuditEntry {
+            id: entry.id.to_hex(),
+            at: format_datetime(&entry.at),
+            actor_id: entry.actor_id.to_hex(),
+            actor: entry.actor,
+            action: entry.action,
+            target: entry.target,
+            request_id: entry.request_id,
+            ip: entry.ip,
+            before: summary(entry.before),
+            after: summary(entry.after),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(GetAuditListResponse { entries }))
+}
+
+pub fn api_scope() -> Scope {
+    web::scope("/audit")
+        .service(get_audit_list)
+}
@@ -0,0 +1,306 @@
+use actix_web::{get, post, web, HttpResponse, Responder, Scope};
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::api::stats::tag_error;
+use crate::audit::Audit;
+use crate::campaign::{self, MAX_USERNAMES};
+use crate::course::Course;
+use crate::db;
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::models::{Audience, Campaign};
+use crate::permission::{Authorized, EmailBroadcast};
+use crate::repository::Store;
+use crate::utils::format_datetime;
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 200;
+
+/// Students of the course matching every given condition, every student the admin can see when empty.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct AudienceRequest {
+    pub section_id: Option<String>,
+    pub inactive_days: Option<i64>,
+    pub tag: Option<String>,
+    pub tag_below: Option<i64>,
+    pub usernames: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct PreviewCampaignRequest {
+    #[serde(default)]
+    pub audience: AudienceRequest,
+}
+
+#[derive(Deserialize)]
+pub struct CreateCampaignRequest {
+    pub subject: String,
+    pub body: String,
+    #[serde(default)]
+    pub audience: AudienceRequest,
+}
+
+#[derive(Deserialize)]
+pub struct CampaignPath {
+    pub campaign_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct GetCampaignListQuery {
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct RecipientEntry {
+    pub username: String,
+    pub email: String,
+    pub real_name: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PreviewCampaignResponse {
+    pub recipients: Vec<RecipientEntry>,
+    /// Usernames of the uploaded list that are not students of the course the admin can see
+    pub unknown: Vec<String>,
+    /// Usernames of the students of the audience who turned off announcements, and are left out
+    pub unsubscribed: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct CampaignEntry {
+    pub id: String,
+    pub subject: String,
+    pub body: String,
+    pub audience: AudienceRequest,
+    /// Username of the admin who sent the campaign
+    pub created_by: String,
+    pub created_at: String,
+    pub recipients: i64,
+    /// Job sending the emails, see `/jobs/get`
+    pub job_id: String,
+    /// Status of the job
+    pub status: String,
+    pub sent: i64,
+    pub failed: i64,
+    pub skipped: i64,
+}
+
+#[derive(Serialize)]
+pub struct DeliveryEntry {
+    pub username: String,
+    pub email: Option<String>,
+    /// `pending`, `sent`, `failed` or `skipped`
+    pub status: String,
+    pub error: Option<String>,
+    pub delivered_at: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GetCampaignResponse {
+    #[serde(flatten)]
+    pub campaign: CampaignEntry,
+    pub deliveries: Vec<DeliveryEntry>,
+}
+
+#[derive(Serialize)]
+pub struct GetCampaignListResponse {
+    pub campaigns: Vec<CampaignEntry>,
+}
+
+fn invalid(message: &str) -> ApiError {
+    ApiError::new(ApiErrorType::InvalidRequest, message.to_string())
+}
+
+/// Check the conditions of an audience against the course.
+async fn parse_audience(db: &dyn Store, course: &Course, req: &AudienceRequest) -> ApiResult<Audience> {
+    let section_id = match &req.section_id {
+        Some(section_id) => {
+            let section_id = ObjectId::parse_str(section_id).map_err(|_| invalid("Invalid section id"))?;
+            db.find_section(&section_id)
+                .await?
+                .filter(|section| section.course_id == course.id)
+                .ok_or_else(ApiError::new_not_found)?;
+            Some(section_id)
+        }
+        None => None,
+    };
+    if req.inactive_days.is_some_and(|days| days < 1) {
+        return Err(invalid("inactive_days must be at least 1"));
+    }
+    match &req.tag {
+        Some(tag) => {
+            if let Some(message) = tag_error(course, std::slice::from_ref(tag)) {
+                return Err(ApiError::new(ApiErrorType::InvalidRequest, message));
+            }
+            if req.tag_below.is_some_and(|below| below < 1) {
+                return Err(invalid("tag_below must be at least 1"));
+            }
+        }
+        None if req.tag_below.is_some() => return Err(invalid("tag_below requires a tag")),
+        None => {}
+    }
+    if req.usernames.as_ref().is_some_and(|usernames| usernames.len() > MAX_USERNAMES) {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            format!("At most {} usernames per list", MAX_USERNAMES),
+        ));
+    }
+    Ok(Audience {
+        section_id,
+        inactive_days: req.inactive_days,
+        tag: req.tag.clone(),
+        tag_below: req.tag_below,
+        usernames: req.usernames.clone(),
+    })
+}
+
+async fn campaign_entry(db: &dyn Store, campaign: Campaign) -> ApiResult<CampaignEntry> {
+    let created_by = db
+        .find_account_in("admins", &campaign.created_by)
+        .await?
+        .map(|admin| admin.username)
+        .unwrap_or_else(|| campaign.created_by.to_hex());
+    let job = db.find_job(&campaign.job_id).await?;
+    let result = job.as_ref().and_then(|job| job.result.clone()).unwrap_or_default();
+    let audience = campaign.audience;
+    Ok(CampaignEntry {
+        id: campaign.id.to_hex(),
+        subject: campaign.subject,
+        body: campaign.body,
+        audience: AudienceRequest {
+            section_id: audience.section_id.map(|id| id.to_hex()),
+            inactive_days: audience.inactive_days,
+            tag: audience.tag,
+            tag_below: audience.tag_below,
+            usernames: audience.usernames,
+        },
+        created_by,
+        created_at: format_datetime(&campaign.created_at),
+        recipients: campaign.recipients,
+        job_id: campaign.job_id.to_hex(),
+        status: job.map(|job| job.status).unwrap_or_else(|| "unknown".to_string()),
+        sent: result.get_i64("sent").unwrap_or(0),
+        failed: result.get_i64("failed").unwrap_or(0),
+        skipped: result.get_i64("skipped").unwrap_or(0),
+    })
+}
+
+/// The recipients a campaign would have, without sending anything.
+#[post("/preview")]
+async fn preview_campaign(
+    db: web::Data<dyn Store>,
+    user: Authorized<EmailBroadcast>,
+    course: Course,
+    req: web::Json<PreviewCampaignRequest>,
+) -> ApiResult<impl Responder> {
+    let filter = db::student_scope(db.get_ref(), &user.user_id, &course.id).await?;
+    let audience = parse_audience(db.get_ref(), &course, &req.audience).await?;
+    let recipients = campaign::audience(db.get_ref(), filter, &audience, chrono::Utc::now()).await?;
+    let unsubscribed = recipients.unsubscribed;
+    let unknown = recipients.unknown;
+    let recipients = recipients
+        .students
+        .into_iter()
+        .map(|student| RecipientEntry {
+            username: student.username,
+            email: student.email,
+            real_name: student.real_name,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(PreviewCampaignResponse { recipients, unknown, unsubscribed }))
+}
+
+/// Queue a campaign to the students of the audience, see `jobs::JobSpec::Campaign`.
+#[post("/create")]
+async fn create_campaign(
+    db: web::Data<dyn Store>,
+    user: Authorized<EmailBroadcast>,
+    course: Course,
+    audit: Audit,
+    req: web::Json<CreateCampaignRequest>,
+) -> ApiResult<impl Responder> {
+    if req.subject.trim().is_empty() || req.body.trim().is_empty() {
+        return Err(invalid("Subject and body are required"));
+    }
+    let filter = db::student_scope(db.get_ref(), &user.user_id, &course.id).await?;
+    let audience = parse_audience(db.get_ref(), &course, &req.audience).await?;
+    let students = campaign::audience(db.get_ref(), filter, &audience, chrono::Utc::now()).await?.students;
+    if students.is_empty() {
+        return Err(invalid("No students match the audience"));
+    }
+
+    let campaign = campaign::start(db.get_ref(), &course.id, user.user_id, &req.subject, &req.body, audience, &students).await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "email.campaign",
+            &course.id,
+            None,
+            Some(doc! { "campaign": campaign.id.to_hex(), "subject": &req.subject, "recipients": campaign.recipients }),
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().json(campaign_entry(db.get_ref(), campaign).await?))
+}
+
+/// Newest campaigns first.
+#[get("/list")]
+async fn get_campaign_list(
+    db: web::Data<dyn Store>,
+    user: Authorized<EmailBroadcast>,
+    course: Course,
+    query: web::Query<GetCampaignListQuery>,
+) -> ApiResult<impl Responder> {
+    db::student_scope(db.get_ref(), &user.user_id, &course.id).await?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let mut campaigns = Vec::new();
+    for campaign in db.list_campaigns(&course.id, limit).await? {
+        campaigns.push(campaign_entry(db.get_ref(), campaign).await?);
+    }
+
+    Ok(HttpResponse::Ok().json(GetCampaignListResponse { campaigns }))
+}
+
+#[get("/get/{campaign_id}")]
+async fn get_campaign(
+    db: web::Data<dyn Store>,
+    user: Authorized<EmailBroadcast>,
+    course: Course,
+    path: web::Path<CampaignPath>,
+) -> ApiResult<impl Responder> {
+    db::student_scope(db.get_ref(), &user.user_id, &course.id).await?;
+    let campaign_id = ObjectId::parse_str(&path.campaign_id).map_err(|_| ApiError::new_not_found())?;
+    let campaign = db
+        .find_campaign(&campaign_id)
+        .await?
+        .filter(|campaign| campaign.course_id == course.id)
+        .ok_or_else(ApiError::new_not_found)?;
+    let deliveries = db
+        .list_deliveries(&campaign.id)
+        .await?
+        .into_iter()
+        .map(|delivery| DeliveryEntry {
+            username: delivery.username,
+            email: delivery.email,
+            status: delivery.status,
+            error: delivery.error,
+            delivered_at: delivery.delivered_at.as_ref().map(format_datetime),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(GetCampaignResponse {
+        campaign: campaign_entry(db.get_ref(), campaign).await?,
+        deliveries,
+    }))
+}
+
+pub fn api_scope() -> Scope {
+    web::scope("/campaigns")
+        .service(preview_campaign)
+        .service(create_campaign)
+        .service(get_campaign_list)
+        .service(get_campaign)
+}
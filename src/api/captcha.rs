@@ -0,0 +1,31 @@
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder, Scope};
+use serde::Serialize;
+
+use crate::captcha::create_challenge;
+use crate::config::Config;
+use crate::error::ApiResult;
+use crate::repository::Store;
+use crate::session::client_ip;
+
+#[derive(Serialize)]
+pub struct CaptchaResponse {
+    pub id: String,
+    /// The question as an SVG image data URL
+    pub image: String,
+}
+
+#[get("")]
+async fn get_captcha(
+    db: web::Data<dyn Store>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> ApiResult<impl Responder> {
+    let ip = client_ip(&req);
+    let (captcha_id, image) = create_challenge(db.get_ref(), &config, ip.as_deref()).await?;
+    Ok(HttpResponse::Ok().json(CaptchaResponse { id: captcha_id.to_hex(), image }))
+}
+
+pub fn api_scope() -> Scope {
+    web::scope("/captcha")
+        .service(get_captcha)
+}
@@ -0,0 +1,171 @@
+use actix_web::{get, post, web, HttpResponse, Responder, Scope};
+use mongodb::bson::Bson;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::Audit;
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::jobs;
+use crate::jwt::ClaimsValidator;
+use crate::models::Job;
+use crate::permission::admin_permissions;
+use crate::repository::Store;
+use crate::utils::format_datetime;
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 500;
+
+#[derive(Deserialize)]
+pub struct GetJobListQuery {
+    pub limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct CancelJobRequest {
+    pub id: String,
+}
+
+#[derive(Serialize)]
+pub struct JobProgressEntry {
+    pub done: i64,
+    pub total: i64,
+}
+
+#[derive(Serialize)]
+pub struct JobEntry {
+    pub id: String,
+    /// `weekly_report`, `clear_stats` or `campaign`
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub course_id: String,
+    /// Username of the admin who queued the job, `None` for jobs of the server itself
+    pub created_by: Option<String>,
+    pub created_at: String,
+    pub status: String,
+    pub attempts: i32,
+    pub started_at: Option<String>,
+    pub finished_at: Option<String>,
+    pub progress: JobProgressEntry,
+    pub cancel_requested: bool,
+    pub result: Option<serde_json::Value>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GetJobListResponse {
+    pub jobs: Vec<JobEntry>,
+}
+
+/// Whose jobs an admin may see: their own, or everyone's (`None`) with `jobs.manage`.
+async fn visible_jobs(db: &dyn Store, user_id: &ObjectId) -> ApiResult<Option<ObjectId>> {
+    let admin = db
+        .find_account_in("admins", user_id)
+        .await?
+        .filter(|admin| !admin.disabled)
+        .ok_or_else(|| ApiError::new(
+            ApiErrorType::Forbidden,
+            "Admin access required".to_string(),
+        ))?;
+    match admin_permissions(&admin).contains(&"jobs.manage") {
+        true => Ok(None),
+        false => Ok(Some(admin.id)),
+    }
+}
+
+/// The job `job_id`, treating the jobs the admin may not see as missing.
+async fn find_visible_job(db: &dyn Store, user_id: &ObjectId, job_id: &str) -> ApiResult<Job> {
+    let owner = visible_jobs(db, user_id).await?;
+    let job_id = ObjectId::parse_str(job_id).map_err(|_| ApiError::new_not_found())?;
+    db.find_job(&job_id)
+        .await?
+        .filter(|job| owner.is_none() || job.created_by == owner)
+        .ok_or_else(ApiError::new_not_found)
+}
+
+async fn job_entry(db: &dyn Store, job: Job) -> ApiResult<JobEntry> {
+    let created_by = match &job.created_by {
+        Some(admin_id) => Some(
+            db.find_account_in("admins", admin_id)
+                .await?
+                .map(|admin| admin.username)
+                .unwrap_or_else(|| admin_id.to_hex()),
+        ),
+        None => None,
+    };
+    Ok(JobEntry {
+        id: job.id.to_hex(),
+        kind: job.spec.kind().to_string(),
+        course_id: job.spec.course_id().to_string(),
+        created_by,
+        created_at: format_datetime(&job.created_at),
+        status: job.status,
+        attempts: job.attempts,
+        started_at: job.started_at.as_ref().map(format_datetime),
+        finished_at: job.finished_at.as_ref().map(format_datetime),
+        progress: JobProgressEntry { done: job.progress.done, total: job.progress.total },
+        cancel_requested: job.cancel_requested,
+        result: job.result.map(|result| Bson::Document(result).into_relaxed_extjson()),
+        error: job.error,
+    })
+}
+
+/// Newest jobs first.
+#[get("/list")]
+async fn get_job_list(
+    db: web::Data<dyn Store>,
+    claims: ClaimsValidator,
+    query: web::Query<GetJobListQuery>,
+) -> ApiResult<impl Responder> {
+    let owner = visible_jobs(db.get_ref(), &claims.user_id).await?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let mut jobs = Vec::new();
+    for job in db.list_jobs(owner.as_ref(), limit).await? {
+        jobs.push(job_entry(db.get_ref(), job).await?);
+    }
+
+    Ok(HttpResponse::Ok().json(GetJobListResponse { jobs }))
+}
+
+#[get("/get/{job_id}")]
+async fn get_job(
+    db: web::Data<dyn Store>,
+    claims: ClaimsValidator,
+    path: web::Path<String>,
+) -> ApiResult<impl Responder> {
+    let job = find_visible_job(db.get_ref(), &claims.user_id, &path).await?;
+
+    Ok(HttpResponse::Ok().json(job_entry(db.get_ref(), job).await?))
+}
+
+/// Cancel a queued job, or ask a running one to stop after the item it is working on.
+#[post("/cancel")]
+async fn cancel_job(
+    db: web::Data<dyn Store>,
+    claims: ClaimsValidator,
+    audit: Audit,
+    req: web::Json<CancelJobRequest>,
+) -> ApiResult<impl Responder> {
+    let job = find_visible_job(db.get_ref(), &claims.user_id, &req.id).await?;
+    if job.is_finished() {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Job already finished".to_string(),
+        ));
+    }
+    let job = db.cancel_job(&job.id).await?.ok_or_else(ApiError::new_not_found)?;
+    if job.is_finished() {
+        jobs::finished(db.get_ref(), &job.id).await?;
+    }
+    audit
+        .record(db.get_ref(), &claims.user_id, "jobs.cancel", &req.id, None, None)
+        .await;
+
+    Ok(HttpResponse::Ok().json(job_entry(db.get_ref(), job).await?))
+}
+
+pub fn api_scope() -> Scope {
+    web::scope("/jobs")
+        .service(get_job_list)
+        .service(get_job)
+        .service(cancel_job)
+}
@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder, Scope};
+use actix_web::web::Bytes;
+use serde::Deserialize;
+use mongodb::bson::oid::ObjectId;
+use tokio::sync::broadcast;
+
+use crate::course::Course;
+use crate::db;
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::live::{LiveEvent, LiveHub};
+use crate::permission::{Authorized, UsersRead};
+use crate::repository::Store;
+
+/// How often an idle stream gets a comment, so proxies keep it open.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+pub struct LiveQuery {
+    pub section_id: Option<String>,
+}
+
+/// The events one dashboard connection may see.
+struct LiveFilter {
+    course_id: String,
+    /// Sections whose events are shown, every event of the course when `None`
+    sections: Option<Vec<ObjectId>>,
+}
+
+impl LiveFilter {
+    fn matches(&self, event: &LiveEvent) -> bool {
+        event.course_id == self.course_id
+            && match &self.sections {
+                Some(sections) => event.section_id.is_some_and(|id| sections.contains(&id)),
+                None => true,
+            }
+    }
+}
+
+struct LiveStream {
+    receiver: broadcast::Receiver<Arc<LiveEvent>>,
+    filter: LiveFilter,
+    backlog: VecDeque<Arc<LiveEvent>>,
+    last_sent: u64,
+}
+
+fn format_event(event: &LiveEvent) -> Bytes {
+    Bytes::from(format!("id: {}\nevent: {}\ndata: {}\n\n", event.id, event.kind, event.data))
+}
+
+/// Next chunk of the stream: buffered events first, then live ones, with keepalives in between.
+async fn next_chunk(mut stream: LiveStream) -> Option<(Result<Bytes, actix_web::Error>, LiveStream)> {
+    loop {
+        let event = match stream.backlog.pop_front() {
+            Some(event) => event,
+            None => match actix_web::rt::time::timeout(KEEPALIVE, stream.receiver.recv()).await {
+                Ok(Ok(event)) => event,
+                // a slow client misses events, like a reconnecting one beyond the replay buffer
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => continue,
+                Ok(Err(broadcast::error::RecvError::Closed)) => return None,
+                Err(_) => return Some((Ok(Bytes::from_static(b": keepalive\n\n")), stream)),
+            },
+        };
+        // events published while the backlog was read arrive twice
+        if event.id > stream.last_sent && stream.filter.matches(&event) {
+            stream.last_sent = event.id;
+            return Some((Ok(format_event(&event)), stream));
+        }
+    }
+}
+
+#[get("")]
+async fn live_events(
+    db: web::Data<dyn Store>,
+    hub: web::Data<LiveHub>,
+    user: Authorized<UsersRead>,
+    course: Course,
+    query: web::Query<LiveQuery>,
+    req: HttpRequest,
+) -> ApiResult<impl Responder> {
+    if !db::check_course_staff(db.get_ref(), &user.user_id, &course.id).await? {
+        return Err(ApiError::new(
+            ApiErrorType::Forbidden,
+            "Not on the staff of this course".to_string(),
+        ));
+    }
+    let mut sections = db::admin_sections(db.get_ref(), &user.user_id, &course.id).await?;
+    if let Some(section_id) = &query.section_id {
+        let section_id = ObjectId::parse_str(section_id).map_err(|_| ApiError::new_not_found())?;
+        if sections.as_ref().is_some_and(|sections| !sections.contains(&section_id)) {
+            return Err(ApiError::new(
+                ApiErrorType::Forbidden,
+                "Not a staff member of this section".to_string(),
+            ));
+        }
+        sections = Some(vec![section_id]);
+    }
+
+    // subscribe before reading the buffer, so nothing falls between the two
+    let receiver = hub.subscribe();
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|id| id.parse::<u64>().ok());
+    let backlog = match last_event_id {
+        Some(last_id) => hub.since(last_id).into(),
+        None => VecDeque::new(),
+    };
+    let stream = LiveStream {
+        receiver,
+        filter: LiveFilter { course_id: course.id.clone(), sections },
+        backlog,
+        last_sent: last_event_id.unwrap_or(0),
+    };
+
+    let retry = futures::stream::once(async { Ok::<_, actix_web::Error>(Bytes::from_static(b"retry: 3000\n\n")) });
+    let events = futures::stream::unfold(stream, next_chunk);
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(futures::StreamExt::chain(retry, events)))
+}
+
+pub fn api_scope() -> Scope {
+    web::scope("/live")
+        .service(live_events)
+}
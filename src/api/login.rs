@@ -1,86 +1,262 @@
 use actix_web::{post, web, HttpResponse, Responder, Scope};
+use lettre::SmtpTransport;
 use serde::{Deserialize, Serialize};
-use mongodb::Database;
-use mongodb::bson::{doc, Document};
-use argon2::{
-    password_hash::{
-        PasswordHash, PasswordVerifier
-    },
-    Argon2
-};
+use mongodb::bson::DateTime;
 
 use crate::jwt;
+use crate::api_key::hash_key;
+use crate::captcha::{self, CaptchaAnswer};
+use crate::config::Config;
 use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::mail::{build_message, send_logged};
+use crate::password::PasswordPolicy;
+use crate::session::{create_session, record_login, ClientInfo};
+use crate::models::{Account, ActivationCode, EmailRecord};
+use crate::repository::{AccountUpdate, CodeQuery, Store};
+use crate::utils::generate_code;
+
+/// Lifetime of a login token and of its session.
+const TOKEN_HOURS: usize = 12;
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
+    /// Answer to a challenge from `/captcha`, needed after repeated failed logins
+    #[serde(default)]
+    pub captcha: Option<CaptchaAnswer>,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct LoginResponse {
     pub token: String,
+    pub must_change_password: bool,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct MagicLinkConfirmRequest {
+    pub token: String,
+}
+
+/// Store a fresh hash of a correct password when the stored one uses outdated parameters.
+async fn upgrade_hash(
+    db: &dyn Store,
+    collection: &str,
+    user: &Account,
+    password: &str,
+    passwords: &PasswordPolicy,
+) -> ApiResult<()> {
+    if passwords.needs_rehash(&user.password) {
+        let password_hash = passwords.hash(password)?;
+        let update = AccountUpdate { password: Some(password_hash), ..Default::default() };
+        db.update_account(collection, &user.id, &update).await?;
+    }
+    Ok(())
+}
+
+/// Ask for a solved captcha before checking the password of an account under attack.
+async fn check_login_captcha(
+    db: &dyn Store,
+    config: &Config,
+    user: &Account,
+    req: &LoginRequest,
+) -> ApiResult<()> {
+    if captcha::required_for_login(db, config, &user.id).await? {
+        captcha::verify(db, req.captcha.as_ref()).await?;
+    }
+    Ok(())
+}
+
+/// Check the password of an account found by username, recording the attempt either way.
+/// `refusal` is the error message given for a wrong password.
+async fn sign_in(
+    db: &dyn Store,
+    collection: &str,
+    user: &Account,
+    req: &LoginRequest,
+    passwords: &PasswordPolicy,
+    client: &ClientInfo,
+    refusal: &str,
+) -> ApiResult<LoginResponse> {
+    let user_id = user.id;
+    if !passwords.verify(&req.password, &user.password)? {
+        record_login(db, &user_id, client, Some("invalid_password")).await?;
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            refusal.to_string(),
+        ));
+    }
+    if let Err(e) = user.check_suspension() {
+        record_login(db, &user_id, client, Some("suspended")).await?;
+        return Err(e);
+    }
+    upgrade_hash(db, collection, user, &req.password, passwords).await?;
+
+    start_session(db, user, client).await
+}
+
+/// Record a successful login and issue a token for a new session of the account.
+pub(crate) async fn start_session(
+    db: &dyn Store,
+    user: &Account,
+    client: &ClientInfo,
+) -> ApiResult<LoginResponse> {
+    record_login(db, &user.id, client, None).await?;
+    let session_id = create_session(db, &user.id, client, TOKEN_HOURS).await?;
+    let token = jwt::Claims::create_jwt(&user.id, &session_id, TOKEN_HOURS)?;
+    Ok(LoginResponse { token, must_change_password: user.must_change_password })
 }
 
 #[post("")]
 async fn login(
-    db: web::Data<Database>,
+    db: web::Data<dyn Store>,
     req: web::Json<LoginRequest>,
+    passwords: web::Data<PasswordPolicy>,
+    config: web::Data<Config>,
+    client: ClientInfo,
 ) -> ApiResult<impl Responder> {  
-    let collection = db.collection("users");
-    let user: Document = collection
-        .find_one(doc! { "username": &req.username })
+    let user = db
+        .find_account_by_username("users", &req.username)
         .await?
         .ok_or(ApiError::new(
             ApiErrorType::InvalidRequest,
             "User not found".to_string(),
         ))?;
-    
-    let password: &str = user.get_str("password")?;
-    let parsed_hash = PasswordHash::new(password)?;
-    if Argon2::default().verify_password(&req.password.as_bytes(), &parsed_hash).is_err() {
-        return Err(ApiError::new(
-            ApiErrorType::InvalidRequest,
-            "Invalid password".to_string(),
-        ));
-    }
-
-    let token = jwt::Claims::create_jwt(req.username.clone(), 12)?;
 
-    Ok(HttpResponse::Ok().json(LoginResponse { token }))
+    check_login_captcha(db.get_ref(), &config, &user, &req).await?;
+    let response = sign_in(db.get_ref(), "users", &user, &req, &passwords, &client, "Invalid password").await?;
+    Ok(HttpResponse::Ok().json(response))
 }
 
 #[post("/admin")]
 async fn admin_login(
-    db: web::Data<Database>,
+    db: web::Data<dyn Store>,
     req: web::Json<LoginRequest>,
+    passwords: web::Data<PasswordPolicy>,
+    config: web::Data<Config>,
+    client: ClientInfo,
 ) -> ApiResult<impl Responder> {
-    let collection = db.collection("admins");
-    let user: Document = collection
-        .find_one(doc! { "username": &req.username })
+    let user = db
+        .find_account_by_username("admins", &req.username)
         .await?
+        .filter(|user| !user.disabled)
         .ok_or(ApiError::new(
             ApiErrorType::InvalidRequest,
             "Error".to_string(),
         ))?;
-    
-    let password: &str = user.get_str("password")?;
-    let parsed_hash = PasswordHash::new(password)?;
-    if Argon2::default().verify_password(&req.password.as_bytes(), &parsed_hash).is_err() {
+
+    check_login_captcha(db.get_ref(), &config, &user, &req).await?;
+    let response = sign_in(db.get_ref(), "admins", &user, &req, &passwords, &client, "Error").await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+fn check_magic_link_enabled(config: &Config) -> ApiResult<()> {
+    if !config.magic_link.enabled {
         return Err(ApiError::new(
-            ApiErrorType::InvalidRequest,
-            "Error".to_string(),
+            ApiErrorType::Forbidden,
+            "Passwordless login is disabled".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Email a single-use login link to the student with this address.
+#[post("/magic")]
+async fn request_magic_link(
+    db: web::Data<dyn Store>,
+    mailer: web::Data<SmtpTransport>,
+    config: web::Data<Config>,
+    req: web::Json<MagicLinkRequest>,
+) -> ApiResult<impl Responder> {
+    check_magic_link_enabled(&config)?;
+    let settings = &config.magic_link;
+
+    // requests are counted per address whether or not it belongs to an account
+    let window_start = chrono::Utc::now() - chrono::Duration::minutes(settings.window_minutes);
+    let window_start = DateTime::from_millis(window_start.timestamp_millis());
+    if db.count_link_requests(&req.email, window_start).await? >= settings.max_requests {
+        return Err(ApiError::new(
+            ApiErrorType::TooManyRequests,
+            "Too many login link requests, try again later".to_string(),
         ));
     }
+    db.add_link_request(&req.email).await?;
+
+    // the response is the same for unknown addresses, so it does not reveal who has an account
+    let user = db.find_account_by_email("users", &req.email).await?;
+    if let Some(user) = user {
+        let username = &user.username;
+        let token = generate_code(32);
+        let body = match &settings.url {
+            Some(url) => format!("Hello {},\n\nOpen this link to log in to YWT:\n{}?token={}\n\nThe link works once and expires in {} minutes. If you did not ask for it, you can ignore this email.\n\nBest regards,\nYWT Team",
+                username, url, token, settings.expiry_minutes),
+            None => format!("Hello {},\n\nYour YWT login code is {}\n\nThe code works once and expires in {} minutes. If you did not ask for it, you can ignore this email.\n\nBest regards,\nYWT Team",
+                username, token, settings.expiry_minutes),
+        };
+        let subject = "Log in to YWT";
+        let message = build_message(&config, "account", username, &req.email, subject, body.clone())?;
+
+        // only the latest link of an account works, and only its hash is stored
+        let mut code = ActivationCode::new(user.id, Some("magic_link"), settings.expiry_minutes);
+        code.code_hash = Some(hash_key(&token));
+        db.replace_code(&code).await?;
+
+        let record = EmailRecord::new(user.id, &req.email, "account", "login_link", subject, &body);
+        send_logged(db.get_ref(), &mailer, &message, record, username, "Login link").await?;
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
 
-    let token = jwt::Claims::create_jwt(req.username.clone(), 12)?;
+/// Exchange an emailed login token for the usual JWT.
+#[post("/magic/confirm")]
+async fn confirm_magic_link(
+    db: web::Data<dyn Store>,
+    config: web::Data<Config>,
+    req: web::Json<MagicLinkConfirmRequest>,
+    client: ClientInfo,
+) -> ApiResult<impl Responder> {
+    check_magic_link_enabled(&config)?;
+
+    // taking the token out in the same operation that finds it makes it single-use
+    let code_hash = hash_key(&req.token);
+    let code = db
+        .take_code(&CodeQuery {
+            purpose: Some("magic_link"),
+            code_hash: Some(&code_hash),
+            ..Default::default()
+        })
+        .await?
+        .filter(|code| !code.is_expired())
+        .ok_or(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Invalid or expired login link".to_string(),
+        ))?;
+    let user = db
+        .find_account_in("users", &code.user_id)
+        .await?
+        .ok_or(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Invalid or expired login link".to_string(),
+        ))?;
+    if let Err(e) = user.check_suspension() {
+        record_login(db.get_ref(), &user.id, &client, Some("suspended")).await?;
+        return Err(e);
+    }
 
-    Ok(HttpResponse::Ok().json(LoginResponse { token }))
+    let response = start_session(db.get_ref(), &user, &client).await?;
+    Ok(HttpResponse::Ok().json(response))
 }
 
 pub fn api_scope() -> Scope {
     web::scope("/login")
         .service(login)
         .service(admin_login)
+        .service(request_magic_link)
+        .service(confirm_magic_link)
 }
\ No newline at end of file
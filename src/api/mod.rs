@@ -6,4 +6,56 @@ pub mod stats;
 pub mod problem;
 pub mod send_email;
 pub mod verify_email;
-pub mod users;
\ No newline at end of file
+pub mod users;
+pub mod admins;
+pub mod api_keys;
+pub mod sections;
+pub mod audit;
+pub mod captcha;
+pub mod live;
+pub mod reports;
+pub mod jobs;
+pub mod campaigns;
+pub mod unsubscribe;
+
+use actix_web::{web, ResponseError};
+
+use crate::error::ApiError;
+
+/// Every route of the server, shared by `main` and the tests.
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(register::api_scope())
+        .service(login::api_scope())
+        .service(profile::api_scope())
+        .service(modify::api_scope())
+        .service(stats::api_scope())
+        .service(problem::api_scope())
+        .service(send_email::api_scope())
+        .service(verify_email::api_scope())
+        .service(users::api_scope())
+        .service(admins::api_scope())
+        .service(api_keys::api_scope())
+        .service(sections::api_scope())
+        .service(audit::api_scope())
+        .service(captcha::api_scope())
+        .service(live::api_scope())
+        .service(reports::api_scope())
+        .service(jobs::api_scope())
+        .service(campaigns::api_scope())
+        .service(unsubscribe::api_scope())
+        // the top-level routes above are about the default course
+        .service(
+            web::scope("/courses/{course_id}")
+                .service(stats::api_scope())
+                .service(problem::api_scope())
+                .service(send_email::api_scope())
+                .service(users::api_scope())
+                .service(sections::api_scope())
+                .service(live::api_scope())
+                .service(reports::api_scope())
+                .service(campaigns::api_scope())
+        )
+        .default_service(web::to(|| async {
+            ApiError::new_not_found().error_response()
+        }));
+}
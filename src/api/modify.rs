@@ -1,19 +1,21 @@
 use actix_web::{post, web, HttpResponse, Responder, Scope};
+use lettre::SmtpTransport;
 use serde::{Deserialize, Serialize};
-use mongodb::Database;
-use mongodb::bson::{doc, Document};
-use argon2::{
-    password_hash::{
-        rand_core::OsRng,
-        PasswordHash, PasswordVerifier, SaltString, PasswordHasher
-    },
-    Argon2
-};
+use mongodb::bson::{doc, DateTime};
 
+use crate::audit::Audit;
+use crate::config::Config;
 use crate::jwt::ClaimsValidator;
 use crate::error::{ApiResult, ApiError, ApiErrorType};
-use crate::db::{check_user_exists, check_admin_exists};
-use crate::utils::{check_username, check_password};
+use crate::db::{check_user_exists, check_admin_exists, check_email_exists, count_other_active_admins};
+use crate::mail::{build_message, send_logged};
+use crate::models::{ActivationCode, EmailRecord};
+use crate::repository::{AccountUpdate, CodeQuery, Store};
+use crate::password::PasswordPolicy;
+use crate::utils::{check_username, check_email, check_email_tsinghua, generate_code};
+
+/// Wrong confirmation codes after which the pending email change is dropped.
+const MAX_CODE_FAILURES: i32 = 5;
 
 #[derive(Deserialize)]
 pub struct ModifyUsernameRequest {
@@ -29,6 +31,19 @@ pub struct ModifyPasswordRequest {
     pub new_password: String,
 }
 
+#[derive(Deserialize)]
+pub struct ModifyEmailRequest {
+    pub role: String,
+    pub new_email: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmEmailRequest {
+    pub role: String,
+    pub code: String,
+}
+
 #[derive(Deserialize)]
 pub struct DeleteRequest {
     pub role: String,
@@ -39,6 +54,12 @@ pub struct ModifyResponse {
     pub status: String,
 }
 
+#[derive(Serialize)]
+pub struct DeleteResponse {
+    pub status: String,
+    pub deletion_scheduled_at: String,
+}
+
 fn check_role(role: &str) -> ApiResult<()> {
     if role != "users" && role != "admins" {
         return Err(ApiError::new(
@@ -51,16 +72,18 @@ fn check_role(role: &str) -> ApiResult<()> {
 
 #[post("/username")]
 async fn modify_username(
-    db: web::Data<Database>,
+    db: web::Data<dyn Store>,
     user: ClaimsValidator,
+    audit: Audit,
     req: web::Json<ModifyUsernameRequest>,
+    passwords: web::Data<PasswordPolicy>,
 ) -> ApiResult<impl Responder> {  
     check_role(&req.role)?;
 
     check_username(&req.new_username)?;
 
     // Check if the new username already exists
-    if check_user_exists(&db, &req.new_username).await? || check_admin_exists(&db, &req.new_username).await? {
+    if check_user_exists(db.get_ref(), &req.new_username).await? || check_admin_exists(db.get_ref(), &req.new_username).await? {
         return Err(ApiError::new(
             ApiErrorType::InvalidRequest,
             "Username already exists".to_string(),
@@ -68,9 +91,8 @@ async fn modify_username(
     }
     
     // Find the current user
-    let collection = db.collection::<Document>(&req.role);
-    let user_doc = collection
-        .find_one(doc! { "username": &user.username })
+    let account = db
+        .find_account_in(&req.role, &user.user_id)
         .await?
         .ok_or(ApiError::new(
             ApiErrorType::InvalidRequest,
@@ -78,22 +100,26 @@ async fn modify_username(
         ))?;
     
     // Verify the password
-    let stored_password = user_doc.get_str("password")?;
-    let parsed_hash = PasswordHash::new(stored_password)?;
-    if Argon2::default().verify_password(req.password.as_bytes(), &parsed_hash).is_err() {
+    if !passwords.verify(&req.password, &account.password)? {
         return Err(ApiError::new(
             ApiErrorType::InvalidRequest,
             "Invalid password".to_string(),
         ));
     }
     
-    // Update the username
-    collection
-        .update_one(
-            doc! { "username": &user.username },
-            doc! { "$set": { "username": &req.new_username } },
+    // Update the username; everything else references the account by id, so nothing needs to cascade
+    let update = AccountUpdate { username: Some(req.new_username.clone()), ..Default::default() };
+    db.update_account(&req.role, &user.user_id, &update).await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "account.username",
+            &user.user_id.to_hex(),
+            Some(doc! { "username": &account.username }),
+            Some(doc! { "username": &req.new_username }),
         )
-        .await?;
+        .await;
     
     Ok(HttpResponse::Ok().json(ModifyResponse { 
         status: "success".to_string() 
@@ -102,19 +128,17 @@ async fn modify_username(
 
 #[post("/password")]
 async fn modify_password(
-    db: web::Data<Database>,
+    db: web::Data<dyn Store>,
     user: ClaimsValidator,
+    audit: Audit,
     req: web::Json<ModifyPasswordRequest>,
+    passwords: web::Data<PasswordPolicy>,
 ) -> ApiResult<impl Responder> {  
     check_role(&req.role)?;
-    
-    check_password(&req.new_password)?;
 
-    let collection = db.collection::<Document>(&req.role);
-    
     // Find the current user
-    let user_doc = collection
-        .find_one(doc! { "username": &user.username })
+    let account = db
+        .find_account_in(&req.role, &user.user_id)
         .await?
         .ok_or(ApiError::new(
             ApiErrorType::InvalidRequest,
@@ -122,48 +146,252 @@ async fn modify_password(
         ))?;
     
     // Verify the current password
-    let stored_password = user_doc.get_str("password")?;
-    let parsed_hash = PasswordHash::new(stored_password)?;
-    if Argon2::default().verify_password(req.current_password.as_bytes(), &parsed_hash).is_err() {
+    if !passwords.verify(&req.current_password, &account.password)? {
         return Err(ApiError::new(
             ApiErrorType::InvalidRequest,
             "Invalid current password".to_string(),
         ));
     }
     
+    passwords.check(&req.new_password, &account.username)?;
+
     // Hash the new password
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(req.new_password.as_bytes(), &salt)?
-        .to_string();
+    let password_hash = passwords.hash(&req.new_password)?;
     
     // Update the password
-    collection
-        .update_one(
-            doc! { "username": &user.username },
-            doc! { "$set": { "password": password_hash } },
-        )
-        .await?;
+    let update = AccountUpdate {
+        password: Some(password_hash),
+        must_change_password: Some(false),
+        ..Default::default()
+    };
+    db.update_account(&req.role, &user.user_id, &update).await?;
+    // whoever knew the old password may be logged in elsewhere
+    db.revoke_sessions(&user.user_id, user.session_id.as_ref()).await?;
+    audit
+        .record(db.get_ref(), &user.user_id, "account.password", &user.user_id.to_hex(), None, None)
+        .await;
     
     Ok(HttpResponse::Ok().json(ModifyResponse { 
         status: "success".to_string() 
     }))
 }
 
+#[post("/email")]
+async fn modify_email(
+    db: web::Data<dyn Store>,
+    mailer: web::Data<SmtpTransport>,
+    config: web::Data<Config>,
+    user: ClaimsValidator,
+    req: web::Json<ModifyEmailRequest>,
+    passwords: web::Data<PasswordPolicy>,
+) -> ApiResult<impl Responder> {  
+    check_role(&req.role)?;
+
+    // students are held to the same domain policy as at registration
+    if req.role == "users" {
+        check_email_tsinghua(&req.new_email)?;
+    } else {
+        check_email(&req.new_email)?;
+    }
+
+    if check_email_exists(db.get_ref(), &req.new_email).await? {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Email already exists".to_string(),
+        ));
+    }
+
+    let account = db
+        .find_account_in(&req.role, &user.user_id)
+        .await?
+        .ok_or(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "User not found".to_string(),
+        ))?;
+
+    // Verify the password
+    if !passwords.verify(&req.password, &account.password)? {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Invalid password".to_string(),
+        ));
+    }
+
+    let username = &account.username;
+    let code = generate_code(6);
+    let subject = "Confirm your new YWT email address";
+    let body = format!("Hello {},\n\nYour confirmation code is {}\n\nThis code will expire in 30 minutes.\n\nBest regards,\nYWT Team",
+        username, code);
+    let message = build_message(&config, "account", username, &req.new_email, subject, body.clone())?;
+
+    // only one pending change per account, a new request replaces the old code
+    let mut pending = ActivationCode::new(user.user_id, Some("email_change"), 30);
+    pending.code = Some(code);
+    pending.new_email = Some(req.new_email.clone());
+    db.replace_code(&pending).await?;
+
+    let record = EmailRecord::new(user.user_id, &req.new_email, "account", "email_change", subject, &body);
+    send_logged(db.get_ref(), &mailer, &message, record, username, "Email confirmation").await?;
+
+    Ok(HttpResponse::Ok().json(ModifyResponse { 
+        status: "success".to_string() 
+    }))
+}
+
+#[post("/email/confirm")]
+async fn confirm_email(
+    db: web::Data<dyn Store>,
+    mailer: web::Data<SmtpTransport>,
+    config: web::Data<Config>,
+    user: ClaimsValidator,
+    audit: Audit,
+    req: web::Json<ConfirmEmailRequest>,
+) -> ApiResult<impl Responder> {  
+    check_role(&req.role)?;
+
+    let query = CodeQuery {
+        user_id: Some(user.user_id),
+        purpose: Some("email_change"),
+        code: Some(&req.code),
+        ..Default::default()
+    };
+    let Some(code) = db.find_code(&query).await? else {
+        // a pending code is dropped after a few wrong guesses, the owner has to request a new one
+        let pending = CodeQuery { code: None, ..query };
+        if db.count_failure(&pending).await?.is_some_and(|failures| failures >= MAX_CODE_FAILURES) {
+            db.take_code(&pending).await?;
+            return Err(ApiError::new(
+                ApiErrorType::TooManyRequests,
+                "Too many wrong confirmation codes, request a new one".to_string(),
+            ));
+        }
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Invalid confirmation code".to_string(),
+        ));
+    };
+    if code.is_expired() {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Confirmation code has expired".to_string(),
+        ));
+    }
+
+    // the address may have been taken while the code was pending
+    let new_email = code.new_email.as_deref().unwrap_or_default();
+    if check_email_exists(db.get_ref(), new_email).await? {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Email already exists".to_string(),
+        ));
+    }
+
+    let account = db
+        .find_account_in(&req.role, &user.user_id)
+        .await?
+        .ok_or(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "User not found".to_string(),
+        ))?;
+    // taking the code first keeps two concurrent confirmations from both applying it
+    if db.take_code(&query).await?.is_none() {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Invalid confirmation code".to_string(),
+        ));
+    }
+    let update = AccountUpdate { email: Some(new_email.to_string()), ..Default::default() };
+    db.update_account(&req.role, &user.user_id, &update).await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "account.email",
+            &user.user_id.to_hex(),
+            Some(doc! { "email": &account.email }),
+            Some(doc! { "email": new_email }),
+        )
+        .await;
+
+    // let the previous owner of the address know, in case the change was not theirs
+    let username = &account.username;
+    let old_email = &account.email;
+    let subject = "Your YWT email address was changed";
+    let body = format!("Hello {},\n\nThe email address of your YWT account was changed to {}.\n\nIf you did not make this change, please contact the course staff.\n\nBest regards,\nYWT Team",
+        username, new_email);
+    let message = build_message(&config, "account", username, old_email, subject, body.clone())?;
+    let record = EmailRecord::new(account.id, old_email, "account", "email_changed", subject, &body);
+    send_logged(db.get_ref(), &mailer, &message, record, username, "Email change notice").await?;
+
+    Ok(HttpResponse::Ok().json(ModifyResponse { 
+        status: "success".to_string() 
+    }))
+}
+
 #[post("/delete")]
 async fn delete_user(
-    db: web::Data<Database>,
+    db: web::Data<dyn Store>,
+    config: web::Data<Config>,
     user: ClaimsValidator,
+    audit: Audit,
     req: web::Json<DeleteRequest>,
 ) -> ApiResult<impl Responder> {  
     check_role(&req.role)?;
 
-    let collection = db.collection::<Document>(&req.role);
+    if req.role == "admins" && count_other_active_admins(db.get_ref(), &user.user_id).await? == 0 {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Cannot remove the last active admin".to_string(),
+        ));
+    }
+
+    // The account and everything it references is purged once the grace period ends,
+    // until then the owner can still log in and cancel the deletion.
+    let scheduled_at = chrono::Utc::now() + chrono::Duration::days(config.deletion_grace_days);
+    let update = AccountUpdate {
+        deletion_scheduled_at: Some(Some(DateTime::from_millis(scheduled_at.timestamp_millis()))),
+        ..Default::default()
+    };
+    if !db.update_account(&req.role, &user.user_id, &update).await? {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "User not found".to_string(),
+        ));
+    }
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "account.delete",
+            &user.user_id.to_hex(),
+            None,
+            Some(doc! { "deletion_scheduled_at": DateTime::from_millis(scheduled_at.timestamp_millis()) }),
+        )
+        .await;
+    
+    Ok(HttpResponse::Ok().json(DeleteResponse { 
+        status: "success".to_string(),
+        deletion_scheduled_at: scheduled_at.with_timezone(&chrono::Local).to_string(),
+    }))
+}
+
+#[post("/delete/cancel")]
+async fn cancel_delete_user(
+    db: web::Data<dyn Store>,
+    user: ClaimsValidator,
+    req: web::Json<DeleteRequest>,
+) -> ApiResult<impl Responder> {  
+    check_role(&req.role)?;
 
-    collection
-        .delete_one(doc! { "username": &user.username })
-        .await?;
+    let account = db.find_account_in(&req.role, &user.user_id).await?;
+    if account.and_then(|account| account.deletion_scheduled_at).is_none() {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "No pending deletion".to_string(),
+        ));
+    }
+    let update = AccountUpdate { deletion_scheduled_at: Some(None), ..Default::default() };
+    db.update_account(&req.role, &user.user_id, &update).await?;
     
     Ok(HttpResponse::Ok().json(ModifyResponse { 
         status: "success".to_string() 
@@ -174,5 +402,8 @@ pub fn api_scope() -> Scope {
     web::scope("/modify")
         .service(modify_username)
         .service(modify_password)
+        .service(modify_email)
+        .service(confirm_email)
         .service(delete_user)
+        .service(cancel_delete_user)
 }
@@ -1,11 +1,13 @@
+use std::collections::{BTreeSet, HashMap};
+
 use actix_web::{get, web, HttpResponse, Responder, Scope};
 use serde::{Deserialize, Serialize};
-use mongodb::{Database, Collection};
-use mongodb::bson::{doc, Document};
 use base64::{Engine, engine::general_purpose};
 
-use crate::jwt::ClaimsValidator;
+use crate::api_key::Caller;
+use crate::course::Course;
 use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::repository::Store;
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ProblemResponse {
@@ -20,38 +22,38 @@ pub struct QBankEntry {
     pub path: String,
 }
 
+/// Question bank entries of every course, keyed by course id.
+pub type QBanks = HashMap<String, Vec<QBankEntry>>;
+
+/// Named so the route also works below `/courses/{course_id}`.
+#[derive(Deserialize)]
+pub struct ProblemPath {
+    pub problem_id: String,
+}
+
+#[derive(Serialize)]
+pub struct TagsResponse {
+    pub tags: Vec<String>,
+}
+
 #[get("/get/{problem_id}")]
 async fn get_problem(
-    db: web::Data<Database>,
-    _user: ClaimsValidator,
-    path: web::Path<String>,
+    db: web::Data<dyn Store>,
+    caller: Caller,
+    course: Course,
+    path: web::Path<ProblemPath>,
 ) -> ApiResult<impl Responder> {
-    let problem_id = path.into_inner();
-    
-    let collection: Collection<Document> = db.collection("qbank");
-    let problem = collection
-        .find_one(doc! { "_id": &problem_id })
-        .await?;
+    caller.authorize("problems:read")?;
+    let problem_id = path.into_inner().problem_id;
     
+    let problem = db.find_problem(&course.config.qbank_collection, &problem_id).await?;
+
     match problem {
-        Some(doc) => {
-            let tags = match doc.get_array("tags") {
-                Ok(tags_array) => {
-                    tags_array
-                        .iter()
-                        .filter_map(|tag| tag.as_str().map(|s| s.to_string()))
-                        .collect::<Vec<String>>()
-                }
-                Err(_) => Vec::new(),
-            };
-            
-            let image = match doc.get_binary_generic("image") {
-                Ok(binary) => {
-                    // Use standard library base64 encoding
-                    let base64_string = general_purpose::STANDARD.encode(binary);
-                    base64_string
-                }
-                Err(_) => {
+        Some(problem) => {
+            let image = match problem.image {
+                // Use standard library base64 encoding
+                Some(image) => general_purpose::STANDARD.encode(image.bytes),
+                None => {
                     return Err(ApiError::new(
                         ApiErrorType::Internal,
                         "Failed to extract image data".to_string(),
@@ -59,7 +61,7 @@ async fn get_problem(
                 }
             };
             
-            Ok(HttpResponse::Ok().json(ProblemResponse { tags, image }))
+            Ok(HttpResponse::Ok().json(ProblemResponse { tags: problem.tags, image }))
         }
         None => Err(ApiError::new(
             ApiErrorType::NotFound,
@@ -70,14 +72,38 @@ async fn get_problem(
 
 #[get("/qbank")]
 async fn get_qbank(
-    qbank_data: web::Data<Vec<QBankEntry>>,
-    _user: ClaimsValidator,
+    qbanks: web::Data<QBanks>,
+    caller: Caller,
+    course: Course,
+) -> ApiResult<impl Responder> {
+    caller.authorize("problems:read")?;
+    Ok(HttpResponse::Ok().json(&qbanks[&course.id]))
+}
+
+/// Tag vocabulary of the course: the configured tags, or every tag used in its question bank.
+#[get("/tags")]
+async fn get_tags(
+    qbanks: web::Data<QBanks>,
+    caller: Caller,
+    course: Course,
 ) -> ApiResult<impl Responder> {
-    Ok(HttpResponse::Ok().json(qbank_data.get_ref()))
+    caller.authorize("problems:read")?;
+    let tags = if course.config.tags.is_empty() {
+        qbanks[&course.id]
+            .iter()
+            .flat_map(|entry| entry.tags.iter().cloned())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    } else {
+        course.config.tags.clone()
+    };
+    Ok(HttpResponse::Ok().json(TagsResponse { tags }))
 }
 
 pub fn api_scope() -> Scope {
     web::scope("/problem")
         .service(get_problem)
         .service(get_qbank)
+        .service(get_tags)
 }
\ No newline at end of file
@@ -1,51 +1,468 @@
-use actix_web::{get, web, HttpResponse, Responder, Scope};
+use std::collections::BTreeMap;
+
+use actix_web::{get, post, http::header, web, HttpResponse, Responder, Scope};
 use serde::{Deserialize, Serialize};
-use mongodb::Database;
-use mongodb::bson::{doc, Document};
+use mongodb::bson::Bson;
+use mongodb::bson::oid::ObjectId;
 
+use crate::config::Config;
+use crate::models::Account;
+use crate::repository::{AccountUpdate, Store};
 use crate::jwt::ClaimsValidator;
+use crate::notification::{self, CATEGORIES};
+use crate::permission::{admin_permissions, ROLES};
 use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::utils::{check_profile_field, format_datetime};
 use crate::db::{
-    check_user_exists,
-    check_admin_exists,
+    check_user_id_exists,
+    check_admin_id_exists,
+    enroll_user,
 };
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct ProfileResponse {
+    pub user_id: String,
     pub username: String,
     pub email: String,
     pub created_at: String,
+    pub student_id: Option<String>,
+    pub real_name: Option<String>,
+    /// Section in the default course, see `/profile/courses` for the others
+    pub section: Option<String>,
+    pub preferred_language: Option<String>,
+    pub avatar: Option<String>,
+    /// Role and permissions of staff accounts, so the frontend can hide unavailable actions
+    pub staff_role: Option<String>,
+    pub permissions: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deletion_scheduled_at: Option<String>,
+}
+
+/// The profile fields a student may edit; `student_id` is set by admins and sections by instructors.
+#[derive(Deserialize)]
+pub struct UpdateProfileRequest {
+    pub real_name: Option<String>,
+    pub preferred_language: Option<String>,
+    pub avatar: Option<String>,
+}
+
+/// Turn the given profile fields into an update: omitted fields are kept, empty ones removed.
+pub fn profile_update(fields: &[(&str, &Option<String>)]) -> ApiResult<AccountUpdate> {
+    let mut update = AccountUpdate::default();
+    let mut changed = false;
+    for (field, value) in fields {
+        if let Some(value) = value {
+            check_profile_field(field, value)?;
+            if let Some(slot) = update.profile_field(field) {
+                *slot = Some((!value.is_empty()).then(|| value.clone()));
+                changed = true;
+            }
+        }
+    }
+    if !changed {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Nothing to update".to_string(),
+        ));
+    }
+    Ok(update)
+}
+
+#[derive(Serialize)]
+pub struct ExportResponse {
+    pub exported_at: String,
+    pub account: serde_json::Value,
+    /// Stats of every course the account took
+    pub stats: Vec<serde_json::Value>,
+    /// Emails sent to the account, newest first
+    pub emails: Vec<serde_json::Value>,
+    /// Every other record kept about the account, by collection: sessions, login history, campaign
+    /// deliveries, mail threads, pending codes, synced stats events and the sections it assists in
+    pub records: BTreeMap<String, Vec<serde_json::Value>>,
+}
+
+#[derive(Serialize)]
+pub struct CourseEntry {
+    pub id: String,
+    pub name: String,
+    pub enrolled: bool,
+    pub section: Option<String>,
+    pub requires_key: bool,
+}
+
+#[derive(Serialize)]
+pub struct GetCoursesResponse {
+    pub courses: Vec<CourseEntry>,
+}
+
+#[derive(Serialize)]
+pub struct SessionEntry {
+    pub id: String,
+    pub created_at: String,
+    pub last_seen_at: String,
+    pub expires_at: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    /// Whether this is the session of the request's token
+    pub current: bool,
+}
+
+#[derive(Serialize)]
+pub struct GetSessionsResponse {
+    pub sessions: Vec<SessionEntry>,
+}
+
+#[derive(Deserialize)]
+pub struct RevokeSessionRequest {
+    pub session_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct GetLoginHistoryQuery {
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct LoginHistoryEntry {
+    pub at: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub success: bool,
+    /// Why a failed login was refused, `invalid_password` or `suspended`
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GetLoginHistoryResponse {
+    pub entries: Vec<LoginHistoryEntry>,
+}
+
+#[derive(Deserialize)]
+pub struct EnrollCourseRequest {
+    pub course_id: String,
+    pub enrollment_key: Option<String>,
+}
+
+/// Find the account of the token owner, whichever collection it lives in.
+async fn find_account(db: &dyn Store, user_id: &ObjectId) -> ApiResult<Account> {
+    db.find_account(user_id)
+        .await?
+        .ok_or(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "User not found".to_string(),
+        ))
+}
+
+/// Name of the section the student is in for `course_id`.
+async fn section_name(db: &dyn Store, user: &Account, course_id: &str) -> ApiResult<Option<String>> {
+    if user.section_ids.is_empty() {
+        return Ok(None);
+    }
+    let section = db
+        .list_sections(course_id)
+        .await?
+        .into_iter()
+        .find(|section| user.section_ids.contains(&section.id));
+    Ok(section.map(|section| section.name))
 }
 
 #[get("")]
 async fn profile(
-    db: web::Data<Database>,
-    user: ClaimsValidator,
-) -> ApiResult<impl Responder> {  
-    let username = user.username;
-    let collection = match check_user_exists(&db, &username).await {
-        Ok(true) => db.collection::<Document>("users"),
-        Ok(false) => match check_admin_exists(&db, &username).await {
-            Ok(true) => db.collection::<Document>("admins"),
-            Ok(false) => return Err(ApiError::new(ApiErrorType::InvalidRequest, "User not found".to_string())),
-            Err(_) => return Err(ApiError::new(ApiErrorType::Internal, "Database error".to_string())),
-        },
-        Err(_) => return Err(ApiError::new(ApiErrorType::Internal, "Database error".to_string())),
+    db: web::Data<dyn Store>,
+    config: web::Data<Config>,
+    user: ClaimsValidator,
+) -> ApiResult<impl Responder> {
+    let user_id = user.user_id;
+    let user = find_account(db.get_ref(), &user_id).await?;
+
+    let section = section_name(db.get_ref(), &user, &config.default_course().id).await?;
+    let (staff_role, permissions) = if check_admin_id_exists(db.get_ref(), &user_id).await? {
+        (user.staff_role.clone().or(Some(ROLES[0].0.to_string())), admin_permissions(&user))
+    } else {
+        (None, &[][..])
     };
-    let user: Document = collection
-        .find_one(doc! { "username": &username })
+    let deletion_scheduled_at = user.deletion_scheduled_at.as_ref().map(format_datetime);
+
+    Ok(HttpResponse::Ok().json(ProfileResponse {
+        user_id: user_id.to_hex(),
+        username: user.username,
+        created_at: user.created_at,
+        email: user.email,
+        student_id: user.student_id,
+        real_name: user.real_name,
+        section,
+        preferred_language: user.preferred_language,
+        avatar: user.avatar,
+        staff_role,
+        permissions: permissions.iter().map(|p| p.to_string()).collect(),
+        deletion_scheduled_at,
+    }))
+}
+
+#[post("/update")]
+async fn update_profile(
+    db: web::Data<dyn Store>,
+    user: ClaimsValidator,
+    req: web::Json<UpdateProfileRequest>,
+) -> ApiResult<impl Responder> {
+    let update = profile_update(&[
+        ("real_name", &req.real_name),
+        ("preferred_language", &req.preferred_language),
+        ("avatar", &req.avatar),
+    ])?;
+
+    if !db.update_account("users", &user.user_id, &update).await? {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "User not found".to_string(),
+        ));
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+#[get("/export")]
+async fn export(
+    db: web::Data<dyn Store>,
+    user: ClaimsValidator,
+) -> ApiResult<impl Responder> {
+    // the export holds every stored field, not only the ones the server reads
+    let account = db
+        .export_account(&user.user_id)
         .await?
         .ok_or(ApiError::new(
             ApiErrorType::InvalidRequest,
             "User not found".to_string(),
         ))?;
-    
-    let email = user.get_str("email")?.to_string();
-    let created_at = user.get_str("created_at")?.to_string();
+    let stats = db
+        .account_stats(&user.user_id)
+        .await?
+        .into_iter()
+        .map(|stats| serde_json::json!({
+            "course_id": stats.course_id,
+            "conversation": stats.conversation,
+            "tags": stats.tags,
+            "messages": stats.messages,
+            "problems_viewed": stats.problems_viewed,
+        }))
+        .collect();
+    let emails = db
+        .list_emails(&user.user_id, i64::MAX)
+        .await?
+        .into_iter()
+        .map(|record| serde_json::json!({
+            "email": record.email,
+            "kind": record.kind,
+            "template": record.template,
+            "course_id": record.course_id,
+            "subject": record.subject,
+            "body": record.body,
+            "status": record.status,
+            "created_at": format_datetime(&record.created_at),
+            "sent_at": record.sent_at.as_ref().map(format_datetime),
+        }))
+        .collect();
+
+    // stats and emails are listed above in a readable form
+    let records = db
+        .export_records(&user.user_id)
+        .await?
+        .into_iter()
+        .filter(|(collection, _)| !["stats", "emails"].contains(&collection.as_str()))
+        .map(|(collection, documents)| {
+            let documents = documents.into_iter().map(|document| Bson::Document(document).into_relaxed_extjson()).collect();
+            (collection, documents)
+        })
+        .collect();
+
+    let exported_at = chrono::Local::now().to_string();
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"ywt-export-{}.json\"", user.user_id.to_hex()),
+        ))
+        .json(ExportResponse {
+            exported_at,
+            account: Bson::Document(account).into_relaxed_extjson(),
+            stats,
+            emails,
+            records,
+        }))
+}
+
+#[get("/courses")]
+async fn get_courses(
+    db: web::Data<dyn Store>,
+    config: web::Data<Config>,
+    user: ClaimsValidator,
+) -> ApiResult<impl Responder> {
+    let account = find_account(db.get_ref(), &user.user_id).await?;
+    let enrolled_in = account.courses();
+
+    let mut courses = Vec::new();
+    for course in &config.courses {
+        courses.push(CourseEntry {
+            id: course.id.clone(),
+            name: course.name.clone(),
+            enrolled: enrolled_in.contains(&course.id),
+            section: section_name(db.get_ref(), &account, &course.id).await?,
+            requires_key: course.enrollment_key.is_some(),
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(GetCoursesResponse { courses }))
+}
+
+#[post("/courses/enroll")]
+async fn enroll_course(
+    db: web::Data<dyn Store>,
+    config: web::Data<Config>,
+    user: ClaimsValidator,
+    req: web::Json<EnrollCourseRequest>,
+) -> ApiResult<impl Responder> {
+    let course = config.course(&req.course_id).ok_or_else(ApiError::new_not_found)?;
+    // only students take courses, staff are assigned to them by instructors
+    if !check_user_id_exists(db.get_ref(), &user.user_id).await? {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Only students can enroll".to_string(),
+        ));
+    }
+    if let Some(key) = &course.enrollment_key {
+        if req.enrollment_key.as_ref() != Some(key) {
+            return Err(ApiError::new(
+                ApiErrorType::Forbidden,
+                "Invalid enrollment key".to_string(),
+            ));
+        }
+    }
+
+    enroll_user(db.get_ref(), "users", &user.user_id, &course.id).await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+/// Whether the caller gets each kind of email, see `notification::CATEGORIES`.
+fn notification_preferences(user: &Account) -> BTreeMap<&'static str, bool> {
+    CATEGORIES.iter().map(|category| (*category, notification::accepts(user, category))).collect()
+}
+
+#[get("/notifications")]
+async fn get_notifications(
+    db: web::Data<dyn Store>,
+    user: ClaimsValidator,
+) -> ApiResult<impl Responder> {
+    let user = find_account(db.get_ref(), &user.user_id).await?;
+
+    Ok(HttpResponse::Ok().json(notification_preferences(&user)))
+}
+
+/// Turn kinds of email on or off, the kinds left out are kept as they are.
+#[post("/notifications")]
+async fn update_notifications(
+    db: web::Data<dyn Store>,
+    user: ClaimsValidator,
+    req: web::Json<BTreeMap<String, bool>>,
+) -> ApiResult<impl Responder> {
+    let mut account = find_account(db.get_ref(), &user.user_id).await?;
+    for (category, enabled) in req.iter() {
+        if !CATEGORIES.contains(&category.as_str()) {
+            return Err(ApiError::new(
+                ApiErrorType::InvalidRequest,
+                format!("Unknown notification {}", category),
+            ));
+        }
+        account.unsubscribed.retain(|unsubscribed| unsubscribed != category);
+        if !enabled {
+            account.unsubscribed.push(category.clone());
+        }
+    }
+    let update = AccountUpdate {
+        unsubscribed: Some(account.unsubscribed.clone()),
+        ..Default::default()
+    };
+    if !db.update_account("users", &user.user_id, &update).await? {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "User not found".to_string(),
+        ));
+    }
 
-    Ok(HttpResponse::Ok().json(ProfileResponse { username, created_at, email }))
+    Ok(HttpResponse::Ok().json(notification_preferences(&account)))
+}
+
+/// Open sessions of the caller, newest first.
+#[get("/sessions")]
+async fn get_sessions(
+    db: web::Data<dyn Store>,
+    user: ClaimsValidator,
+) -> ApiResult<impl Responder> {
+    let sessions = db
+        .open_sessions(&user.user_id)
+        .await?
+        .into_iter()
+        .map(|session| SessionEntry {
+            id: session.id.to_hex(),
+            created_at: format_datetime(&session.created_at),
+            last_seen_at: format_datetime(&session.last_seen_at),
+            expires_at: format_datetime(&session.expires_at),
+            ip: session.ip,
+            user_agent: session.user_agent,
+            current: user.session_id == Some(session.id),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(GetSessionsResponse { sessions }))
+}
+
+#[post("/sessions/revoke")]
+async fn revoke_session(
+    db: web::Data<dyn Store>,
+    user: ClaimsValidator,
+    req: web::Json<RevokeSessionRequest>,
+) -> ApiResult<impl Responder> {
+    let session_id = ObjectId::parse_str(&req.session_id).map_err(|_| ApiError::new_not_found())?;
+    if !db.revoke_session(&session_id, &user.user_id).await? {
+        return Err(ApiError::new_not_found());
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+/// Login attempts on the caller's account, newest first.
+#[get("/login_history")]
+async fn get_login_history(
+    db: web::Data<dyn Store>,
+    user: ClaimsValidator,
+    query: web::Query<GetLoginHistoryQuery>,
+) -> ApiResult<impl Responder> {
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+    let entries = db
+        .login_history(&user.user_id, limit)
+        .await?
+        .into_iter()
+        .map(|attempt| LoginHistoryEntry {
+            at: format_datetime(&attempt.at),
+            ip: attempt.ip,
+            user_agent: attempt.user_agent,
+            success: attempt.success,
+            reason: attempt.reason,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(GetLoginHistoryResponse { entries }))
 }
 
 pub fn api_scope() -> Scope {
-    web::scope("/profile").service(profile)
-}
\ No newline at end of file
+    web::scope("/profile")
+        .service(profile)
+        .service(update_profile)
+        .service(export)
+        .service(get_courses)
+        .service(enroll_course)
+        .service(get_sessions)
+        .service(revoke_session)
+        .service(get_login_history)
+        .service(get_notifications)
+        .service(update_notifications)
+}
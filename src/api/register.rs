@@ -1,29 +1,36 @@
 use actix_web::{post, web, HttpResponse, Responder, Scope};
-use rand::distr::Alphanumeric;
 use serde::{Deserialize, Serialize};
-use mongodb::Database;
 use mongodb::bson::doc;
-use lettre::{Message, SmtpTransport, Transport};
-use lettre::message::header::ContentType;
-use rand::{rng, Rng};
+use lettre::SmtpTransport;
 
 use crate::error::{ApiResult, ApiError, ApiErrorType};
-use crate::jwt::ClaimsValidator;
+use crate::permission::{Authorized, AdminsManage};
 use crate::db::{
     check_user_exists,
     check_admin_exists,
     check_email_exists,
     create_user,
+    enroll_user,
     AdminType, UserType
 };
+use crate::audit::Audit;
+use crate::captcha::{self, CaptchaAnswer};
 use crate::config::Config;
-use crate::utils::{check_email, check_username, check_password, check_email_tsinghua};
+use crate::live::LiveHub;
+use crate::mail::{build_message, send_logged};
+use crate::models::{ActivationCode, EmailRecord};
+use crate::repository::Store;
+use crate::password::PasswordPolicy;
+use crate::utils::{check_email, check_username, check_email_tsinghua, generate_code};
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct RegisterRequest {
     pub username: String,
     pub email: String,
     pub password: String,
+    /// Answer to a challenge from `/captcha`, needed when captchas are enabled
+    #[serde(default)]
+    pub captcha: Option<CaptchaAnswer>,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -31,113 +38,99 @@ pub struct RegisterResponse {
     pub created_at: String,
 }
 
-fn check_req(req: &RegisterRequest, tsinghua: bool) -> ApiResult<()> {
+fn check_req(req: &RegisterRequest, tsinghua: bool, passwords: &PasswordPolicy) -> ApiResult<()> {
     check_username(&req.username)?;
     if tsinghua {
         check_email_tsinghua(&req.email)?;
     } else {
         check_email(&req.email)?;
     }
-    check_password(&req.password)?;
+    passwords.check(&req.password, &req.username)?;
     Ok(())
 }
 
 #[post("")]
 async fn register(
-    db: web::Data<Database>,
+    db: web::Data<dyn Store>,
     req: web::Json<RegisterRequest>,
     mailer: web::Data<SmtpTransport>,
     config: web::Data<Config>,
+    passwords: web::Data<PasswordPolicy>,
+    hub: web::Data<LiveHub>,
 ) -> ApiResult<impl Responder> {
-    check_req(&req, true)?;
+    check_req(&req, true, &passwords)?;
+    if config.captcha.enabled {
+        captcha::verify(db.get_ref(), req.captcha.as_ref()).await?;
+    }
 
-    if check_admin_exists(&db, &req.username).await? || check_user_exists(&db, &req.username).await? {
+    if check_admin_exists(db.get_ref(), &req.username).await? || check_user_exists(db.get_ref(), &req.username).await? {
         return Err(ApiError::new(
             ApiErrorType::InvalidRequest,
             "Username already exists".to_string(),
         ));
     }
     
-    if check_email_exists(&db, &req.email).await? {
+    if check_email_exists(db.get_ref(), &req.email).await? {
         return Err(ApiError::new(
             ApiErrorType::InvalidRequest,
             "Email already exists".to_string(),
         ));
     }
 
-    let created_at = chrono::Local::now().to_string();
-    create_user::<UserType>(&db, &req.username, &req.email, &req.password, &created_at).await?;
-
-    let collection = db.collection("stats");
-    let tag_doc = doc! {
-        "username": &req.username,
-        "conversation": 0,
-        "tags": {}, // Initialize with an empty object
-    };
-    collection.insert_one(tag_doc).await?;
-
     // generate activation code - random 6-character string
-    let activation_code: String = rng()
-        .sample_iter(&Alphanumeric)
-        .take(6)
-        .map(char::from)
-        .collect();
+    let activation_code = generate_code(6);
     
+    // the message is built first, so an address it cannot be sent to leaves no account behind
+    let subject = "Activate your YWT account";
+    let body = format!("Hello {},\n\nYour activation code is {}\n\nThis code will expire in 30 minutes.\n\nBest regards,\nYWT Team",
+        req.username, activation_code);
+    let message = build_message(&config, "account", &req.username, &req.email, subject, body.clone())?;
+
+    let created_at = chrono::Local::now().to_string();
+    let password_hash = passwords.hash(&req.password)?;
+    let user_id = create_user::<UserType>(db.get_ref(), &req.username, &req.email, &password_hash, &created_at, None, None).await?;
+
+    // new students take the default course, other courses are joined from the profile
+    enroll_user(db.get_ref(), "tmp_users", &user_id, &config.default_course().id).await?;
+    // new students have no section yet, so only staff seeing the whole course get this
+    hub.publish(
+        &config.default_course().id,
+        None,
+        "registration",
+        serde_json::json!({ "username": &req.username }),
+    );
+
     // Store the activation code in the database
-    let activation_collection = db.collection("activation_codes");
-    let activation_doc = doc! {
-        "username": &req.username,
-        "code": &activation_code,
-        "created_at": &created_at,
-        "expires_at": (chrono::Local::now() + chrono::Duration::minutes(30)).to_string(),
-    };
-    activation_collection.insert_one(activation_doc).await?;
+    let mut code = ActivationCode::new(user_id, None, 30);
+    code.code = Some(activation_code);
+    db.replace_code(&code).await?;
 
     // send activation email
-    let sender = format!("YWT Bot <{}>", config.smtp_username);
-    let to = format!("{} <{}>", req.username, req.email);
-    let email = Message::builder()
-        .from(sender.parse().unwrap())
-        .to(to.parse().unwrap())
-        .subject("Activate your YWT account")
-        .header(ContentType::TEXT_PLAIN)
-        .body(format!("Hello {},\n\nYour activation code is {}\n\nThis code will expire in 30 minutes.\n\nBest regards,\nYWT Team", 
-            req.username, activation_code))
-        .unwrap();
-    
-    match mailer.send(&email) {
-        Ok(_) => log::info!("Activation email sent to {}", req.username),
-        Err(e) => log::error!("Failed to send activation email to {}: {}", req.username, e),
-    }
+    let record = EmailRecord::new(user_id, &req.email, "account", "activation", subject, &body);
+    send_logged(db.get_ref(), &mailer, &message, record, &req.username, "Activation email").await?;
 
     Ok(HttpResponse::Ok().json(RegisterResponse { created_at }))
 }
 
 #[post("/admin")]
 async fn admin_register(
-    db: web::Data<Database>,
+    db: web::Data<dyn Store>,
     req: web::Json<RegisterRequest>,
-    user: ClaimsValidator,
+    user: Authorized<AdminsManage>,
+    audit: Audit,
+    passwords: web::Data<PasswordPolicy>,
 ) -> ApiResult<impl Responder> {
-    // check if user is admin
-    if !check_admin_exists(&db, &user.username).await? {
-        return Err(ApiError::new(
-            ApiErrorType::InvalidRequest,
-            "Error".to_string(),
-        ));
-    }
-    
-    check_req(&req, false)?;
+    check_req(&req, false, &passwords)?;
 
     // check if user with the same username exists
-    if check_user_exists(&db, &req.username).await? {
+    if check_user_exists(db.get_ref(), &req.username).await? {
         return Err(ApiError::new(
             ApiErrorType::InvalidRequest,
             "Error".to_string(),
         ));
     }
 
-    if check_admin_exists(&db, &req.username).await? {
+    if check_admin_exists(db.get_ref(), &req.username).await? {
         return Err(ApiError::new(
             ApiErrorType::InvalidRequest,
             "Error".to_string(),
@@ -145,7 +138,28 @@ async fn admin_register(
     }
 
     let created_at = chrono::Local::now().to_string();
-    create_user::<AdminType>(&db, &req.username, &req.email, &req.password, &created_at).await?;
+    let password_hash = passwords.hash(&req.password)?;
+    // new staff start as instructors, see `/admins/role`
+    create_user::<AdminType>(
+        db.get_ref(),
+        &req.username,
+        &req.email,
+        &password_hash,
+        &created_at,
+        Some(&user.user_id),
+        Some("instructor"),
+    )
+    .await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "admins.create",
+            &req.username,
+            None,
+            Some(doc! { "username": &req.username, "email": &req.email, "staff_role": "instructor" }),
+        )
+        .await;
 
     Ok(HttpResponse::Ok().json(RegisterResponse { created_at }))
 }
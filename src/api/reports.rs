@@ -0,0 +1,127 @@
+use actix_web::{get, post, web, HttpResponse, Responder, Scope};
+use mongodb::bson::doc;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::Audit;
+use crate::config::Config;
+use crate::course::Course;
+use crate::db;
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::models::ReportRun;
+use crate::permission::{Authorized, EmailBroadcast};
+use crate::report::{self, ReportSchedule};
+use crate::repository::Store;
+use crate::utils::format_datetime;
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Deserialize)]
+pub struct GetReportRunsQuery {
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ReportRunEntry {
+    pub id: String,
+    pub scheduled_for: Option<String>,
+    /// Username of the admin who started the run, `None` for scheduled runs
+    pub triggered_by: Option<String>,
+    /// Job delivering the report, see `/jobs/get`
+    pub job_id: Option<String>,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+    pub status: String,
+    pub sent: i64,
+    pub failed: Vec<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GetReportRunsResponse {
+    pub enabled: bool,
+    /// Next scheduled run, `None` when the schedule is disabled
+    pub next_run: Option<String>,
+    pub runs: Vec<ReportRunEntry>,
+}
+
+async fn run_entry(db: &dyn Store, run: ReportRun) -> ApiResult<ReportRunEntry> {
+    let triggered_by = match &run.triggered_by {
+        Some(admin_id) => Some(
+            db.find_account_in("admins", admin_id)
+                .await?
+                .map(|admin| admin.username)
+                .unwrap_or_else(|| admin_id.to_hex()),
+        ),
+        None => None,
+    };
+    Ok(ReportRunEntry {
+        id: run.id,
+        scheduled_for: run.scheduled_for.as_ref().map(format_datetime),
+        triggered_by,
+        job_id: run.job_id.map(|job_id| job_id.to_hex()),
+        started_at: format_datetime(&run.started_at),
+        finished_at: run.finished_at.as_ref().map(format_datetime),
+        status: run.status,
+        sent: run.sent,
+        failed: run.failed,
+        error: run.error,
+    })
+}
+
+/// Newest runs first.
+#[get("/runs")]
+async fn get_report_runs(
+    db: web::Data<dyn Store>,
+    user: Authorized<EmailBroadcast>,
+    course: Course,
+    config: web::Data<Config>,
+    query: web::Query<GetReportRunsQuery>,
+) -> ApiResult<impl Responder> {
+    db::student_scope(db.get_ref(), &user.user_id, &course.id).await?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let mut runs = Vec::new();
+    for run in db.list_report_runs(&course.id, limit).await? {
+        runs.push(run_entry(db.get_ref(), run).await?);
+    }
+    let next_run = match config.report.enabled {
+        true => ReportSchedule::new(&config.report)
+            .map_err(|e| ApiError::new(ApiErrorType::Internal, e.to_string()))?
+            .next(chrono::Utc::now())
+            .map(|slot| slot.with_timezone(&chrono::Local).to_string()),
+        false => None,
+    };
+
+    Ok(HttpResponse::Ok().json(GetReportRunsResponse {
+        enabled: config.report.enabled,
+        next_run,
+        runs,
+    }))
+}
+
+/// Queue the weekly report of the course now, outside the schedule.
+#[post("/run")]
+async fn run_report(
+    db: web::Data<dyn Store>,
+    user: Authorized<EmailBroadcast>,
+    course: Course,
+    audit: Audit,
+) -> ApiResult<impl Responder> {
+    let filter = db::student_scope(db.get_ref(), &user.user_id, &course.id).await?;
+    let run = report::new_run(&course.id, None, Some(user.user_id));
+    // a manual run has a fresh id, so it is only refused while another run of the course is running
+    let run = report::start(db.get_ref(), run, filter.sections)
+        .await?
+        .ok_or_else(|| ApiError::new(ApiErrorType::InvalidRequest, "A report run is in progress".to_string()))?;
+    audit
+        .record(db.get_ref(), &user.user_id, "report.run", &course.id, None, Some(doc! { "run": &run.id }))
+        .await;
+
+    Ok(HttpResponse::Ok().json(run_entry(db.get_ref(), run).await?))
+}
+
+pub fn api_scope() -> Scope {
+    web::scope("/reports")
+        .service(get_report_runs)
+        .service(run_report)
+}
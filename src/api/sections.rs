@@ -0,0 +1,265 @@
+use actix_web::{get, post, web, HttpResponse, Responder, Scope};
+use serde::{Deserialize, Serialize};
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
+
+use crate::audit::{summary, Audit};
+use crate::course::Course;
+use crate::permission::{Authorized, Permission, SectionsManage, SectionsRead};
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::db::{admin_sections, check_course_staff, check_enrolled, course_sections, find_user_id};
+use crate::utils::check_profile_field;
+use crate::models::Section;
+use crate::repository::Store;
+
+#[derive(Serialize)]
+pub struct SectionEntry {
+    pub id: String,
+    pub name: String,
+    pub tas: Vec<String>,
+    pub students: u64,
+}
+
+#[derive(Serialize)]
+pub struct GetSectionListResponse {
+    pub sections: Vec<SectionEntry>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateSectionRequest {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct SectionRequest {
+    pub section_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct AssignTaRequest {
+    pub section_id: String,
+    pub username: String,
+    pub assigned: bool,
+}
+
+#[derive(Deserialize)]
+pub struct EnrollRequest {
+    pub username: String,
+    /// Section to enroll in, an empty string removes the student from their section
+    pub section_id: String,
+}
+
+/// Sections are managed by the staff of their own course.
+async fn check_staff<P: Permission>(db: &dyn Store, user: &Authorized<P>, course: &Course) -> ApiResult<()> {
+    if !check_course_staff(db, &user.user_id, &course.id).await? {
+        return Err(ApiError::new(
+            ApiErrorType::Forbidden,
+            "Not on the staff of this course".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+async fn find_section(db: &dyn Store, course: &Course, section_id: &str) -> ApiResult<Section> {
+    let section_id = ObjectId::parse_str(section_id).map_err(|_| ApiError::new_not_found())?;
+    db.find_section(&section_id)
+        .await?
+        .filter(|section| section.course_id == course.id)
+        .ok_or_else(ApiError::new_not_found)
+}
+
+#[get("/list")]
+async fn get_section_list(
+    db: web::Data<dyn Store>,
+    user: Authorized<SectionsRead>,
+    course: Course,
+) -> ApiResult<impl Responder> {
+    check_staff(db.get_ref(), &user, &course).await?;
+
+    // TAs only see the sections they are assigned to
+    let visible = admin_sections(db.get_ref(), &user.user_id, &course.id).await?;
+
+    let admins = db.list_accounts("admins").await?;
+    let mut sections = Vec::new();
+    for section in db.list_sections(&course.id).await? {
+        if visible.as_ref().is_some_and(|visible| !visible.contains(&section.id)) {
+            continue;
+        }
+        let tas = admins
+            .iter()
+            .filter(|admin| section.ta_ids.contains(&admin.id))
+            .map(|ta| ta.username.clone())
+            .collect();
+        sections.push(SectionEntry {
+            id: section.id.to_hex(),
+            name: section.name,
+            tas,
+            students: db.count_section_students(&section.id).await?,
+        });
+    }
+
+    Ok(HttpResponse::Ok().json(GetSectionListResponse { sections }))
+}
+
+#[post("/create")]
+async fn create_section(
+    db: web::Data<dyn Store>,
+    user: Authorized<SectionsManage>,
+    course: Course,
+    audit: Audit,
+    req: web::Json<CreateSectionRequest>,
+) -> ApiResult<impl Responder> {
+    check_staff(db.get_ref(), &user, &course).await?;
+
+    if req.name.is_empty() {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Invalid section name".to_string(),
+        ));
+    }
+    check_profile_field("section name", &req.name)?;
+
+    if db.find_section_by_name(&course.id, &req.name).await?.is_some() {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Section already exists".to_string(),
+        ));
+    }
+    let id = ObjectId::new();
+    db.insert_section(&Section {
+        id,
+        course_id: course.id.clone(),
+        name: req.name.clone(),
+        ta_ids: Vec::new(),
+        created_at: chrono::Local::now().to_string(),
+    })
+    .await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "sections.create",
+            &id.to_hex(),
+            None,
+            Some(doc! { "course_id": &course.id, "name": &req.name }),
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success", "id": id.to_hex() })))
+}
+
+#[post("/delete")]
+async fn delete_section(
+    db: web::Data<dyn Store>,
+    user: Authorized<SectionsManage>,
+    course: Course,
+    audit: Audit,
+    req: web::Json<SectionRequest>,
+) -> ApiResult<impl Responder> {
+    check_staff(db.get_ref(), &user, &course).await?;
+
+    let section = find_section(db.get_ref(), &course, &req.section_id).await?;
+    // students of a deleted section are left without a section rather than deleted
+    db.delete_section(&section.id).await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "sections.delete",
+            &req.section_id,
+            Some(summary(&section, &["course_id", "name", "ta_ids"])),
+            None,
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+#[post("/assign_ta")]
+async fn assign_ta(
+    db: web::Data<dyn Store>,
+    user: Authorized<SectionsManage>,
+    course: Course,
+    audit: Audit,
+    req: web::Json<AssignTaRequest>,
+) -> ApiResult<impl Responder> {
+    check_staff(db.get_ref(), &user, &course).await?;
+
+    let section_id = find_section(db.get_ref(), &course, &req.section_id).await?.id;
+    let ta_id = find_user_id(db.get_ref(), "admins", &req.username)
+        .await?
+        .ok_or_else(ApiError::new_not_found)?;
+    if req.assigned && !check_course_staff(db.get_ref(), &ta_id, &course.id).await? {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Admin is not on the staff of this course".to_string(),
+        ));
+    }
+
+    db.set_section_ta(&section_id, &ta_id, req.assigned).await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "sections.assign_ta",
+            &req.username,
+            None,
+            Some(doc! { "section_id": section_id, "assigned": req.assigned }),
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+#[post("/enroll")]
+async fn enroll(
+    db: web::Data<dyn Store>,
+    user: Authorized<SectionsManage>,
+    course: Course,
+    audit: Audit,
+    req: web::Json<EnrollRequest>,
+) -> ApiResult<impl Responder> {
+    check_staff(db.get_ref(), &user, &course).await?;
+
+    let user_id = find_user_id(db.get_ref(), "users", &req.username)
+        .await?
+        .ok_or_else(ApiError::new_not_found)?;
+
+    if !check_enrolled(db.get_ref(), &user_id, &course.id).await? {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Not enrolled in this course".to_string(),
+        ));
+    }
+
+    let section_id = if req.section_id.is_empty() {
+        None
+    } else {
+        Some(find_section(db.get_ref(), &course, &req.section_id).await?.id)
+    };
+    // a student is in at most one section per course
+    let remove = course_sections(db.get_ref(), &course.id).await?;
+    db.move_student(&user_id, &remove, section_id).await?;
+
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "sections.enroll",
+            &req.username,
+            None,
+            Some(doc! { "course_id": &course.id, "section_id": section_id }),
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+pub fn api_scope() -> Scope {
+    web::scope("/sections")
+        .service(get_section_list)
+        .service(create_section)
+        .service(delete_section)
+        .service(assign_ta)
+        .service(enroll)
+}
@@ -1,9 +1,17 @@
 use actix_web::{get, post, web, HttpResponse, Responder, Scope};
 use serde::{Deserialize, Serialize};
-use mongodb::{Database, Collection};
-use mongodb::bson::{doc, Document};
+use mongodb::bson::doc;
+use mongodb::bson::oid::ObjectId;
 
-use crate::jwt::ClaimsValidator;
+use crate::api::problem::QBanks;
+use crate::api_key::Caller;
+use crate::audit::Audit;
+use crate::course::Course;
+use crate::live::LiveHub;
+use crate::jobs;
+use crate::models::{JobSpec, Stats};
+use crate::repository::{StatsIncrement, Store};
+use crate::permission::{Authorized, StatsClear};
 use crate::error::{ApiResult, ApiError, ApiErrorType};
 use crate::db;
 
@@ -14,75 +22,259 @@ pub struct StatsRequest {
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct StatsResponse {
-    pub conversation: i32,
-    pub tags: Vec<(String, i32)>,
+    pub conversation: i64,
+    pub tags: Vec<(String, i64)>,
+    pub messages: i64,
+    pub problems_viewed: Vec<(String, i64)>,
+}
+
+impl From<Stats> for StatsResponse {
+    fn from(stats: Stats) -> Self {
+        StatsResponse {
+            conversation: stats.conversation,
+            tags: stats.tags.into_iter().collect(),
+            messages: stats.messages,
+            problems_viewed: stats.problems_viewed.into_iter().collect(),
+        }
+    }
+}
+
+/// Most events accepted in one `/stats/batch` call.
+const MAX_BATCH_EVENTS: usize = 100;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StatsEvent {
+    ConversationStarted,
+    Message,
+    TagsMentioned { tags: Vec<String> },
+    ProblemViewed { problem_id: String },
+}
+
+#[derive(Deserialize)]
+pub struct BatchEvent {
+    /// Idempotency key, an event whose key was already applied is skipped
+    pub id: String,
+    #[serde(flatten)]
+    pub event: StatsEvent,
+}
+
+#[derive(Deserialize)]
+pub struct BatchStatsRequest {
+    /// Parsed one by one, so a malformed event only fails itself
+    pub events: Vec<serde_json::Value>,
+}
+
+#[derive(Serialize)]
+pub struct BatchEventResult {
+    pub id: Option<String>,
+    /// `applied`, `duplicate` or `rejected`
+    pub status: &'static str,
+    pub message: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct BatchStatsResponse {
+    pub results: Vec<BatchEventResult>,
+}
+
+/// Why a set of tags cannot be counted in a course, if it cannot.
+pub(crate) fn tag_error(course: &Course, tags: &[String]) -> Option<String> {
+    // tags become field names of the stats document
+    if let Some(tag) = tags.iter().find(|tag| tag.is_empty() || tag.contains('.') || tag.starts_with('$')) {
+        return Some(format!("Invalid tag {}", tag));
+    }
+    if !course.config.tags.is_empty() {
+        if let Some(tag) = tags.iter().find(|tag| !course.config.tags.contains(tag)) {
+            return Some(format!("Unknown tag {}", tag));
+        }
+    }
+    None
+}
+
+/// The stats increment an event stands for.
+fn event_increment(event: &StatsEvent, course: &Course, qbanks: &QBanks) -> Result<StatsIncrement, String> {
+    match event {
+        StatsEvent::ConversationStarted => Ok(StatsIncrement { conversation: 1, ..Default::default() }),
+        StatsEvent::Message => Ok(StatsIncrement { messages: 1, ..Default::default() }),
+        StatsEvent::TagsMentioned { tags } => {
+            if tags.is_empty() {
+                return Err("No tags given".to_string());
+            }
+            if let Some(message) = tag_error(course, tags) {
+                return Err(message);
+            }
+            let mut inc = StatsIncrement::default();
+            for tag in tags {
+                // a tag mentioned twice in one event counts once, as in `POST /stats`
+                inc.tags.insert(tag.clone(), 1);
+            }
+            Ok(inc)
+        }
+        StatsEvent::ProblemViewed { problem_id } => {
+            if !qbanks[&course.id].iter().any(|entry| &entry.id == problem_id) {
+                return Err(format!("Unknown problem {}", problem_id));
+            }
+            let mut inc = StatsIncrement::default();
+            inc.problems_viewed.insert(problem_id.clone(), 1);
+            Ok(inc)
+        }
+    }
+}
+
+/// Push a counted event to the live dashboard.
+async fn publish_change(
+    hub: &LiveHub,
+    db: &dyn Store,
+    user_id: &ObjectId,
+    course_id: &str,
+    event: &StatsEvent,
+    stats: &Stats,
+) {
+    let (kind, data) = match event {
+        StatsEvent::ConversationStarted => (
+            "conversation",
+            serde_json::json!({ "conversation": stats.conversation }),
+        ),
+        StatsEvent::Message => (
+            "message",
+            serde_json::json!({ "messages": stats.messages }),
+        ),
+        StatsEvent::TagsMentioned { tags } => (
+            "tags",
+            serde_json::json!({ "tags": tags, "trending": hub.mention(course_id, tags) }),
+        ),
+        StatsEvent::ProblemViewed { problem_id } => (
+            "problem_viewed",
+            serde_json::json!({ "problem_id": problem_id }),
+        ),
+    };
+    hub.publish_student(db, user_id, course_id, kind, data).await;
+}
+
+/// Resolve the student a stats call is about and make sure they take the course.
+async fn enrolled_student(
+    db: &dyn Store,
+    caller: &Caller,
+    course: &Course,
+    scope: &str,
+) -> ApiResult<ObjectId> {
+    let user_id = caller.student_id(scope)?;
+    if !db::check_enrolled(db, &user_id, &course.id).await? {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Not enrolled in this course".to_string(),
+        ));
+    }
+    Ok(user_id)
 }
 
 #[post("")]
 async fn post_stats(
-    db: web::Data<Database>,
-    user: ClaimsValidator,
+    db: web::Data<dyn Store>,
+    hub: web::Data<LiveHub>,
+    caller: Caller,
+    course: Course,
     req: web::Json<StatsRequest>,
 ) -> ApiResult<impl Responder> {
-    let collection: Collection<Document> = db.collection("stats");
+    let user_id = enrolled_student(db.get_ref(), &caller, &course, "stats:write").await?;
     let tags = &req.tag;
-    let mut update_doc = doc! {};
+    if let Some(message) = tag_error(&course, tags) {
+        return Err(ApiError::new(ApiErrorType::InvalidRequest, message));
+    }
+    let mut inc = StatsIncrement::default();
     for tag in tags {
-        update_doc.insert(
-            format!("tags.{}", tag),
-            1,
-        );
+        inc.tags.insert(tag.clone(), 1);
+    }
+    if !inc.tags.is_empty() {
+        let stats = db.increment_stats(&user_id, &course.id, &inc).await?;
+        let event = StatsEvent::TagsMentioned { tags: tags.clone() };
+        publish_change(&hub, db.get_ref(), &user_id, &course.id, &event, &stats).await;
     }
-    collection
-        .update_one(
-            doc! { "username": &user.username },
-            doc! { "$inc": update_doc },
-        )
-        .await?;
 
     Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
 }
 
 #[post("/conv")]
 async fn post_conv_stats(
-    db: web::Data<Database>,
-    user: ClaimsValidator,
+    db: web::Data<dyn Store>,
+    hub: web::Data<LiveHub>,
+    caller: Caller,
+    course: Course,
 ) -> ApiResult<impl Responder> {
-    let collection: Collection<Document> = db.collection("stats");
-    collection
-        .update_one(
-            doc! { "username": &user.username },
-            doc! { "$inc": { "conversation": 1 } },
-        )
-        .await?;
+    let user_id = enrolled_student(db.get_ref(), &caller, &course, "stats:write").await?;
+    let inc = StatsIncrement { conversation: 1, ..Default::default() };
+    let stats = db.increment_stats(&user_id, &course.id, &inc).await?;
+    publish_change(&hub, db.get_ref(), &user_id, &course.id, &StatsEvent::ConversationStarted, &stats).await;
     Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
 }
 
-#[get("")]
-async fn get_stats(
-    db: web::Data<Database>,
-    user: ClaimsValidator,
+/// Apply a batch of typed events, each at most once per idempotency key.
+#[post("/batch")]
+async fn post_batch_stats(
+    db: web::Data<dyn Store>,
+    qbanks: web::Data<QBanks>,
+    hub: web::Data<LiveHub>,
+    caller: Caller,
+    course: Course,
+    req: web::Json<BatchStatsRequest>,
 ) -> ApiResult<impl Responder> {
-    let collection: Collection<Document> = db.collection("stats");
-    let user_doc = collection
-        .find_one(doc! { "username": &user.username })
-        .await?;
-
-    match user_doc {
-        Some(doc) => {
-            let conversation = doc.get_i32("conversation")?;
-            let tags_doc = doc.get_document("tags")?;
-            let mut tags: Vec<(String, i32)> = vec![];
-            for (key, value) in tags_doc.iter() {
-                if let Some(count) = value.as_i32() {
-                    tags.push((key.to_string(), count));
-                }
+    let user_id = enrolled_student(db.get_ref(), &caller, &course, "stats:write").await?;
+    if req.events.len() > MAX_BATCH_EVENTS {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            format!("At most {} events per batch", MAX_BATCH_EVENTS),
+        ));
+    }
+
+    let mut results = Vec::new();
+    for value in &req.events {
+        let id = value.get("id").and_then(|id| id.as_str()).map(str::to_string);
+        let rejected = |message: String| BatchEventResult { id: id.clone(), status: "rejected", message: Some(message) };
+        let event: BatchEvent = match serde_json::from_value(value.clone()) {
+            Ok(event) => event,
+            Err(e) => {
+                results.push(rejected(format!("Invalid event: {}", e)));
+                continue;
             }
-            Ok(HttpResponse::Ok().json(StatsResponse {
-                conversation,
-                tags,
-            }))
+        };
+        let inc = match event_increment(&event.event, &course, &qbanks) {
+            Ok(inc) => inc,
+            Err(message) => {
+                results.push(rejected(message));
+                continue;
+            }
+        };
+
+        // the key is claimed before counting, so a retried event is a duplicate
+        if !db.claim_event(&user_id, &course.id, &event.id).await? {
+            results.push(BatchEventResult { id, status: "duplicate", message: None });
+            continue;
         }
+        let stats = match db.increment_stats(&user_id, &course.id, &inc).await {
+            Ok(stats) => stats,
+            Err(e) => {
+                // release the key so the event can be retried
+                db.release_event(&user_id, &course.id, &event.id).await?;
+                return Err(e);
+            }
+        };
+        publish_change(&hub, db.get_ref(), &user_id, &course.id, &event.event, &stats).await;
+        results.push(BatchEventResult { id, status: "applied", message: None });
+    }
+
+    Ok(HttpResponse::Ok().json(BatchStatsResponse { results }))
+}
+
+#[get("")]
+async fn get_stats(
+    db: web::Data<dyn Store>,
+    caller: Caller,
+    course: Course,
+) -> ApiResult<impl Responder> {
+    let user_id = enrolled_student(db.get_ref(), &caller, &course, "stats:read").await?;
+    match db.find_stats(&user_id, &course.id).await? {
+        Some(stats) => Ok(HttpResponse::Ok().json(StatsResponse::from(stats))),
         None => Err(ApiError::new(
             ApiErrorType::InvalidRequest,
             "User not found".to_string(),
@@ -92,26 +284,27 @@ async fn get_stats(
 
 #[post("/clear")]
 async fn clear_stats(
-    db: web::Data<Database>,
-    user: ClaimsValidator,
+    db: web::Data<dyn Store>,
+    user: Authorized<StatsClear>,
+    course: Course,
+    audit: Audit,
 ) -> ApiResult<impl Responder> {
-    // Verify if the user is an admin
-    if !db::check_admin_exists(&db, &user.username).await? {
-        return Err(ApiError::new(
-            ApiErrorType::InvalidRequest,
-            "Admin access required".to_string(),
-        ));
-    }
-
-    // clear all stats
-    let collection: Collection<Document> = db.collection("stats");
-    collection
-        .update_many(
-            doc! {},
-            doc! { "$set": { "conversation": 0, "tags": {} } },
+    // clear the course stats of every student the admin can see
+    let user_ids = db::scoped_user_ids(db.get_ref(), &user.user_id, &course.id).await?;
+    let students = user_ids.len() as i64;
+    let spec = JobSpec::ClearStats { course_id: course.id.clone(), user_ids };
+    let job_id = jobs::enqueue(db.get_ref(), spec, Some(user.user_id)).await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "stats.clear",
+            &course.id,
+            None,
+            Some(doc! { "job": job_id.to_hex(), "students": students }),
         )
-        .await?;
-    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+        .await;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success", "job_id": job_id.to_hex() })))
 }
 
 pub fn api_scope() -> Scope {
@@ -119,5 +312,6 @@ pub fn api_scope() -> Scope {
         .service(post_stats)
         .service(get_stats)
         .service(post_conv_stats)
+        .service(post_batch_stats)
         .service(clear_stats)
 }
\ No newline at end of file
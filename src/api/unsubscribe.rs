@@ -0,0 +1,64 @@
+use actix_web::{get, post, web, HttpResponse, Responder, Scope};
+use serde::Deserialize;
+
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::models::Account;
+use crate::notification;
+use crate::repository::{AccountUpdate, Store};
+
+#[derive(Deserialize)]
+pub struct UnsubscribeQuery {
+    pub token: String,
+}
+
+/// The student and the kind of email a signed link was sent for.
+async fn find_link(db: &dyn Store, token: &str) -> ApiResult<(Account, String)> {
+    let invalid = || ApiError::new(
+        ApiErrorType::InvalidRequest,
+        "Invalid unsubscribe link".to_string(),
+    );
+    let (user_id, category) = notification::verify_token(token).ok_or_else(invalid)?;
+    let account = db.find_account_in("users", &user_id).await?.ok_or_else(invalid)?;
+    Ok((account, category))
+}
+
+/// Check the link in the body of bulk emails without changing anything, since mail scanners and
+/// link previews open links nobody clicked. The page it leads to confirms with a `POST`.
+#[get("")]
+async fn check_link(
+    db: web::Data<dyn Store>,
+    query: web::Query<UnsubscribeQuery>,
+) -> ApiResult<impl Responder> {
+    let (account, category) = find_link(db.get_ref(), &query.token).await?;
+    let unsubscribed = !notification::accepts(&account, &category);
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "category": category, "unsubscribed": unsubscribed })))
+}
+
+/// Turn off the kind of email a link was sent for. Mail clients send this for one-click unsubscribe,
+/// see the `List-Unsubscribe-Post` header (RFC 8058).
+#[post("")]
+async fn unsubscribe(
+    db: web::Data<dyn Store>,
+    query: web::Query<UnsubscribeQuery>,
+) -> ApiResult<impl Responder> {
+    let (account, category) = find_link(db.get_ref(), &query.token).await?;
+    let user_id = account.id;
+    if notification::accepts(&account, &category) {
+        let mut unsubscribed = account.unsubscribed;
+        unsubscribed.push(category.clone());
+        let update = AccountUpdate {
+            unsubscribed: Some(unsubscribed),
+            ..Default::default()
+        };
+        db.update_account("users", &user_id, &update).await?;
+        log::info!("{} unsubscribed from {}", account.username, category);
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success", "category": category })))
+}
+
+pub fn api_scope() -> Scope {
+    web::scope("/unsubscribe")
+        .service(check_link)
+        .service(unsubscribe)
+}
@@ -1,19 +1,52 @@
 use actix_web::{get, post, web, HttpResponse, Responder, Scope};
-use futures::TryStreamExt;
+use lettre::SmtpTransport;
 use serde::{Deserialize, Serialize};
-use mongodb::Database;
-use mongodb::bson::{doc, Document};
+use mongodb::bson::{doc, DateTime};
+use mongodb::bson::oid::ObjectId;
 
-use crate::jwt::ClaimsValidator;
+use crate::audit::{summary, Audit};
+use crate::config::Config;
+use crate::course::Course;
+use crate::permission::{AccountsManage, Authorized, EmailSend, UsersDelete, UsersRead, UsersWrite};
 use crate::error::{ApiResult, ApiError, ApiErrorType};
-use crate::db::{check_user_exists, check_admin_exists};
+use crate::db::{course_sections, find_scoped_user_id, student_scope};
+use crate::api::profile::profile_update;
 use crate::api::stats::StatsResponse as GetUserStatsResponse;
+use crate::mail::send_from_staff;
+use crate::models::{EmailRecord, Suspension};
+use crate::notification;
+use crate::repository::{AccountUpdate, Store};
+use crate::utils::format_datetime;
+
+const DEFAULT_EMAIL_LIMIT: i64 = 50;
+const MAX_EMAIL_LIMIT: i64 = 500;
 
 #[derive(Serialize)]
 pub struct GetUserListResponse {
+    pub user_ids: Vec<String>,
     pub usernames: Vec<String>,
     pub emails: Vec<String>,
     pub created_at: Vec<String>,
+    pub student_ids: Vec<Option<String>>,
+    pub real_names: Vec<Option<String>>,
+    pub section_ids: Vec<Option<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct GetUserListQuery {
+    pub section_id: Option<String>,
+    pub student_id: Option<String>,
+    /// Case-insensitive substring of the real name
+    pub real_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateUserProfileRequest {
+    pub username: String,
+    pub student_id: Option<String>,
+    pub real_name: Option<String>,
+    pub preferred_language: Option<String>,
+    pub avatar: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -21,112 +54,455 @@ pub struct DeleteUserRequest {
     pub username: String,
 }
 
+#[derive(Deserialize)]
+pub struct SuspendUserRequest {
+    pub username: String,
+    pub reason: String,
+    /// Length of the suspension, indefinite when omitted
+    pub duration_hours: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct UserPath {
+    pub username: String,
+}
+
+#[derive(Deserialize)]
+pub struct UserRequest {
+    pub username: String,
+}
+
+#[derive(Deserialize)]
+pub struct GetUserEmailsQuery {
+    pub limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ResendEmailRequest {
+    pub username: String,
+    pub email_id: String,
+}
+
+#[derive(Serialize)]
+pub struct EmailEntry {
+    pub id: String,
+    /// Address the email went to
+    pub email: String,
+    pub kind: String,
+    pub template: String,
+    pub course_id: Option<String>,
+    pub subject: String,
+    pub body_digest: String,
+    /// Username of the staff member who sent it, `null` for emails the server sent on its own
+    pub sent_by: Option<String>,
+    pub resent_from: Option<String>,
+    /// `sent` or `failed`
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub sent_at: Option<String>,
+    /// Whether `/users/emails/resend` can send it again
+    pub resendable: bool,
+}
+
+#[derive(Serialize)]
+pub struct GetUserEmailsResponse {
+    pub emails: Vec<EmailEntry>,
+}
+
+async fn email_entry(db: &dyn Store, record: EmailRecord) -> ApiResult<EmailEntry> {
+    let sent_by = match record.sent_by {
+        Some(admin_id) => Some(
+            db.find_account_in("admins", &admin_id)
+                .await?
+                .map(|admin| admin.username)
+                .unwrap_or_else(|| admin_id.to_hex()),
+        ),
+        None => None,
+    };
+    Ok(EmailEntry {
+        id: record.id.to_hex(),
+        email: record.email,
+        kind: record.kind,
+        template: record.template,
+        course_id: record.course_id,
+        subject: record.subject,
+        body_digest: record.body_digest,
+        sent_by,
+        resent_from: record.resent_from.map(|id| id.to_hex()),
+        status: record.status,
+        error: record.error,
+        created_at: format_datetime(&record.created_at),
+        sent_at: record.sent_at.as_ref().map(format_datetime),
+        resendable: record.body.is_some(),
+    })
+}
+
+/// Find a student by username in any course, for the actions on their whole account.
+async fn find_student_id(db: &dyn Store, username: &str) -> ApiResult<ObjectId> {
+    db.find_account_by_username("users", username)
+        .await?
+        .map(|student| student.id)
+        .ok_or_else(ApiError::new_not_found)
+}
+
+/// Emails of other courses are left out, as their staff may not see them.
+fn in_course(record: &EmailRecord, course: &Course) -> bool {
+    record.course_id.as_ref().is_none_or(|course_id| course_id == &course.id)
+}
+
 #[get("/list")]
 async fn get_user_list(
-    db: web::Data<Database>,
-    user: ClaimsValidator,
+    db: web::Data<dyn Store>,
+    user: Authorized<UsersRead>,
+    course: Course,
+    query: web::Query<GetUserListQuery>,
 ) -> ApiResult<impl Responder> {
-    // Verify if the user is an admin
-    if !check_admin_exists(&db, &user.username).await? {
-        return Err(ApiError::new(
+    // students of the course, TAs only see the students of their own sections
+    let mut filter = student_scope(db.get_ref(), &user.user_id, &course.id).await?;
+    if let Some(section_id) = &query.section_id {
+        let section_id = ObjectId::parse_str(section_id).map_err(|_| ApiError::new(
             ApiErrorType::InvalidRequest,
-            "Admin access required".to_string(),
-        ));
+            "Invalid section id".to_string(),
+        ))?;
+        filter.section_id = Some(section_id);
     }
+    filter.student_id = query.student_id.clone();
+    filter.real_name = query.real_name.clone();
+    let sections = course_sections(db.get_ref(), &course.id).await?;
 
     // Get the list of users from the database
-    let collection = db.collection::<Document>("users");
-    let mut cursor = collection.find(doc! {}).await?;
+    let students = db.list_students(&filter).await?;
+    let mut user_ids = Vec::new();
     let mut usernames = Vec::new();
     let mut emails = Vec::new();
     let mut created_at = Vec::new();
+    let mut student_ids = Vec::new();
+    let mut real_names = Vec::new();
+    let mut section_ids = Vec::new();
 
-    while let Some(user_doc) = cursor.try_next().await? {
-        if let Some(username) = user_doc.get_str("username").ok() {
-            usernames.push(username.to_string());
-        }
-        if let Some(email) = user_doc.get_str("email").ok() {
-            emails.push(email.to_string());
-        }
-        if let Some(created_at_str) = user_doc.get_str("created_at").ok() {
-            created_at.push(created_at_str.to_string());
-        }
+    for student in students {
+        user_ids.push(student.id.to_hex());
+        // students hold one section per course, report the one of this course
+        let section_id = student.section_ids.iter().find(|id| sections.contains(id));
+        section_ids.push(section_id.map(|id| id.to_hex()));
+        usernames.push(student.username);
+        emails.push(student.email);
+        created_at.push(student.created_at);
+        // profile fields are optional, keep the arrays aligned with null entries
+        student_ids.push(student.student_id);
+        real_names.push(student.real_name);
     }
 
-    Ok(HttpResponse::Ok().json(GetUserListResponse { usernames, emails, created_at }))
+    Ok(HttpResponse::Ok().json(GetUserListResponse {
+        user_ids,
+        usernames,
+        emails,
+        created_at,
+        student_ids,
+        real_names,
+        section_ids,
+    }))
 }
 
+#[post("/profile")]
+async fn update_user_profile(
+    db: web::Data<dyn Store>,
+    user: Authorized<UsersWrite>,
+    course: Course,
+    audit: Audit,
+    req: web::Json<UpdateUserProfileRequest>,
+) -> ApiResult<impl Responder> {
+    let user_id = find_scoped_user_id(db.get_ref(), &user.user_id, &course.id, &req.username)
+        .await?
+        .ok_or_else(ApiError::new_not_found)?;
+
+    let update = profile_update(&[
+        ("student_id", &req.student_id),
+        ("real_name", &req.real_name),
+        ("preferred_language", &req.preferred_language),
+        ("avatar", &req.avatar),
+    ])?;
+
+    let profile_fields = ["student_id", "real_name", "preferred_language", "avatar"];
+    let before = db.find_account_in("users", &user_id).await?;
+    db.update_account("users", &user_id, &update).await?;
+    let after = db.find_account_in("users", &user_id).await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "users.profile",
+            &req.username,
+            before.map(|user_doc| summary(&user_doc, &profile_fields)),
+            after.map(|user_doc| summary(&user_doc, &profile_fields)),
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+/// Take a student out of the course. Their account, and what they have in other courses, is kept.
 #[post("/delete")]
 async fn delete_user(
-    db: web::Data<Database>,
-    user: ClaimsValidator,
+    db: web::Data<dyn Store>,
+    user: Authorized<UsersDelete>,
+    course: Course,
+    audit: Audit,
     req: web::Json<DeleteUserRequest>,
 ) -> ApiResult<impl Responder> {
-    // Verify if the user is an admin
-    if !check_admin_exists(&db, &user.username).await? {
-        return Err(ApiError::new(
-            ApiErrorType::InvalidRequest,
-            "Admin access required".to_string(),
-        ));
-    }
+    let user_id = find_scoped_user_id(db.get_ref(), &user.user_id, &course.id, &req.username)
+        .await?
+        .ok_or_else(ApiError::new_not_found)?;
 
-    if !check_user_exists(&db, &req.username).await? {
-        return Err(ApiError::new_not_found());
-    }
+    let before = db.find_account_in("users", &user_id).await?;
+    let sections = course_sections(db.get_ref(), &course.id).await?;
+    db.remove_course(&user_id, &course.id, &sections).await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "users.delete",
+            &req.username,
+            before.map(|user_doc| summary(&user_doc, &["_id", "username", "email", "student_id", "course_ids", "section_ids"])),
+            Some(doc! { "course": &course.id }),
+        )
+        .await;
 
-    let collection = db.collection::<Document>("users");
-    collection.delete_one(doc! { "username": &req.username }).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
 
-    // also delete the user's stats
-    let collection = db.collection::<Document>("stats");
-    collection.delete_one(doc! { "username": &req.username }).await?;
+/// Delete a student account in every course, together with everything stored about it.
+#[post("/purge")]
+async fn purge_user(
+    db: web::Data<dyn Store>,
+    user: Authorized<AccountsManage>,
+    audit: Audit,
+    req: web::Json<DeleteUserRequest>,
+) -> ApiResult<impl Responder> {
+    let user_id = find_student_id(db.get_ref(), &req.username).await?;
+
+    let before = db.find_account_in("users", &user_id).await?;
+    db.purge_account("users", &user_id).await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "users.purge",
+            &req.username,
+            before.map(|user_doc| summary(&user_doc, &["_id", "username", "email", "student_id", "created_at"])),
+            None,
+        )
+        .await;
 
     Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
 }
 
 #[get("/stats/{username}")]
 async fn get_user_stats(
-    db: web::Data<Database>,
-    user: ClaimsValidator,
-    path: web::Path<String>,
+    db: web::Data<dyn Store>,
+    user: Authorized<UsersRead>,
+    course: Course,
+    path: web::Path<UserPath>,
+) -> ApiResult<impl Responder> {
+    let username = path.into_inner().username;
+    let user_id = find_scoped_user_id(db.get_ref(), &user.user_id, &course.id, &username)
+        .await?
+        .ok_or_else(ApiError::new_not_found)?;
+
+    match db.find_stats(&user_id, &course.id).await? {
+        Some(stats) => Ok(HttpResponse::Ok().json(GetUserStatsResponse::from(stats))),
+        None => Err(ApiError::new_not_found()),
+    }
+}
+
+/// Suspend a student account, which locks them out of every course.
+#[post("/suspend")]
+async fn suspend_user(
+    db: web::Data<dyn Store>,
+    user: Authorized<AccountsManage>,
+    audit: Audit,
+    req: web::Json<SuspendUserRequest>,
+) -> ApiResult<impl Responder> {
+    let user_id = find_student_id(db.get_ref(), &req.username).await?;
+
+    let mut suspension = Suspension {
+        reason: req.reason.clone(),
+        suspended_by: Some(user.user_id),
+        suspended_at: Some(DateTime::now()),
+        until: None,
+    };
+    if let Some(hours) = req.duration_hours {
+        if hours <= 0 {
+            return Err(ApiError::new(
+                ApiErrorType::InvalidRequest,
+                "Invalid suspension duration".to_string(),
+            ));
+        }
+        let until = chrono::Utc::now() + chrono::Duration::hours(hours);
+        suspension.until = Some(DateTime::from_millis(until.timestamp_millis()));
+    }
+
+    let update = AccountUpdate { suspension: Some(Some(suspension.clone())), ..Default::default() };
+    db.update_account("users", &user_id, &update).await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "users.suspend",
+            &req.username,
+            None,
+            Some(summary(&suspension, &["reason", "suspended_by", "suspended_at", "until"])),
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+#[post("/unsuspend")]
+async fn unsuspend_user(
+    db: web::Data<dyn Store>,
+    user: Authorized<AccountsManage>,
+    audit: Audit,
+    req: web::Json<UserRequest>,
+) -> ApiResult<impl Responder> {
+    let user_id = find_student_id(db.get_ref(), &req.username).await?;
+
+    let before = db.find_account_in("users", &user_id).await?;
+    let update = AccountUpdate { suspension: Some(None), ..Default::default() };
+    db.update_account("users", &user_id, &update).await?;
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "users.unsuspend",
+            &req.username,
+            before.map(|user_doc| summary(&user_doc, &["suspension"])),
+            None,
+        )
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+/// Make a student choose a new password before doing anything else.
+#[post("/require_password_change")]
+async fn require_password_change(
+    db: web::Data<dyn Store>,
+    user: Authorized<AccountsManage>,
+    audit: Audit,
+    req: web::Json<UserRequest>,
+) -> ApiResult<impl Responder> {
+    let user_id = find_student_id(db.get_ref(), &req.username).await?;
+
+    let update = AccountUpdate { must_change_password: Some(true), ..Default::default() };
+    db.update_account("users", &user_id, &update).await?;
+    audit
+        .record(db.get_ref(), &user.user_id, "users.require_password_change", &req.username, None, None)
+        .await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
+}
+
+/// Emails sent to a student, newest first.
+#[get("/emails/{username}")]
+async fn get_user_emails(
+    db: web::Data<dyn Store>,
+    user: Authorized<UsersRead>,
+    course: Course,
+    path: web::Path<UserPath>,
+    query: web::Query<GetUserEmailsQuery>,
+) -> ApiResult<impl Responder> {
+    let user_id = find_scoped_user_id(db.get_ref(), &user.user_id, &course.id, &path.username)
+        .await?
+        .ok_or_else(ApiError::new_not_found)?;
+
+    let limit = query.limit.unwrap_or(DEFAULT_EMAIL_LIMIT).clamp(1, MAX_EMAIL_LIMIT);
+    let mut emails = Vec::new();
+    for record in db.list_emails(&user_id, limit).await? {
+        if in_course(&record, &course) {
+            emails.push(email_entry(db.get_ref(), record).await?);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(GetUserEmailsResponse { emails }))
+}
+
+/// Send an email of a student's history again, to their current address and from the caller.
+#[post("/emails/resend")]
+async fn resend_email(
+    db: web::Data<dyn Store>,
+    mailer: web::Data<SmtpTransport>,
+    config: web::Data<Config>,
+    user: Authorized<EmailSend>,
+    course: Course,
+    audit: Audit,
+    req: web::Json<ResendEmailRequest>,
 ) -> ApiResult<impl Responder> {
-    let username = path.into_inner();
-    // Verify if the user is an admin
-    if !check_admin_exists(&db, &user.username).await? {
+    let user_id = find_scoped_user_id(db.get_ref(), &user.user_id, &course.id, &req.username)
+        .await?
+        .ok_or_else(ApiError::new_not_found)?;
+    let email_id = ObjectId::parse_str(&req.email_id).map_err(|_| ApiError::new_not_found())?;
+    let original = db
+        .find_email(&email_id)
+        .await?
+        .filter(|record| record.user_id == user_id && in_course(record, &course))
+        .ok_or_else(ApiError::new_not_found)?;
+    let Some(body) = &original.body else {
         return Err(ApiError::new(
             ApiErrorType::InvalidRequest,
-            "Admin access required".to_string(),
+            "Account emails cannot be resent".to_string(),
+        ));
+    };
+    let student = db.find_account_in("users", &user_id).await?.ok_or_else(ApiError::new_not_found)?;
+    if !notification::accepts(&student, &original.kind) {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            format!("{} turned off {} emails", student.username, original.kind),
         ));
     }
+    let admin = db
+        .find_account_in("admins", &user.user_id)
+        .await?
+        .ok_or_else(ApiError::new_not_found)?;
 
-    if !check_user_exists(&db, &username).await? {
-        return Err(ApiError::new_not_found());
+    let mut record = EmailRecord::new(student.id, &student.email, &original.kind, &original.template, &original.subject, body);
+    record.course_id = original.course_id.clone();
+    record.body = Some(body.clone());
+    record.sent_by = Some(admin.id);
+    record.resent_from = Some(original.id);
+    let record = send_from_staff(db.get_ref(), &mailer, &config, &admin, &student, record).await?;
+    if let Some(e) = &record.error {
+        log::error!("Failed to resend email to {}: {}", student.username, e);
+        return Err(ApiError::new(
+            ApiErrorType::Internal,
+            format!("Failed to send email to {}: {}", student.username, e),
+        ));
     }
+    audit
+        .record(
+            db.get_ref(),
+            &user.user_id,
+            "email.resend",
+            &student.username,
+            None,
+            Some(doc! { "email": record.id.to_hex(), "resent_from": original.id.to_hex(), "subject": &record.subject }),
+        )
+        .await;
 
-    let collection = db.collection::<Document>("stats");
-    let user_doc = collection
-        .find_one(doc! { "username": &username })
-        .await?;
-
-    match user_doc {
-        Some(doc) => {
-            let conversation = doc.get_i32("conversation")?;
-            let tags_doc = doc.get_document("tags")?;
-            let mut tags: Vec<(String, i32)> = vec![];
-            for (key, value) in tags_doc.iter() {
-                if let Some(count) = value.as_i32() {
-                    tags.push((key.to_string(), count));
-                }
-            }
-            Ok(HttpResponse::Ok().json(GetUserStatsResponse { conversation, tags }))
-        }
-        None => Err(ApiError::new_not_found()),
-    }
+    Ok(HttpResponse::Ok().json(email_entry(db.get_ref(), record).await?))
 }
 
 pub fn api_scope() -> Scope {
     web::scope("/users")
         .service(get_user_list)
+        .service(update_user_profile)
         .service(delete_user)
+        .service(purge_user)
         .service(get_user_stats)
+        .service(suspend_user)
+        .service(unsuspend_user)
+        .service(require_password_change)
+        .service(get_user_emails)
+        .service(resend_email)
 }
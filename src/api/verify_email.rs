@@ -1,10 +1,9 @@
 use actix_web::{get, web, HttpResponse, Responder, Scope};
 use serde::Deserialize;
-use mongodb::{Collection, Database};
-use mongodb::bson::{doc, Document};
 
 use crate::error::{ApiResult, ApiError, ApiErrorType};
-use crate::db::activate_user;
+use crate::db::find_user_id;
+use crate::repository::{CodeQuery, Store};
 
 #[derive(Deserialize)]
 pub struct ActivationRequest {
@@ -13,51 +12,41 @@ pub struct ActivationRequest {
 
 #[get("/{username}")]
 async fn verify_email(
-    db: web::Data<Database>,
+    db: web::Data<dyn Store>,
     path: web::Path<String>,
     query: web::Query<ActivationRequest>,
 ) -> ApiResult<impl Responder> {
     let username = path.into_inner();
-    let code = &query.code;
-    
-    // Verify the activation code
-    let activation_collection: Collection<Document> = db.collection("activation_codes");
-    let filter = doc! {
-        "username": &username,
-        "code": code,
-    };
-    
-    let activation_doc = activation_collection.find_one(filter.clone()).await?;
-    
-    if let Some(doc) = activation_doc {
-        // Check if the activation code has expired
-        let expires_at = doc.get_str("expires_at")?;
-        let expires_datetime = chrono::DateTime::parse_from_str(expires_at, "%Y-%m-%d %H:%M:%S%.f %z")
-            .map_err(|_| ApiError::new(
-                ApiErrorType::Internal,
-                "Failed to parse expiration date".to_string(),
-            ))?;
-            
-        if chrono::Local::now() > expires_datetime {
-            return Err(ApiError::new(
-                ApiErrorType::InvalidRequest,
-                "Activation code has expired".to_string(),
-            ));
-        }
-        
-        // Activate the user
-        activate_user(&db, &username).await?;
-        
-        // Remove the activation code
-        activation_collection.delete_one(filter).await?;
-        
-        Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
-    } else {
-        Err(ApiError::new(
+
+    let user_id = find_user_id(db.get_ref(), "tmp_users", &username)
+        .await?
+        .ok_or(ApiError::new(
             ApiErrorType::InvalidRequest,
             "Invalid activation code".to_string(),
-        ))
+        ))?;
+    
+    // Verify the activation code, it can only be used once
+    let code = db.take_code(&CodeQuery {
+        user_id: Some(user_id),
+        code: Some(&query.code),
+        ..Default::default()
+    })
+    .await?
+    .ok_or(ApiError::new(
+        ApiErrorType::InvalidRequest,
+        "Invalid activation code".to_string(),
+    ))?;
+    if code.is_expired() {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Activation code has expired".to_string(),
+        ));
     }
+
+    // Activate the user
+    db.activate_account(&user_id).await?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "status": "success" })))
 }
 
 pub fn api_scope() -> Scope {
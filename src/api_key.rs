@@ -0,0 +1,148 @@
+use actix_web::{web, FromRequest};
+use futures::future::{ready, LocalBoxFuture};
+use futures::FutureExt;
+use mongodb::bson::DateTime;
+use mongodb::bson::oid::ObjectId;
+use rand::distr::Alphanumeric;
+use rand::{rng, Rng};
+use sha2::{Digest, Sha256};
+
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::jwt::ClaimsValidator;
+use crate::repository::Store;
+
+/// Header carrying the raw API key of a service.
+pub const API_KEY_HEADER: &str = "X-API-Key";
+/// Header naming the student id a service acts for.
+pub const ACT_AS_HEADER: &str = "X-Act-As";
+
+pub const SCOPES: &[&str] = &["stats:read", "stats:write", "problems:read"];
+
+const KEY_PREFIX: &str = "ywt_";
+
+/// Generate a new raw key. Only its hash is ever stored.
+pub fn generate_key() -> String {
+    let secret: String = rng()
+        .sample_iter(&Alphanumeric)
+        .take(40)
+        .map(char::from)
+        .collect();
+    format!("{}{}", KEY_PREFIX, secret)
+}
+
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+pub struct ApiKeyValidator {
+    pub key_id: ObjectId,
+    pub scopes: Vec<String>,
+    pub act_as: Option<ObjectId>,
+}
+
+impl ApiKeyValidator {
+    pub fn require_scope(&self, scope: &str) -> ApiResult<()> {
+        if !self.scopes.iter().any(|s| s == scope) {
+            return Err(ApiError::new(
+                ApiErrorType::Forbidden,
+                format!("API key lacks scope {}", scope),
+            ));
+        }
+        Ok(())
+    }
+}
+
+async fn validate_key(db: &dyn Store, key: &str, act_as: Option<&str>) -> Result<ApiKeyValidator, actix_web::Error> {
+    let api_key = db
+        .find_api_key(&hash_key(key))
+        .await?
+        .ok_or(actix_web::error::ErrorUnauthorized("Invalid API key"))?;
+    if api_key.expires_at.is_some_and(|expires_at| expires_at <= DateTime::now()) {
+        return Err(actix_web::error::ErrorUnauthorized("API key expired"));
+    }
+    db.touch_api_key(&api_key.id).await?;
+
+    let act_as = match act_as {
+        Some(student_id) => {
+            let student_id = ObjectId::parse_str(student_id).map_err(|_| ApiError::new(
+                ApiErrorType::InvalidRequest,
+                "Invalid student id".to_string(),
+            ))?;
+            // a service may only act for students, and only while they are allowed in
+            let student = db
+                .find_account_in("users", &student_id)
+                .await?
+                .ok_or_else(ApiError::new_not_found)?;
+            student.check_suspension()?;
+            Some(student_id)
+        }
+        None => None,
+    };
+
+    Ok(ApiKeyValidator { key_id: api_key.id, scopes: api_key.scopes, act_as })
+}
+
+impl FromRequest for ApiKeyValidator {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let header = |name| req.headers().get(name).and_then(|h| h.to_str().ok()).map(str::to_string);
+        let Some(key) = header(API_KEY_HEADER) else {
+            return Box::pin(ready(Err(actix_web::error::ErrorUnauthorized("Missing API key"))));
+        };
+        let act_as = header(ACT_AS_HEADER);
+        let db = req.app_data::<web::Data<dyn Store>>().cloned();
+        Box::pin(async move {
+            let db = db.ok_or_else(|| ApiError::new(
+                ApiErrorType::Internal,
+                "Database is not configured".to_string(),
+            ))?;
+            validate_key(db.get_ref(), &key, act_as.as_deref()).await
+        })
+    }
+}
+
+/// Either a logged in account or a service holding an API key.
+pub enum Caller {
+    User(ClaimsValidator),
+    Service(ApiKeyValidator),
+}
+
+impl Caller {
+    /// Allow users through and require `scope` from services.
+    pub fn authorize(&self, scope: &str) -> ApiResult<()> {
+        match self {
+            Caller::User(_) => Ok(()),
+            Caller::Service(key) => key.require_scope(scope),
+        }
+    }
+
+    /// The student whose data the call is about: the user themselves, or the student a service acts for.
+    pub fn student_id(&self, scope: &str) -> ApiResult<ObjectId> {
+        match self {
+            Caller::User(user) => Ok(user.user_id),
+            Caller::Service(key) => {
+                key.require_scope(scope)?;
+                key.act_as.ok_or_else(|| ApiError::new(
+                    ApiErrorType::InvalidRequest,
+                    format!("Missing {} header", ACT_AS_HEADER),
+                ))
+            }
+        }
+    }
+}
+
+impl FromRequest for Caller {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, payload: &mut actix_web::dev::Payload) -> Self::Future {
+        if req.headers().contains_key(API_KEY_HEADER) {
+            ApiKeyValidator::from_request(req, payload).map(|r| r.map(Caller::Service)).boxed_local()
+        } else {
+            ClaimsValidator::from_request(req, payload).map(|r| r.map(Caller::User)).boxed_local()
+        }
+    }
+}
+
@@ -0,0 +1,94 @@
+use actix_web::FromRequest;
+use futures::future::{ready, Ready};
+use serde::Serialize;
+use mongodb::bson::{self, doc, DateTime, Document};
+use mongodb::bson::oid::ObjectId;
+
+use crate::error::ApiResult;
+use crate::models::AuditEntry;
+use crate::repository::Store;
+use crate::session::client_ip;
+
+/// Header carrying the id of a request, set by the reverse proxy or generated here.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Where a request comes from, recorded with every audit entry it causes.
+pub struct Audit {
+    pub request_id: String,
+    pub ip: Option<String>,
+}
+
+impl Audit {
+    /// Append an entry to the audit log. `before` and `after` summarize the target around the action.
+    /// The action has already happened, so a failure is logged rather than failing the request.
+    pub async fn record(
+        &self,
+        db: &dyn Store,
+        actor_id: &ObjectId,
+        action: &str,
+        target: &str,
+        before: Option<Document>,
+        after: Option<Document>,
+    ) {
+        if let Err(e) = self.insert(db, actor_id, action, target, before, after).await {
+            log::error!("Failed to audit {} on {} by {}: {}", action, target, actor_id, e);
+        }
+    }
+
+    async fn insert(
+        &self,
+        db: &dyn Store,
+        actor_id: &ObjectId,
+        action: &str,
+        target: &str,
+        before: Option<Document>,
+        after: Option<Document>,
+    ) -> ApiResult<()> {
+        // keep the username of the time, the account may be renamed or deleted later
+        let actor = db
+            .find_account(actor_id)
+            .await?
+            .map(|account| account.username);
+        db.insert_audit(&AuditEntry {
+            id: ObjectId::new(),
+            at: DateTime::now(),
+            actor_id: *actor_id,
+            actor,
+            action: action.to_string(),
+            target: target.to_string(),
+            request_id: self.request_id.clone(),
+            ip: self.ip.clone(),
+            before,
+            after,
+        })
+        .await
+    }
+}
+
+/// Summarize a record by the given fields, leaving out everything else such as password hashes.
+pub fn summary<T: Serialize>(record: &T, fields: &[&str]) -> Document {
+    let document = bson::to_document(record).unwrap_or_default();
+    let mut summary = doc! {};
+    for field in fields {
+        if let Some(value) = document.get(*field) {
+            summary.insert(*field, value.clone());
+        }
+    }
+    summary
+}
+
+impl FromRequest for Audit {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string)
+            .unwrap_or_else(|| ObjectId::new().to_hex());
+        let ip = client_ip(req);
+        ready(Ok(Audit { request_id, ip }))
+    }
+}
@@ -0,0 +1,156 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::Utc;
+use lettre::SmtpTransport;
+use mongodb::bson::DateTime;
+use mongodb::bson::oid::ObjectId;
+
+use crate::config::Config;
+use crate::error::ApiResult;
+use crate::mail::{build_notification, record_failure, send_recorded, Thread};
+use crate::models::{Account, Audience, Campaign, CampaignDelivery, EmailRecord, Job, JobSpec};
+use crate::notification;
+use crate::repository::{Store, StudentFilter};
+
+/// Most usernames an uploaded list may hold.
+pub const MAX_USERNAMES: usize = 5000;
+
+/// The students of an audience a campaign goes to.
+pub struct Recipients {
+    /// Ordered by id
+    pub students: Vec<Account>,
+    /// Usernames of the uploaded list that are not among the students of the filter
+    pub unknown: Vec<String>,
+    /// Usernames of the students of the audience who turned off announcements
+    pub unsubscribed: Vec<String>,
+}
+
+/// The students of `filter` in the audience.
+pub async fn audience(
+    db: &dyn Store,
+    mut filter: StudentFilter,
+    audience: &Audience,
+    now: chrono::DateTime<Utc>,
+) -> ApiResult<Recipients> {
+    filter.section_id = audience.section_id;
+    let mut students = db.list_students(&filter).await?;
+
+    let mut unknown = Vec::new();
+    if let Some(usernames) = &audience.usernames {
+        let listed: BTreeSet<&str> = usernames.iter().map(String::as_str).collect();
+        let found: BTreeSet<&str> = students.iter().map(|student| student.username.as_str()).collect();
+        unknown = listed.difference(&found).map(|username| username.to_string()).collect();
+        students.retain(|student| listed.contains(student.username.as_str()));
+    }
+
+    if audience.inactive_days.is_some() || audience.tag.is_some() {
+        let stats: BTreeMap<ObjectId, _> = db
+            .course_stats(&filter.course_id)
+            .await?
+            .into_iter()
+            .map(|stats| (stats.user_id, stats))
+            .collect();
+        if let Some(days) = audience.inactive_days {
+            let since = DateTime::from_millis((now - chrono::Duration::days(days)).timestamp_millis());
+            // students without stats never used the assistant
+            students.retain(|student| {
+                stats
+                    .get(&student.id)
+                    .is_none_or(|stats| stats.last_active_at.is_none_or(|at| at < since))
+            });
+        }
+        if let Some(tag) = &audience.tag {
+            let below = audience.tag_below.unwrap_or(1);
+            students.retain(|student| {
+                let mentions = stats.get(&student.id).and_then(|stats| stats.tags.get(tag)).copied().unwrap_or(0);
+                mentions < below
+            });
+        }
+    }
+
+    let (mut students, unsubscribed): (Vec<Account>, Vec<Account>) = students
+        .into_iter()
+        .partition(|student| notification::accepts(student, "announcements"));
+    students.sort_by_key(|student| student.id);
+    Ok(Recipients {
+        students,
+        unknown,
+        unsubscribed: unsubscribed.into_iter().map(|student| student.username).collect(),
+    })
+}
+
+/// The body of a campaign email to `student`.
+pub fn render(body: &str, student: &Account) -> String {
+    body.replace("{username}", &student.username)
+        .replace("{real_name}", student.real_name.as_deref().unwrap_or(&student.username))
+}
+
+/// Email a campaign to a student, naming the admin who sent it when they still exist.
+/// The record tells whether it went out.
+pub async fn send(
+    db: &dyn Store,
+    mailer: &SmtpTransport,
+    config: &Config,
+    campaign: &Campaign,
+    admin: Option<&Account>,
+    student: &Account,
+) -> ApiResult<EmailRecord> {
+    let mut body = render(&campaign.body, student);
+    if let Some(admin) = admin {
+        body = format!("{}\n\n此邮件由 {} <{}> 触发 YWT Bot 发送。若要回复，请直接回复发件人。", body, admin.username, admin.email);
+    }
+    let mut record = EmailRecord::new(student.id, &student.email, "announcements", "campaign", &campaign.subject, &body);
+    record.course_id = Some(campaign.course_id.clone());
+    record.body = Some(body.clone());
+    record.sent_by = Some(campaign.created_by);
+    let thread = admin.map(|admin| Thread { staff: admin, references: &[] });
+    match build_notification(config, student, "announcements", &campaign.subject, body, thread.as_ref()) {
+        Ok(message) => send_recorded(db, mailer, &message, record).await,
+        Err(e) => record_failure(db, record, e.to_string()).await,
+    }
+}
+
+/// Store a campaign to `students` and queue the job sending it.
+pub async fn start(
+    db: &dyn Store,
+    course_id: &str,
+    created_by: ObjectId,
+    subject: &str,
+    body: &str,
+    audience: Audience,
+    students: &[Account],
+) -> ApiResult<Campaign> {
+    let campaign_id = ObjectId::new();
+    let job = Job::new(
+        JobSpec::Campaign { course_id: course_id.to_string(), campaign_id },
+        Some(created_by),
+    );
+    let campaign = Campaign {
+        id: campaign_id,
+        course_id: course_id.to_string(),
+        created_by,
+        created_at: DateTime::now(),
+        subject: subject.to_string(),
+        body: body.to_string(),
+        audience,
+        recipients: students.len() as i64,
+        job_id: job.id,
+    };
+    let deliveries: Vec<CampaignDelivery> = students
+        .iter()
+        .map(|student| CampaignDelivery {
+            id: ObjectId::new(),
+            campaign_id,
+            user_id: student.id,
+            username: student.username.clone(),
+            email: None,
+            status: "pending".to_string(),
+            error: None,
+            delivered_at: None,
+        })
+        .collect();
+    db.insert_campaign(&campaign, &deliveries).await?;
+    db.insert_job(&job).await?;
+    log::info!("Queued campaign {} to {} students", campaign.id, deliveries.len());
+    Ok(campaign)
+}
@@ -0,0 +1,146 @@
+use base64::{Engine, engine::general_purpose};
+use mongodb::bson::DateTime;
+use mongodb::bson::oid::ObjectId;
+use rand::{rng, Rng};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::models::Captcha;
+use crate::repository::Store;
+
+/// A client's answer to a challenge from `/captcha`.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct CaptchaAnswer {
+    pub id: String,
+    pub answer: String,
+}
+
+/// Strokes of the characters a challenge is drawn with, on a grid one unit wide and two high.
+fn glyph(c: char) -> &'static [&'static [(f64, f64)]] {
+    match c {
+        '0' => &[&[(0.0, 0.0), (1.0, 0.0), (1.0, 2.0), (0.0, 2.0), (0.0, 0.0)]],
+        '1' => &[&[(0.2, 0.4), (0.6, 0.0), (0.6, 2.0)]],
+        '2' => &[&[(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (0.0, 2.0), (1.0, 2.0)]],
+        '3' => &[&[(0.0, 0.0), (1.0, 0.0), (1.0, 2.0), (0.0, 2.0)], &[(0.2, 1.0), (1.0, 1.0)]],
+        '4' => &[&[(0.0, 0.0), (0.0, 1.2), (1.0, 1.2)], &[(0.8, 0.4), (0.8, 2.0)]],
+        '5' => &[&[(1.0, 0.0), (0.0, 0.0), (0.0, 1.0), (1.0, 1.0), (1.0, 2.0), (0.0, 2.0)]],
+        '6' => &[&[(1.0, 0.0), (0.0, 0.0), (0.0, 2.0), (1.0, 2.0), (1.0, 1.0), (0.0, 1.0)]],
+        '7' => &[&[(0.0, 0.0), (1.0, 0.0), (0.4, 2.0)]],
+        '8' => &[&[(0.0, 0.0), (1.0, 0.0), (1.0, 2.0), (0.0, 2.0), (0.0, 0.0)], &[(0.0, 1.0), (1.0, 1.0)]],
+        '9' => &[&[(1.0, 1.0), (0.0, 1.0), (0.0, 0.0), (1.0, 0.0), (1.0, 2.0), (0.0, 2.0)]],
+        '+' => &[&[(0.0, 1.0), (1.0, 1.0)], &[(0.5, 0.5), (0.5, 1.5)]],
+        '-' => &[&[(0.0, 1.0), (1.0, 1.0)]],
+        '×' => &[&[(0.1, 0.6), (0.9, 1.4)], &[(0.9, 0.6), (0.1, 1.4)]],
+        '=' => &[&[(0.0, 0.7), (1.0, 0.7)], &[(0.0, 1.3), (1.0, 1.3)]],
+        '?' => &[&[(0.0, 0.3), (0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.5, 1.0), (0.5, 1.5)], &[(0.5, 1.8), (0.5, 2.0)]],
+        _ => &[],
+    }
+}
+
+/// Draw a question as an SVG image. Every character is a few jittered, rotated strokes among noise
+/// lines, so the question is not in the image as text.
+fn render(question: &str, rng: &mut impl Rng) -> String {
+    const WIDTH: f64 = 200.0;
+    const HEIGHT: f64 = 60.0;
+    let mut paths = Vec::new();
+    for _ in 0..6 {
+        let (x1, y1) = (rng.random_range(0.0..WIDTH), rng.random_range(0.0..HEIGHT));
+        let (x2, y2) = (rng.random_range(0.0..WIDTH), rng.random_range(0.0..HEIGHT));
+        paths.push(format!("M{:.1} {:.1}L{:.1} {:.1}", x1, y1, x2, y2));
+    }
+    let mut x = 12.0;
+    for c in question.chars().filter(|c| !c.is_whitespace()) {
+        let scale = rng.random_range(9.0..13.0);
+        let angle: f64 = rng.random_range(-0.3..0.3);
+        let (cx, cy) = (x + scale / 2.0, HEIGHT / 2.0 + rng.random_range(-5.0..5.0));
+        for stroke in glyph(c) {
+            let points: Vec<String> = stroke
+                .iter()
+                .map(|(gx, gy)| {
+                    let dx = (gx - 0.5) * scale + rng.random_range(-1.5..1.5);
+                    let dy = (gy - 1.0) * scale + rng.random_range(-1.5..1.5);
+                    let px = cx + dx * angle.cos() - dy * angle.sin();
+                    let py = cy + dx * angle.sin() + dy * angle.cos();
+                    format!("{:.1} {:.1}", px, py)
+                })
+                .collect();
+            paths.push(format!("M{}", points.join("L")));
+        }
+        x += scale + rng.random_range(5.0..9.0);
+    }
+    // strokes are shuffled so their order does not spell out the question either
+    for i in (1..paths.len()).rev() {
+        paths.swap(i, rng.random_range(0..=i));
+    }
+    let image = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}"><rect width="100%" height="100%" fill="white"/><path d="{}" fill="none" stroke="black" stroke-width="2" stroke-linecap="round"/></svg>"#,
+        WIDTH,
+        HEIGHT,
+        paths.join(""),
+    );
+    format!("data:image/svg+xml;base64,{}", general_purpose::STANDARD.encode(image))
+}
+
+/// Create an arithmetic challenge, returning its id and the question as an image data URL. Only the
+/// server knows the answer. An address can hold `max_pending` unanswered challenges at a time.
+pub async fn create_challenge(db: &dyn Store, config: &Config, ip: Option<&str>) -> ApiResult<(ObjectId, String)> {
+    if db.count_captchas(ip).await? >= config.captcha.max_pending {
+        return Err(ApiError::new(
+            ApiErrorType::TooManyRequests,
+            "Too many captcha requests, try again later".to_string(),
+        ));
+    }
+    let (image, answer) = {
+        let mut rng = rng();
+        let a: i32 = rng.random_range(1..=20);
+        let b: i32 = rng.random_range(1..=20);
+        let (question, answer) = match rng.random_range(0..3) {
+            0 => (format!("{} + {} = ?", a, b), a + b),
+            1 => (format!("{} - {} = ?", a.max(b), a.min(b)), a.max(b) - a.min(b)),
+            _ => (format!("{} × {} = ?", a % 10, b % 10), (a % 10) * (b % 10)),
+        };
+        (render(&question, &mut rng), answer)
+    };
+
+    let expires_at = chrono::Utc::now() + chrono::Duration::minutes(config.captcha.expiry_minutes);
+    let captcha = Captcha {
+        id: ObjectId::new(),
+        answer: answer.to_string(),
+        ip: ip.map(str::to_string),
+        expires_at: DateTime::from_millis(expires_at.timestamp_millis()),
+    };
+    // expired challenges nobody answered are cleaned up here rather than by a job
+    db.insert_captcha(&captcha).await?;
+    Ok((captcha.id, image))
+}
+
+/// Check an answer. A challenge can be tried once, whether the answer is right or not.
+pub async fn verify(db: &dyn Store, captcha: Option<&CaptchaAnswer>) -> ApiResult<()> {
+    let captcha = captcha.ok_or(ApiError::new(
+        ApiErrorType::InvalidRequest,
+        "Captcha required".to_string(),
+    ))?;
+    let invalid = || ApiError::new(
+        ApiErrorType::InvalidRequest,
+        "Invalid captcha".to_string(),
+    );
+    let captcha_id = ObjectId::parse_str(&captcha.id).map_err(|_| invalid())?;
+    let challenge = db.take_captcha(&captcha_id).await?.ok_or_else(invalid)?;
+    if challenge.expires_at <= DateTime::now() || challenge.answer != captcha.answer.trim() {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// Whether logging in to an account needs a captcha because of its recent failed attempts.
+pub async fn required_for_login(db: &dyn Store, config: &Config, user_id: &ObjectId) -> ApiResult<bool> {
+    if !config.captcha.enabled {
+        return Ok(false);
+    }
+    let since = chrono::Utc::now() - chrono::Duration::minutes(config.captcha.window_minutes);
+    let failures = db
+        .count_failed_logins(user_id, DateTime::from_millis(since.timestamp_millis()))
+        .await?;
+    Ok(failures >= config.captcha.failed_logins)
+}
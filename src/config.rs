@@ -1,3 +1,7 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use lettre::message::dkim;
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug, Clone)]
@@ -11,4 +15,259 @@ pub struct Config {
     pub smtp_server: String,
     pub smtp_port: u16,
     pub smtp_username: String,
-}
\ No newline at end of file
+    /// Days an account stays recoverable after its owner asks for deletion
+    #[serde(default = "default_deletion_grace_days")]
+    pub deletion_grace_days: i64,
+    /// Addresses of reverse proxies whose `Forwarded` and `X-Forwarded-For` headers are trusted
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    #[serde(default)]
+    pub password: PasswordConfig,
+    #[serde(default)]
+    pub magic_link: MagicLinkConfig,
+    #[serde(default)]
+    pub captcha: CaptchaConfig,
+    #[serde(default)]
+    pub report: ReportConfig,
+    #[serde(default)]
+    pub jobs: JobConfig,
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+    #[serde(default)]
+    pub mail: MailConfig,
+    /// Courses served by this instance, the first one is the default course
+    #[serde(default = "default_courses")]
+    pub courses: Vec<CourseConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CourseConfig {
+    pub id: String,
+    pub name: String,
+    #[serde(default = "default_qbank_path")]
+    pub qbank_path: String,
+    /// Collection holding the problem images of this course
+    #[serde(default = "default_qbank_collection")]
+    pub qbank_collection: String,
+    /// Tags accepted in stats, any tag is accepted when empty
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Key students must provide to enroll themselves, enrollment is open when unset
+    pub enrollment_key: Option<String>,
+    /// Weekly report subject and body, `{username}`, `{conversation}` and `{tags}` are substituted
+    pub report_subject: Option<String>,
+    pub report_template: Option<String>,
+}
+
+impl Config {
+    pub fn default_course(&self) -> &CourseConfig {
+        &self.courses[0]
+    }
+
+    pub fn course(&self, id: &str) -> Option<&CourseConfig> {
+        self.courses.iter().find(|course| course.id == id)
+    }
+}
+
+fn default_courses() -> Vec<CourseConfig> {
+    vec![CourseConfig {
+        id: "default".to_string(),
+        name: "电子电路与系统基础".to_string(),
+        qbank_path: default_qbank_path(),
+        qbank_collection: default_qbank_collection(),
+        tags: Vec::new(),
+        enrollment_key: None,
+        report_subject: None,
+        report_template: None,
+    }]
+}
+
+fn default_qbank_path() -> String {
+    "./Q_bank/Q_bank.json".to_string()
+}
+
+fn default_qbank_collection() -> String {
+    "qbank".to_string()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PasswordConfig {
+    /// Length limits, counted in characters rather than bytes
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    pub reject_username: bool,
+    /// File with one forbidden password per line, the bundled list is used when unset
+    pub blocklist_path: Option<String>,
+    /// Argon2id cost; hashes made with other parameters are upgraded on the next login
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        PasswordConfig {
+            min_length: 8,
+            max_length: 64,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            reject_username: true,
+            blocklist_path: None,
+            argon2_memory_kib: argon2::Params::DEFAULT_M_COST,
+            argon2_iterations: argon2::Params::DEFAULT_T_COST,
+            argon2_parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MagicLinkConfig {
+    /// Whether students may log in with a code sent to their email instead of a password
+    pub enabled: bool,
+    /// Frontend page the emailed link points to, the token is appended as `?token=`
+    pub url: Option<String>,
+    pub expiry_minutes: i64,
+    /// Requests accepted per email address within `window_minutes`
+    pub max_requests: u64,
+    pub window_minutes: i64,
+}
+
+impl Default for MagicLinkConfig {
+    fn default() -> Self {
+        MagicLinkConfig {
+            enabled: false,
+            url: None,
+            expiry_minutes: 15,
+            max_requests: 3,
+            window_minutes: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CaptchaConfig {
+    /// Whether registration, and logins after repeated failures, need a solved challenge
+    pub enabled: bool,
+    /// Failed logins of an account within `window_minutes` after which a captcha is needed
+    pub failed_logins: u64,
+    pub window_minutes: i64,
+    /// How long a challenge can be answered
+    pub expiry_minutes: i64,
+    /// Unanswered challenges an address can hold before `/captcha` refuses it more
+    pub max_pending: u64,
+}
+
+impl Default for CaptchaConfig {
+    fn default() -> Self {
+        CaptchaConfig {
+            enabled: false,
+            failed_logins: 3,
+            window_minutes: 15,
+            expiry_minutes: 5,
+            max_pending: 10,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ReportConfig {
+    /// Whether the server sends the weekly report of every course on its own
+    pub enabled: bool,
+    /// Cron expression with a seconds field: `sec min hour day-of-month month day-of-week [year]`
+    pub schedule: String,
+    /// IANA time zone the schedule is read in
+    pub timezone: String,
+    /// How long after its time a report missed while the server was down is still sent
+    pub catch_up_hours: i64,
+}
+
+impl Default for ReportConfig {
+    fn default() -> Self {
+        ReportConfig {
+            enabled: false,
+            schedule: "0 0 8 * * Mon".to_string(),
+            timezone: "Asia/Shanghai".to_string(),
+            catch_up_hours: 12,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct JobConfig {
+    /// Background jobs run at the same time
+    pub workers: usize,
+    /// Minutes without progress after which a running job is taken for dead and resumed
+    pub stale_minutes: i64,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        JobConfig {
+            workers: 2,
+            stale_minutes: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct NotificationConfig {
+    /// Public URL of the `/unsubscribe` route, linked from every bulk email with the token appended as `?token=`
+    pub unsubscribe_url: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MailConfig {
+    /// Sender of every email, `YWT Bot <smtp_username>` when unset
+    pub from: Option<String>,
+    /// Where replies go, the sender when unset
+    pub reply_to: Option<String>,
+    /// Overrides by kind of email, see `mail::KINDS`
+    pub senders: BTreeMap<String, SenderConfig>,
+    /// Signs every email when set
+    pub dkim: Option<DkimConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SenderConfig {
+    pub from: Option<String>,
+    pub reply_to: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DkimConfig {
+    /// Domain the signature is made for, published with the key under `<selector>._domainkey`
+    pub domain: String,
+    pub selector: String,
+    /// `rsa` or `ed25519`
+    #[serde(default = "default_dkim_algorithm")]
+    pub algorithm: String,
+    /// PKCS#1 PEM for RSA, the base64 secret key for Ed25519
+    pub private_key: Option<String>,
+    /// File holding the private key, read at startup when `private_key` is unset
+    pub private_key_path: Option<String>,
+    /// The key parsed once by `mail::prepare`, which drops `private_key`
+    #[serde(skip)]
+    pub signer: Option<Arc<dkim::DkimConfig>>,
+}
+
+fn default_dkim_algorithm() -> String {
+    "rsa".to_string()
+}
+
+fn default_deletion_grace_days() -> i64 {
+    7
+}
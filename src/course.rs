@@ -0,0 +1,34 @@
+use actix_web::{web, FromRequest};
+use futures::future::{ready, Ready};
+
+use crate::config::{Config, CourseConfig};
+use crate::error::{ApiError, ApiErrorType};
+
+/// The course a request is about: the `{course_id}` route segment under `/courses`,
+/// or the default course for the top-level routes.
+pub struct Course {
+    pub id: String,
+    pub config: CourseConfig,
+}
+
+impl FromRequest for Course {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let Some(config) = req.app_data::<web::Data<Config>>() else {
+            return ready(Err(ApiError::new(
+                ApiErrorType::Internal,
+                "Config is not available".to_string(),
+            ).into()));
+        };
+        let course = match req.match_info().get("course_id") {
+            Some(course_id) => config.course(course_id),
+            None => Some(config.default_course()),
+        };
+        match course {
+            Some(course) => ready(Ok(Course { id: course.id.clone(), config: course.clone() })),
+            None => ready(Err(ApiError::new_not_found().into())),
+        }
+    }
+}
@@ -1,14 +1,11 @@
 use mongodb::{Collection, Database};
 use mongodb::bson::{doc, Document};
-use argon2::{
-    password_hash::{
-        rand_core::OsRng,
-        PasswordHasher, SaltString
-    },
-    Argon2
-};
+use mongodb::bson::oid::ObjectId;
+use futures::TryStreamExt;
 
-use crate::error::ApiResult;
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::models::Account;
+use crate::repository::{Store, StudentFilter};
 
 pub struct AdminType;
 pub struct UserType;
@@ -26,76 +23,366 @@ impl UserTypeTrait for UserType {
 }
 
 pub async fn check_user_exists(
-    db: &Database,
+    db: &dyn Store,
     username: &str,
 ) -> ApiResult<bool> {
-    let collection: Collection<Document> = db.collection("users");
-    let filter = doc! { "username": username };
-    let user = collection.find_one(filter).await?;
-    Ok(user.is_some())
+    Ok(db.find_account_by_username("users", username).await?.is_some())
 }
 
 pub async fn check_admin_exists(
-    db: &Database,
+    db: &dyn Store,
     username: &str,
 ) -> ApiResult<bool> {
-    let collection: Collection<Document> = db.collection("admins");
-    let filter = doc! { "username": username };
-    let admin = collection.find_one(filter).await?;
-    Ok(admin.is_some())
+    Ok(db.find_account_by_username("admins", username).await?.is_some())
+}
+
+pub async fn check_user_id_exists(
+    db: &dyn Store,
+    user_id: &ObjectId,
+) -> ApiResult<bool> {
+    Ok(db.find_account_in("users", user_id).await?.is_some())
+}
+
+pub async fn check_admin_id_exists(
+    db: &dyn Store,
+    user_id: &ObjectId,
+) -> ApiResult<bool> {
+    // disabled admins keep their account but lose every admin privilege
+    let admin = db.find_account_in("admins", user_id).await?;
+    Ok(admin.is_some_and(|admin| !admin.disabled))
 }
 
+/// Whether any account holds `email`, including staff and registrations still to be activated.
 pub async fn check_email_exists(
-    db: &Database,
+    db: &dyn Store,
     email: &str,
 ) -> ApiResult<bool> {
-    let collection: Collection<Document> = db.collection("users");
-    let filter = doc! { "email": email };
-    let user = collection.find_one(filter).await?;
-    Ok(user.is_some())
+    for collection in ["users", "tmp_users", "admins"] {
+        if db.find_account_by_email(collection, email).await?.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether `user_id` is an active admin with the instructor role.
+pub async fn check_instructor_exists(
+    db: &dyn Store,
+    user_id: &ObjectId,
+) -> ApiResult<bool> {
+    let admin = db.find_account_in("admins", user_id).await?;
+    Ok(admin.is_some_and(|admin| admin.is_instructor()))
+}
+
+/// Whether `user_id` is an active admin on the staff of `course_id`.
+pub async fn check_course_staff(
+    db: &dyn Store,
+    user_id: &ObjectId,
+    course_id: &str,
+) -> ApiResult<bool> {
+    let admin = db.find_account_in("admins", user_id).await?;
+    Ok(admin.is_some_and(|admin| admin.is_staff_of(course_id)))
+}
+
+pub async fn check_enrolled(
+    db: &dyn Store,
+    user_id: &ObjectId,
+    course_id: &str,
+) -> ApiResult<bool> {
+    let user = db.find_account_in("users", user_id).await?;
+    Ok(user.is_some_and(|user| user.courses().iter().any(|id| id == course_id)))
+}
+
+/// Sections of `course_id` whose students an admin may see, or `None` for instructors who see every student.
+pub async fn admin_sections(
+    db: &dyn Store,
+    user_id: &ObjectId,
+    course_id: &str,
+) -> ApiResult<Option<Vec<ObjectId>>> {
+    if check_instructor_exists(db, user_id).await? {
+        return Ok(None);
+    }
+    let sections = db
+        .list_sections(course_id)
+        .await?
+        .into_iter()
+        .filter(|section| section.ta_ids.contains(user_id))
+        .map(|section| section.id)
+        .collect();
+    Ok(Some(sections))
+}
+
+/// Filter matching the students of `course_id` an admin may see.
+/// Admins outside the course staff see nobody.
+pub async fn student_scope(
+    db: &dyn Store,
+    admin_id: &ObjectId,
+    course_id: &str,
+) -> ApiResult<StudentFilter> {
+    if !check_course_staff(db, admin_id, course_id).await? {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            "Admin access required".to_string(),
+        ));
+    }
+    Ok(StudentFilter {
+        course_id: course_id.to_string(),
+        sections: admin_sections(db, admin_id, course_id).await?,
+        ..Default::default()
+    })
+}
+
+/// Look up a student by username, treating students outside the admin's scope as missing.
+pub async fn find_scoped_user_id(
+    db: &dyn Store,
+    admin_id: &ObjectId,
+    course_id: &str,
+    username: &str,
+) -> ApiResult<Option<ObjectId>> {
+    let mut filter = student_scope(db, admin_id, course_id).await?;
+    filter.username = Some(username.to_string());
+    Ok(db.list_students(&filter).await?.first().map(|user| user.id))
+}
+
+/// Ids of every student an admin may see in `course_id`.
+pub async fn scoped_user_ids(
+    db: &dyn Store,
+    admin_id: &ObjectId,
+    course_id: &str,
+) -> ApiResult<Vec<ObjectId>> {
+    let filter = student_scope(db, admin_id, course_id).await?;
+    Ok(db.list_students(&filter).await?.into_iter().map(|user| user.id).collect())
+}
+
+/// Count the super admins other than `user_id` that are still able to log in.
+/// Other staff are not counted since they cannot manage staff accounts.
+pub async fn count_other_active_admins(
+    db: &dyn Store,
+    user_id: &ObjectId,
+) -> ApiResult<u64> {
+    let count = db
+        .list_accounts("admins")
+        .await?
+        .iter()
+        .filter(|admin| {
+            &admin.id != user_id
+                && !admin.disabled
+                && admin.staff_role.as_deref() == Some("super_admin")
+                && admin.deletion_scheduled_at.is_none()
+        })
+        .count();
+    Ok(count as u64)
+}
+
+/// Look up the stable id of an account by its current username.
+pub async fn find_user_id(
+    db: &dyn Store,
+    collection: &str,
+    username: &str,
+) -> ApiResult<Option<ObjectId>> {
+    Ok(db.find_account_by_username(collection, username).await?.map(|account| account.id))
 }
 
 pub async fn create_user<T: UserTypeTrait>(
-    db: &Database,
+    db: &dyn Store,
     username: &str,
     email: &str,
-    password: &str,
+    password_hash: &str,
     created_at: &str,
-) -> ApiResult<()> {
-    let typ = T::VALUE;
-    let collection = db.collection(typ);
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    let password_hash = argon2.hash_password(password.as_bytes(), &salt)?.to_string();
-    let user_doc = doc! {
-        "username": username,
-        "email": email,
-        "password": password_hash,
-        "created_at": created_at,
+    created_by: Option<&ObjectId>,
+    staff_role: Option<&str>,
+) -> ApiResult<ObjectId> {
+    let account = Account {
+        id: ObjectId::new(),
+        username: username.to_string(),
+        email: email.to_string(),
+        password: password_hash.to_string(),
+        created_at: created_at.to_string(),
+        created_by: created_by.copied(),
+        staff_role: staff_role.map(str::to_string),
+        disabled: false,
+        course_ids: None,
+        section_ids: Vec::new(),
+        student_id: None,
+        real_name: None,
+        preferred_language: None,
+        avatar: None,
+        suspension: None,
+        must_change_password: false,
+        deletion_scheduled_at: None,
+        unsubscribed: Vec::new(),
     };
-    collection.insert_one(user_doc).await?;
+    db.insert_account(T::VALUE, &account).await?;
+    Ok(account.id)
+}
+
+/// Enroll an account in a course, creating its empty stats for the course.
+pub async fn enroll_user(
+    db: &dyn Store,
+    collection: &str,
+    user_id: &ObjectId,
+    course_id: &str,
+) -> ApiResult<()> {
+    db.add_course(collection, user_id, course_id).await?;
+    db.ensure_stats(user_id, course_id).await?;
     Ok(())
 }
 
-pub async fn activate_user(
+/// Ids of the sections of `course_id`.
+pub async fn course_sections(db: &dyn Store, course_id: &str) -> ApiResult<Vec<ObjectId>> {
+    Ok(db.list_sections(course_id).await?.into_iter().map(|section| section.id).collect())
+}
+
+/// Purge every account whose deletion grace period has run out.
+pub async fn purge_deleted_users(db: &dyn Store) -> ApiResult<()> {
+    for accounts in ["users", "admins"] {
+        for account in db.due_for_deletion(accounts).await? {
+            db.purge_account(accounts, &account.id).await?;
+            log::info!("Purged account {} after its deletion grace period", account.id);
+        }
+    }
+    Ok(())
+}
+
+/// Id of an account in a collection by username, as seen by the migrations.
+async fn legacy_user_id(
     db: &Database,
+    collection: &str,
     username: &str,
-) -> ApiResult<()> {
-    let tmp_collection: Collection<Document> = db.collection("tmp_users");
-    let users_collection = db.collection("users");
-    let filter = doc! { "username": username };
-    let user = tmp_collection.find_one(filter.clone()).await?;
-    if let Some(user_doc) = user {
-        let email = user_doc.get_str("email")?;
-        let password = user_doc.get_str("password")?;
-        let created_at = user_doc.get_str("created_at")?;
-        users_collection.insert_one(doc! {
-            "username": username,
-            "email": email,
-            "password": password,
-            "created_at": created_at,
-        }).await?;
-        tmp_collection.delete_one(filter).await?;
+) -> ApiResult<Option<ObjectId>> {
+    let collection: Collection<Document> = db.collection(collection);
+    let user = collection.find_one(doc! { "username": username }).await?;
+    match user {
+        Some(user_doc) => Ok(Some(user_doc.get_object_id("_id")?)),
+        None => Ok(None),
+    }
+}
+
+/// Rewrite username references left by older versions into `user_id` references.
+/// Documents that already carry a `user_id` are left untouched, so this is safe to run on every start.
+pub async fn migrate_user_ids(db: &Database) -> ApiResult<()> {
+    for referencing in ["stats", "activation_codes"] {
+        let collection: Collection<Document> = db.collection(referencing);
+        let mut cursor = collection
+            .find(doc! { "user_id": { "$exists": false }, "username": { "$exists": true } })
+            .await?;
+        let mut migrated = 0;
+        let mut orphaned = 0;
+        while let Some(legacy_doc) = cursor.try_next().await? {
+            let username = legacy_doc.get_str("username")?;
+            let mut user_id = None;
+            for accounts in ["users", "tmp_users", "admins"] {
+                user_id = legacy_user_id(db, accounts, username).await?;
+                if user_id.is_some() {
+                    break;
+                }
+            }
+            match user_id {
+                Some(user_id) => {
+                    collection
+                        .update_one(
+                            doc! { "_id": legacy_doc.get_object_id("_id")? },
+                            doc! { "$set": { "user_id": user_id }, "$unset": { "username": "" } },
+                        )
+                        .await?;
+                    migrated += 1;
+                }
+                None => orphaned += 1,
+            }
+        }
+        if migrated > 0 || orphaned > 0 {
+            log::info!(
+                "Migrated {} documents in {} to user ids ({} orphaned documents left as is)",
+                migrated, referencing, orphaned
+            );
+        }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Turn free-text `section` profile fields into enrollments in section entities of the same name.
+pub async fn migrate_sections(db: &Database, default_course: &str) -> ApiResult<()> {
+    let users: Collection<Document> = db.collection("users");
+    let sections: Collection<Document> = db.collection("sections");
+    let mut cursor = users.find(doc! { "section": { "$type": "string" } }).await?;
+    while let Some(user_doc) = cursor.try_next().await? {
+        let name = user_doc.get_str("section")?;
+        let section_id = match sections.find_one(doc! { "course_id": default_course, "name": name }).await? {
+            Some(section_doc) => section_doc.get_object_id("_id")?,
+            None => {
+                let section_id = ObjectId::new();
+                sections
+                    .insert_one(doc! {
+                        "_id": section_id,
+                        "course_id": default_course,
+                        "name": name,
+                        "ta_ids": [],
+                        "created_at": chrono::Local::now().to_string(),
+                    })
+                    .await?;
+                log::info!("Created section {} from existing profiles", name);
+                section_id
+            }
+        };
+        users
+            .update_one(
+                doc! { "_id": user_doc.get_object_id("_id")? },
+                doc! { "$set": { "section_ids": [section_id] }, "$unset": { "section": "" } },
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Put data created before courses existed into the default course.
+/// Students now hold one section per course, so the single `section_id` becomes `section_ids`.
+pub async fn migrate_courses(db: &Database, default_course: &str) -> ApiResult<()> {
+    let users: Collection<Document> = db.collection("users");
+    let mut cursor = users.find(doc! { "section_id": { "$exists": true } }).await?;
+    while let Some(user_doc) = cursor.try_next().await? {
+        let section_id = user_doc.get_object_id("section_id")?;
+        users
+            .update_one(
+                doc! { "_id": user_doc.get_object_id("_id")? },
+                doc! { "$set": { "section_ids": [section_id] }, "$unset": { "section_id": "" } },
+            )
+            .await?;
+    }
+
+    let missing = doc! { "course_id": { "$exists": false } };
+    for referencing in ["stats", "sections"] {
+        let collection: Collection<Document> = db.collection(referencing);
+        collection
+            .update_many(missing.clone(), doc! { "$set": { "course_id": default_course } })
+            .await?;
+    }
+    for accounts in ["users", "tmp_users"] {
+        let collection: Collection<Document> = db.collection(accounts);
+        collection
+            .update_many(
+                doc! { "course_ids": { "$exists": false } },
+                doc! { "$set": { "course_ids": [default_course] } },
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Give admins created before permissions existed an explicit role. Admins of every course could
+/// manage staff, so they become super admins; admins restricted to courses become instructors.
+pub async fn migrate_staff_roles(db: &Database) -> ApiResult<()> {
+    let collection: Collection<Document> = db.collection("admins");
+    collection
+        .update_many(
+            doc! { "staff_role": { "$exists": false }, "course_ids": { "$exists": false } },
+            doc! { "$set": { "staff_role": "super_admin" } },
+        )
+        .await?;
+    collection
+        .update_many(
+            doc! { "staff_role": { "$exists": false } },
+            doc! { "$set": { "staff_role": "instructor" } },
+        )
+        .await?;
+    Ok(())
+}
@@ -8,6 +8,8 @@ pub enum ApiErrorType {
     NotFound = 1,
     InvalidRequest = 2,
     Internal = 3,
+    Forbidden = 4,
+    TooManyRequests = 5,
 }
 
 impl ApiErrorType {
@@ -16,6 +18,8 @@ impl ApiErrorType {
             ApiErrorType::NotFound => "ERR_NOT_FOUND",
             ApiErrorType::InvalidRequest => "ERR_INVALID_REQUEST",
             ApiErrorType::Internal => "ERR_INTERNAL_SERVER_ERROR",
+            ApiErrorType::Forbidden => "ERR_FORBIDDEN",
+            ApiErrorType::TooManyRequests => "ERR_TOO_MANY_REQUESTS",
         }
     }
 
@@ -24,6 +28,8 @@ impl ApiErrorType {
             ApiErrorType::NotFound => StatusCode::NOT_FOUND,
             ApiErrorType::InvalidRequest => StatusCode::BAD_REQUEST,
             ApiErrorType::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiErrorType::Forbidden => StatusCode::FORBIDDEN,
+            ApiErrorType::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
         }
     }
 }
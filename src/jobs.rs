@@ -0,0 +1,335 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use actix_web::web;
+use lettre::SmtpTransport;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::bson::oid::ObjectId;
+
+use crate::campaign;
+use crate::config::Config;
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::models::{Job, JobProgress, JobSpec};
+use crate::notification;
+use crate::report;
+use crate::repository::{Store, StudentFilter};
+
+/// Times a job is taken by a worker before a job whose workers keep dying is given up.
+pub const MAX_ATTEMPTS: i32 = 3;
+/// How long an idle worker waits before looking for queued jobs again.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Students whose stats are cleared at once.
+const CLEAR_BATCH: usize = 500;
+
+impl JobSpec {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            JobSpec::WeeklyReport { .. } => "weekly_report",
+            JobSpec::ClearStats { .. } => "clear_stats",
+            JobSpec::Campaign { .. } => "campaign",
+        }
+    }
+
+    pub fn course_id(&self) -> &str {
+        match self {
+            JobSpec::WeeklyReport { course_id, .. }
+            | JobSpec::ClearStats { course_id, .. }
+            | JobSpec::Campaign { course_id, .. } => course_id,
+        }
+    }
+}
+
+/// Queue a job for the workers, returning its id.
+pub async fn enqueue(db: &dyn Store, spec: JobSpec, created_by: Option<ObjectId>) -> ApiResult<ObjectId> {
+    let job = Job::new(spec, created_by);
+    db.insert_job(&job).await?;
+    log::info!("Queued {} job {}", job.spec.kind(), job.id);
+    Ok(job.id)
+}
+
+/// A job taken by a worker, with what its work needs.
+struct JobContext<'a> {
+    db: &'a dyn Store,
+    mailer: &'a SmtpTransport,
+    config: &'a Config,
+    job: &'a Job,
+    cancelled: AtomicBool,
+}
+
+impl JobContext<'_> {
+    /// Save how far the job got, returning `false` when it should stop because it was cancelled.
+    async fn progress(&self, progress: &JobProgress, result: &Document) -> ApiResult<bool> {
+        let go_on = self.db.update_job_progress(&self.job.id, progress, Some(result)).await?;
+        if !go_on {
+            self.cancelled.store(true, Ordering::Relaxed);
+        }
+        Ok(go_on)
+    }
+}
+
+/// Run the oldest queued job, returning whether there was one.
+pub async fn run_next(db: &dyn Store, mailer: &SmtpTransport, config: &Config, worker: &str) -> ApiResult<bool> {
+    let Some(job) = db.claim_job(worker).await? else {
+        return Ok(false);
+    };
+    log::info!("Worker {} runs {} job {}, attempt {}", worker, job.spec.kind(), job.id, job.attempts);
+    let ctx = JobContext { db, mailer, config, job: &job, cancelled: AtomicBool::new(false) };
+    match execute(&ctx).await {
+        Ok(result) => {
+            let status = match ctx.cancelled.load(Ordering::Relaxed) {
+                true => "cancelled",
+                false => "succeeded",
+            };
+            db.finish_job(&job.id, status, Some(&result), None).await?;
+        }
+        Err(e) => {
+            log::error!("Job {} failed: {}", job.id, e);
+            db.finish_job(&job.id, "failed", None, Some(&e.to_string())).await?;
+        }
+    }
+    finished(db, &job.id).await?;
+    Ok(true)
+}
+
+async fn execute(ctx: &JobContext<'_>) -> ApiResult<Document> {
+    match &ctx.job.spec {
+        JobSpec::WeeklyReport { course_id, sections, .. } => weekly_report(ctx, course_id, sections.clone()).await,
+        JobSpec::ClearStats { course_id, user_ids } => clear_stats(ctx, course_id, user_ids).await,
+        JobSpec::Campaign { course_id, campaign_id } => campaign(ctx, course_id, campaign_id).await,
+    }
+}
+
+async fn weekly_report(ctx: &JobContext<'_>, course_id: &str, sections: Option<Vec<ObjectId>>) -> ApiResult<Document> {
+    let course = ctx.config.course(course_id).ok_or_else(|| ApiError::new(
+        ApiErrorType::InvalidRequest,
+        format!("Unknown course {}", course_id),
+    ))?;
+    let filter = StudentFilter {
+        course_id: course_id.to_string(),
+        sections,
+        ..Default::default()
+    };
+    // a stable order, so a resumed job knows who got the report already
+    let mut students = ctx.db.list_students(&filter).await?;
+    students.sort_by_key(|student| student.id);
+
+    let mut progress = ctx.job.progress.clone();
+    progress.total = students.len() as i64;
+    let previous = ctx.job.result.clone().unwrap_or_default();
+    let mut sent = previous.get_i64("sent").unwrap_or(0);
+    let mut unsubscribed = previous.get_i64("unsubscribed").unwrap_or(0);
+    let mut failed = failed_usernames(&previous);
+    let mut result = doc! { "sent": sent, "unsubscribed": unsubscribed, "failed": &failed };
+
+    let resume_after = progress.cursor;
+    let pending = students.iter().filter(|student| resume_after.is_none_or(|cursor| student.id > cursor));
+    for student in pending {
+        if !notification::accepts(student, "weekly_report") {
+            unsubscribed += 1;
+        } else if let Some(stats) = ctx.db.find_stats(&student.id, course_id).await? {
+            match report::send(ctx.db, ctx.mailer, ctx.config, course, student, &stats).await?.error {
+                None => {
+                    log::info!("Email sent to {}", student.username);
+                    sent += 1;
+                }
+                Some(e) => {
+                    log::error!("Failed to send email to {}: {}", student.username, e);
+                    failed.push(student.username.clone());
+                }
+            }
+        }
+        progress.done += 1;
+        progress.cursor = Some(student.id);
+        result = doc! { "sent": sent, "unsubscribed": unsubscribed, "failed": &failed };
+        if !ctx.progress(&progress, &result).await? {
+            break;
+        }
+    }
+    // the partial result saved above keeps who got the report
+    if !failed.is_empty() {
+        return Err(ApiError::new(
+            ApiErrorType::Internal,
+            format!("Failed to send email to {}", failed.join(", ")),
+        ));
+    }
+    Ok(result)
+}
+
+fn failed_usernames(result: &Document) -> Vec<String> {
+    result
+        .get_array("failed")
+        .map(|failed| failed.iter().filter_map(Bson::as_str).map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+async fn clear_stats(ctx: &JobContext<'_>, course_id: &str, user_ids: &[ObjectId]) -> ApiResult<Document> {
+    let mut progress = ctx.job.progress.clone();
+    progress.total = user_ids.len() as i64;
+    let mut cleared = ctx.job.result.as_ref().and_then(|result| result.get_i64("cleared").ok()).unwrap_or(0);
+    let done = (progress.done as usize).min(user_ids.len());
+    for batch in user_ids[done..].chunks(CLEAR_BATCH) {
+        cleared += ctx.db.clear_stats(course_id, batch).await? as i64;
+        progress.done += batch.len() as i64;
+        if !ctx.progress(&progress, &doc! { "cleared": cleared }).await? {
+            break;
+        }
+    }
+    Ok(doc! { "cleared": cleared })
+}
+
+async fn campaign(ctx: &JobContext<'_>, course_id: &str, campaign_id: &ObjectId) -> ApiResult<Document> {
+    let campaign = ctx.db.find_campaign(campaign_id).await?.ok_or_else(|| ApiError::new(
+        ApiErrorType::InvalidRequest,
+        format!("Unknown campaign {}", campaign_id),
+    ))?;
+    let admin = ctx.db.find_account_in("admins", &campaign.created_by).await?;
+    // deliveries are only pending until they were tried, so a resumed job skips the others
+    let deliveries = ctx.db.list_deliveries(campaign_id).await?;
+    let count = |status: &str| deliveries.iter().filter(|delivery| delivery.status == status).count() as i64;
+    let (mut sent, mut failed, mut skipped) = (count("sent"), count("failed"), count("skipped"));
+    let mut progress = ctx.job.progress.clone();
+    progress.total = deliveries.len() as i64;
+    progress.done = sent + failed + skipped;
+    let mut result = doc! { "sent": sent, "failed": failed, "skipped": skipped };
+
+    for mut delivery in deliveries.into_iter().filter(|delivery| delivery.status == "pending") {
+        let student = ctx
+            .db
+            .find_account_in("users", &delivery.user_id)
+            .await?
+            .filter(|student| student.courses().iter().any(|id| id == course_id));
+        match student {
+            Some(student) if !notification::accepts(&student, "announcements") => {
+                delivery.status = "skipped".to_string();
+                delivery.error = Some("Unsubscribed from announcements".to_string());
+                skipped += 1;
+            }
+            Some(student) => match campaign::send(ctx.db, ctx.mailer, ctx.config, &campaign, admin.as_ref(), &student).await?.error {
+                None => {
+                    log::info!("Email sent to {}", student.username);
+                    delivery.status = "sent".to_string();
+                    delivery.email = Some(student.email);
+                    sent += 1;
+                }
+                Some(e) => {
+                    log::error!("Failed to send email to {}: {}", student.username, e);
+                    delivery.status = "failed".to_string();
+                    delivery.error = Some(e);
+                    failed += 1;
+                }
+            },
+            None => {
+                delivery.status = "skipped".to_string();
+                skipped += 1;
+            }
+        }
+        delivery.delivered_at = Some(DateTime::now());
+        ctx.db.update_delivery(&delivery).await?;
+        progress.done += 1;
+        result = doc! { "sent": sent, "failed": failed, "skipped": skipped };
+        if !ctx.progress(&progress, &result).await? {
+            break;
+        }
+    }
+    if failed > 0 {
+        return Err(ApiError::new(
+            ApiErrorType::Internal,
+            format!("Failed to send email to {} students", failed),
+        ));
+    }
+    Ok(result)
+}
+
+/// Record the end of a job where others need to know, e.g. in the report run it delivered.
+pub async fn finished(db: &dyn Store, job_id: &ObjectId) -> ApiResult<()> {
+    let Some(job) = db.find_job(job_id).await? else {
+        return Ok(());
+    };
+    if let JobSpec::WeeklyReport { run_id: Some(run_id), .. } = &job.spec {
+        if let Some(mut run) = db.find_report_run(run_id).await? {
+            let result = job.result.unwrap_or_default();
+            run.status = job.status;
+            run.sent = result.get_i64("sent").unwrap_or(0);
+            run.failed = failed_usernames(&result);
+            run.error = job.error;
+            run.finished_at = Some(DateTime::now());
+            db.update_report_run(&run).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Put the jobs whose worker died since `before` back in the queue, so another worker resumes them.
+/// Jobs that were cancelled meanwhile, or already taken `MAX_ATTEMPTS` times, are finished instead.
+/// Report runs started before `before` whose job is gone or over are closed as well.
+pub async fn recover(db: &dyn Store, before: DateTime) -> ApiResult<u64> {
+    let mut requeued = 0;
+    for job in db.stale_jobs(before).await? {
+        if job.cancel_requested {
+            db.finish_job(&job.id, "cancelled", None, None).await?;
+        } else if job.attempts >= MAX_ATTEMPTS {
+            log::error!("Giving up job {} after {} attempts", job.id, job.attempts);
+            db.finish_job(&job.id, "failed", None, Some("Interrupted too many times")).await?;
+        } else {
+            if db.requeue_job(&job.id, before).await? {
+                log::warn!("Requeued job {} interrupted on worker {}", job.id, job.worker.as_deref().unwrap_or("?"));
+                requeued += 1;
+            }
+            continue;
+        }
+        finished(db, &job.id).await?;
+    }
+
+    for mut run in db.running_report_runs(before).await? {
+        let job = match &run.job_id {
+            Some(job_id) => db.find_job(job_id).await?,
+            None => None,
+        };
+        match job {
+            Some(job) if !job.is_finished() => {}
+            Some(job) => finished(db, &job.id).await?,
+            // the server stopped between claiming the run and queueing its job
+            None => {
+                log::warn!("Report run {} lost its job", run.id);
+                run.status = "failed".to_string();
+                run.error = Some("Interrupted by a restart".to_string());
+                run.finished_at = Some(DateTime::now());
+                db.update_report_run(&run).await?;
+            }
+        }
+    }
+    Ok(requeued)
+}
+
+/// Spawn the workers running queued jobs, and the task resuming the jobs of dead workers.
+pub fn start_workers(db: web::Data<dyn Store>, mailer: SmtpTransport, config: Config) {
+    if config.jobs.workers == 0 {
+        log::warn!("No job workers configured, queued jobs will not run.");
+    }
+    for n in 0..config.jobs.workers {
+        let worker = format!("{}-{}", std::process::id(), n);
+        let (db, mailer, config) = (db.clone(), mailer.clone(), config.clone());
+        actix_web::rt::spawn(async move {
+            loop {
+                match run_next(db.get_ref(), &mailer, &config, &worker).await {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(e) => log::error!("Job worker {} failed: {}", worker, e),
+                }
+                actix_web::rt::time::sleep(POLL_INTERVAL).await;
+            }
+        });
+    }
+
+    let stale = chrono::Duration::minutes(config.jobs.stale_minutes);
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let before = DateTime::from_millis((chrono::Utc::now() - stale).timestamp_millis());
+            if let Err(e) = recover(db.get_ref(), before).await {
+                log::error!("Failed to recover interrupted jobs: {}", e);
+            }
+        }
+    });
+}
@@ -1,60 +1,121 @@
 use std::env;
-use actix_web::FromRequest;
+use actix_web::{web, FromRequest};
 use serde::{Serialize, Deserialize};
 use jsonwebtoken::{encode, decode, Header, EncodingKey, DecodingKey, Validation, errors::Error};
-use futures::future::{ready, Ready};
+use futures::future::{ready, LocalBoxFuture};
+use mongodb::bson::oid::ObjectId;
+
+use crate::error::{ApiError, ApiErrorType};
+use crate::models::Account;
+use crate::repository::Store;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
-    username: String,
+    sub: String,
+    /// Session the token was issued for, tokens issued before sessions existed have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sid: Option<String>,
     iat: usize,
     exp: usize,
 }
 
 impl Claims {
-    pub fn new(username: String, exp_hours: usize) -> Self {
+    pub fn new(user_id: &ObjectId, session_id: &ObjectId, exp_hours: usize) -> Self {
         let iat = chrono::Utc::now().timestamp() as usize;
         let exp = iat + exp_hours * 3600;
-        Claims { username, iat, exp }
+        Claims { sub: user_id.to_hex(), sid: Some(session_id.to_hex()), iat, exp }
     }
 
-    pub fn create_jwt(username: String, exp_hours: usize) -> Result<String, Error> {
-        let claims = Claims::new(username, exp_hours);
+    pub fn create_jwt(user_id: &ObjectId, session_id: &ObjectId, exp_hours: usize) -> Result<String, Error> {
+        let claims = Claims::new(user_id, session_id, exp_hours);
         let secret = env::var("YWT_SECRET").unwrap_or_else(|_| "ywt_secret".to_string());
         encode(
-            &Header::default(), 
-            &claims, 
+            &Header::default(),
+            &claims,
             &EncodingKey::from_secret(secret.as_bytes())
         )
     }
 }
 
 pub struct ClaimsValidator {
-    pub username: String,
+    pub user_id: ObjectId,
+    pub session_id: Option<ObjectId>,
+}
+
+/// The only route a token may reach while its account must change password.
+const PASSWORD_CHANGE_PATH: &str = "/modify/password";
+
+/// The account and session a request's token was issued for.
+pub(crate) fn decode_token(req: &actix_web::HttpRequest) -> Result<(ObjectId, Option<ObjectId>), actix_web::Error> {
+    let auth_header = req.headers().get("Authorization").and_then(|h| h.to_str().ok());
+    let secret = env::var("YWT_SECRET").unwrap_or_else(|_| "ywt_secret".to_string());
+    let invalid = || actix_web::error::ErrorUnauthorized("Invalid token");
+    if let Some(token) = auth_header.and_then(|h| h.strip_prefix("Bearer ")) {
+        match decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(secret.as_bytes()),
+            &Validation::default(),
+        ) {
+            Ok(token_data) => {
+                let user_id = ObjectId::parse_str(&token_data.claims.sub).map_err(|_| invalid())?;
+                let session_id = match token_data.claims.sid {
+                    Some(sid) => Some(ObjectId::parse_str(&sid).map_err(|_| invalid())?),
+                    None => None,
+                };
+                Ok((user_id, session_id))
+            }
+            Err(_) => Err(invalid()),
+        }
+    } else {
+        Err(actix_web::error::ErrorUnauthorized("Missing token"))
+    }
+}
+
+/// Load the account behind a token and check that it may still be used on `path`.
+pub(crate) async fn authenticate(
+    db: Option<web::Data<dyn Store>>,
+    user_id: ObjectId,
+    session_id: Option<ObjectId>,
+    path: &str,
+) -> Result<(web::Data<dyn Store>, Account), actix_web::Error> {
+    let db = db.ok_or_else(|| ApiError::new(
+        ApiErrorType::Internal,
+        "Database is not configured".to_string(),
+    ))?;
+    if let Some(session_id) = session_id {
+        if !db.touch_session(&session_id, &user_id).await? {
+            return Err(actix_web::error::ErrorUnauthorized("Session revoked"));
+        }
+    }
+    // a valid signature is not enough, the account behind it must still be usable
+    let account = db
+        .find_account(&user_id)
+        .await?
+        .ok_or(actix_web::error::ErrorUnauthorized("Invalid token"))?;
+    account.check_suspension()?;
+    if account.must_change_password && path != PASSWORD_CHANGE_PATH {
+        return Err(ApiError::new(
+            ApiErrorType::Forbidden,
+            "Password change required".to_string(),
+        ).into());
+    }
+    Ok((db, account))
 }
 
 impl FromRequest for ClaimsValidator {
     type Error = actix_web::Error;
-    type Future = Ready<Result<Self, Self::Error>>;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
 
     fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
-        let auth_header = req.headers().get("Authorization").and_then(|h| h.to_str().ok());
-        let secret = env::var("YWT_SECRET").unwrap_or_else(|_| "ywt_secret".to_string());
-        if let Some(token) = auth_header.and_then(|h| h.strip_prefix("Bearer ")) {
-            match decode::<Claims>(
-                token,
-                &DecodingKey::from_secret(secret.as_bytes()),
-                &Validation::default(),
-            ) {
-                Ok(token_data) => {
-                    ready(Ok(ClaimsValidator {
-                        username: token_data.claims.username,
-                    }))
-                }
-                Err(_) => ready(Err(actix_web::error::ErrorUnauthorized("Invalid token"))),
-            }
-        } else {
-            ready(Err(actix_web::error::ErrorUnauthorized("Missing token")))
-        }
+        let (user_id, session_id) = match decode_token(req) {
+            Ok(token) => token,
+            Err(e) => return Box::pin(ready(Err(e))),
+        };
+        let db = req.app_data::<web::Data<dyn Store>>().cloned();
+        let path = req.path().to_string();
+        Box::pin(async move {
+            authenticate(db, user_id, session_id, &path).await?;
+            Ok(ClaimsValidator { user_id, session_id })
+        })
     }
-}
\ No newline at end of file
+}
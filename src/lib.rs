@@ -4,4 +4,19 @@ pub mod db;
 pub mod error;
 pub mod api;
 pub mod jwt;
-pub mod utils;
\ No newline at end of file
+pub mod utils;
+pub mod password;
+pub mod api_key;
+pub mod mail;
+pub mod course;
+pub mod permission;
+pub mod audit;
+pub mod session;
+pub mod captcha;
+pub mod live;
+pub mod models;
+pub mod repository;
+pub mod report;
+pub mod jobs;
+pub mod campaign;
+pub mod notification;
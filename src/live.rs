@@ -0,0 +1,157 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use mongodb::bson::oid::ObjectId;
+use tokio::sync::broadcast;
+
+use crate::error::ApiResult;
+use crate::repository::Store;
+
+/// Events kept for clients reconnecting with `Last-Event-ID`.
+const REPLAY_EVENTS: usize = 1000;
+/// Window over which tag mentions count as trending.
+const TRENDING_WINDOW: Duration = Duration::from_secs(3600);
+const TRENDING_TAGS: usize = 5;
+
+/// Something that happened in a course, as pushed to the live dashboard.
+#[derive(Debug)]
+pub struct LiveEvent {
+    pub id: u64,
+    pub course_id: String,
+    /// Section of the student in the course, staff limited to sections only see events that have one
+    pub section_id: Option<ObjectId>,
+    pub kind: &'static str,
+    pub data: serde_json::Value,
+}
+
+struct HubState {
+    next_id: u64,
+    recent: VecDeque<Arc<LiveEvent>>,
+    mentions: VecDeque<(Instant, String, String)>,
+}
+
+/// Fans out events written by the stats and registration handlers to dashboard connections.
+pub struct LiveHub {
+    sender: broadcast::Sender<Arc<LiveEvent>>,
+    state: Mutex<HubState>,
+}
+
+impl Default for LiveHub {
+    fn default() -> Self {
+        LiveHub::new()
+    }
+}
+
+impl LiveHub {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(REPLAY_EVENTS);
+        LiveHub {
+            sender,
+            state: Mutex::new(HubState {
+                // ids keep growing across restarts, so an old `Last-Event-ID` never hides new events
+                next_id: chrono::Utc::now().timestamp_millis() as u64 * 1000,
+                recent: VecDeque::new(),
+                mentions: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<LiveEvent>> {
+        self.sender.subscribe()
+    }
+
+    /// Buffered events newer than `last_id`, oldest first.
+    pub fn since(&self, last_id: u64) -> Vec<Arc<LiveEvent>> {
+        let state = self.state.lock().unwrap();
+        state.recent.iter().filter(|event| event.id > last_id).cloned().collect()
+    }
+
+    pub fn publish(
+        &self,
+        course_id: &str,
+        section_id: Option<ObjectId>,
+        kind: &'static str,
+        data: serde_json::Value,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let event = Arc::new(LiveEvent {
+            id: state.next_id,
+            course_id: course_id.to_string(),
+            section_id,
+            kind,
+            data,
+        });
+        state.next_id += 1;
+        if state.recent.len() == REPLAY_EVENTS {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(event.clone());
+        // sending only fails when no dashboard is connected
+        let _ = self.sender.send(event);
+    }
+
+    /// Count tag mentions in a course and return its most mentioned tags of the last hour.
+    pub fn mention(&self, course_id: &str, tags: &[String]) -> Vec<(String, usize)> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        while state.mentions.front().is_some_and(|(at, _, _)| now.duration_since(*at) > TRENDING_WINDOW) {
+            state.mentions.pop_front();
+        }
+        for tag in tags {
+            state.mentions.push_back((now, course_id.to_string(), tag.clone()));
+        }
+
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for (_, course, tag) in &state.mentions {
+            if course == course_id {
+                *counts.entry(tag.as_str()).or_default() += 1;
+            }
+        }
+        let mut trending: Vec<(String, usize)> = counts
+            .into_iter()
+            .map(|(tag, count)| (tag.to_string(), count))
+            .collect();
+        trending.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        trending.truncate(TRENDING_TAGS);
+        trending
+    }
+
+    /// Publish an event about a student, adding their username and their section in the course.
+    /// The write it reports already happened, so failing to look the student up is only logged.
+    pub async fn publish_student(
+        &self,
+        db: &dyn Store,
+        user_id: &ObjectId,
+        course_id: &str,
+        kind: &'static str,
+        mut data: serde_json::Value,
+    ) {
+        match student_info(db, user_id, course_id).await {
+            Ok(Some((username, section_id))) => {
+                data["username"] = username.into();
+                self.publish(course_id, section_id, kind, data);
+            }
+            Ok(None) => {}
+            Err(e) => log::error!("Failed to publish {} event: {}", kind, e),
+        }
+    }
+}
+
+/// Username of a student and their section in `course_id`.
+async fn student_info(
+    db: &dyn Store,
+    user_id: &ObjectId,
+    course_id: &str,
+) -> ApiResult<Option<(String, Option<ObjectId>)>> {
+    let Some(student) = db.find_account_in("users", user_id).await? else {
+        return Ok(None);
+    };
+    let section_id = db
+        .list_sections(course_id)
+        .await?
+        .into_iter()
+        .find(|section| student.section_ids.contains(&section.id))
+        .map(|section| section.id);
+    Ok(Some((student.username, section_id)))
+}
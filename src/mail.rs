@@ -0,0 +1,282 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use lettre::{Message, SmtpTransport, Transport};
+use lettre::address::{Address, AddressError};
+use lettre::message::Mailbox;
+use lettre::message::dkim::{DkimConfig, DkimSigningAlgorithm, DkimSigningKey};
+use lettre::message::header::{ContentType, HeaderName, HeaderValue};
+use mongodb::bson::DateTime;
+use mongodb::bson::oid::ObjectId;
+
+use crate::config::{self, Config};
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::models::{Account, EmailRecord, MailThread};
+use crate::notification::unsubscribe_link;
+use crate::repository::Store;
+
+/// Kinds of email that may have their own sender: account emails, and the notification categories.
+pub const KINDS: [&str; 4] = ["account", "weekly_report", "announcements", "ta_messages"];
+
+/// Most Message-IDs an email references, the first of the thread and the latest ones are kept.
+const MAX_REFERENCES: usize = 20;
+
+/// Where an email from staff belongs: replies go to the staff member, and mail clients show it
+/// after the earlier emails of the thread.
+pub struct Thread<'a> {
+    pub staff: &'a Account,
+    /// Message-IDs of the earlier emails, oldest first
+    pub references: &'a [String],
+}
+
+fn parse_mailbox(address: &str) -> Result<Mailbox, String> {
+    address
+        .parse()
+        .map_err(|e: AddressError| format!("Invalid address {}: {}", address, e))
+}
+
+/// The mailbox of an account, its name quoted and encoded as needed whatever characters it holds.
+fn account_mailbox(name: &str, email: &str) -> Result<Mailbox, String> {
+    let address: Address = email.parse().map_err(|e: AddressError| format!("Invalid address {}: {}", email, e))?;
+    Ok(Mailbox::new(Some(name.to_string()), address))
+}
+
+fn sender(config: &Config, kind: &str) -> String {
+    config
+        .mail
+        .senders
+        .get(kind)
+        .and_then(|sender| sender.from.clone())
+        .or_else(|| config.mail.from.clone())
+        .unwrap_or_else(|| format!("YWT Bot <{}>", config.smtp_username))
+}
+
+fn dkim_config(dkim: &config::DkimConfig, key: &str) -> Result<DkimConfig> {
+    let algorithm = match dkim.algorithm.as_str() {
+        "rsa" => DkimSigningAlgorithm::Rsa,
+        "ed25519" => DkimSigningAlgorithm::Ed25519,
+        other => return Err(anyhow!("Unknown DKIM algorithm {}", other)),
+    };
+    let key = DkimSigningKey::new(key.trim(), algorithm).map_err(|e| anyhow!("Invalid DKIM private key: {}", e))?;
+    Ok(DkimConfig::default_config(dkim.selector.clone(), dkim.domain.clone(), key))
+}
+
+/// Check every configured address and build the DKIM signer from the key, read from its file when
+/// needed, so a bad mail setup stops the server at startup instead of failing each email.
+pub fn prepare(config: &mut Config) -> Result<()> {
+    let mail = &config.mail;
+    let mut addresses = vec![sender(config, "account")];
+    addresses.extend(mail.reply_to.clone());
+    for (kind, overrides) in &mail.senders {
+        if !KINDS.contains(&kind.as_str()) {
+            return Err(anyhow!("Unknown mail kind {}, expected one of {}", kind, KINDS.join(", ")));
+        }
+        addresses.extend(overrides.from.clone());
+        addresses.extend(overrides.reply_to.clone());
+    }
+    for address in &addresses {
+        parse_mailbox(address).map_err(|e| anyhow!(e))?;
+    }
+
+    if let Some(dkim) = &mut config.mail.dkim {
+        let key = match (dkim.private_key.take(), &dkim.private_key_path) {
+            (Some(key), _) => key,
+            (None, Some(path)) => {
+                std::fs::read_to_string(path).map_err(|e| anyhow!("Failed to read DKIM key {}: {}", path, e))?
+            }
+            (None, None) => return Err(anyhow!("DKIM needs a private_key or a private_key_path")),
+        };
+        dkim.signer = Some(Arc::new(dkim_config(dkim, &key)?));
+    }
+    Ok(())
+}
+
+/// The Message-ID of a built message.
+pub fn message_id(message: &Message) -> Option<String> {
+    message.headers().get_raw("Message-ID").map(str::to_string)
+}
+
+/// The subject a thread is kept under, without the reply prefixes staff may type.
+pub fn thread_subject(subject: &str) -> &str {
+    let mut subject = subject.trim();
+    while let Some(rest) = ["Re:", "RE:", "re:", "回复:", "回复："].iter().find_map(|prefix| subject.strip_prefix(prefix)) {
+        subject = rest.trim_start();
+    }
+    subject
+}
+
+/// An unsigned plain text message of `kind` to `name <email>`.
+fn compose(
+    config: &Config,
+    kind: &str,
+    name: &str,
+    email: &str,
+    subject: &str,
+    body: String,
+    thread: Option<&Thread>,
+) -> ApiResult<Message> {
+    let internal = |e: String| ApiError::new(ApiErrorType::Internal, format!("Invalid mail configuration: {}", e));
+    let invalid = |e: String| ApiError::new(ApiErrorType::InvalidRequest, format!("Invalid email: {}", e));
+    let from = parse_mailbox(&sender(config, kind)).map_err(internal)?;
+    let to = account_mailbox(name, email).map_err(invalid)?;
+    // replies to staff go to them, unless the kind says otherwise
+    let configured = config.mail.senders.get(kind).and_then(|sender| sender.reply_to.as_deref());
+    let reply_to = match (configured, thread) {
+        (Some(reply_to), _) => Some(parse_mailbox(reply_to).map_err(internal)?),
+        (None, Some(thread)) => Some(account_mailbox(&thread.staff.username, &thread.staff.email).map_err(invalid)?),
+        (None, None) => config.mail.reply_to.as_deref().map(parse_mailbox).transpose().map_err(internal)?,
+    };
+
+    let message_id = format!("<{}@{}>", ObjectId::new().to_hex(), from.email.domain());
+    let mut builder = Message::builder()
+        .from(from)
+        .to(to)
+        .subject(subject)
+        .message_id(Some(message_id))
+        .header(ContentType::TEXT_PLAIN);
+    if let Some(reply_to) = reply_to {
+        builder = builder.reply_to(reply_to);
+    }
+    if let Some(references) = thread.map(|thread| thread.references).filter(|references| !references.is_empty()) {
+        let mut kept: Vec<&str> = references.iter().map(String::as_str).collect();
+        if kept.len() > MAX_REFERENCES {
+            kept.drain(1..kept.len() + 1 - MAX_REFERENCES);
+        }
+        builder = builder
+            .in_reply_to(references[references.len() - 1].clone())
+            .references(kept.join(" "));
+    }
+    builder.body(body).map_err(|e| invalid(e.to_string()))
+}
+
+/// Add a DKIM signature when configured, once every header is set.
+fn sign(config: &Config, message: &mut Message) -> ApiResult<()> {
+    if let Some(dkim) = &config.mail.dkim {
+        let signer = dkim.signer.as_ref().ok_or_else(|| ApiError::new(
+            ApiErrorType::Internal,
+            "DKIM key not loaded, see mail::prepare".to_string(),
+        ))?;
+        message.sign(signer);
+    }
+    Ok(())
+}
+
+/// Build a plain text message of `kind`, see `KINDS`, to `name <email>`.
+pub fn build_message(
+    config: &Config,
+    kind: &str,
+    name: &str,
+    email: &str,
+    subject: &str,
+    body: String,
+) -> ApiResult<Message> {
+    let mut message = compose(config, kind, name, email, subject, body, None)?;
+    sign(config, &mut message)?;
+    Ok(message)
+}
+
+/// Build a bulk email of `category` to a student. The body ends with a link turning the category off,
+/// which the `List-Unsubscribe` headers also offer for one-click unsubscribe in mail clients.
+pub fn build_notification(
+    config: &Config,
+    student: &Account,
+    category: &str,
+    subject: &str,
+    body: String,
+    thread: Option<&Thread>,
+) -> ApiResult<Message> {
+    let link = unsubscribe_link(config, &student.id, category)?;
+    let footer = match &link {
+        Some(link) => format!("不想再收到此类邮件？点击退订：{}", link),
+        None => "不想再收到此类邮件？可在 YWT 的通知设置中关闭。".to_string(),
+    };
+    let body = format!("{}\n\n--\n{}", body, footer);
+    let mut message = compose(config, category, &student.username, &student.email, subject, body, thread)?;
+    if let Some(link) = link {
+        let headers = message.headers_mut();
+        headers.insert_raw(HeaderValue::new(HeaderName::new_from_ascii_str("List-Unsubscribe"), format!("<{}>", link)));
+        headers.insert_raw(HeaderValue::new(
+            HeaderName::new_from_ascii_str("List-Unsubscribe-Post"),
+            "List-Unsubscribe=One-Click".to_string(),
+        ));
+    }
+    sign(config, &mut message)?;
+    Ok(message)
+}
+
+/// Send an email from staff to a student and record it. It follows the earlier emails the staff member
+/// sent them under the same subject, so mail clients show one conversation.
+pub async fn send_from_staff(
+    db: &dyn Store,
+    mailer: &SmtpTransport,
+    config: &Config,
+    staff: &Account,
+    student: &Account,
+    record: EmailRecord,
+) -> ApiResult<EmailRecord> {
+    let subject = thread_subject(&record.subject).to_string();
+    let mut thread = db.find_mail_thread(&staff.id, &student.id, &subject).await?.unwrap_or_else(|| MailThread {
+        id: ObjectId::new(),
+        staff_id: staff.id,
+        user_id: student.id,
+        subject,
+        message_ids: Vec::new(),
+        updated_at: DateTime::now(),
+    });
+    let message = build_notification(
+        config,
+        student,
+        &record.kind,
+        &record.subject,
+        record.body.clone().unwrap_or_default(),
+        Some(&Thread { staff, references: &thread.message_ids }),
+    )?;
+    let record = send_recorded(db, mailer, &message, record).await?;
+    if let (None, Some(message_id)) = (&record.error, &record.message_id) {
+        thread.message_ids.push(message_id.clone());
+        thread.updated_at = DateTime::now();
+        db.save_mail_thread(&thread).await?;
+    }
+    Ok(record)
+}
+
+/// Add an email that could not be sent to the history of its recipient.
+pub async fn record_failure(db: &dyn Store, mut record: EmailRecord, error: String) -> ApiResult<EmailRecord> {
+    record.status = "failed".to_string();
+    record.error = Some(error);
+    db.insert_email(&record).await?;
+    Ok(record)
+}
+
+/// Send a message and add it to the history of its recipient, whether it went out or not.
+pub async fn send_recorded(
+    db: &dyn Store,
+    mailer: &SmtpTransport,
+    message: &Message,
+    mut record: EmailRecord,
+) -> ApiResult<EmailRecord> {
+    record.message_id = message_id(message);
+    if let Err(e) = mailer.send(message) {
+        return record_failure(db, record, e.to_string()).await;
+    }
+    record.status = "sent".to_string();
+    record.sent_at = Some(DateTime::now());
+    db.insert_email(&record).await?;
+    Ok(record)
+}
+
+/// Send and record a message, logging instead of failing the request when delivery fails.
+pub async fn send_logged(
+    db: &dyn Store,
+    mailer: &SmtpTransport,
+    message: &Message,
+    record: EmailRecord,
+    name: &str,
+    what: &str,
+) -> ApiResult<()> {
+    match send_recorded(db, mailer, message, record).await?.error {
+        None => log::info!("{} sent to {}", what, name),
+        Some(e) => log::error!("Failed to send {} to {}: {}", what.to_lowercase(), name, e),
+    }
+    Ok(())
+}
@@ -1,30 +1,24 @@
 use core::panic;
+use std::sync::Arc;
 
 use clap::Parser;
 use dotenvy::dotenv;
 use mongodb::Client;
 use mongodb::bson::doc;
 use anyhow::Result;
-use env_logger;
-use log;
-use serde_json;
-use actix_web::{middleware::Logger, web, App, HttpServer, ResponseError};
+use actix_web::{middleware::Logger, web, App, HttpServer};
 use actix_cors::Cors;
-use argon2::{
-    password_hash::{
-        rand_core::OsRng,
-        PasswordHasher, SaltString
-    },
-    Argon2
-};
 use lettre::transport::smtp::authentication::Credentials;
 use lettre::SmtpTransport;
 
-use ywt::api::{register, login, profile, modify, stats, problem, send_email, verify_email, users};
+use ywt::api;
 use ywt::cli::Cli;
 use ywt::config::Config;
-use ywt::error::ApiError;
-use ywt::api::problem::QBankEntry;
+use ywt::password::PasswordPolicy;
+use ywt::live::LiveHub;
+use ywt::report::ReportSchedule;
+use ywt::repository::{MongoStore, Store};
+use ywt::api::problem::{QBankEntry, QBanks};
 
 #[actix_web::main]
 async fn main() -> Result<()> {
@@ -33,7 +27,7 @@ async fn main() -> Result<()> {
     log::info!("Starting YWT server...");
     let args = Cli::parse();
     let (
-        config,
+        mut config,
         bind_address,
         bind_port,
         mongo_uri,
@@ -65,23 +59,74 @@ async fn main() -> Result<()> {
         }
     };
 
-    let qbank_path = "./Q_bank/Q_bank.json";
-    let qbank_json_string = std::fs::read_to_string(qbank_path)
-        .expect(&format!("Failed to read Q_bank file at {}", qbank_path));
-    let qbank_data: Vec<QBankEntry> = serde_json::from_str(&qbank_json_string)
-        .expect("Failed to parse Q_bank.json");
-    log::info!("Successfully loaded {} entries from {}", qbank_data.len(), qbank_path);
+    if config.courses.is_empty() {
+        panic!("At least one course must be configured");
+    }
+    let mut qbank_data = QBanks::new();
+    for course in &config.courses {
+        let qbank_path = &course.qbank_path;
+        let qbank_json_string = std::fs::read_to_string(qbank_path)
+            .unwrap_or_else(|_| panic!("Failed to read Q_bank file at {}", qbank_path));
+        let entries: Vec<QBankEntry> = serde_json::from_str(&qbank_json_string)
+            .unwrap_or_else(|_| panic!("Failed to parse Q_bank file at {}", qbank_path));
+        log::info!("Successfully loaded {} entries from {} for course {}", entries.len(), qbank_path, course.id);
+        if qbank_data.insert(course.id.clone(), entries).is_some() {
+            panic!("Duplicate course id {}", course.id);
+        }
+    }
+
+    let report_schedule = ReportSchedule::new(&config.report)?;
+    ywt::mail::prepare(&mut config)?;
+
+    let passwords = web::Data::new(PasswordPolicy::new(&config.password)?);
+    // shared by every worker, so a dashboard sees events written through any of them
+    let live_hub = web::Data::new(LiveHub::new());
 
     let smtp_password = std::env::var("YWT_SMTP_PASSWORD").unwrap_or_else(|_| "your_password".to_string());
     let creds = Credentials::new(smtp_username, smtp_password);
-    let mailer = SmtpTransport::starttls_relay(&smtp_server)
-        .unwrap()
+    let mailer = SmtpTransport::starttls_relay(&smtp_server)?
         .port(smtp_port)
         .credentials(creds)
         .build();
 
     let client = Client::with_uri_str(mongo_uri).await?;
     let db = client.database(&mongo_db);
+    let default_course = config.default_course().id.clone();
+    ywt::db::migrate_user_ids(&db).await?;
+    ywt::db::migrate_courses(&db, &default_course).await?;
+    ywt::db::migrate_sections(&db, &default_course).await?;
+    ywt::db::migrate_staff_roles(&db).await?;
+    // one store for every worker, the handlers only see it through `dyn Store`
+    let store: web::Data<dyn Store> = web::Data::from(Arc::new(MongoStore::new(db.clone()).await?) as Arc<dyn Store>);
+
+    // purge accounts whose deletion grace period has ended
+    let purge_store = store.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            if let Err(e) = ywt::db::purge_deleted_users(purge_store.get_ref()).await {
+                log::error!("Failed to purge deleted accounts: {}", e);
+            }
+        }
+    });
+
+    // jobs cut short by the last shutdown are resumed once they look stale
+    ywt::jobs::start_workers(store.clone(), mailer.clone(), config.clone());
+    if config.report.enabled {
+        let report_store = store.clone();
+        let report_config = config.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                let now = chrono::Utc::now();
+                if let Err(e) = ywt::report::run_due(report_store.get_ref(), &report_config, &report_schedule, now).await {
+                    log::error!("Failed to send the weekly report: {}", e);
+                }
+            }
+        });
+    }
 
     let admin_password = std::env::var("YWT_ADMIN_PASSWORD").unwrap_or_else(|_| "adminpassword".to_string());
     // check if the admins collection is empty
@@ -90,21 +135,22 @@ async fn main() -> Result<()> {
 
     if admin_count == 0 {
         // create the admin user
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
-        let password_hash = argon2
-            .hash_password(admin_password.as_bytes(), &salt)
-            .unwrap()
-            .to_string();
+        let password_hash = passwords.hash(&admin_password)?;
         collection.insert_one(doc! {
             "username": &admin_username,
             "password": password_hash,
             "email": &admin_email,
+            "staff_role": "super_admin",
             "created_at": chrono::Utc::now().to_rfc3339(), // Use UTC time and standard format
         }).await?;
         log::info!("Admin user created: {}", admin_username);
     } else {
         log::info!("Admin collection is not empty, skipping admin creation.");
+        if collection.count_documents(doc! { "disabled": { "$ne": true } }).await? == 0 {
+            log::warn!("All admin accounts are disabled, nobody can log in as admin.");
+        } else if collection.count_documents(doc! { "disabled": { "$ne": true }, "staff_role": "super_admin" }).await? == 0 {
+            log::warn!("No active super admin, nobody can manage staff accounts.");
+        }
     }
 
     HttpServer::new(move || {
@@ -116,22 +162,13 @@ async fn main() -> Result<()> {
         App::new()
             .wrap(Logger::default())
             .wrap(cors)
-            .app_data(web::Data::new(db.clone()))
+            .app_data(store.clone())
             .app_data(web::Data::new(mailer.clone()))
             .app_data(web::Data::new(config.clone()))
             .app_data(web::Data::new(qbank_data.clone()))
-            .service(register::api_scope())
-            .service(login::api_scope())
-            .service(profile::api_scope())
-            .service(modify::api_scope())
-            .service(stats::api_scope())
-            .service(problem::api_scope())
-            .service(send_email::api_scope())
-            .service(verify_email::api_scope())
-            .service(users::api_scope())
-            .default_service(web::to(|| async {
-                ApiError::new_not_found().error_response()
-            }))
+            .app_data(passwords.clone())
+            .app_data(live_hub.clone())
+            .configure(api::routes)
     })
     .bind((bind_address, bind_port))?
     .run()
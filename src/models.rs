@@ -0,0 +1,557 @@
+use std::collections::BTreeMap;
+
+use mongodb::bson::{Binary, Bson, DateTime, Document};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::api_key::hash_key;
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::utils::format_datetime;
+
+/// A student in `users`/`tmp_users` or a staff member in `admins`.
+/// Fields added over time are optional, so documents written by older versions still decode.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Account {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub username: String,
+    #[serde(default)]
+    pub email: String,
+    /// Password hash
+    #[serde(default)]
+    pub password: String,
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub created_at: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<ObjectId>,
+    /// Role of staff accounts, see `permission::ROLES`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staff_role: Option<String>,
+    #[serde(default)]
+    pub disabled: bool,
+    /// Courses a student takes; for staff the courses they are on, every course when `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub course_ids: Option<Vec<String>>,
+    /// Sections of a student, at most one per course
+    #[serde(default)]
+    pub section_ids: Vec<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub student_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub real_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preferred_language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspension: Option<Suspension>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub must_change_password: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deletion_scheduled_at: Option<DateTime>,
+    /// Kinds of email the student turned off, see `notification::CATEGORIES`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unsubscribed: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Suspension {
+    #[serde(default)]
+    pub reason: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspended_by: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspended_at: Option<DateTime>,
+    /// End of the suspension, indefinite when `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<DateTime>,
+}
+
+impl Account {
+    /// Reject accounts with an active suspension. Expired suspensions are ignored.
+    pub fn check_suspension(&self) -> ApiResult<()> {
+        if let Some(suspension) = &self.suspension {
+            if suspension.until.is_none_or(|until| until > DateTime::now()) {
+                let message = match &suspension.until {
+                    Some(until) => format!("Account suspended until {}: {}", format_datetime(until), suspension.reason),
+                    None => format!("Account suspended: {}", suspension.reason),
+                };
+                return Err(ApiError::new(ApiErrorType::Forbidden, message));
+            }
+        }
+        Ok(())
+    }
+
+    /// Courses the account is enrolled in, or on the staff of.
+    pub fn courses(&self) -> &[String] {
+        self.course_ids.as_deref().unwrap_or_default()
+    }
+
+    /// Whether a staff account may act on `course_id`.
+    /// Admins without a course list predate courses and are staff of every course.
+    pub fn is_staff_of(&self, course_id: &str) -> bool {
+        !self.disabled && self.course_ids.as_ref().is_none_or(|courses| courses.iter().any(|id| id == course_id))
+    }
+
+    /// Whether a staff account sees every student rather than those of its sections.
+    /// Admins without a role predate TAs and are treated as instructors.
+    pub fn is_instructor(&self) -> bool {
+        !self.disabled && self.staff_role.as_deref() != Some("ta")
+    }
+}
+
+/// Counters of a student in a course.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Stats {
+    pub user_id: ObjectId,
+    #[serde(default)]
+    pub course_id: String,
+    #[serde(default, deserialize_with = "lenient_count")]
+    pub conversation: i64,
+    #[serde(default, deserialize_with = "lenient_counts")]
+    pub tags: BTreeMap<String, i64>,
+    #[serde(default, deserialize_with = "lenient_count")]
+    pub messages: i64,
+    #[serde(default, deserialize_with = "lenient_counts")]
+    pub problems_viewed: BTreeMap<String, i64>,
+    /// Time of the latest counted event, `None` for stats written before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_active_at: Option<DateTime>,
+}
+
+/// A pending emailed code: account activation when `purpose` is unset, otherwise
+/// `email_change` or `magic_link`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ActivationCode {
+    pub user_id: ObjectId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// Hash of a code that is not stored in clear, see `api_key::hash_key`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code_hash: Option<String>,
+    /// Address an `email_change` code confirms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_email: Option<String>,
+    /// Wrong codes entered so far, see `CodeStore::count_failure`
+    #[serde(default)]
+    pub failures: i32,
+    #[serde(deserialize_with = "lenient_datetime")]
+    pub created_at: DateTime,
+    #[serde(deserialize_with = "lenient_datetime")]
+    pub expires_at: DateTime,
+}
+
+impl ActivationCode {
+    pub fn new(user_id: ObjectId, purpose: Option<&str>, expiry_minutes: i64) -> Self {
+        let expires_at = chrono::Utc::now() + chrono::Duration::minutes(expiry_minutes);
+        ActivationCode {
+            user_id,
+            purpose: purpose.map(str::to_string),
+            code: None,
+            code_hash: None,
+            new_email: None,
+            failures: 0,
+            created_at: DateTime::now(),
+            expires_at: DateTime::from_millis(expires_at.timestamp_millis()),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= DateTime::now()
+    }
+}
+
+/// A problem of a course question bank, as stored in its `qbank_collection`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Problem {
+    #[serde(rename = "_id")]
+    pub id: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub image: Option<Binary>,
+}
+
+/// A login of an account, valid until it expires or is revoked.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Session {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user_id: ObjectId,
+    pub created_at: DateTime,
+    pub last_seen_at: DateTime,
+    pub expires_at: DateTime,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+/// An attempt to log in to an existing account.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct LoginAttempt {
+    pub user_id: ObjectId,
+    pub at: DateTime,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub success: bool,
+    /// Why a failed attempt was refused, `invalid_password` or `suspended`
+    pub reason: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Section {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub course_id: String,
+    pub name: String,
+    #[serde(default)]
+    pub ta_ids: Vec<ObjectId>,
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub created_at: String,
+}
+
+/// An entry of the audit log, see `audit::Audit::record`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuditEntry {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub at: DateTime,
+    pub actor_id: ObjectId,
+    /// Username of the actor at the time
+    pub actor: Option<String>,
+    pub action: String,
+    pub target: String,
+    pub request_id: String,
+    pub ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<Document>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<Document>,
+}
+
+/// A key a service authenticates with, only its hash is stored.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ApiKey {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub name: String,
+    pub key_hash: String,
+    pub scopes: Vec<String>,
+    pub created_by: ObjectId,
+    #[serde(default, deserialize_with = "lenient_string")]
+    pub created_at: String,
+    /// The key never expires when `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_at: Option<DateTime>,
+    #[serde(default)]
+    pub revoked: bool,
+}
+
+/// An arithmetic challenge from `/captcha`, see `captcha::create_challenge`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Captcha {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub answer: String,
+    /// Address the challenge was requested from
+    #[serde(default)]
+    pub ip: Option<String>,
+    pub expires_at: DateTime,
+}
+
+/// A delivery of the weekly report of a course, see `report::run`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReportRun {
+    /// `{course_id}@{slot}` for scheduled runs, so each slot is claimed once even across restarts
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub course_id: String,
+    /// Time the schedule set for the run, `None` for runs started by an admin
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheduled_for: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub triggered_by: Option<ObjectId>,
+    /// Job delivering the report, see `jobs::JobSpec::WeeklyReport`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<ObjectId>,
+    pub started_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime>,
+    /// `running`, `succeeded`, `failed` or `cancelled`
+    pub status: String,
+    #[serde(default)]
+    pub sent: i64,
+    /// Usernames the report could not be delivered to
+    #[serde(default)]
+    pub failed: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What a background job does, see `jobs::execute`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobSpec {
+    /// Email the weekly report to the students of a course, optionally only those of `sections`,
+    /// and record the outcome in the report run `run_id`
+    WeeklyReport {
+        course_id: String,
+        sections: Option<Vec<ObjectId>>,
+        run_id: Option<String>,
+    },
+    /// Reset the course stats of the given students
+    ClearStats {
+        course_id: String,
+        user_ids: Vec<ObjectId>,
+    },
+    /// Email a campaign to its pending recipients
+    Campaign {
+        course_id: String,
+        campaign_id: ObjectId,
+    },
+}
+
+/// How far a job got. A job resumed after a crash skips the items up to `cursor`.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct JobProgress {
+    pub done: i64,
+    pub total: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<ObjectId>,
+}
+
+/// A queued background job, run by the workers of `jobs::start_workers`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Job {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub spec: JobSpec,
+    /// Admin who queued the job, `None` for jobs of the server itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<ObjectId>,
+    pub created_at: DateTime,
+    /// `queued`, `running`, `succeeded`, `failed` or `cancelled`
+    pub status: String,
+    /// Times a worker took the job, more than one when it was resumed after a crash
+    #[serde(default)]
+    pub attempts: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime>,
+    /// Last sign of life of the worker running the job
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heartbeat_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime>,
+    #[serde(default)]
+    pub progress: JobProgress,
+    #[serde(default)]
+    pub cancel_requested: bool,
+    /// Outcome of the job, kept up to date while it runs so a resumed job carries on from it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Document>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Job {
+    pub fn new(spec: JobSpec, created_by: Option<ObjectId>) -> Self {
+        Job {
+            id: ObjectId::new(),
+            spec,
+            created_by,
+            created_at: DateTime::now(),
+            status: "queued".to_string(),
+            attempts: 0,
+            worker: None,
+            started_at: None,
+            heartbeat_at: None,
+            finished_at: None,
+            progress: JobProgress::default(),
+            cancel_requested: false,
+            result: None,
+            error: None,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.status.as_str(), "succeeded" | "failed" | "cancelled")
+    }
+}
+
+/// Students of a course a campaign is for, those matching every given condition.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct Audience {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub section_id: Option<ObjectId>,
+    /// Students with no counted activity for this many days
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inactive_days: Option<i64>,
+    /// Students who mentioned `tag` fewer than `tag_below` times
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag_below: Option<i64>,
+    /// Usernames of an uploaded list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usernames: Option<Vec<String>>,
+}
+
+/// An email with a custom subject and body to a filtered group of students, see `campaign::audience`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Campaign {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub course_id: String,
+    pub created_by: ObjectId,
+    pub created_at: DateTime,
+    pub subject: String,
+    /// Body template, `{username}` and `{real_name}` are substituted
+    pub body: String,
+    pub audience: Audience,
+    pub recipients: i64,
+    /// Job sending the emails, see `jobs::JobSpec::Campaign`
+    pub job_id: ObjectId,
+}
+
+/// The email of a campaign to one student.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CampaignDelivery {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub campaign_id: ObjectId,
+    pub user_id: ObjectId,
+    pub username: String,
+    /// Address the email went to, set once it was sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// `pending`, `sent`, `failed` or `skipped` when the student left the course meanwhile
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delivered_at: Option<DateTime>,
+}
+
+/// Emails a staff member sent a student under one subject, threaded together in mail clients.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MailThread {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub staff_id: ObjectId,
+    pub user_id: ObjectId,
+    /// Subject without reply prefixes, see `mail::thread_subject`
+    pub subject: String,
+    /// Message-IDs of the emails, oldest first
+    pub message_ids: Vec<String>,
+    pub updated_at: DateTime,
+}
+
+/// An email sent to an account, kept in its email history, see `mail::send_recorded`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EmailRecord {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub user_id: ObjectId,
+    /// Address the email went to
+    pub email: String,
+    /// `account` or a notification category, see `mail::KINDS`
+    pub kind: String,
+    /// What the body was made from: `activation`, `login_link`, `email_change`, `email_changed`,
+    /// `weekly_report`, `campaign` or `staff_message`
+    pub template: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub course_id: Option<String>,
+    pub subject: String,
+    /// SHA-256 of the body, before the unsubscribe footer
+    pub body_digest: String,
+    /// The body, kept to resend the email; unset for account emails, which may carry one-time codes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<String>,
+    /// Staff member who sent the email, unset for emails the server sends on its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_by: Option<ObjectId>,
+    /// The email this one is a resend of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resent_from: Option<ObjectId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    /// `sent` or `failed`
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: DateTime,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sent_at: Option<DateTime>,
+}
+
+impl EmailRecord {
+    pub fn new(user_id: ObjectId, email: &str, kind: &str, template: &str, subject: &str, body: &str) -> Self {
+        EmailRecord {
+            id: ObjectId::new(),
+            user_id,
+            email: email.to_string(),
+            kind: kind.to_string(),
+            template: template.to_string(),
+            course_id: None,
+            subject: subject.to_string(),
+            body_digest: hash_key(body),
+            body: None,
+            sent_by: None,
+            resent_from: None,
+            message_id: None,
+            status: "failed".to_string(),
+            error: None,
+            created_at: DateTime::now(),
+            sent_at: None,
+        }
+    }
+}
+
+fn count(value: &Bson) -> i64 {
+    match value {
+        Bson::Int32(n) => *n as i64,
+        Bson::Int64(n) => *n,
+        Bson::Double(n) => *n as i64,
+        _ => 0,
+    }
+}
+
+/// A counter stored as any BSON number, counters outgrow Int32 and old documents may hold doubles.
+fn lenient_count<'de, D: Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    Ok(count(&Bson::deserialize(deserializer)?))
+}
+
+fn lenient_counts<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, i64>, D::Error> {
+    match Bson::deserialize(deserializer)? {
+        Bson::Document(counts) => Ok(counts.iter().map(|(key, value)| (key.clone(), count(value))).collect()),
+        _ => Ok(BTreeMap::new()),
+    }
+}
+
+/// Timestamps were once stored as `chrono::Local` strings, rendered timestamps keep that format.
+fn lenient_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match Bson::deserialize(deserializer)? {
+        Bson::String(s) => s,
+        Bson::DateTime(t) => format_datetime(&t),
+        _ => String::new(),
+    })
+}
+
+/// A timestamp stored as a BSON date or as a `chrono::Local` string. Unreadable ones are in the past,
+/// so a code with a broken expiry counts as expired.
+fn lenient_datetime<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime, D::Error> {
+    Ok(match Bson::deserialize(deserializer)? {
+        Bson::DateTime(t) => t,
+        Bson::String(s) => chrono::DateTime::parse_from_str(&s, "%Y-%m-%d %H:%M:%S%.f %z")
+            .or_else(|_| chrono::DateTime::parse_from_rfc3339(&s))
+            .map(|t| DateTime::from_millis(t.timestamp_millis()))
+            .unwrap_or(DateTime::MIN),
+        _ => DateTime::MIN,
+    })
+}
@@ -0,0 +1,64 @@
+use std::env;
+
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::models::Account;
+
+/// Kinds of email a student may turn off: the weekly report, campaigns, and emails from staff.
+pub const CATEGORIES: [&str; 3] = ["weekly_report", "announcements", "ta_messages"];
+
+/// Audience of unsubscribe tokens, so they are never taken for login tokens.
+const AUDIENCE: &str = "unsubscribe";
+
+#[derive(Serialize, Deserialize)]
+struct UnsubscribeClaims {
+    sub: String,
+    aud: String,
+    category: String,
+}
+
+fn secret() -> String {
+    env::var("YWT_SECRET").unwrap_or_else(|_| "ywt_secret".to_string())
+}
+
+/// Whether the student still gets emails of `category`.
+pub fn accepts(account: &Account, category: &str) -> bool {
+    !account.unsubscribed.iter().any(|unsubscribed| unsubscribed == category)
+}
+
+/// A token turning off `category` for the account, signed so links cannot be forged for others.
+/// It does not expire, as the links of old emails should keep working.
+pub fn unsubscribe_token(user_id: &ObjectId, category: &str) -> ApiResult<String> {
+    let claims = UnsubscribeClaims {
+        sub: user_id.to_hex(),
+        aud: AUDIENCE.to_string(),
+        category: category.to_string(),
+    };
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(secret().as_bytes()))
+        .map_err(|e| ApiError::new(ApiErrorType::Internal, format!("Failed to sign unsubscribe token: {}", e)))
+}
+
+/// The account and category of a valid unsubscribe token.
+pub fn verify_token(token: &str) -> Option<(ObjectId, String)> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[AUDIENCE]);
+    validation.set_required_spec_claims(&["sub", "aud"]);
+    validation.validate_exp = false;
+    let claims = decode::<UnsubscribeClaims>(token, &DecodingKey::from_secret(secret().as_bytes()), &validation)
+        .ok()?
+        .claims;
+    let user_id = ObjectId::parse_str(&claims.sub).ok()?;
+    CATEGORIES.contains(&claims.category.as_str()).then_some((user_id, claims.category))
+}
+
+/// The link turning off `category` for the account, `None` when no `unsubscribe_url` is configured.
+pub fn unsubscribe_link(config: &Config, user_id: &ObjectId, category: &str) -> ApiResult<Option<String>> {
+    match &config.notifications.unsubscribe_url {
+        Some(url) => Ok(Some(format!("{}?token={}", url, unsubscribe_token(user_id, category)?))),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use argon2::{
+    password_hash::{
+        rand_core::OsRng,
+        PasswordHash, PasswordHasher, PasswordVerifier, SaltString
+    },
+    Algorithm, Argon2, Params, Version
+};
+
+use crate::config::PasswordConfig;
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+
+const BUNDLED_BLOCKLIST: &str = include_str!("../assets/common_passwords.txt");
+
+/// Password rules and hashing parameters, built once from the config at startup.
+pub struct PasswordPolicy {
+    config: PasswordConfig,
+    params: Params,
+    blocklist: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    pub fn new(config: &PasswordConfig) -> Result<Self> {
+        let params = Params::new(
+            config.argon2_memory_kib,
+            config.argon2_iterations,
+            config.argon2_parallelism,
+            None,
+        ).map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {}", e))?;
+        let blocklist_text = match &config.blocklist_path {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => BUNDLED_BLOCKLIST.to_string(),
+        };
+        let blocklist = blocklist_text
+            .lines()
+            .map(|line| line.trim().to_lowercase())
+            .filter(|line| !line.is_empty())
+            .collect();
+        Ok(PasswordPolicy { config: config.clone(), params, blocklist })
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    /// Check a new password of the account named `username` against the policy.
+    pub fn check(&self, password: &str, username: &str) -> ApiResult<()> {
+        let invalid = |message: String| Err(ApiError::new(ApiErrorType::InvalidRequest, message));
+
+        let length = password.chars().count();
+        if length < self.config.min_length || length > self.config.max_length {
+            return invalid(format!(
+                "Password must be between {} and {} characters",
+                self.config.min_length, self.config.max_length,
+            ));
+        }
+        if self.config.require_lowercase && !password.chars().any(|c| c.is_lowercase()) {
+            return invalid("Password must contain a lowercase letter".to_string());
+        }
+        if self.config.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+            return invalid("Password must contain an uppercase letter".to_string());
+        }
+        if self.config.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            return invalid("Password must contain a digit".to_string());
+        }
+        if self.config.require_symbol && password.chars().all(|c| c.is_alphanumeric()) {
+            return invalid("Password must contain a symbol".to_string());
+        }
+        let lowercase = password.to_lowercase();
+        if self.blocklist.contains(&lowercase) {
+            return invalid("Password is too common".to_string());
+        }
+        if self.config.reject_username && !username.is_empty() && lowercase.contains(&username.to_lowercase()) {
+            return invalid("Password must not contain the username".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn hash(&self, password: &str) -> ApiResult<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        Ok(self.argon2().hash_password(password.as_bytes(), &salt)?.to_string())
+    }
+
+    /// Verify a password against a stored hash, whatever parameters it was made with.
+    pub fn verify(&self, password: &str, hash: &str) -> ApiResult<bool> {
+        let parsed_hash = PasswordHash::new(hash)?;
+        Ok(self.argon2().verify_password(password.as_bytes(), &parsed_hash).is_ok())
+    }
+
+    /// Whether a stored hash was made with a different algorithm or cost than the current one.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let Ok(parsed_hash) = PasswordHash::new(hash) else {
+            return true;
+        };
+        if parsed_hash.algorithm != Algorithm::Argon2id.ident() {
+            return true;
+        }
+        match Params::try_from(&parsed_hash) {
+            Ok(params) => {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
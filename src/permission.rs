@@ -0,0 +1,137 @@
+use std::marker::PhantomData;
+
+use actix_web::{web, FromRequest};
+use futures::future::{ready, LocalBoxFuture};
+use mongodb::bson::oid::ObjectId;
+
+use crate::error::{ApiError, ApiErrorType};
+use crate::jwt::{authenticate, decode_token};
+use crate::models::Account;
+use crate::repository::Store;
+
+/// A permission that can be required by a route through [`Authorized`].
+pub trait Permission {
+    const NAME: &'static str;
+}
+
+macro_rules! permissions {
+    ($($(#[$meta:meta])* $name:ident => $value:literal,)*) => {
+        $(
+            $(#[$meta])*
+            pub struct $name;
+
+            impl Permission for $name {
+                const NAME: &'static str = $value;
+            }
+        )*
+
+        pub const PERMISSIONS: &[&str] = &[$($value),*];
+    };
+}
+
+permissions! {
+    /// List students and read their stats
+    UsersRead => "users.read",
+    /// Edit student profiles
+    UsersWrite => "users.write",
+    /// Take students out of a course, with their stats there
+    UsersDelete => "users.delete",
+    /// Delete student accounts, suspend them and force password changes, in every course at once
+    AccountsManage => "accounts.manage",
+    StatsClear => "stats.clear",
+    /// Send the weekly report to every student
+    EmailBroadcast => "email.broadcast",
+    /// Send an email to a single student
+    EmailSend => "email.send",
+    SectionsRead => "sections.read",
+    /// Create and delete sections, assign TAs and enroll students
+    SectionsManage => "sections.manage",
+    AdminsRead => "admins.read",
+    /// Create, disable, delete and reassign staff accounts
+    AdminsManage => "admins.manage",
+    ApiKeysManage => "api_keys.manage",
+    /// Query the audit log
+    AuditRead => "audit.read",
+    /// See and cancel the background jobs of every admin
+    JobsManage => "jobs.manage",
+}
+
+const TA_PERMISSIONS: &[&str] = &[
+    "users.read",
+    "users.write",
+    "email.send",
+    "sections.read",
+    "admins.read",
+];
+
+const INSTRUCTOR_PERMISSIONS: &[&str] = &[
+    "users.read",
+    "users.write",
+    "users.delete",
+    "stats.clear",
+    "email.broadcast",
+    "email.send",
+    "sections.read",
+    "sections.manage",
+    "admins.read",
+];
+
+/// Staff roles with their permissions, super admins hold every permission.
+pub const ROLES: &[(&str, &[&str])] = &[
+    ("instructor", INSTRUCTOR_PERMISSIONS),
+    ("ta", TA_PERMISSIONS),
+    ("super_admin", PERMISSIONS),
+];
+
+pub fn role_permissions(role: &str) -> &'static [&'static str] {
+    ROLES
+        .iter()
+        .find(|(name, _)| *name == role)
+        .map(|(_, permissions)| *permissions)
+        .unwrap_or_default()
+}
+
+/// Permissions held by an admin account, none once it is disabled.
+pub fn admin_permissions(admin: &Account) -> &'static [&'static str] {
+    if admin.disabled {
+        return &[];
+    }
+    role_permissions(admin.staff_role.as_deref().unwrap_or(ROLES[0].0))
+}
+
+/// A logged in admin whose role grants the permission `P`.
+pub struct Authorized<P: Permission> {
+    pub user_id: ObjectId,
+    _permission: PhantomData<P>,
+}
+
+async fn authorize(db: &dyn Store, user_id: &ObjectId, permission: &str) -> Result<(), actix_web::Error> {
+    let admin = db.find_account_in("admins", user_id).await?;
+    let granted = admin.is_some_and(|admin| admin_permissions(&admin).contains(&permission));
+    if !granted {
+        return Err(ApiError::new(
+            ApiErrorType::Forbidden,
+            format!("Permission {} required", permission),
+        ).into());
+    }
+    Ok(())
+}
+
+impl<P: Permission + 'static> FromRequest for Authorized<P> {
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let (user_id, session_id) = match decode_token(req) {
+            Ok(token) => token,
+            Err(e) => return Box::pin(ready(Err(e))),
+        };
+        let db = req.app_data::<web::Data<dyn Store>>().cloned();
+        let path = req.path().to_string();
+        Box::pin(async move {
+            let (db, _) = authenticate(db, user_id, session_id, &path).await?;
+            authorize(db.get_ref(), &user_id, P::NAME).await?;
+            Ok(Authorized { user_id, _permission: PhantomData })
+        })
+    }
+}
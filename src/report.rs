@@ -0,0 +1,142 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use chrono_tz::Tz;
+use lettre::SmtpTransport;
+use mongodb::bson::DateTime;
+use mongodb::bson::oid::ObjectId;
+
+use crate::config::{Config, CourseConfig, ReportConfig};
+use crate::error::ApiResult;
+use crate::mail::{build_notification, record_failure, send_recorded};
+use crate::models::{Account, EmailRecord, Job, JobSpec, ReportRun, Stats};
+use crate::repository::Store;
+
+/// When the weekly report goes out, parsed from `Config::report`.
+pub struct ReportSchedule {
+    schedule: cron::Schedule,
+    timezone: Tz,
+    catch_up: chrono::Duration,
+}
+
+impl ReportSchedule {
+    pub fn new(config: &ReportConfig) -> Result<Self> {
+        let schedule = cron::Schedule::from_str(&config.schedule)
+            .map_err(|e| anyhow!("Invalid report schedule {}: {}", config.schedule, e))?;
+        let timezone = config
+            .timezone
+            .parse::<Tz>()
+            .map_err(|e| anyhow!("Invalid report timezone {}: {}", config.timezone, e))?;
+        Ok(ReportSchedule {
+            schedule,
+            timezone,
+            catch_up: chrono::Duration::hours(config.catch_up_hours.max(0)),
+        })
+    }
+
+    /// The latest slot at or before `now` that is still within the catch-up window.
+    pub fn due(&self, now: chrono::DateTime<Utc>) -> Option<chrono::DateTime<Utc>> {
+        let from = (now - self.catch_up - chrono::Duration::seconds(1)).with_timezone(&self.timezone);
+        self.schedule
+            .after(&from)
+            .take_while(|slot| *slot <= now)
+            .last()
+            .map(|slot| slot.with_timezone(&Utc))
+    }
+
+    pub fn next(&self, now: chrono::DateTime<Utc>) -> Option<chrono::DateTime<Utc>> {
+        self.schedule
+            .after(&now.with_timezone(&self.timezone))
+            .next()
+            .map(|slot| slot.with_timezone(&Utc))
+    }
+}
+
+/// Subject and body of the weekly report, from the course templates when configured.
+pub fn weekly_report(course: &CourseConfig, username: &str, conversation_count: i64, tag_str: &str) -> (String, String) {
+    let subject = course
+        .report_subject
+        .clone()
+        .unwrap_or_else(|| "YWT 答疑周报".to_string());
+    let body = match &course.report_template {
+        Some(template) => template
+            .replace("{username}", username)
+            .replace("{conversation}", &conversation_count.to_string())
+            .replace("{tags}", tag_str),
+        None => format!("{} 同学你好！\n\n感谢使用 YWT。以下是你的答疑周报：\n\n在过去一周内，你一共与智能助手交谈 {} 轮次，主要围绕 {} 等知识点。\n\n祝好！\nYWT Team",
+            username, conversation_count, tag_str),
+    };
+    (subject, body)
+}
+
+/// Email the weekly report of `course` to a student, the record tells whether it went out.
+pub async fn send(
+    db: &dyn Store,
+    mailer: &SmtpTransport,
+    config: &Config,
+    course: &CourseConfig,
+    student: &Account,
+    stats: &Stats,
+) -> ApiResult<EmailRecord> {
+    let tag_str = stats.tags.keys().map(String::as_str).collect::<Vec<_>>().join(", ");
+    let (subject, body) = weekly_report(course, &student.username, stats.conversation, &tag_str);
+    let mut record = EmailRecord::new(student.id, &student.email, "weekly_report", "weekly_report", &subject, &body);
+    record.course_id = Some(course.id.clone());
+    record.body = Some(body.clone());
+    match build_notification(config, student, "weekly_report", &subject, body, None) {
+        Ok(message) => send_recorded(db, mailer, &message, record).await,
+        Err(e) => record_failure(db, record, e.to_string()).await,
+    }
+}
+
+/// A new run of the report of `course_id`, for the schedule slot or for `triggered_by`.
+pub fn new_run(course_id: &str, scheduled_for: Option<chrono::DateTime<Utc>>, triggered_by: Option<ObjectId>) -> ReportRun {
+    let id = match scheduled_for {
+        Some(slot) => format!("{}@{}", course_id, slot.to_rfc3339()),
+        None => ObjectId::new().to_hex(),
+    };
+    ReportRun {
+        id,
+        course_id: course_id.to_string(),
+        scheduled_for: scheduled_for.map(|slot| DateTime::from_millis(slot.timestamp_millis())),
+        triggered_by,
+        job_id: None,
+        started_at: DateTime::now(),
+        finished_at: None,
+        status: "running".to_string(),
+        sent: 0,
+        failed: Vec::new(),
+        error: None,
+    }
+}
+
+/// Claim `run` and queue the job delivering it, to the students of `sections` only when set.
+/// Returns `None` when the run was claimed before, or while another run of the course is running.
+pub async fn start(db: &dyn Store, mut run: ReportRun, sections: Option<Vec<ObjectId>>) -> ApiResult<Option<ReportRun>> {
+    let spec = JobSpec::WeeklyReport {
+        course_id: run.course_id.clone(),
+        sections,
+        run_id: Some(run.id.clone()),
+    };
+    let job = Job::new(spec, run.triggered_by);
+    run.job_id = Some(job.id);
+    if !db.claim_report_run(&run).await? {
+        return Ok(None);
+    }
+    db.insert_job(&job).await?;
+    Ok(Some(run))
+}
+
+/// Queue the report of every course whose slot is due at `now` and was not claimed yet.
+pub async fn run_due(db: &dyn Store, config: &Config, schedule: &ReportSchedule, now: chrono::DateTime<Utc>) -> ApiResult<()> {
+    let Some(slot) = schedule.due(now) else {
+        return Ok(());
+    };
+    for course in &config.courses {
+        if start(db, new_run(&course.id, Some(slot), None), None).await?.is_some() {
+            log::info!("Queued the weekly report of course {} for {}", course.id, slot);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,908 @@
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use mongodb::bson::{self, doc, DateTime, Document};
+use mongodb::bson::oid::ObjectId;
+use serde::Serialize;
+
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::models::{
+    Account, ActivationCode, ApiKey, AuditEntry, Campaign, CampaignDelivery, Captcha, EmailRecord, Job, JobProgress,
+    LoginAttempt, MailThread, Problem, ReportRun, Section, Session, Stats,
+};
+use super::{
+    AccountStore, AccountUpdate, ApiKeyStore, AuditQuery, AuditStore, CampaignStore, CodeQuery, CodeStore, JobStore,
+    MailStore, ProblemStore, ReportStore, SectionStore, SessionStore, StatsIncrement, StatsStore, StudentFilter,
+};
+
+#[derive(Default)]
+struct Data {
+    /// Accounts by collection name
+    accounts: BTreeMap<String, Vec<Account>>,
+    stats: Vec<Stats>,
+    stats_events: HashSet<(ObjectId, String, String)>,
+    codes: Vec<ActivationCode>,
+    captchas: Vec<Captcha>,
+    link_requests: Vec<(String, DateTime)>,
+    /// Problems by question bank collection
+    problems: BTreeMap<String, Vec<Problem>>,
+    logins: Vec<LoginAttempt>,
+    sessions: Vec<Session>,
+    sections: Vec<Section>,
+    audit: Vec<AuditEntry>,
+    api_keys: Vec<ApiKey>,
+    report_runs: Vec<ReportRun>,
+    jobs: Vec<Job>,
+    campaigns: Vec<Campaign>,
+    deliveries: Vec<CampaignDelivery>,
+    mail_threads: Vec<MailThread>,
+    emails: Vec<EmailRecord>,
+}
+
+impl Data {
+    fn job(&mut self, job_id: &ObjectId) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| &job.id == job_id)
+    }
+}
+
+impl Data {
+    fn accounts(&mut self, collection: &str) -> &mut Vec<Account> {
+        self.accounts.entry(collection.to_string()).or_default()
+    }
+
+    fn account(&mut self, collection: &str, user_id: &ObjectId) -> Option<&mut Account> {
+        self.accounts(collection).iter_mut().find(|account| &account.id == user_id)
+    }
+
+    fn stats(&mut self, user_id: &ObjectId, course_id: &str) -> &mut Stats {
+        let position = self.stats.iter().position(|stats| &stats.user_id == user_id && stats.course_id == course_id);
+        let position = position.unwrap_or_else(|| {
+            self.stats.push(Stats {
+                user_id: *user_id,
+                course_id: course_id.to_string(),
+                conversation: 0,
+                tags: BTreeMap::new(),
+                messages: 0,
+                problems_viewed: BTreeMap::new(),
+                last_active_at: None,
+            });
+            self.stats.len() - 1
+        });
+        &mut self.stats[position]
+    }
+}
+
+/// A store holding everything in process memory, for tests. It starts empty and forgets
+/// everything when dropped.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<Data>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    fn data(&self) -> MutexGuard<'_, Data> {
+        self.data.lock().unwrap()
+    }
+
+    /// Add a problem to a question bank collection, which the server itself never writes.
+    pub fn insert_problem(&self, collection: &str, problem: Problem) {
+        self.data().problems.entry(collection.to_string()).or_default().push(problem);
+    }
+
+    /// The answer to a pending challenge, which clients only get as an image.
+    pub fn captcha_answer(&self, captcha_id: &ObjectId) -> Option<String> {
+        self.data().captchas.iter().find(|captcha| &captcha.id == captcha_id).map(|captcha| captcha.answer.clone())
+    }
+
+    /// Every stored audit entry, oldest first.
+    pub fn audit_log(&self) -> Vec<AuditEntry> {
+        self.data().audit.clone()
+    }
+}
+
+fn matches_student(account: &Account, filter: &StudentFilter) -> bool {
+    account.courses().contains(&filter.course_id)
+        && filter.sections.as_ref().is_none_or(|sections| account.section_ids.iter().any(|id| sections.contains(id)))
+        && filter.section_id.is_none_or(|section_id| account.section_ids.contains(&section_id))
+        && filter.username.as_ref().is_none_or(|username| &account.username == username)
+        && filter.student_id.as_ref().is_none_or(|student_id| account.student_id.as_ref() == Some(student_id))
+        && filter.real_name.as_ref().is_none_or(|real_name| {
+            account
+                .real_name
+                .as_ref()
+                .is_some_and(|name| name.to_lowercase().contains(&real_name.to_lowercase()))
+        })
+}
+
+fn apply_update(account: &mut Account, update: &AccountUpdate) {
+    if let Some(username) = &update.username {
+        account.username = username.clone();
+    }
+    if let Some(email) = &update.email {
+        account.email = email.clone();
+    }
+    if let Some(password) = &update.password {
+        account.password = password.clone();
+    }
+    if let Some(staff_role) = &update.staff_role {
+        account.staff_role = Some(staff_role.clone());
+    }
+    if let Some(disabled) = update.disabled {
+        account.disabled = disabled;
+    }
+    if let Some(course_ids) = &update.course_ids {
+        account.course_ids = course_ids.clone();
+    }
+    if let Some(student_id) = &update.student_id {
+        account.student_id = student_id.clone();
+    }
+    if let Some(real_name) = &update.real_name {
+        account.real_name = real_name.clone();
+    }
+    if let Some(preferred_language) = &update.preferred_language {
+        account.preferred_language = preferred_language.clone();
+    }
+    if let Some(avatar) = &update.avatar {
+        account.avatar = avatar.clone();
+    }
+    if let Some(suspension) = &update.suspension {
+        account.suspension = suspension.clone();
+    }
+    if let Some(must_change_password) = update.must_change_password {
+        account.must_change_password = must_change_password;
+    }
+    if let Some(deletion_scheduled_at) = update.deletion_scheduled_at {
+        account.deletion_scheduled_at = deletion_scheduled_at;
+    }
+    if let Some(unsubscribed) = &update.unsubscribed {
+        account.unsubscribed = unsubscribed.clone();
+    }
+}
+
+fn matches_code(code: &ActivationCode, query: &CodeQuery) -> bool {
+    code.purpose.as_deref() == query.purpose
+        && query.user_id.is_none_or(|user_id| code.user_id == user_id)
+        && query.code.is_none_or(|value| code.code.as_deref() == Some(value))
+        && query.code_hash.is_none_or(|hash| code.code_hash.as_deref() == Some(hash))
+}
+
+fn matches_audit(entry: &AuditEntry, query: &AuditQuery) -> bool {
+    query.actor_id.is_none_or(|actor_id| entry.actor_id == actor_id)
+        && query.actor.as_ref().is_none_or(|actor| entry.actor.as_ref() == Some(actor))
+        && query.action.as_ref().is_none_or(|action| &entry.action == action)
+        && query.since.is_none_or(|since| entry.at >= since)
+        && query.until.is_none_or(|until| entry.at < until)
+}
+
+/// Newest first, keeping the insertion order of entries with the same timestamp reversed as well.
+fn newest_first<T: Clone>(items: &[T], at: impl Fn(&T) -> DateTime) -> Vec<T> {
+    let mut sorted: Vec<T> = items.iter().rev().cloned().collect();
+    sorted.sort_by_key(|item| std::cmp::Reverse(at(item)));
+    sorted
+}
+
+#[async_trait]
+impl AccountStore for MemoryStore {
+    async fn find_account_in(&self, collection: &str, user_id: &ObjectId) -> ApiResult<Option<Account>> {
+        Ok(self.data().account(collection, user_id).cloned())
+    }
+
+    async fn find_account_by_username(&self, collection: &str, username: &str) -> ApiResult<Option<Account>> {
+        Ok(self.data().accounts(collection).iter().find(|account| account.username == username).cloned())
+    }
+
+    async fn find_account_by_email(&self, collection: &str, email: &str) -> ApiResult<Option<Account>> {
+        Ok(self.data().accounts(collection).iter().find(|account| account.email == email).cloned())
+    }
+
+    async fn list_accounts(&self, collection: &str) -> ApiResult<Vec<Account>> {
+        Ok(self.data().accounts(collection).clone())
+    }
+
+    async fn list_students(&self, filter: &StudentFilter) -> ApiResult<Vec<Account>> {
+        Ok(self
+            .data()
+            .accounts("users")
+            .iter()
+            .filter(|account| matches_student(account, filter))
+            .cloned()
+            .collect())
+    }
+
+    async fn count_section_students(&self, section_id: &ObjectId) -> ApiResult<u64> {
+        Ok(self
+            .data()
+            .accounts("users")
+            .iter()
+            .filter(|account| account.section_ids.contains(section_id))
+            .count() as u64)
+    }
+
+    async fn due_for_deletion(&self, collection: &str) -> ApiResult<Vec<Account>> {
+        let now = DateTime::now();
+        Ok(self
+            .data()
+            .accounts(collection)
+            .iter()
+            .filter(|account| account.deletion_scheduled_at.is_some_and(|at| at <= now))
+            .cloned()
+            .collect())
+    }
+
+    async fn insert_account(&self, collection: &str, account: &Account) -> ApiResult<()> {
+        self.data().accounts(collection).push(account.clone());
+        Ok(())
+    }
+
+    async fn update_account(&self, collection: &str, user_id: &ObjectId, update: &AccountUpdate) -> ApiResult<bool> {
+        let mut data = self.data();
+        match data.account(collection, user_id) {
+            Some(account) => {
+                apply_update(account, update);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn add_course(&self, collection: &str, user_id: &ObjectId, course_id: &str) -> ApiResult<()> {
+        if let Some(account) = self.data().account(collection, user_id) {
+            let courses = account.course_ids.get_or_insert_with(Vec::new);
+            if !courses.iter().any(|id| id == course_id) {
+                courses.push(course_id.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    async fn move_student(&self, user_id: &ObjectId, remove: &[ObjectId], add: Option<ObjectId>) -> ApiResult<()> {
+        if let Some(account) = self.data().account("users", user_id) {
+            account.section_ids.retain(|id| !remove.contains(id));
+            if let Some(section_id) = add {
+                if !account.section_ids.contains(&section_id) {
+                    account.section_ids.push(section_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_course(&self, user_id: &ObjectId, course_id: &str, sections: &[ObjectId]) -> ApiResult<()> {
+        let mut data = self.data();
+        if let Some(account) = data.account("users", user_id) {
+            if let Some(courses) = &mut account.course_ids {
+                courses.retain(|id| id != course_id);
+            }
+            account.section_ids.retain(|id| !sections.contains(id));
+        }
+        data.stats.retain(|stats| &stats.user_id != user_id || stats.course_id != course_id);
+        data.stats_events.retain(|(id, course, _)| id != user_id || course != course_id);
+        Ok(())
+    }
+
+    async fn activate_account(&self, user_id: &ObjectId) -> ApiResult<()> {
+        let mut data = self.data();
+        let pending = data.accounts("tmp_users");
+        if let Some(position) = pending.iter().position(|account| &account.id == user_id) {
+            let account = pending.remove(position);
+            data.accounts("users").push(account);
+        }
+        Ok(())
+    }
+
+    async fn purge_account(&self, collection: &str, user_id: &ObjectId) -> ApiResult<()> {
+        let mut data = self.data();
+        data.accounts(collection).retain(|account| &account.id != user_id);
+        data.stats.retain(|stats| &stats.user_id != user_id);
+        data.stats_events.retain(|(id, _, _)| id != user_id);
+        data.codes.retain(|code| &code.user_id != user_id);
+        data.sessions.retain(|session| &session.user_id != user_id);
+        data.logins.retain(|attempt| &attempt.user_id != user_id);
+        data.deliveries.retain(|delivery| &delivery.user_id != user_id);
+        data.mail_threads.retain(|thread| &thread.user_id != user_id);
+        data.emails.retain(|record| &record.user_id != user_id);
+        for section in &mut data.sections {
+            section.ta_ids.retain(|id| id != user_id);
+        }
+        Ok(())
+    }
+
+    async fn export_account(&self, user_id: &ObjectId) -> ApiResult<Option<Document>> {
+        let Some(account) = self.find_account(user_id).await? else {
+            return Ok(None);
+        };
+        let mut account = bson::to_document(&account).map_err(|e| ApiError::new(
+            ApiErrorType::Internal,
+            format!("Failed to encode document: {}", e),
+        ))?;
+        account.remove("password");
+        Ok(Some(account))
+    }
+
+    async fn export_records(&self, user_id: &ObjectId) -> ApiResult<BTreeMap<String, Vec<Document>>> {
+        fn documents<'a, T: Serialize + 'a>(records: impl Iterator<Item = &'a T>) -> ApiResult<Vec<Document>> {
+            records
+                .map(|record| bson::to_document(record).map_err(|e| ApiError::new(
+                    ApiErrorType::Internal,
+                    format!("Failed to encode document: {}", e),
+                )))
+                .collect()
+        }
+        let data = self.data();
+        let mut codes = documents(data.codes.iter().filter(|code| &code.user_id == user_id))?;
+        for code in &mut codes {
+            code.remove("code");
+            code.remove("code_hash");
+        }
+        let events = data
+            .stats_events
+            .iter()
+            .filter(|(id, _, _)| id == user_id)
+            .map(|(id, course_id, key)| doc! { "_id": { "user_id": id, "course_id": course_id, "key": key }, "user_id": id })
+            .collect();
+        Ok(BTreeMap::from([
+            ("stats".to_string(), documents(data.stats.iter().filter(|stats| &stats.user_id == user_id))?),
+            ("stats_events".to_string(), events),
+            ("activation_codes".to_string(), codes),
+            ("sessions".to_string(), documents(data.sessions.iter().filter(|session| &session.user_id == user_id))?),
+            ("login_history".to_string(), documents(data.logins.iter().filter(|attempt| &attempt.user_id == user_id))?),
+            ("campaign_deliveries".to_string(), documents(data.deliveries.iter().filter(|delivery| &delivery.user_id == user_id))?),
+            ("mail_threads".to_string(), documents(data.mail_threads.iter().filter(|thread| &thread.user_id == user_id))?),
+            ("emails".to_string(), documents(data.emails.iter().filter(|record| &record.user_id == user_id))?),
+            ("sections".to_string(), documents(data.sections.iter().filter(|section| section.ta_ids.contains(user_id)))?),
+        ]))
+    }
+}
+
+#[async_trait]
+impl StatsStore for MemoryStore {
+    async fn find_stats(&self, user_id: &ObjectId, course_id: &str) -> ApiResult<Option<Stats>> {
+        Ok(self
+            .data()
+            .stats
+            .iter()
+            .find(|stats| &stats.user_id == user_id && stats.course_id == course_id)
+            .cloned())
+    }
+
+    async fn account_stats(&self, user_id: &ObjectId) -> ApiResult<Vec<Stats>> {
+        Ok(self.data().stats.iter().filter(|stats| &stats.user_id == user_id).cloned().collect())
+    }
+
+    async fn course_stats(&self, course_id: &str) -> ApiResult<Vec<Stats>> {
+        Ok(self.data().stats.iter().filter(|stats| stats.course_id == course_id).cloned().collect())
+    }
+
+    async fn ensure_stats(&self, user_id: &ObjectId, course_id: &str) -> ApiResult<()> {
+        self.data().stats(user_id, course_id);
+        Ok(())
+    }
+
+    async fn increment_stats(&self, user_id: &ObjectId, course_id: &str, inc: &StatsIncrement) -> ApiResult<Stats> {
+        let mut data = self.data();
+        let stats = data.stats(user_id, course_id);
+        stats.conversation += inc.conversation;
+        stats.messages += inc.messages;
+        for (tag, count) in &inc.tags {
+            *stats.tags.entry(tag.clone()).or_default() += count;
+        }
+        for (problem_id, count) in &inc.problems_viewed {
+            *stats.problems_viewed.entry(problem_id.clone()).or_default() += count;
+        }
+        stats.last_active_at = Some(DateTime::now());
+        Ok(stats.clone())
+    }
+
+    async fn clear_stats(&self, course_id: &str, user_ids: &[ObjectId]) -> ApiResult<u64> {
+        let mut cleared = 0;
+        for stats in &mut self.data().stats {
+            if stats.course_id == course_id && user_ids.contains(&stats.user_id) {
+                let empty = stats.conversation == 0
+                    && stats.messages == 0
+                    && stats.tags.is_empty()
+                    && stats.problems_viewed.is_empty();
+                // like MongoDB, only count the stats that actually changed
+                if !empty {
+                    cleared += 1;
+                }
+                stats.conversation = 0;
+                stats.messages = 0;
+                stats.tags.clear();
+                stats.problems_viewed.clear();
+            }
+        }
+        Ok(cleared)
+    }
+
+    async fn claim_event(&self, user_id: &ObjectId, course_id: &str, key: &str) -> ApiResult<bool> {
+        Ok(self.data().stats_events.insert((*user_id, course_id.to_string(), key.to_string())))
+    }
+
+    async fn release_event(&self, user_id: &ObjectId, course_id: &str, key: &str) -> ApiResult<()> {
+        self.data().stats_events.remove(&(*user_id, course_id.to_string(), key.to_string()));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CodeStore for MemoryStore {
+    async fn replace_code(&self, code: &ActivationCode) -> ApiResult<()> {
+        let mut data = self.data();
+        data.codes.retain(|pending| pending.user_id != code.user_id || pending.purpose != code.purpose);
+        data.codes.push(code.clone());
+        Ok(())
+    }
+
+    async fn find_code(&self, query: &CodeQuery<'_>) -> ApiResult<Option<ActivationCode>> {
+        Ok(self.data().codes.iter().find(|code| matches_code(code, query)).cloned())
+    }
+
+    async fn take_code(&self, query: &CodeQuery<'_>) -> ApiResult<Option<ActivationCode>> {
+        let mut data = self.data();
+        let position = data.codes.iter().position(|code| matches_code(code, query));
+        Ok(position.map(|position| data.codes.remove(position)))
+    }
+
+    async fn count_failure(&self, query: &CodeQuery<'_>) -> ApiResult<Option<i32>> {
+        let mut data = self.data();
+        let code = data.codes.iter_mut().find(|code| matches_code(code, query));
+        Ok(code.map(|code| {
+            code.failures += 1;
+            code.failures
+        }))
+    }
+
+    async fn insert_captcha(&self, captcha: &Captcha) -> ApiResult<()> {
+        let mut data = self.data();
+        let now = DateTime::now();
+        data.captchas.retain(|pending| pending.expires_at > now);
+        data.captchas.push(captcha.clone());
+        Ok(())
+    }
+
+    async fn take_captcha(&self, captcha_id: &ObjectId) -> ApiResult<Option<Captcha>> {
+        let mut data = self.data();
+        let position = data.captchas.iter().position(|captcha| &captcha.id == captcha_id);
+        Ok(position.map(|position| data.captchas.remove(position)))
+    }
+
+    async fn count_captchas(&self, ip: Option<&str>) -> ApiResult<u64> {
+        let now = DateTime::now();
+        let data = self.data();
+        let pending = data.captchas.iter().filter(|captcha| captcha.ip.as_deref() == ip && captcha.expires_at > now);
+        Ok(pending.count() as u64)
+    }
+
+    async fn count_link_requests(&self, email: &str, since: DateTime) -> ApiResult<u64> {
+        let mut data = self.data();
+        data.link_requests.retain(|(_, at)| *at >= since);
+        Ok(data.link_requests.iter().filter(|(address, _)| address == email).count() as u64)
+    }
+
+    async fn add_link_request(&self, email: &str) -> ApiResult<()> {
+        self.data().link_requests.push((email.to_string(), DateTime::now()));
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ProblemStore for MemoryStore {
+    async fn find_problem(&self, collection: &str, problem_id: &str) -> ApiResult<Option<Problem>> {
+        Ok(self
+            .data()
+            .problems
+            .get(collection)
+            .and_then(|problems| problems.iter().find(|problem| problem.id == problem_id))
+            .cloned())
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    async fn record_login(&self, attempt: &LoginAttempt) -> ApiResult<()> {
+        self.data().logins.push(attempt.clone());
+        Ok(())
+    }
+
+    async fn login_history(&self, user_id: &ObjectId, limit: i64) -> ApiResult<Vec<LoginAttempt>> {
+        let data = self.data();
+        let attempts: Vec<LoginAttempt> = data.logins.iter().filter(|attempt| &attempt.user_id == user_id).cloned().collect();
+        let mut attempts = newest_first(&attempts, |attempt| attempt.at);
+        attempts.truncate(limit as usize);
+        Ok(attempts)
+    }
+
+    async fn count_failed_logins(&self, user_id: &ObjectId, since: DateTime) -> ApiResult<u64> {
+        Ok(self
+            .data()
+            .logins
+            .iter()
+            .filter(|attempt| &attempt.user_id == user_id && !attempt.success && attempt.at >= since)
+            .count() as u64)
+    }
+
+    async fn insert_session(&self, session: &Session) -> ApiResult<()> {
+        self.data().sessions.push(session.clone());
+        Ok(())
+    }
+
+    async fn touch_session(&self, session_id: &ObjectId, user_id: &ObjectId) -> ApiResult<bool> {
+        let mut data = self.data();
+        let session = data
+            .sessions
+            .iter_mut()
+            .find(|session| &session.id == session_id && &session.user_id == user_id && !session.revoked);
+        match session {
+            Some(session) => {
+                session.last_seen_at = DateTime::now();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn open_sessions(&self, user_id: &ObjectId) -> ApiResult<Vec<Session>> {
+        let now = DateTime::now();
+        let data = self.data();
+        let sessions: Vec<Session> = data
+            .sessions
+            .iter()
+            .filter(|session| &session.user_id == user_id && !session.revoked && session.expires_at > now)
+            .cloned()
+            .collect();
+        Ok(newest_first(&sessions, |session| session.created_at))
+    }
+
+    async fn revoke_session(&self, session_id: &ObjectId, user_id: &ObjectId) -> ApiResult<bool> {
+        let mut data = self.data();
+        let session = data
+            .sessions
+            .iter_mut()
+            .find(|session| &session.id == session_id && &session.user_id == user_id);
+        match session {
+            Some(session) => {
+                session.revoked = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn revoke_sessions(&self, user_id: &ObjectId, keep: Option<&ObjectId>) -> ApiResult<()> {
+        for session in &mut self.data().sessions {
+            if &session.user_id == user_id && Some(&session.id) != keep {
+                session.revoked = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SectionStore for MemoryStore {
+    async fn find_section(&self, section_id: &ObjectId) -> ApiResult<Option<Section>> {
+        Ok(self.data().sections.iter().find(|section| &section.id == section_id).cloned())
+    }
+
+    async fn find_section_by_name(&self, course_id: &str, name: &str) -> ApiResult<Option<Section>> {
+        Ok(self
+            .data()
+            .sections
+            .iter()
+            .find(|section| section.course_id == course_id && section.name == name)
+            .cloned())
+    }
+
+    async fn list_sections(&self, course_id: &str) -> ApiResult<Vec<Section>> {
+        Ok(self.data().sections.iter().filter(|section| section.course_id == course_id).cloned().collect())
+    }
+
+    async fn insert_section(&self, section: &Section) -> ApiResult<()> {
+        self.data().sections.push(section.clone());
+        Ok(())
+    }
+
+    async fn delete_section(&self, section_id: &ObjectId) -> ApiResult<()> {
+        let mut data = self.data();
+        for account in data.accounts("users") {
+            account.section_ids.retain(|id| id != section_id);
+        }
+        data.sections.retain(|section| &section.id != section_id);
+        Ok(())
+    }
+
+    async fn set_section_ta(&self, section_id: &ObjectId, ta_id: &ObjectId, assigned: bool) -> ApiResult<()> {
+        let mut data = self.data();
+        if let Some(section) = data.sections.iter_mut().find(|section| &section.id == section_id) {
+            section.ta_ids.retain(|id| id != ta_id);
+            if assigned {
+                section.ta_ids.push(*ta_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AuditStore for MemoryStore {
+    async fn insert_audit(&self, entry: &AuditEntry) -> ApiResult<()> {
+        self.data().audit.push(entry.clone());
+        Ok(())
+    }
+
+    async fn list_audit(&self, query: &AuditQuery) -> ApiResult<Vec<AuditEntry>> {
+        let data = self.data();
+        let entries: Vec<AuditEntry> = data.audit.iter().filter(|entry| matches_audit(entry, query)).cloned().collect();
+        let mut entries = newest_first(&entries, |entry| entry.at);
+        entries.truncate(query.limit as usize);
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for MemoryStore {
+    async fn insert_api_key(&self, key: &ApiKey) -> ApiResult<()> {
+        self.data().api_keys.push(key.clone());
+        Ok(())
+    }
+
+    async fn list_api_keys(&self) -> ApiResult<Vec<ApiKey>> {
+        Ok(self.data().api_keys.clone())
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> ApiResult<Option<ApiKey>> {
+        Ok(self.data().api_keys.iter().find(|key| key.key_hash == key_hash && !key.revoked).cloned())
+    }
+
+    async fn touch_api_key(&self, key_id: &ObjectId) -> ApiResult<()> {
+        if let Some(key) = self.data().api_keys.iter_mut().find(|key| &key.id == key_id) {
+            key.last_used_at = Some(DateTime::now());
+        }
+        Ok(())
+    }
+
+    async fn revoke_api_key(&self, key_id: &ObjectId) -> ApiResult<bool> {
+        let mut data = self.data();
+        match data.api_keys.iter_mut().find(|key| &key.id == key_id) {
+            Some(key) => {
+                key.revoked = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+#[async_trait]
+impl ReportStore for MemoryStore {
+    async fn claim_report_run(&self, run: &ReportRun) -> ApiResult<bool> {
+        let mut data = self.data();
+        if data.report_runs.iter().any(|other| {
+            other.id == run.id || (other.course_id == run.course_id && other.status == "running")
+        }) {
+            return Ok(false);
+        }
+        data.report_runs.push(run.clone());
+        Ok(true)
+    }
+
+    async fn update_report_run(&self, run: &ReportRun) -> ApiResult<()> {
+        if let Some(stored) = self.data().report_runs.iter_mut().find(|other| other.id == run.id) {
+            *stored = run.clone();
+        }
+        Ok(())
+    }
+
+    async fn list_report_runs(&self, course_id: &str, limit: i64) -> ApiResult<Vec<ReportRun>> {
+        let data = self.data();
+        let runs: Vec<ReportRun> = data.report_runs.iter().filter(|run| run.course_id == course_id).cloned().collect();
+        let mut runs = newest_first(&runs, |run| run.started_at);
+        runs.truncate(limit as usize);
+        Ok(runs)
+    }
+
+    async fn find_report_run(&self, run_id: &str) -> ApiResult<Option<ReportRun>> {
+        Ok(self.data().report_runs.iter().find(|run| run.id == run_id).cloned())
+    }
+
+    async fn running_report_runs(&self, before: DateTime) -> ApiResult<Vec<ReportRun>> {
+        let data = self.data();
+        Ok(data
+            .report_runs
+            .iter()
+            .filter(|run| run.status == "running" && run.started_at < before)
+            .cloned()
+            .collect())
+    }
+}
+
+#[async_trait]
+impl JobStore for MemoryStore {
+    async fn insert_job(&self, job: &Job) -> ApiResult<()> {
+        self.data().jobs.push(job.clone());
+        Ok(())
+    }
+
+    async fn find_job(&self, job_id: &ObjectId) -> ApiResult<Option<Job>> {
+        Ok(self.data().job(job_id).cloned())
+    }
+
+    async fn list_jobs(&self, created_by: Option<&ObjectId>, limit: i64) -> ApiResult<Vec<Job>> {
+        let data = self.data();
+        let jobs: Vec<Job> = data
+            .jobs
+            .iter()
+            .filter(|job| created_by.is_none_or(|created_by| job.created_by.as_ref() == Some(created_by)))
+            .cloned()
+            .collect();
+        let mut jobs = newest_first(&jobs, |job| job.created_at);
+        jobs.truncate(limit as usize);
+        Ok(jobs)
+    }
+
+    async fn claim_job(&self, worker: &str) -> ApiResult<Option<Job>> {
+        let mut data = self.data();
+        let Some(job) = data.jobs.iter_mut().filter(|job| job.status == "queued").min_by_key(|job| job.created_at) else {
+            return Ok(None);
+        };
+        let now = DateTime::now();
+        job.status = "running".to_string();
+        job.worker = Some(worker.to_string());
+        job.started_at = Some(now);
+        job.heartbeat_at = Some(now);
+        job.attempts += 1;
+        Ok(Some(job.clone()))
+    }
+
+    async fn update_job_progress(&self, job_id: &ObjectId, progress: &JobProgress, result: Option<&Document>) -> ApiResult<bool> {
+        let mut data = self.data();
+        let Some(job) = data.job(job_id) else {
+            return Ok(false);
+        };
+        job.progress = progress.clone();
+        job.heartbeat_at = Some(DateTime::now());
+        if let Some(result) = result {
+            job.result = Some(result.clone());
+        }
+        Ok(!job.cancel_requested)
+    }
+
+    async fn finish_job(&self, job_id: &ObjectId, status: &str, result: Option<&Document>, error: Option<&str>) -> ApiResult<()> {
+        if let Some(job) = self.data().job(job_id) {
+            job.status = status.to_string();
+            job.finished_at = Some(DateTime::now());
+            if let Some(result) = result {
+                job.result = Some(result.clone());
+            }
+            if let Some(error) = error {
+                job.error = Some(error.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    async fn cancel_job(&self, job_id: &ObjectId) -> ApiResult<Option<Job>> {
+        let mut data = self.data();
+        let Some(job) = data.job(job_id) else {
+            return Ok(None);
+        };
+        match job.status.as_str() {
+            "queued" => {
+                job.status = "cancelled".to_string();
+                job.finished_at = Some(DateTime::now());
+            }
+            "running" => job.cancel_requested = true,
+            _ => {}
+        }
+        Ok(Some(job.clone()))
+    }
+
+    async fn stale_jobs(&self, before: DateTime) -> ApiResult<Vec<Job>> {
+        Ok(self
+            .data()
+            .jobs
+            .iter()
+            .filter(|job| job.status == "running" && job.heartbeat_at.is_some_and(|at| at < before))
+            .cloned()
+            .collect())
+    }
+
+    async fn requeue_job(&self, job_id: &ObjectId, before: DateTime) -> ApiResult<bool> {
+        let mut data = self.data();
+        match data.job(job_id) {
+            Some(job) if job.status == "running" && job.heartbeat_at.is_some_and(|at| at < before) => {
+                job.status = "queued".to_string();
+                job.worker = None;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[async_trait]
+impl CampaignStore for MemoryStore {
+    async fn insert_campaign(&self, campaign: &Campaign, deliveries: &[CampaignDelivery]) -> ApiResult<()> {
+        let mut data = self.data();
+        data.campaigns.push(campaign.clone());
+        data.deliveries.extend_from_slice(deliveries);
+        Ok(())
+    }
+
+    async fn find_campaign(&self, campaign_id: &ObjectId) -> ApiResult<Option<Campaign>> {
+        Ok(self.data().campaigns.iter().find(|campaign| &campaign.id == campaign_id).cloned())
+    }
+
+    async fn list_campaigns(&self, course_id: &str, limit: i64) -> ApiResult<Vec<Campaign>> {
+        let data = self.data();
+        let campaigns: Vec<Campaign> = data
+            .campaigns
+            .iter()
+            .filter(|campaign| campaign.course_id == course_id)
+            .cloned()
+            .collect();
+        let mut campaigns = newest_first(&campaigns, |campaign| campaign.created_at);
+        campaigns.truncate(limit as usize);
+        Ok(campaigns)
+    }
+
+    async fn list_deliveries(&self, campaign_id: &ObjectId) -> ApiResult<Vec<CampaignDelivery>> {
+        let mut deliveries: Vec<CampaignDelivery> = self
+            .data()
+            .deliveries
+            .iter()
+            .filter(|delivery| &delivery.campaign_id == campaign_id)
+            .cloned()
+            .collect();
+        deliveries.sort_by_key(|delivery| delivery.user_id);
+        Ok(deliveries)
+    }
+
+    async fn update_delivery(&self, delivery: &CampaignDelivery) -> ApiResult<()> {
+        if let Some(stored) = self.data().deliveries.iter_mut().find(|stored| stored.id == delivery.id) {
+            *stored = delivery.clone();
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MailStore for MemoryStore {
+    async fn find_mail_thread(&self, staff_id: &ObjectId, user_id: &ObjectId, subject: &str) -> ApiResult<Option<MailThread>> {
+        Ok(self
+            .data()
+            .mail_threads
+            .iter()
+            .find(|thread| &thread.staff_id == staff_id && &thread.user_id == user_id && thread.subject == subject)
+            .cloned())
+    }
+
+    async fn save_mail_thread(&self, thread: &MailThread) -> ApiResult<()> {
+        let mut data = self.data();
+        data.mail_threads.retain(|stored| stored.id != thread.id);
+        data.mail_threads.push(thread.clone());
+        Ok(())
+    }
+
+    async fn insert_email(&self, record: &EmailRecord) -> ApiResult<()> {
+        self.data().emails.push(record.clone());
+        Ok(())
+    }
+
+    async fn find_email(&self, email_id: &ObjectId) -> ApiResult<Option<EmailRecord>> {
+        Ok(self.data().emails.iter().find(|record| &record.id == email_id).cloned())
+    }
+
+    async fn list_emails(&self, user_id: &ObjectId, limit: i64) -> ApiResult<Vec<EmailRecord>> {
+        let data = self.data();
+        let records: Vec<EmailRecord> = data.emails.iter().filter(|record| &record.user_id == user_id).cloned().collect();
+        let mut records = newest_first(&records, |record| record.created_at);
+        records.truncate(limit as usize);
+        Ok(records)
+    }
+}
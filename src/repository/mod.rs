@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use mongodb::bson::{DateTime, Document};
+use mongodb::bson::oid::ObjectId;
+
+use crate::error::ApiResult;
+use crate::models::{
+    Account, ActivationCode, ApiKey, AuditEntry, Campaign, CampaignDelivery, Captcha, EmailRecord, Job, JobProgress,
+    LoginAttempt, MailThread, Problem, ReportRun, Section, Session, Stats, Suspension,
+};
+
+mod memory;
+mod mongo;
+
+pub use memory::MemoryStore;
+pub use mongo::MongoStore;
+
+/// Students of a course matching every given condition.
+#[derive(Default, Clone, Debug)]
+pub struct StudentFilter {
+    pub course_id: String,
+    /// Sections the students must be in, every student of the course when `None`
+    pub sections: Option<Vec<ObjectId>>,
+    pub section_id: Option<ObjectId>,
+    pub username: Option<String>,
+    pub student_id: Option<String>,
+    /// Case-insensitive substring of the real name
+    pub real_name: Option<String>,
+}
+
+/// Changes to an account; fields left `None` are kept, `Some(None)` removes an optional field.
+#[derive(Default, Clone, Debug)]
+pub struct AccountUpdate {
+    pub username: Option<String>,
+    pub email: Option<String>,
+    /// Password hash
+    pub password: Option<String>,
+    pub staff_role: Option<String>,
+    pub disabled: Option<bool>,
+    pub course_ids: Option<Option<Vec<String>>>,
+    pub student_id: Option<Option<String>>,
+    pub real_name: Option<Option<String>>,
+    pub preferred_language: Option<Option<String>>,
+    pub avatar: Option<Option<String>>,
+    pub suspension: Option<Option<Suspension>>,
+    pub must_change_password: Option<bool>,
+    pub deletion_scheduled_at: Option<Option<DateTime>>,
+    pub unsubscribed: Option<Vec<String>>,
+}
+
+impl AccountUpdate {
+    /// The optional profile field called `field`, if there is one.
+    pub fn profile_field(&mut self, field: &str) -> Option<&mut Option<Option<String>>> {
+        match field {
+            "student_id" => Some(&mut self.student_id),
+            "real_name" => Some(&mut self.real_name),
+            "preferred_language" => Some(&mut self.preferred_language),
+            "avatar" => Some(&mut self.avatar),
+            _ => None,
+        }
+    }
+}
+
+/// Amounts added to the counters of a student in a course.
+#[derive(Default, Clone, Debug)]
+pub struct StatsIncrement {
+    pub conversation: i64,
+    pub messages: i64,
+    pub tags: BTreeMap<String, i64>,
+    pub problems_viewed: BTreeMap<String, i64>,
+}
+
+/// Pending codes matching every given condition.
+#[derive(Default, Clone, Debug)]
+pub struct CodeQuery<'a> {
+    pub user_id: Option<ObjectId>,
+    /// Account activation codes have no purpose, so `None` matches only those
+    pub purpose: Option<&'a str>,
+    pub code: Option<&'a str>,
+    pub code_hash: Option<&'a str>,
+}
+
+/// Audit log entries matching every given condition, newest first.
+#[derive(Default, Clone, Debug)]
+pub struct AuditQuery {
+    pub actor_id: Option<ObjectId>,
+    /// Username the actor had, for accounts renamed or deleted since
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub since: Option<DateTime>,
+    pub until: Option<DateTime>,
+    pub limit: i64,
+}
+
+/// Student accounts live in `users`, or `tmp_users` until they are activated; staff live in `admins`.
+#[async_trait]
+pub trait AccountStore {
+    async fn find_account_in(&self, collection: &str, user_id: &ObjectId) -> ApiResult<Option<Account>>;
+    async fn find_account_by_username(&self, collection: &str, username: &str) -> ApiResult<Option<Account>>;
+    async fn find_account_by_email(&self, collection: &str, email: &str) -> ApiResult<Option<Account>>;
+    async fn list_accounts(&self, collection: &str) -> ApiResult<Vec<Account>>;
+    async fn list_students(&self, filter: &StudentFilter) -> ApiResult<Vec<Account>>;
+    async fn count_section_students(&self, section_id: &ObjectId) -> ApiResult<u64>;
+    /// Accounts of a collection whose deletion grace period has run out.
+    async fn due_for_deletion(&self, collection: &str) -> ApiResult<Vec<Account>>;
+    async fn insert_account(&self, collection: &str, account: &Account) -> ApiResult<()>;
+    /// Apply `update`, returning whether the account exists.
+    async fn update_account(&self, collection: &str, user_id: &ObjectId, update: &AccountUpdate) -> ApiResult<bool>;
+    async fn add_course(&self, collection: &str, user_id: &ObjectId, course_id: &str) -> ApiResult<()>;
+    /// Take a student out of the `remove` sections and put them in `add`.
+    async fn move_student(&self, user_id: &ObjectId, remove: &[ObjectId], add: Option<ObjectId>) -> ApiResult<()>;
+    /// Take a student out of a course, the `sections` of that course and their stats there.
+    async fn remove_course(&self, user_id: &ObjectId, course_id: &str, sections: &[ObjectId]) -> ApiResult<()>;
+    /// Move an account from `tmp_users` to `users`, keeping its id.
+    async fn activate_account(&self, user_id: &ObjectId) -> ApiResult<()>;
+    /// Remove an account and every record that references it.
+    async fn purge_account(&self, collection: &str, user_id: &ObjectId) -> ApiResult<()>;
+    /// The stored account without its password hash, for exports where every field matters.
+    async fn export_account(&self, user_id: &ObjectId) -> ApiResult<Option<Document>>;
+    /// Every record `purge_account` deletes or edits along with an account, by collection, for exports.
+    /// Pending codes are left without the code itself.
+    async fn export_records(&self, user_id: &ObjectId) -> ApiResult<BTreeMap<String, Vec<Document>>>;
+
+    /// Find an account by id, looking at students first and admins second.
+    async fn find_account(&self, user_id: &ObjectId) -> ApiResult<Option<Account>> {
+        for collection in ["users", "admins"] {
+            if let Some(account) = self.find_account_in(collection, user_id).await? {
+                return Ok(Some(account));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[async_trait]
+pub trait StatsStore {
+    async fn find_stats(&self, user_id: &ObjectId, course_id: &str) -> ApiResult<Option<Stats>>;
+    /// Stats of every course a student took.
+    async fn account_stats(&self, user_id: &ObjectId) -> ApiResult<Vec<Stats>>;
+    /// Stats of every student of a course.
+    async fn course_stats(&self, course_id: &str) -> ApiResult<Vec<Stats>>;
+    /// Create the empty stats of a student in a course, unless they exist.
+    async fn ensure_stats(&self, user_id: &ObjectId, course_id: &str) -> ApiResult<()>;
+    /// Add to the counters of a student, creating their stats when missing. Returns the stats after the increment.
+    async fn increment_stats(&self, user_id: &ObjectId, course_id: &str, inc: &StatsIncrement) -> ApiResult<Stats>;
+    /// Reset the counters of the given students in a course, returning how many were reset.
+    async fn clear_stats(&self, course_id: &str, user_ids: &[ObjectId]) -> ApiResult<u64>;
+    /// Claim the idempotency key of a stats event, `false` when it was claimed before.
+    async fn claim_event(&self, user_id: &ObjectId, course_id: &str, key: &str) -> ApiResult<bool>;
+    async fn release_event(&self, user_id: &ObjectId, course_id: &str, key: &str) -> ApiResult<()>;
+}
+
+/// Emailed codes, captcha challenges and the login link requests they are rate limited by.
+#[async_trait]
+pub trait CodeStore {
+    /// Store a code, replacing the pending code of the same account and purpose.
+    async fn replace_code(&self, code: &ActivationCode) -> ApiResult<()>;
+    async fn find_code(&self, query: &CodeQuery<'_>) -> ApiResult<Option<ActivationCode>>;
+    /// Take a pending code out, so it cannot be used twice even by concurrent requests.
+    async fn take_code(&self, query: &CodeQuery<'_>) -> ApiResult<Option<ActivationCode>>;
+    /// Count a wrong code entered for the pending code, returning the failures so far.
+    async fn count_failure(&self, query: &CodeQuery<'_>) -> ApiResult<Option<i32>>;
+    /// Store a challenge, dropping the expired ones nobody answered.
+    async fn insert_captcha(&self, captcha: &Captcha) -> ApiResult<()>;
+    async fn take_captcha(&self, captcha_id: &ObjectId) -> ApiResult<Option<Captcha>>;
+    /// Count the challenges requested from an address that are neither answered nor expired.
+    async fn count_captchas(&self, ip: Option<&str>) -> ApiResult<u64>;
+    /// Count the login link requests for an address since `since`, forgetting older ones.
+    async fn count_link_requests(&self, email: &str, since: DateTime) -> ApiResult<u64>;
+    async fn add_link_request(&self, email: &str) -> ApiResult<()>;
+}
+
+#[async_trait]
+pub trait ProblemStore {
+    async fn find_problem(&self, collection: &str, problem_id: &str) -> ApiResult<Option<Problem>>;
+}
+
+#[async_trait]
+pub trait SessionStore {
+    async fn record_login(&self, attempt: &LoginAttempt) -> ApiResult<()>;
+    /// Latest login attempts on an account, newest first.
+    async fn login_history(&self, user_id: &ObjectId, limit: i64) -> ApiResult<Vec<LoginAttempt>>;
+    async fn count_failed_logins(&self, user_id: &ObjectId, since: DateTime) -> ApiResult<u64>;
+    async fn insert_session(&self, session: &Session) -> ApiResult<()>;
+    /// Whether a session of the account is still open, marking it as seen when it is.
+    async fn touch_session(&self, session_id: &ObjectId, user_id: &ObjectId) -> ApiResult<bool>;
+    /// Sessions of an account that are neither revoked nor expired, newest first.
+    async fn open_sessions(&self, user_id: &ObjectId) -> ApiResult<Vec<Session>>;
+    /// Revoke a session of the account, returning whether it exists.
+    async fn revoke_session(&self, session_id: &ObjectId, user_id: &ObjectId) -> ApiResult<bool>;
+    /// Revoke every session of an account except `keep`.
+    async fn revoke_sessions(&self, user_id: &ObjectId, keep: Option<&ObjectId>) -> ApiResult<()>;
+}
+
+#[async_trait]
+pub trait SectionStore {
+    async fn find_section(&self, section_id: &ObjectId) -> ApiResult<Option<Section>>;
+    async fn find_section_by_name(&self, course_id: &str, name: &str) -> ApiResult<Option<Section>>;
+    async fn list_sections(&self, course_id: &str) -> ApiResult<Vec<Section>>;
+    async fn insert_section(&self, section: &Section) -> ApiResult<()>;
+    /// Delete a section, leaving its students without a section.
+    async fn delete_section(&self, section_id: &ObjectId) -> ApiResult<()>;
+    async fn set_section_ta(&self, section_id: &ObjectId, ta_id: &ObjectId, assigned: bool) -> ApiResult<()>;
+}
+
+#[async_trait]
+pub trait AuditStore {
+    async fn insert_audit(&self, entry: &AuditEntry) -> ApiResult<()>;
+    async fn list_audit(&self, query: &AuditQuery) -> ApiResult<Vec<AuditEntry>>;
+}
+
+#[async_trait]
+pub trait ApiKeyStore {
+    async fn insert_api_key(&self, key: &ApiKey) -> ApiResult<()>;
+    async fn list_api_keys(&self) -> ApiResult<Vec<ApiKey>>;
+    /// The unrevoked key with this hash.
+    async fn find_api_key(&self, key_hash: &str) -> ApiResult<Option<ApiKey>>;
+    async fn touch_api_key(&self, key_id: &ObjectId) -> ApiResult<()>;
+    /// Revoke a key, returning whether it exists.
+    async fn revoke_api_key(&self, key_id: &ObjectId) -> ApiResult<bool>;
+}
+
+#[async_trait]
+pub trait ReportStore {
+    /// Store a new run, `false` when a run with the same id was stored before,
+    /// or when another run of the course is still running.
+    async fn claim_report_run(&self, run: &ReportRun) -> ApiResult<bool>;
+    /// Save a run, which lets the next run of the course start once its status is no longer `running`.
+    async fn update_report_run(&self, run: &ReportRun) -> ApiResult<()>;
+    /// Latest runs of a course, newest first.
+    async fn list_report_runs(&self, course_id: &str, limit: i64) -> ApiResult<Vec<ReportRun>>;
+    async fn find_report_run(&self, run_id: &str) -> ApiResult<Option<ReportRun>>;
+    /// Runs of every course still running that started before `before`.
+    async fn running_report_runs(&self, before: DateTime) -> ApiResult<Vec<ReportRun>>;
+}
+
+#[async_trait]
+pub trait JobStore {
+    async fn insert_job(&self, job: &Job) -> ApiResult<()>;
+    async fn find_job(&self, job_id: &ObjectId) -> ApiResult<Option<Job>>;
+    /// Latest jobs, of one admin when `created_by` is set, newest first.
+    async fn list_jobs(&self, created_by: Option<&ObjectId>, limit: i64) -> ApiResult<Vec<Job>>;
+    /// Take the oldest queued job for `worker`, marking it running.
+    async fn claim_job(&self, worker: &str) -> ApiResult<Option<Job>>;
+    /// Save the progress and partial result of a running job, `false` once its cancellation was requested.
+    async fn update_job_progress(&self, job_id: &ObjectId, progress: &JobProgress, result: Option<&Document>) -> ApiResult<bool>;
+    async fn finish_job(&self, job_id: &ObjectId, status: &str, result: Option<&Document>, error: Option<&str>) -> ApiResult<()>;
+    /// Cancel a queued job or ask a running one to stop, returning the job as it is now.
+    async fn cancel_job(&self, job_id: &ObjectId) -> ApiResult<Option<Job>>;
+    /// Running jobs whose worker gave no sign of life since `before`.
+    async fn stale_jobs(&self, before: DateTime) -> ApiResult<Vec<Job>>;
+    /// Put a stale job back in the queue, unless its worker came back meanwhile.
+    async fn requeue_job(&self, job_id: &ObjectId, before: DateTime) -> ApiResult<bool>;
+}
+
+#[async_trait]
+pub trait CampaignStore {
+    /// Store a campaign with the pending deliveries to its recipients.
+    async fn insert_campaign(&self, campaign: &Campaign, deliveries: &[CampaignDelivery]) -> ApiResult<()>;
+    async fn find_campaign(&self, campaign_id: &ObjectId) -> ApiResult<Option<Campaign>>;
+    /// Latest campaigns of a course, newest first.
+    async fn list_campaigns(&self, course_id: &str, limit: i64) -> ApiResult<Vec<Campaign>>;
+    /// Deliveries of a campaign, ordered by student id.
+    async fn list_deliveries(&self, campaign_id: &ObjectId) -> ApiResult<Vec<CampaignDelivery>>;
+    async fn update_delivery(&self, delivery: &CampaignDelivery) -> ApiResult<()>;
+}
+
+#[async_trait]
+pub trait MailStore {
+    async fn find_mail_thread(&self, staff_id: &ObjectId, user_id: &ObjectId, subject: &str) -> ApiResult<Option<MailThread>>;
+    /// Insert the thread, or replace the stored one with the same id.
+    async fn save_mail_thread(&self, thread: &MailThread) -> ApiResult<()>;
+    async fn insert_email(&self, record: &EmailRecord) -> ApiResult<()>;
+    async fn find_email(&self, email_id: &ObjectId) -> ApiResult<Option<EmailRecord>>;
+    /// Newest first.
+    async fn list_emails(&self, user_id: &ObjectId, limit: i64) -> ApiResult<Vec<EmailRecord>>;
+}
+
+/// Everything the handlers store, shared as `web::Data<dyn Store>`. Implemented for MongoDB by
+/// [`MongoStore`] and in memory by [`MemoryStore`], which the tests run against.
+pub trait Store:
+    AccountStore + StatsStore + CodeStore + ProblemStore + SessionStore + SectionStore + AuditStore + ApiKeyStore
+    + ReportStore + JobStore + CampaignStore + MailStore + Send + Sync
+{
+}
+
+impl<T> Store for T where
+    T: AccountStore + StatsStore + CodeStore + ProblemStore + SessionStore + SectionStore + AuditStore + ApiKeyStore
+        + ReportStore + JobStore + CampaignStore + MailStore + Send + Sync
+{
+}
@@ -0,0 +1,1004 @@
+use std::collections::BTreeMap;
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use mongodb::{ClientSession, Collection, Database};
+use mongodb::bson::{self, doc, DateTime, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::ReturnDocument;
+
+use crate::error::{ApiResult, ApiError, ApiErrorType};
+use crate::models::{
+    Account, ActivationCode, ApiKey, AuditEntry, Campaign, CampaignDelivery, Captcha, EmailRecord, Job, JobProgress,
+    LoginAttempt, MailThread, Problem, ReportRun, Section, Session, Stats,
+};
+use super::{
+    AccountStore, AccountUpdate, ApiKeyStore, AuditQuery, AuditStore, CampaignStore, CodeQuery, CodeStore, JobStore,
+    MailStore, ProblemStore, ReportStore, SectionStore, SessionStore, StatsIncrement, StatsStore, StudentFilter,
+};
+
+/// Collections holding the records of an account in a `user_id` field, deleted along with it.
+const ACCOUNT_RECORDS: [&str; 8] = [
+    "stats",
+    "stats_events",
+    "activation_codes",
+    "sessions",
+    "login_history",
+    "campaign_deliveries",
+    "mail_threads",
+    "emails",
+];
+
+/// The store of the running server.
+#[derive(Clone)]
+pub struct MongoStore {
+    db: Database,
+    /// Whether the server is a replica set member or mongos, which transactions need
+    transactions: bool,
+}
+
+impl MongoStore {
+    /// A store on `db`, using transactions when the deployment supports them.
+    pub async fn new(db: Database) -> mongodb::error::Result<Self> {
+        let hello = db.run_command(doc! { "hello": 1 }).await?;
+        let transactions = hello.contains_key("setName") || hello.get_str("msg") == Ok("isdbgrid");
+        if !transactions {
+            log::warn!("MongoDB is not a replica set, accounts are deleted without a transaction.");
+        }
+        Ok(MongoStore { db, transactions })
+    }
+
+    /// Delete an account and its records, within `session` when given.
+    async fn purge_records(
+        &self,
+        collection: &str,
+        user_id: &ObjectId,
+        mut session: Option<&mut ClientSession>,
+    ) -> mongodb::error::Result<()> {
+        for referencing in ACCOUNT_RECORDS {
+            let records = self.db.collection::<Document>(referencing);
+            let delete = records.delete_many(doc! { "user_id": user_id });
+            match session.as_deref_mut() {
+                Some(session) => delete.session(session).await?,
+                None => delete.await?,
+            };
+        }
+        let sections = self.db.collection::<Document>("sections");
+        let pull = sections.update_many(doc! { "ta_ids": user_id }, doc! { "$pull": { "ta_ids": user_id } });
+        match session.as_deref_mut() {
+            Some(session) => pull.session(session).await?,
+            None => pull.await?,
+        };
+        let accounts = self.db.collection::<Document>(collection);
+        let delete = accounts.delete_one(doc! { "_id": user_id });
+        match session {
+            Some(session) => delete.session(session).await?,
+            None => delete.await?,
+        };
+        Ok(())
+    }
+
+    fn accounts(&self, collection: &str) -> Collection<Account> {
+        self.db.collection(collection)
+    }
+
+    fn stats(&self) -> Collection<Stats> {
+        self.db.collection("stats")
+    }
+
+    fn codes(&self) -> Collection<ActivationCode> {
+        self.db.collection("activation_codes")
+    }
+
+    fn sessions(&self) -> Collection<Session> {
+        self.db.collection("sessions")
+    }
+
+    fn sections(&self) -> Collection<Section> {
+        self.db.collection("sections")
+    }
+
+    fn api_keys(&self) -> Collection<ApiKey> {
+        self.db.collection("api_keys")
+    }
+
+    fn report_runs(&self) -> Collection<ReportRun> {
+        self.db.collection("report_runs")
+    }
+
+    /// One document per course with a run in progress, `_id` being the course id.
+    fn report_locks(&self) -> Collection<Document> {
+        self.db.collection("report_locks")
+    }
+
+    fn jobs(&self) -> Collection<Job> {
+        self.db.collection("jobs")
+    }
+
+    fn campaigns(&self) -> Collection<Campaign> {
+        self.db.collection("campaigns")
+    }
+
+    fn deliveries(&self) -> Collection<CampaignDelivery> {
+        self.db.collection("campaign_deliveries")
+    }
+
+    fn mail_threads(&self) -> Collection<MailThread> {
+        self.db.collection("mail_threads")
+    }
+
+    fn emails(&self) -> Collection<EmailRecord> {
+        self.db.collection("emails")
+    }
+}
+
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(
+        e.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000
+    )
+}
+
+fn to_bson<T: serde::Serialize>(value: &T) -> ApiResult<bson::Bson> {
+    bson::to_bson(value).map_err(|e| ApiError::new(
+        ApiErrorType::Internal,
+        format!("Failed to encode document: {}", e),
+    ))
+}
+
+fn student_filter(filter: &StudentFilter) -> Document {
+    let mut conditions = vec![doc! { "course_ids": &filter.course_id }];
+    if let Some(sections) = &filter.sections {
+        conditions.push(doc! { "section_ids": { "$in": sections } });
+    }
+    if let Some(section_id) = &filter.section_id {
+        conditions.push(doc! { "section_ids": section_id });
+    }
+    if let Some(username) = &filter.username {
+        conditions.push(doc! { "username": username });
+    }
+    if let Some(student_id) = &filter.student_id {
+        conditions.push(doc! { "student_id": student_id });
+    }
+    if let Some(real_name) = &filter.real_name {
+        conditions.push(doc! { "real_name": { "$regex": escape_regex(real_name), "$options": "i" } });
+    }
+    doc! { "$and": conditions }
+}
+
+fn account_update(update: &AccountUpdate) -> ApiResult<Document> {
+    let mut set = doc! {};
+    let mut unset = doc! {};
+    let mut field = |name: &str, value: Option<bson::Bson>| match value {
+        Some(value) => {
+            set.insert(name, value);
+        }
+        None => {
+            unset.insert(name, "");
+        }
+    };
+    if let Some(username) = &update.username {
+        field("username", Some(username.into()));
+    }
+    if let Some(email) = &update.email {
+        field("email", Some(email.into()));
+    }
+    if let Some(password) = &update.password {
+        field("password", Some(password.into()));
+    }
+    if let Some(staff_role) = &update.staff_role {
+        field("staff_role", Some(staff_role.into()));
+    }
+    if let Some(disabled) = update.disabled {
+        field("disabled", Some(disabled.into()));
+    }
+    if let Some(course_ids) = &update.course_ids {
+        field("course_ids", course_ids.as_ref().map(|ids| ids.into()));
+    }
+    for (name, value) in [
+        ("student_id", &update.student_id),
+        ("real_name", &update.real_name),
+        ("preferred_language", &update.preferred_language),
+        ("avatar", &update.avatar),
+    ] {
+        if let Some(value) = value {
+            field(name, value.as_ref().map(|value| value.into()));
+        }
+    }
+    if let Some(suspension) = &update.suspension {
+        field("suspension", suspension.as_ref().map(to_bson).transpose()?);
+    }
+    if let Some(must_change_password) = update.must_change_password {
+        field("must_change_password", must_change_password.then_some(true.into()));
+    }
+    if let Some(deletion_scheduled_at) = update.deletion_scheduled_at {
+        field("deletion_scheduled_at", deletion_scheduled_at.map(|at| at.into()));
+    }
+    if let Some(unsubscribed) = &update.unsubscribed {
+        field("unsubscribed", (!unsubscribed.is_empty()).then(|| unsubscribed.into()));
+    }
+
+    let mut update = doc! {};
+    if !set.is_empty() {
+        update.insert("$set", set);
+    }
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+    Ok(update)
+}
+
+fn code_filter(query: &CodeQuery) -> Document {
+    let mut filter = match query.purpose {
+        Some(purpose) => doc! { "purpose": purpose },
+        None => doc! { "purpose": { "$exists": false } },
+    };
+    if let Some(user_id) = &query.user_id {
+        filter.insert("user_id", user_id);
+    }
+    if let Some(code) = query.code {
+        filter.insert("code", code);
+    }
+    if let Some(code_hash) = query.code_hash {
+        filter.insert("code_hash", code_hash);
+    }
+    filter
+}
+
+#[async_trait]
+impl AccountStore for MongoStore {
+    async fn find_account_in(&self, collection: &str, user_id: &ObjectId) -> ApiResult<Option<Account>> {
+        Ok(self.accounts(collection).find_one(doc! { "_id": user_id }).await?)
+    }
+
+    async fn find_account_by_username(&self, collection: &str, username: &str) -> ApiResult<Option<Account>> {
+        Ok(self.accounts(collection).find_one(doc! { "username": username }).await?)
+    }
+
+    async fn find_account_by_email(&self, collection: &str, email: &str) -> ApiResult<Option<Account>> {
+        Ok(self.accounts(collection).find_one(doc! { "email": email }).await?)
+    }
+
+    async fn list_accounts(&self, collection: &str) -> ApiResult<Vec<Account>> {
+        Ok(self.accounts(collection).find(doc! {}).await?.try_collect().await?)
+    }
+
+    async fn list_students(&self, filter: &StudentFilter) -> ApiResult<Vec<Account>> {
+        Ok(self.accounts("users").find(student_filter(filter)).await?.try_collect().await?)
+    }
+
+    async fn count_section_students(&self, section_id: &ObjectId) -> ApiResult<u64> {
+        Ok(self.accounts("users").count_documents(doc! { "section_ids": section_id }).await?)
+    }
+
+    async fn due_for_deletion(&self, collection: &str) -> ApiResult<Vec<Account>> {
+        Ok(self
+            .accounts(collection)
+            .find(doc! { "deletion_scheduled_at": { "$lte": DateTime::now() } })
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn insert_account(&self, collection: &str, account: &Account) -> ApiResult<()> {
+        self.accounts(collection).insert_one(account).await?;
+        Ok(())
+    }
+
+    async fn update_account(&self, collection: &str, user_id: &ObjectId, update: &AccountUpdate) -> ApiResult<bool> {
+        let update = account_update(update)?;
+        if update.is_empty() {
+            return Ok(self.find_account_in(collection, user_id).await?.is_some());
+        }
+        let result = self.accounts(collection).update_one(doc! { "_id": user_id }, update).await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn add_course(&self, collection: &str, user_id: &ObjectId, course_id: &str) -> ApiResult<()> {
+        self.accounts(collection)
+            .update_one(
+                doc! { "_id": user_id },
+                doc! { "$addToSet": { "course_ids": course_id } },
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn move_student(&self, user_id: &ObjectId, remove: &[ObjectId], add: Option<ObjectId>) -> ApiResult<()> {
+        let users = self.accounts("users");
+        users
+            .update_one(
+                doc! { "_id": user_id },
+                doc! { "$pull": { "section_ids": { "$in": remove } } },
+            )
+            .await?;
+        if let Some(section_id) = add {
+            users
+                .update_one(
+                    doc! { "_id": user_id },
+                    doc! { "$addToSet": { "section_ids": section_id } },
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn activate_account(&self, user_id: &ObjectId) -> ApiResult<()> {
+        // moved as a raw document, so fields this version does not know about are kept
+        let tmp_collection: Collection<Document> = self.db.collection("tmp_users");
+        let users_collection: Collection<Document> = self.db.collection("users");
+        let filter = doc! { "_id": user_id };
+        if let Some(user_doc) = tmp_collection.find_one(filter.clone()).await? {
+            users_collection.insert_one(user_doc).await?;
+            tmp_collection.delete_one(filter).await?;
+        }
+        Ok(())
+    }
+
+    async fn remove_course(&self, user_id: &ObjectId, course_id: &str, sections: &[ObjectId]) -> ApiResult<()> {
+        self.accounts("users")
+            .update_one(
+                doc! { "_id": user_id },
+                doc! { "$pull": { "course_ids": course_id, "section_ids": { "$in": sections } } },
+            )
+            .await?;
+        self.stats().delete_many(doc! { "user_id": user_id, "course_id": course_id }).await?;
+        let events: Collection<Document> = self.db.collection("stats_events");
+        events.delete_many(doc! { "_id.user_id": user_id, "_id.course_id": course_id }).await?;
+        Ok(())
+    }
+
+    async fn purge_account(&self, collection: &str, user_id: &ObjectId) -> ApiResult<()> {
+        if !self.transactions {
+            // the account goes last, so a purge cut short is finished by the next try
+            return Ok(self.purge_records(collection, user_id, None).await?);
+        }
+        let mut session = self.db.client().start_session().await?;
+        session.start_transaction().await?;
+        match self.purge_records(collection, user_id, Some(&mut session)).await {
+            Ok(()) => session.commit_transaction().await?,
+            Err(e) => {
+                session.abort_transaction().await?;
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    async fn export_account(&self, user_id: &ObjectId) -> ApiResult<Option<Document>> {
+        for collection in ["users", "admins"] {
+            let collection: Collection<Document> = self.db.collection(collection);
+            if let Some(mut account) = collection.find_one(doc! { "_id": user_id }).await? {
+                account.remove("password");
+                return Ok(Some(account));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn export_records(&self, user_id: &ObjectId) -> ApiResult<BTreeMap<String, Vec<Document>>> {
+        let mut records = BTreeMap::new();
+        for referencing in ACCOUNT_RECORDS {
+            let collection: Collection<Document> = self.db.collection(referencing);
+            let mut documents: Vec<Document> = collection.find(doc! { "user_id": user_id }).await?.try_collect().await?;
+            for document in &mut documents {
+                document.remove("code");
+                document.remove("code_hash");
+            }
+            records.insert(referencing.to_string(), documents);
+        }
+        let sections: Collection<Document> = self.db.collection("sections");
+        let assisted = sections.find(doc! { "ta_ids": user_id }).await?.try_collect().await?;
+        records.insert("sections".to_string(), assisted);
+        Ok(records)
+    }
+}
+
+#[async_trait]
+impl StatsStore for MongoStore {
+    async fn find_stats(&self, user_id: &ObjectId, course_id: &str) -> ApiResult<Option<Stats>> {
+        Ok(self.stats().find_one(doc! { "user_id": user_id, "course_id": course_id }).await?)
+    }
+
+    async fn account_stats(&self, user_id: &ObjectId) -> ApiResult<Vec<Stats>> {
+        Ok(self.stats().find(doc! { "user_id": user_id }).await?.try_collect().await?)
+    }
+
+    async fn course_stats(&self, course_id: &str) -> ApiResult<Vec<Stats>> {
+        Ok(self.stats().find(doc! { "course_id": course_id }).await?.try_collect().await?)
+    }
+
+    async fn ensure_stats(&self, user_id: &ObjectId, course_id: &str) -> ApiResult<()> {
+        self.stats()
+            .update_one(
+                doc! { "user_id": user_id, "course_id": course_id },
+                doc! { "$setOnInsert": { "conversation": 0, "tags": {} } },
+            )
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+
+    async fn increment_stats(&self, user_id: &ObjectId, course_id: &str, inc: &StatsIncrement) -> ApiResult<Stats> {
+        let mut counters = doc! {};
+        if inc.conversation != 0 {
+            counters.insert("conversation", inc.conversation);
+        }
+        if inc.messages != 0 {
+            counters.insert("messages", inc.messages);
+        }
+        for (tag, count) in &inc.tags {
+            counters.insert(format!("tags.{}", tag), *count);
+        }
+        for (problem_id, count) in &inc.problems_viewed {
+            counters.insert(format!("problems_viewed.{}", problem_id), *count);
+        }
+        // fields the increment does not touch get their initial value, `$inc` creates the others
+        let mut defaults = doc! {};
+        if !counters.contains_key("conversation") {
+            defaults.insert("conversation", 0);
+        }
+        if inc.tags.is_empty() {
+            defaults.insert("tags", doc! {});
+        }
+        let mut update = doc! { "$inc": counters, "$set": { "last_active_at": DateTime::now() } };
+        if !defaults.is_empty() {
+            update.insert("$setOnInsert", defaults);
+        }
+        self.stats()
+            .find_one_and_update(doc! { "user_id": user_id, "course_id": course_id }, update)
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .await?
+            .ok_or(ApiError::new(
+                ApiErrorType::Internal,
+                "Failed to update stats".to_string(),
+            ))
+    }
+
+    async fn clear_stats(&self, course_id: &str, user_ids: &[ObjectId]) -> ApiResult<u64> {
+        let result = self
+            .stats()
+            .update_many(
+                doc! { "course_id": course_id, "user_id": { "$in": user_ids } },
+                doc! { "$set": { "conversation": 0, "tags": {}, "messages": 0, "problems_viewed": {} } },
+            )
+            .await?;
+        Ok(result.modified_count)
+    }
+
+    async fn claim_event(&self, user_id: &ObjectId, course_id: &str, key: &str) -> ApiResult<bool> {
+        // the unique `_id` makes a retried event a duplicate, even when both arrive at once
+        let events: Collection<Document> = self.db.collection("stats_events");
+        let key = doc! { "user_id": user_id, "course_id": course_id, "key": key };
+        match events.insert_one(doc! { "_id": key, "user_id": user_id, "at": DateTime::now() }).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn release_event(&self, user_id: &ObjectId, course_id: &str, key: &str) -> ApiResult<()> {
+        let events: Collection<Document> = self.db.collection("stats_events");
+        let key = doc! { "user_id": user_id, "course_id": course_id, "key": key };
+        events.delete_one(doc! { "_id": key }).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CodeStore for MongoStore {
+    async fn replace_code(&self, code: &ActivationCode) -> ApiResult<()> {
+        let filter = code_filter(&CodeQuery {
+            user_id: Some(code.user_id),
+            purpose: code.purpose.as_deref(),
+            ..Default::default()
+        });
+        self.codes().delete_many(filter).await?;
+        self.codes().insert_one(code).await?;
+        Ok(())
+    }
+
+    async fn find_code(&self, query: &CodeQuery<'_>) -> ApiResult<Option<ActivationCode>> {
+        Ok(self.codes().find_one(code_filter(query)).await?)
+    }
+
+    async fn take_code(&self, query: &CodeQuery<'_>) -> ApiResult<Option<ActivationCode>> {
+        Ok(self.codes().find_one_and_delete(code_filter(query)).await?)
+    }
+
+    async fn count_failure(&self, query: &CodeQuery<'_>) -> ApiResult<Option<i32>> {
+        Ok(self
+            .codes()
+            .find_one_and_update(code_filter(query), doc! { "$inc": { "failures": 1 } })
+            .return_document(ReturnDocument::After)
+            .await?
+            .map(|code| code.failures))
+    }
+
+    async fn insert_captcha(&self, captcha: &Captcha) -> ApiResult<()> {
+        let collection: Collection<Captcha> = self.db.collection("captchas");
+        collection.delete_many(doc! { "expires_at": { "$lte": DateTime::now() } }).await?;
+        collection.insert_one(captcha).await?;
+        Ok(())
+    }
+
+    async fn take_captcha(&self, captcha_id: &ObjectId) -> ApiResult<Option<Captcha>> {
+        let collection: Collection<Captcha> = self.db.collection("captchas");
+        Ok(collection.find_one_and_delete(doc! { "_id": captcha_id }).await?)
+    }
+
+    async fn count_captchas(&self, ip: Option<&str>) -> ApiResult<u64> {
+        let collection: Collection<Captcha> = self.db.collection("captchas");
+        Ok(collection.count_documents(doc! { "ip": ip, "expires_at": { "$gt": DateTime::now() } }).await?)
+    }
+
+    async fn count_link_requests(&self, email: &str, since: DateTime) -> ApiResult<u64> {
+        let requests: Collection<Document> = self.db.collection("magic_link_requests");
+        requests.delete_many(doc! { "at": { "$lt": since } }).await?;
+        Ok(requests.count_documents(doc! { "email": email }).await?)
+    }
+
+    async fn add_link_request(&self, email: &str) -> ApiResult<()> {
+        let requests: Collection<Document> = self.db.collection("magic_link_requests");
+        requests.insert_one(doc! { "email": email, "at": DateTime::now() }).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ProblemStore for MongoStore {
+    async fn find_problem(&self, collection: &str, problem_id: &str) -> ApiResult<Option<Problem>> {
+        let problems: Collection<Problem> = self.db.collection(collection);
+        Ok(problems.find_one(doc! { "_id": problem_id }).await?)
+    }
+}
+
+#[async_trait]
+impl SessionStore for MongoStore {
+    async fn record_login(&self, attempt: &LoginAttempt) -> ApiResult<()> {
+        let collection: Collection<LoginAttempt> = self.db.collection("login_history");
+        collection.insert_one(attempt).await?;
+        Ok(())
+    }
+
+    async fn login_history(&self, user_id: &ObjectId, limit: i64) -> ApiResult<Vec<LoginAttempt>> {
+        let collection: Collection<LoginAttempt> = self.db.collection("login_history");
+        Ok(collection
+            .find(doc! { "user_id": user_id })
+            .sort(doc! { "at": -1 })
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn count_failed_logins(&self, user_id: &ObjectId, since: DateTime) -> ApiResult<u64> {
+        let collection: Collection<LoginAttempt> = self.db.collection("login_history");
+        Ok(collection
+            .count_documents(doc! { "user_id": user_id, "success": false, "at": { "$gte": since } })
+            .await?)
+    }
+
+    async fn insert_session(&self, session: &Session) -> ApiResult<()> {
+        self.sessions().insert_one(session).await?;
+        Ok(())
+    }
+
+    async fn touch_session(&self, session_id: &ObjectId, user_id: &ObjectId) -> ApiResult<bool> {
+        let result = self
+            .sessions()
+            .update_one(
+                doc! { "_id": session_id, "user_id": user_id, "revoked": { "$ne": true } },
+                doc! { "$set": { "last_seen_at": DateTime::now() } },
+            )
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn open_sessions(&self, user_id: &ObjectId) -> ApiResult<Vec<Session>> {
+        Ok(self
+            .sessions()
+            .find(doc! {
+                "user_id": user_id,
+                "revoked": { "$ne": true },
+                "expires_at": { "$gt": DateTime::now() },
+            })
+            .sort(doc! { "created_at": -1 })
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn revoke_session(&self, session_id: &ObjectId, user_id: &ObjectId) -> ApiResult<bool> {
+        let result = self
+            .sessions()
+            .update_one(
+                doc! { "_id": session_id, "user_id": user_id },
+                doc! { "$set": { "revoked": true } },
+            )
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+
+    async fn revoke_sessions(&self, user_id: &ObjectId, keep: Option<&ObjectId>) -> ApiResult<()> {
+        let mut filter = doc! { "user_id": user_id, "revoked": { "$ne": true } };
+        if let Some(keep) = keep {
+            filter.insert("_id", doc! { "$ne": keep });
+        }
+        self.sessions()
+            .update_many(filter, doc! { "$set": { "revoked": true } })
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl SectionStore for MongoStore {
+    async fn find_section(&self, section_id: &ObjectId) -> ApiResult<Option<Section>> {
+        Ok(self.sections().find_one(doc! { "_id": section_id }).await?)
+    }
+
+    async fn find_section_by_name(&self, course_id: &str, name: &str) -> ApiResult<Option<Section>> {
+        Ok(self.sections().find_one(doc! { "course_id": course_id, "name": name }).await?)
+    }
+
+    async fn list_sections(&self, course_id: &str) -> ApiResult<Vec<Section>> {
+        Ok(self.sections().find(doc! { "course_id": course_id }).await?.try_collect().await?)
+    }
+
+    async fn insert_section(&self, section: &Section) -> ApiResult<()> {
+        self.sections().insert_one(section).await?;
+        Ok(())
+    }
+
+    async fn delete_section(&self, section_id: &ObjectId) -> ApiResult<()> {
+        self.accounts("users")
+            .update_many(
+                doc! { "section_ids": section_id },
+                doc! { "$pull": { "section_ids": section_id } },
+            )
+            .await?;
+        self.sections().delete_one(doc! { "_id": section_id }).await?;
+        Ok(())
+    }
+
+    async fn set_section_ta(&self, section_id: &ObjectId, ta_id: &ObjectId, assigned: bool) -> ApiResult<()> {
+        let update = if assigned {
+            doc! { "$addToSet": { "ta_ids": ta_id } }
+        } else {
+            doc! { "$pull": { "ta_ids": ta_id } }
+        };
+        self.sections().update_one(doc! { "_id": section_id }, update).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AuditStore for MongoStore {
+    async fn insert_audit(&self, entry: &AuditEntry) -> ApiResult<()> {
+        let collection: Collection<AuditEntry> = self.db.collection("audit_log");
+        collection.insert_one(entry).await?;
+        Ok(())
+    }
+
+    async fn list_audit(&self, query: &AuditQuery) -> ApiResult<Vec<AuditEntry>> {
+        let mut filter = doc! {};
+        if let Some(actor_id) = &query.actor_id {
+            filter.insert("actor_id", actor_id);
+        }
+        if let Some(actor) = &query.actor {
+            filter.insert("actor", actor);
+        }
+        if let Some(action) = &query.action {
+            filter.insert("action", action);
+        }
+        let mut at = doc! {};
+        if let Some(since) = query.since {
+            at.insert("$gte", since);
+        }
+        if let Some(until) = query.until {
+            at.insert("$lt", until);
+        }
+        if !at.is_empty() {
+            filter.insert("at", at);
+        }
+        let collection: Collection<AuditEntry> = self.db.collection("audit_log");
+        Ok(collection
+            .find(filter)
+            .sort(doc! { "at": -1 })
+            .limit(query.limit)
+            .await?
+            .try_collect()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl ApiKeyStore for MongoStore {
+    async fn insert_api_key(&self, key: &ApiKey) -> ApiResult<()> {
+        self.api_keys().insert_one(key).await?;
+        Ok(())
+    }
+
+    async fn list_api_keys(&self) -> ApiResult<Vec<ApiKey>> {
+        Ok(self.api_keys().find(doc! {}).await?.try_collect().await?)
+    }
+
+    async fn find_api_key(&self, key_hash: &str) -> ApiResult<Option<ApiKey>> {
+        Ok(self
+            .api_keys()
+            .find_one(doc! { "key_hash": key_hash, "revoked": { "$ne": true } })
+            .await?)
+    }
+
+    async fn touch_api_key(&self, key_id: &ObjectId) -> ApiResult<()> {
+        self.api_keys()
+            .update_one(
+                doc! { "_id": key_id },
+                doc! { "$set": { "last_used_at": DateTime::now() } },
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn revoke_api_key(&self, key_id: &ObjectId) -> ApiResult<bool> {
+        let result = self
+            .api_keys()
+            .update_one(
+                doc! { "_id": key_id },
+                doc! { "$set": { "revoked": true } },
+            )
+            .await?;
+        Ok(result.matched_count > 0)
+    }
+}
+
+#[async_trait]
+impl ReportStore for MongoStore {
+    async fn claim_report_run(&self, run: &ReportRun) -> ApiResult<bool> {
+        // the unique `_id` keeps two workers or a restarted server from claiming the same slot
+        match self.report_runs().insert_one(run).await {
+            Ok(_) => {}
+            Err(e) if is_duplicate_key(&e) => return Ok(false),
+            Err(e) => return Err(e.into()),
+        }
+        // and the unique course lock keeps two runs of a course from overlapping
+        match self.report_locks().insert_one(doc! { "_id": &run.course_id, "run_id": &run.id }).await {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => {
+                // a scheduled slot given back this way is claimed again at the next tick
+                self.report_runs().delete_one(doc! { "_id": &run.id }).await?;
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn update_report_run(&self, run: &ReportRun) -> ApiResult<()> {
+        self.report_runs().replace_one(doc! { "_id": &run.id }, run).await?;
+        if run.status != "running" {
+            self.report_locks()
+                .delete_one(doc! { "_id": &run.course_id, "run_id": &run.id })
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn list_report_runs(&self, course_id: &str, limit: i64) -> ApiResult<Vec<ReportRun>> {
+        Ok(self
+            .report_runs()
+            .find(doc! { "course_id": course_id })
+            .sort(doc! { "started_at": -1 })
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn find_report_run(&self, run_id: &str) -> ApiResult<Option<ReportRun>> {
+        Ok(self.report_runs().find_one(doc! { "_id": run_id }).await?)
+    }
+
+    async fn running_report_runs(&self, before: DateTime) -> ApiResult<Vec<ReportRun>> {
+        Ok(self
+            .report_runs()
+            .find(doc! { "status": "running", "started_at": { "$lt": before } })
+            .await?
+            .try_collect()
+            .await?)
+    }
+}
+
+#[async_trait]
+impl JobStore for MongoStore {
+    async fn insert_job(&self, job: &Job) -> ApiResult<()> {
+        self.jobs().insert_one(job).await?;
+        Ok(())
+    }
+
+    async fn find_job(&self, job_id: &ObjectId) -> ApiResult<Option<Job>> {
+        Ok(self.jobs().find_one(doc! { "_id": job_id }).await?)
+    }
+
+    async fn list_jobs(&self, created_by: Option<&ObjectId>, limit: i64) -> ApiResult<Vec<Job>> {
+        let filter = match created_by {
+            Some(created_by) => doc! { "created_by": created_by },
+            None => doc! {},
+        };
+        Ok(self
+            .jobs()
+            .find(filter)
+            .sort(doc! { "created_at": -1 })
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn claim_job(&self, worker: &str) -> ApiResult<Option<Job>> {
+        // a single atomic update, so two workers never take the same job
+        let now = DateTime::now();
+        Ok(self
+            .jobs()
+            .find_one_and_update(
+                doc! { "status": "queued" },
+                doc! {
+                    "$set": { "status": "running", "worker": worker, "started_at": now, "heartbeat_at": now },
+                    "$inc": { "attempts": 1 },
+                },
+            )
+            .sort(doc! { "created_at": 1 })
+            .return_document(ReturnDocument::After)
+            .await?)
+    }
+
+    async fn update_job_progress(&self, job_id: &ObjectId, progress: &JobProgress, result: Option<&Document>) -> ApiResult<bool> {
+        let mut set = doc! { "progress": to_bson(progress)?, "heartbeat_at": DateTime::now() };
+        if let Some(result) = result {
+            set.insert("result", result);
+        }
+        let job = self
+            .jobs()
+            .find_one_and_update(doc! { "_id": job_id }, doc! { "$set": set })
+            .return_document(ReturnDocument::After)
+            .await?;
+        Ok(job.is_some_and(|job| !job.cancel_requested))
+    }
+
+    async fn finish_job(&self, job_id: &ObjectId, status: &str, result: Option<&Document>, error: Option<&str>) -> ApiResult<()> {
+        let mut set = doc! { "status": status, "finished_at": DateTime::now() };
+        if let Some(result) = result {
+            set.insert("result", result);
+        }
+        if let Some(error) = error {
+            set.insert("error", error);
+        }
+        self.jobs().update_one(doc! { "_id": job_id }, doc! { "$set": set }).await?;
+        Ok(())
+    }
+
+    async fn cancel_job(&self, job_id: &ObjectId) -> ApiResult<Option<Job>> {
+        self.jobs()
+            .update_one(
+                doc! { "_id": job_id, "status": "queued" },
+                doc! { "$set": { "status": "cancelled", "finished_at": DateTime::now() } },
+            )
+            .await?;
+        self.jobs()
+            .update_one(
+                doc! { "_id": job_id, "status": "running" },
+                doc! { "$set": { "cancel_requested": true } },
+            )
+            .await?;
+        self.find_job(job_id).await
+    }
+
+    async fn stale_jobs(&self, before: DateTime) -> ApiResult<Vec<Job>> {
+        Ok(self
+            .jobs()
+            .find(doc! { "status": "running", "heartbeat_at": { "$lt": before } })
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn requeue_job(&self, job_id: &ObjectId, before: DateTime) -> ApiResult<bool> {
+        let result = self
+            .jobs()
+            .update_one(
+                doc! { "_id": job_id, "status": "running", "heartbeat_at": { "$lt": before } },
+                doc! { "$set": { "status": "queued" }, "$unset": { "worker": "" } },
+            )
+            .await?;
+        Ok(result.modified_count > 0)
+    }
+}
+
+#[async_trait]
+impl CampaignStore for MongoStore {
+    async fn insert_campaign(&self, campaign: &Campaign, deliveries: &[CampaignDelivery]) -> ApiResult<()> {
+        self.campaigns().insert_one(campaign).await?;
+        if !deliveries.is_empty() {
+            self.deliveries().insert_many(deliveries).await?;
+        }
+        Ok(())
+    }
+
+    async fn find_campaign(&self, campaign_id: &ObjectId) -> ApiResult<Option<Campaign>> {
+        Ok(self.campaigns().find_one(doc! { "_id": campaign_id }).await?)
+    }
+
+    async fn list_campaigns(&self, course_id: &str, limit: i64) -> ApiResult<Vec<Campaign>> {
+        Ok(self
+            .campaigns()
+            .find(doc! { "course_id": course_id })
+            .sort(doc! { "created_at": -1 })
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn list_deliveries(&self, campaign_id: &ObjectId) -> ApiResult<Vec<CampaignDelivery>> {
+        Ok(self
+            .deliveries()
+            .find(doc! { "campaign_id": campaign_id })
+            .sort(doc! { "user_id": 1 })
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn update_delivery(&self, delivery: &CampaignDelivery) -> ApiResult<()> {
+        self.deliveries().replace_one(doc! { "_id": delivery.id }, delivery).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MailStore for MongoStore {
+    async fn find_mail_thread(&self, staff_id: &ObjectId, user_id: &ObjectId, subject: &str) -> ApiResult<Option<MailThread>> {
+        Ok(self
+            .mail_threads()
+            .find_one(doc! { "staff_id": staff_id, "user_id": user_id, "subject": subject })
+            .await?)
+    }
+
+    async fn save_mail_thread(&self, thread: &MailThread) -> ApiResult<()> {
+        self.mail_threads().replace_one(doc! { "_id": thread.id }, thread).upsert(true).await?;
+        Ok(())
+    }
+
+    async fn insert_email(&self, record: &EmailRecord) -> ApiResult<()> {
+        self.emails().insert_one(record).await?;
+        Ok(())
+    }
+
+    async fn find_email(&self, email_id: &ObjectId) -> ApiResult<Option<EmailRecord>> {
+        Ok(self.emails().find_one(doc! { "_id": email_id }).await?)
+    }
+
+    async fn list_emails(&self, user_id: &ObjectId, limit: i64) -> ApiResult<Vec<EmailRecord>> {
+        Ok(self
+            .emails()
+            .find(doc! { "user_id": user_id })
+            .sort(doc! { "created_at": -1 })
+            .limit(limit)
+            .await?
+            .try_collect()
+            .await?)
+    }
+}
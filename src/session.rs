@@ -0,0 +1,84 @@
+use actix_web::{web, FromRequest, HttpRequest};
+use actix_web::http::header;
+use futures::future::{ready, Ready};
+use mongodb::bson::DateTime;
+use mongodb::bson::oid::ObjectId;
+
+use crate::config::Config;
+use crate::error::ApiResult;
+use crate::models::{LoginAttempt, Session};
+use crate::repository::Store;
+
+/// The address a request comes from. Forwarding headers can be set by any client, so they are only
+/// read when the connection comes from one of `trusted_proxies`.
+pub fn client_ip(req: &HttpRequest) -> Option<String> {
+    let peer = req.peer_addr()?.ip().to_string();
+    let trusted = req
+        .app_data::<web::Data<Config>>()
+        .is_some_and(|config| config.trusted_proxies.contains(&peer));
+    if !trusted {
+        return Some(peer);
+    }
+    req.connection_info().realip_remote_addr().map(str::to_string)
+}
+
+/// Where a login comes from.
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl FromRequest for ClientInfo {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &actix_web::HttpRequest, _payload: &mut actix_web::dev::Payload) -> Self::Future {
+        let ip = client_ip(req);
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string);
+        ready(Ok(ClientInfo { ip, user_agent }))
+    }
+}
+
+/// Record a login attempt on an existing account. `reason` tells why a failed attempt was refused.
+pub async fn record_login(
+    db: &dyn Store,
+    user_id: &ObjectId,
+    client: &ClientInfo,
+    reason: Option<&str>,
+) -> ApiResult<()> {
+    db.record_login(&LoginAttempt {
+        user_id: *user_id,
+        at: DateTime::now(),
+        ip: client.ip.clone(),
+        user_agent: client.user_agent.clone(),
+        success: reason.is_none(),
+        reason: reason.map(str::to_string),
+    })
+    .await
+}
+
+/// Open a session for a successful login, it lives as long as the token issued for it.
+pub async fn create_session(
+    db: &dyn Store,
+    user_id: &ObjectId,
+    client: &ClientInfo,
+    exp_hours: usize,
+) -> ApiResult<ObjectId> {
+    let expires_at = chrono::Utc::now() + chrono::Duration::hours(exp_hours as i64);
+    let session = Session {
+        id: ObjectId::new(),
+        user_id: *user_id,
+        created_at: DateTime::now(),
+        last_seen_at: DateTime::now(),
+        expires_at: DateTime::from_millis(expires_at.timestamp_millis()),
+        ip: client.ip.clone(),
+        user_agent: client.user_agent.clone(),
+        revoked: false,
+    };
+    db.insert_session(&session).await?;
+    Ok(session.id)
+}
@@ -1,14 +1,18 @@
 use fast_chemail::is_valid_email;
+use rand::distr::Alphanumeric;
+use rand::{rng, Rng};
+use mongodb::bson::DateTime;
 
 use crate::error::{ApiResult, ApiError, ApiErrorType};
 
 pub const MAX_USERNAME: usize = 32;
 pub const MAX_EMAIL: usize = 64;
-pub const MAX_PASSWORD: usize = 64;
-pub const MIN_PASSWORD: usize = 8;
+pub const MAX_PROFILE_FIELD: usize = 64;
+pub const MAX_AVATAR_URL: usize = 512;
+pub const LANGUAGES: &[&str] = &["zh", "en"];
 
 pub fn check_username(username: &str) -> ApiResult<()> {
-    if username.len() > MAX_USERNAME || username.len() == 0 {
+    if username.len() > MAX_USERNAME || username.is_empty() {
         return Err(ApiError::new(
             ApiErrorType::InvalidRequest,
             "Invalid username".to_string(),
@@ -17,14 +21,31 @@ pub fn check_username(username: &str) -> ApiResult<()> {
     Ok(())
 }
 
+/// Validate one of the extended profile fields. Empty values are allowed and clear the field.
+pub fn check_profile_field(field: &str, value: &str) -> ApiResult<()> {
+    let valid = value.is_empty() || match field {
+        "student_id" => value.len() <= MAX_PROFILE_FIELD && value.chars().all(|c| c.is_ascii_alphanumeric()),
+        "preferred_language" => LANGUAGES.contains(&value),
+        "avatar" => value.len() <= MAX_AVATAR_URL && value.starts_with("https://"),
+        _ => value.chars().count() <= MAX_PROFILE_FIELD,
+    };
+    if !valid {
+        return Err(ApiError::new(
+            ApiErrorType::InvalidRequest,
+            format!("Invalid {}", field),
+        ));
+    }
+    Ok(())
+}
+
 pub fn check_email(email: &str) -> ApiResult<()> {
-    if email.len() > MAX_EMAIL || email.len() == 0 {
+    if email.len() > MAX_EMAIL || email.is_empty() {
         return Err(ApiError::new(
             ApiErrorType::InvalidRequest,
             "Invalid email".to_string(),
         ));
     }
-    if !is_valid_email(&email) {
+    if !is_valid_email(email) {
         return Err(ApiError::new(
             ApiErrorType::InvalidRequest,
             "Invalid email".to_string(),
@@ -44,12 +65,19 @@ pub fn check_email_tsinghua(email: &str) -> ApiResult<()> {
     Ok(())
 }
 
-pub fn check_password(password: &str) -> ApiResult<()> {
-    if password.len() > MAX_PASSWORD || password.len() < MIN_PASSWORD {
-        return Err(ApiError::new(
-            ApiErrorType::InvalidRequest,
-            "Invalid password".to_string(),
-        ));
-    }
-    Ok(())
-}
\ No newline at end of file
+/// Render a stored BSON timestamp in the same local format used for `created_at`.
+pub fn format_datetime(t: &DateTime) -> String {
+    chrono::DateTime::from_timestamp_millis(t.timestamp_millis())
+        .unwrap_or_default()
+        .with_timezone(&chrono::Local)
+        .to_string()
+}
+
+/// Random alphanumeric code of `len` characters, as sent in verification emails.
+pub fn generate_code(len: usize) -> String {
+    rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
@@ -0,0 +1,261 @@
+use actix_web::http::StatusCode;
+use actix_web::test;
+use mongodb::bson::oid::ObjectId;
+use serde_json::{json, Value};
+
+use crate::common::*;
+
+#[actix_web::test]
+async fn register_verify_and_login() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let email = email_of("alice");
+
+    // students must use a university address
+    let (status, _) = call(&app, anonymous("/register", json!({ "username": "alice", "email": "alice@example.com", "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    ok(&app, anonymous("/register", json!({ "username": "alice", "email": email, "password": PASSWORD }))).await;
+
+    // not activated yet
+    let (status, _) = call(&app, anonymous("/login", json!({ "username": "alice", "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) = call(&app, test::TestRequest::get().uri("/verify_email/alice?code=wrong1")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let code = code_after(&ctx.mailbox.last_to(&email).body, "activation code is", 6);
+    ok(&app, test::TestRequest::get().uri(&format!("/verify_email/alice?code={}", code))).await;
+    // a code works once
+    let (status, _) = call(&app, test::TestRequest::get().uri(&format!("/verify_email/alice?code={}", code))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, anonymous("/register", json!({ "username": "alice", "email": email_of("other"), "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, anonymous("/register", json!({ "username": "alice2", "email": email, "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) = call(&app, anonymous("/login", json!({ "username": "alice", "password": "wrong-password" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let body = ok(&app, anonymous("/login", json!({ "username": "alice", "password": PASSWORD }))).await;
+    assert_eq!(body["must_change_password"], false);
+    let token = body["token"].as_str().unwrap();
+
+    let profile = ok(&app, get("/profile", token)).await;
+    assert_eq!(profile["username"], "alice");
+    assert_eq!(profile["email"], email);
+    assert_eq!(profile["staff_role"], json!(null));
+    assert_eq!(profile["permissions"], json!([]));
+
+    // students cannot use the staff login
+    let (status, _) = call(&app, anonymous("/login/admin", json!({ "username": "alice", "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, test::TestRequest::get().uri("/profile")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn magic_link_login() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    student(&ctx, &app, "bob").await;
+    let email = email_of("bob");
+
+    ok(&app, anonymous("/login/magic", json!({ "email": email }))).await;
+    let token = code_after(&ctx.mailbox.last_to(&email).body, "login code is", 32);
+    let (status, _) = call(&app, anonymous("/login/magic/confirm", json!({ "token": "not-the-token" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let body = ok(&app, anonymous("/login/magic/confirm", json!({ "token": token }))).await;
+    ok(&app, get("/profile", body["token"].as_str().unwrap())).await;
+    let (status, _) = call(&app, anonymous("/login/magic/confirm", json!({ "token": token }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // unknown addresses get the same answer and no mail
+    let stranger = email_of("nobody");
+    ok(&app, anonymous("/login/magic", json!({ "email": stranger }))).await;
+    assert_eq!(ctx.mailbox.count_to(&stranger), 0);
+
+    ok(&app, anonymous("/login/magic", json!({ "email": email }))).await;
+    ok(&app, anonymous("/login/magic", json!({ "email": email }))).await;
+    let (status, _) = call(&app, anonymous("/login/magic", json!({ "email": email }))).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+/// Answer a challenge from `/captcha`, which is only readable from the image.
+fn solve(ctx: &Context, challenge: &Value) -> String {
+    assert!(challenge["image"].as_str().unwrap().starts_with("data:image/svg+xml;base64,"));
+    let captcha_id = ObjectId::parse_str(challenge["id"].as_str().unwrap()).unwrap();
+    ctx.store.captcha_answer(&captcha_id).unwrap()
+}
+
+#[actix_web::test]
+async fn captcha_guards_registration_and_repeated_logins() {
+    let mut config = config();
+    config.captcha.enabled = true;
+    let ctx = Context::with_config(config).await;
+    let app = ctx.app().await;
+    let email = email_of("carol");
+
+    let register = |captcha| anonymous("/register", json!({ "username": "carol", "email": email, "password": PASSWORD, "captcha": captcha }));
+    let (status, _) = call(&app, register(json!(null))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let challenge = ok(&app, test::TestRequest::get().uri("/captcha")).await;
+    let answer = solve(&ctx, &challenge);
+    let (status, _) = call(&app, register(json!({ "id": challenge["id"], "answer": "-1" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    // a challenge can only be tried once
+    let (status, _) = call(&app, register(json!({ "id": challenge["id"], "answer": answer }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let challenge = ok(&app, test::TestRequest::get().uri("/captcha")).await;
+    let answer = solve(&ctx, &challenge);
+    ok(&app, register(json!({ "id": challenge["id"], "answer": answer }))).await;
+    let code = code_after(&ctx.mailbox.last_to(&email).body, "activation code is", 6);
+    ok(&app, test::TestRequest::get().uri(&format!("/verify_email/carol?code={}", code))).await;
+
+    for _ in 0..3 {
+        let (status, _) = call(&app, anonymous("/login", json!({ "username": "carol", "password": "wrong-password" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+    let (status, body) = call(&app, anonymous("/login", json!({ "username": "carol", "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    let challenge = ok(&app, test::TestRequest::get().uri("/captcha")).await;
+    let answer = solve(&ctx, &challenge);
+    ok(&app, anonymous("/login", json!({ "username": "carol", "password": PASSWORD, "captcha": { "id": challenge["id"], "answer": answer } }))).await;
+
+    // each address can only hold a few unanswered challenges
+    let from = |ip: &str| test::TestRequest::get().uri("/captcha").peer_addr(format!("{}:4000", ip).parse().unwrap());
+    for _ in 0..10 {
+        ok(&app, from("198.51.100.2")).await;
+    }
+    let (status, _) = call(&app, from("198.51.100.2")).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    ok(&app, from("198.51.100.3")).await;
+}
+
+#[actix_web::test]
+async fn profile_courses_and_sessions() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let token = student(&ctx, &app, "dave").await;
+
+    ok(&app, post("/profile/update", &token, json!({ "real_name": "Dave", "preferred_language": "en" }))).await;
+    let (status, _) = call(&app, post("/profile/update", &token, json!({ "avatar": "http://insecure" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, post("/profile/update", &token, json!({}))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let profile = ok(&app, get("/profile", &token)).await;
+    assert_eq!(profile["real_name"], "Dave");
+    assert_eq!(profile["preferred_language"], "en");
+    // an empty value clears the field
+    ok(&app, post("/profile/update", &token, json!({ "preferred_language": "" }))).await;
+    assert_eq!(ok(&app, get("/profile", &token)).await["preferred_language"], json!(null));
+
+    let courses = ok(&app, get("/profile/courses", &token)).await;
+    assert_eq!(courses["courses"][0]["enrolled"], true);
+    assert_eq!(courses["courses"][1]["enrolled"], false);
+    assert_eq!(courses["courses"][1]["requires_key"], true);
+    let (status, _) = call(&app, post("/profile/courses/enroll", &token, json!({ "course_id": "signals", "enrollment_key": "guess" }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = call(&app, post("/profile/courses/enroll", &token, json!({ "course_id": "missing" }))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    ok(&app, post("/profile/courses/enroll", &token, json!({ "course_id": "signals", "enrollment_key": "s1gnal" }))).await;
+    assert_eq!(ok(&app, get("/profile/courses", &token)).await["courses"][1]["enrolled"], true);
+
+    let export = ok(&app, get("/profile/export", &token)).await;
+    assert_eq!(export["account"]["username"], "dave");
+    assert!(export["account"].get("password").is_none());
+    assert_eq!(export["stats"].as_array().unwrap().len(), 2);
+    assert_eq!(export["emails"][0]["template"], "activation");
+    assert_eq!(export["emails"][0]["body"], json!(null));
+    // with everything else that is deleted along with the account
+    let records = &export["records"];
+    assert_eq!(records["sessions"].as_array().unwrap().len(), 1);
+    assert_eq!(records["login_history"][0]["success"], true);
+    for collection in ["activation_codes", "stats_events", "campaign_deliveries", "mail_threads", "sections"] {
+        assert!(records[collection].is_array(), "no {}", collection);
+    }
+
+    let (status, _) = call(&app, anonymous("/login", json!({ "username": "dave", "password": "wrong-password" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let other = login(&app, "/login", "dave", PASSWORD).await;
+    let sessions = ok(&app, get("/profile/sessions", &token)).await["sessions"].clone();
+    assert_eq!(sessions.as_array().unwrap().len(), 2);
+    let other_session = sessions
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|session| session["current"] == false)
+        .unwrap()["id"]
+        .clone();
+    ok(&app, post("/profile/sessions/revoke", &token, json!({ "session_id": other_session }))).await;
+    let (status, _) = call(&app, get("/profile", &other)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = call(&app, post("/profile/sessions/revoke", &token, json!({ "session_id": "bogus" }))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let history = ok(&app, get("/profile/login_history?limit=10", &token)).await["entries"].clone();
+    let reasons: Vec<_> = history.as_array().unwrap().iter().map(|entry| entry["reason"].clone()).collect();
+    assert_eq!(reasons, vec![json!(null), json!("invalid_password"), json!(null)]);
+}
+
+#[actix_web::test]
+async fn modify_account() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let token = student(&ctx, &app, "erin").await;
+    let other = login(&app, "/login", "erin", PASSWORD).await;
+
+    let (status, _) = call(&app, post("/modify/username", &token, json!({ "role": "users", "new_username": "erin2", "password": "wrong-password" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, post("/modify/username", &token, json!({ "role": "staff", "new_username": "erin2", "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    ok(&app, post("/modify/username", &token, json!({ "role": "users", "new_username": "erin2", "password": PASSWORD }))).await;
+    assert_eq!(ok(&app, get("/profile", &token)).await["username"], "erin2");
+
+    let new_password = "quiet-lantern-88";
+    ok(&app, post("/modify/password", &token, json!({ "role": "users", "current_password": PASSWORD, "new_password": new_password }))).await;
+    // the other session knew the old password, this one stays logged in
+    let (status, _) = call(&app, get("/profile", &other)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    ok(&app, get("/profile", &token)).await;
+    login(&app, "/login", "erin2", new_password).await;
+
+    let old_email = email_of("erin");
+    let new_email = email_of("erin.new");
+    ok(&app, post("/modify/email", &token, json!({ "role": "users", "new_email": new_email, "password": new_password }))).await;
+    let (status, _) = call(&app, post("/modify/email/confirm", &token, json!({ "role": "users", "code": "wrong1" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let code = code_after(&ctx.mailbox.last_to(&new_email).body, "confirmation code is", 6);
+    ok(&app, post("/modify/email/confirm", &token, json!({ "role": "users", "code": code }))).await;
+    assert_eq!(ok(&app, get("/profile", &token)).await["email"], new_email);
+    assert!(ctx.mailbox.last_to(&old_email).body.contains(&new_email));
+    // addresses of registrations still to be activated are taken as well
+    ok(&app, anonymous("/register", json!({ "username": "fay", "email": email_of("fay"), "password": PASSWORD }))).await;
+    let (status, body) = call(&app, post("/modify/email", &token, json!({ "role": "users", "new_email": email_of("fay"), "password": new_password }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(body.to_string().contains("Email already exists"), "{}", body);
+    // a pending change does not survive guessing
+    let other_email = email_of("erin.other");
+    ok(&app, post("/modify/email", &token, json!({ "role": "users", "new_email": other_email, "password": new_password }))).await;
+    let code = code_after(&ctx.mailbox.last_to(&other_email).body, "confirmation code is", 6);
+    for _ in 0..4 {
+        let (status, _) = call(&app, post("/modify/email/confirm", &token, json!({ "role": "users", "code": "wrong1" }))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+    let (status, _) = call(&app, post("/modify/email/confirm", &token, json!({ "role": "users", "code": "wrong1" }))).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let (status, _) = call(&app, post("/modify/email/confirm", &token, json!({ "role": "users", "code": code }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, _) = call(&app, post("/modify/delete/cancel", &token, json!({ "role": "users" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let body = ok(&app, post("/modify/delete", &token, json!({ "role": "users" }))).await;
+    assert!(body["deletion_scheduled_at"].is_string());
+    assert!(ok(&app, get("/profile", &token)).await["deletion_scheduled_at"].is_string());
+    ok(&app, post("/modify/delete/cancel", &token, json!({ "role": "users" }))).await;
+    assert!(ok(&app, get("/profile", &token)).await.get("deletion_scheduled_at").is_none());
+
+    // the only super admin cannot delete their own account
+    let root = root_token(&app).await;
+    let (status, _) = call(&app, post("/modify/delete", &root, json!({ "role": "admins" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,144 @@
+use actix_web::http::StatusCode;
+use actix_web::test;
+use serde_json::json;
+
+use crate::common::*;
+
+#[actix_web::test]
+async fn send_targeted_campaigns() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    let ann = student(&ctx, &app, "ann").await;
+    let bob = student(&ctx, &app, "bob").await;
+    student(&ctx, &app, "cat").await;
+    ok(&app, post("/stats", &ann, json!({ "tag": ["ohm"] }))).await;
+    ok(&app, post("/stats/conv", &bob, json!({}))).await;
+
+    let names = |body: &serde_json::Value| -> Vec<String> {
+        body["recipients"].as_array().unwrap().iter().map(|r| r["username"].as_str().unwrap().to_string()).collect()
+    };
+    let preview = ok(&app, post("/campaigns/preview", &root, json!({ "audience": { "inactive_days": 7 } }))).await;
+    assert_eq!(names(&preview), vec!["cat"]);
+    let preview = ok(&app, post("/campaigns/preview", &root, json!({ "audience": { "tag": "ohm" } }))).await;
+    assert_eq!(names(&preview), vec!["bob", "cat"]);
+    let audience = json!({ "tag": "ohm", "tag_below": 2, "usernames": ["ann", "bob", "ghost"] });
+    let preview = ok(&app, post("/campaigns/preview", &root, json!({ "audience": audience }))).await;
+    assert_eq!(names(&preview), vec!["ann", "bob"]);
+    assert_eq!(preview["unknown"], json!(["ghost"]));
+    // a preview sends nothing
+    assert_eq!(ctx.mailbox.count_to(&email_of("ann")), 1);
+
+    let (status, _) = call(&app, post("/campaigns/preview", &root, json!({ "audience": { "tag_below": 2 } }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, post("/courses/signals/campaigns/preview", &root, json!({ "audience": { "tag": "ohm" } }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, post("/campaigns/preview", &root, json!({ "audience": { "section_id": "000000000000000000000000" } }))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = call(&app, post("/campaigns/preview", &ann, json!({}))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let request = json!({ "subject": "Office hours", "body": "Hi {username}, see you", "audience": { "usernames": ["ghost"] } });
+    let (status, _) = call(&app, post("/campaigns/create", &root, request)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let request = json!({ "subject": "Office hours", "body": "Hi {username}, see you", "audience": { "tag": "ohm" } });
+    let campaign = ok(&app, post("/campaigns/create", &root, request)).await;
+    assert_eq!(campaign["recipients"], 2);
+    assert_eq!(campaign["status"], "queued");
+    ctx.run_jobs().await;
+    let mail = ctx.mailbox.last_to(&email_of("bob"));
+    assert_eq!(mail.subject, "Office hours");
+    assert!(mail.body.starts_with("Hi bob, see you"), "{}", mail.body);
+    assert!(mail.body.contains("root <root@example.com>"));
+    assert_eq!(ctx.mailbox.count_to(&email_of("cat")), 2);
+    assert_eq!(ctx.mailbox.count_to(&email_of("ann")), 1);
+
+    let id = campaign["id"].as_str().unwrap();
+    let campaign = ok(&app, get(&format!("/campaigns/get/{}", id), &root)).await;
+    assert_eq!(campaign["status"], "succeeded");
+    assert_eq!(campaign["sent"], 2);
+    assert_eq!(campaign["audience"]["tag"], "ohm");
+    let deliveries = campaign["deliveries"].as_array().unwrap();
+    assert_eq!(deliveries.len(), 2);
+    assert!(deliveries.iter().all(|delivery| delivery["status"] == "sent"));
+    assert_eq!(deliveries[0]["email"], email_of("bob"));
+    let (status, _) = call(&app, get(&format!("/courses/signals/campaigns/get/{}", id), &root)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let history = ok(&app, get("/users/emails/bob", &root)).await["emails"].clone();
+    assert_eq!(history[0]["template"], "campaign");
+    assert_eq!(history[0]["course_id"], "default");
+
+    let campaigns = ok(&app, get("/campaigns/list", &root)).await["campaigns"].clone();
+    assert_eq!(campaigns[0]["id"], id);
+    assert_eq!(campaigns[0]["created_by"], "root");
+    let job = ok(&app, get(&format!("/jobs/get/{}", campaigns[0]["job_id"].as_str().unwrap()), &root)).await;
+    assert_eq!(job["type"], "campaign");
+    let entries = ok(&app, get("/audit/list?action=email.campaign", &root)).await["entries"].clone();
+    assert_eq!(entries[0]["after"]["recipients"], 2);
+}
+
+/// The unsubscribe token linked from a mail body.
+fn unsubscribe_token(mail: &Mail) -> String {
+    let start = mail.body.find("?token=").unwrap_or_else(|| panic!("No unsubscribe link in {:?}", mail.body)) + 7;
+    mail.body[start..].split_whitespace().next().unwrap().to_string()
+}
+
+#[actix_web::test]
+async fn honor_notification_preferences() {
+    let mut config = config();
+    config.notifications.unsubscribe_url = Some("https://ywt.example.com/api/unsubscribe".to_string());
+    let ctx = Context::with_config(config).await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    let dan = student(&ctx, &app, "dan").await;
+    let eve = student(&ctx, &app, "eve").await;
+    ok(&app, post("/stats/conv", &dan, json!({}))).await;
+    ok(&app, post("/stats/conv", &eve, json!({}))).await;
+
+    let all_on = json!({ "announcements": true, "ta_messages": true, "weekly_report": true });
+    assert_eq!(ok(&app, get("/profile/notifications", &dan)).await, all_on);
+    let (status, _) = call(&app, post("/profile/notifications", &dan, json!({ "newsletter": false }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // one click in a mail client turns off what the email was about
+    let message = json!({ "username": "dan", "title": "Lab", "content": "Bring your kit." });
+    ok(&app, post("/send_email/single", &root, message.clone())).await;
+    let mail = ctx.mailbox.last_to(&email_of("dan"));
+    assert!(mail.headers.contains("List-Unsubscribe: <https://ywt.example.com/api/unsubscribe?token="), "{}", mail.headers);
+    assert!(mail.headers.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"), "{}", mail.headers);
+    let token = unsubscribe_token(&mail);
+    let (status, _) = call(&app, anonymous(&format!("/unsubscribe?token={}x", token), json!({}))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let done = ok(&app, anonymous(&format!("/unsubscribe?token={}", token), json!({}))).await;
+    assert_eq!(done["category"], "ta_messages");
+    let (status, _) = call(&app, post("/send_email/single", &root, message)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(ok(&app, get("/profile/notifications", &dan)).await["ta_messages"], false);
+
+    let preferences = ok(&app, post("/profile/notifications", &eve, json!({ "weekly_report": false }))).await;
+    assert_eq!(preferences["weekly_report"], false);
+    let run = ok(&app, post("/reports/run", &root, json!({}))).await;
+    ctx.run_jobs().await;
+    assert!(ctx.mailbox.last_to(&email_of("dan")).body.contains("交谈 1 轮次"));
+    assert!(!ctx.mailbox.last_to(&email_of("eve")).body.contains("交谈"));
+    let job = ok(&app, get(&format!("/jobs/get/{}", run["job_id"].as_str().unwrap()), &root)).await;
+    assert_eq!(job["result"]["sent"], 1);
+    assert_eq!(job["result"]["unsubscribed"], 1);
+
+    // the weekly report links to its own category, opening the link only shows what it is about
+    let token = unsubscribe_token(&ctx.mailbox.last_to(&email_of("dan")));
+    let link = ok(&app, test::TestRequest::get().uri(&format!("/unsubscribe?token={}", token))).await;
+    assert_eq!(link, json!({ "category": "weekly_report", "unsubscribed": false }));
+    assert_eq!(ok(&app, get("/profile/notifications", &dan)).await["weekly_report"], true);
+    let done = ok(&app, anonymous(&format!("/unsubscribe?token={}", token), json!({}))).await;
+    assert_eq!(done["category"], "weekly_report");
+    let link = ok(&app, test::TestRequest::get().uri(&format!("/unsubscribe?token={}", token))).await;
+    assert_eq!(link["unsubscribed"], true);
+    assert_eq!(ok(&app, get("/profile/notifications", &dan)).await["weekly_report"], false);
+    ok(&app, post("/profile/notifications", &eve, json!({ "announcements": false, "weekly_report": true }))).await;
+    let preview = ok(&app, post("/campaigns/preview", &root, json!({}))).await;
+    assert_eq!(preview["recipients"].as_array().unwrap().len(), 1);
+    assert_eq!(preview["unsubscribed"], json!(["eve"]));
+    assert_eq!(ok(&app, get("/profile/notifications", &eve)).await["weekly_report"], true);
+}
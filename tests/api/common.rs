@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+use actix_http::Request;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use base64::{engine::general_purpose, Engine};
+use lettre::SmtpTransport;
+use mongodb::bson::oid::ObjectId;
+use serde_json::{json, Value};
+
+use ywt::api;
+use ywt::api::problem::{QBankEntry, QBanks};
+use ywt::config::Config;
+use ywt::jobs;
+use ywt::live::LiveHub;
+use ywt::mail;
+use ywt::models::{Account, Problem};
+use ywt::password::PasswordPolicy;
+use ywt::repository::{AccountStore, MemoryStore, Store};
+
+pub const ADMIN: &str = "root";
+pub const ADMIN_PASSWORD: &str = "root-password-42";
+pub const PASSWORD: &str = "plum-harbor-17";
+
+/// A message received by the fake SMTP server.
+#[derive(Clone, Debug)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    /// Raw header lines
+    pub headers: String,
+}
+
+impl Mail {
+    /// The value of a header, with folded lines joined.
+    pub fn header(&self, name: &str) -> Option<String> {
+        let prefix = format!("{}: ", name);
+        let mut lines = self.headers.lines().skip_while(|line| !line.starts_with(&prefix));
+        let mut value = lines.next()?[prefix.len()..].to_string();
+        for line in lines.take_while(|line| line.starts_with(' ') || line.starts_with('\t')) {
+            value.push(' ');
+            value.push_str(line.trim());
+        }
+        Some(value)
+    }
+}
+
+/// An SMTP server on a local port that accepts every message and keeps it.
+#[derive(Clone)]
+pub struct Mailbox {
+    pub port: u16,
+    received: Arc<Mutex<Vec<Mail>>>,
+}
+
+impl Mailbox {
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let inbox = received.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let inbox = inbox.clone();
+                std::thread::spawn(move || serve_smtp(stream, inbox));
+            }
+        });
+        Mailbox { port, received }
+    }
+
+    pub fn all(&self) -> Vec<Mail> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// The latest message sent to `to`.
+    pub fn last_to(&self, to: &str) -> Mail {
+        self.all()
+            .into_iter()
+            .rev()
+            .find(|mail| mail.to == to)
+            .unwrap_or_else(|| panic!("No mail sent to {}", to))
+    }
+
+    pub fn count_to(&self, to: &str) -> usize {
+        self.all().iter().filter(|mail| mail.to == to).count()
+    }
+}
+
+fn serve_smtp(stream: TcpStream, inbox: Arc<Mutex<Vec<Mail>>>) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut reply = |line: &str| writer.write_all(format!("{}\r\n", line).as_bytes());
+    if reply("220 localhost ESMTP").is_err() {
+        return;
+    }
+    let mut recipients = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let command = line.trim_end().to_ascii_uppercase();
+        let response = if command.starts_with("EHLO") || command.starts_with("HELO") {
+            "250 localhost"
+        } else if command.starts_with("RCPT TO:") {
+            let address = line.trim_end()[8..].trim_matches(|c| c == '<' || c == '>' || c == ' ');
+            recipients.push(address.to_string());
+            "250 OK"
+        } else if command == "DATA" {
+            reply("354 End data with <CR><LF>.<CR><LF>").ok();
+            let mut data = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return;
+                }
+                if line == ".\r\n" {
+                    break;
+                }
+                data.push_str(line.strip_prefix('.').unwrap_or(&line));
+            }
+            let (headers, subject, body) = parse_message(&data);
+            let mut inbox = inbox.lock().unwrap();
+            for to in recipients.drain(..) {
+                inbox.push(Mail { to, subject: subject.clone(), body: body.clone(), headers: headers.clone() });
+            }
+            "250 OK"
+        } else if command == "QUIT" {
+            reply("221 Bye").ok();
+            return;
+        } else {
+            "250 OK"
+        };
+        if reply(response).is_err() {
+            return;
+        }
+    }
+}
+
+/// Headers, subject and decoded body of a single-part message.
+fn parse_message(data: &str) -> (String, String, String) {
+    let (headers, body) = data.split_once("\r\n\r\n").unwrap_or((data, ""));
+    let header = |name: &str| {
+        headers
+            .lines()
+            .find_map(|line| line.strip_prefix(name).map(|value| value.trim().to_string()))
+            .unwrap_or_default()
+    };
+    let subject = decode_header(&header("Subject:"));
+    let body = match header("Content-Transfer-Encoding:").as_str() {
+        "base64" => {
+            let joined: String = body.split_whitespace().collect();
+            String::from_utf8(general_purpose::STANDARD.decode(joined).unwrap()).unwrap()
+        }
+        "quoted-printable" => decode_quoted_printable(body),
+        _ => body.to_string(),
+    };
+    (headers.to_string(), subject, body.replace("\r\n", "\n"))
+}
+
+/// Decode RFC 2047 words of the form `=?utf-8?b?...?=`, leaving plain subjects as they are.
+fn decode_header(value: &str) -> String {
+    value
+        .split_whitespace()
+        .map(|word| {
+            let lower = word.to_ascii_lowercase();
+            match lower.strip_prefix("=?utf-8?b?").and_then(|rest| rest.strip_suffix("?=")) {
+                Some(_) => {
+                    let encoded = &word[10..word.len() - 2];
+                    String::from_utf8(general_purpose::STANDARD.decode(encoded).unwrap()).unwrap()
+                }
+                None => format!("{} ", word),
+            }
+        })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+fn decode_quoted_printable(body: &str) -> String {
+    let mut bytes = Vec::new();
+    let joined = body.replace("=\r\n", "");
+    let mut chars = joined.bytes();
+    while let Some(b) = chars.next() {
+        if b == b'=' {
+            let hex: Vec<u8> = chars.by_ref().take(2).collect();
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap());
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).unwrap()
+}
+
+/// Everything a test app is built from, shared between the app and the test's own checks.
+pub struct Context {
+    pub store: Arc<MemoryStore>,
+    pub mailbox: Mailbox,
+    pub config: Config,
+    pub passwords: web::Data<PasswordPolicy>,
+    pub hub: web::Data<LiveHub>,
+    pub qbanks: QBanks,
+}
+
+pub fn config() -> Config {
+    serde_json::from_value(json!({
+        "bind_address": "127.0.0.1",
+        "bind_port": 0,
+        "mongo_uri": "mongodb://unused",
+        "mongo_db": "unused",
+        "admin_username": ADMIN,
+        "admin_email": "root@example.com",
+        "smtp_server": "127.0.0.1",
+        "smtp_port": 0,
+        "smtp_username": "bot@example.com",
+        "password": { "argon2_memory_kib": 64, "argon2_iterations": 1, "argon2_parallelism": 1 },
+        "magic_link": { "enabled": true },
+        "courses": [
+            { "id": "default", "name": "Circuits" },
+            {
+                "id": "signals",
+                "name": "Signals",
+                "qbank_collection": "signals_qbank",
+                "tags": ["fourier", "laplace"],
+                "enrollment_key": "s1gnal",
+                "report_subject": "Signals weekly",
+                "report_template": "Hi {username}, {conversation} conversations about {tags}."
+            }
+        ]
+    }))
+    .unwrap()
+}
+
+impl Context {
+    pub async fn new() -> Self {
+        Context::with_config(config()).await
+    }
+
+    /// A fresh store holding the super admin `root` and one problem per course.
+    pub async fn with_config(mut config: Config) -> Self {
+        mail::prepare(&mut config).unwrap();
+        let store = Arc::new(MemoryStore::new());
+        let passwords = web::Data::new(PasswordPolicy::new(&config.password).unwrap());
+        let admin = Account {
+            id: ObjectId::new(),
+            username: ADMIN.to_string(),
+            email: "root@example.com".to_string(),
+            password: passwords.hash(ADMIN_PASSWORD).unwrap(),
+            created_at: chrono::Local::now().to_string(),
+            created_by: None,
+            staff_role: Some("super_admin".to_string()),
+            disabled: false,
+            course_ids: None,
+            section_ids: Vec::new(),
+            student_id: None,
+            real_name: None,
+            preferred_language: None,
+            avatar: None,
+            suspension: None,
+            must_change_password: false,
+            deletion_scheduled_at: None,
+            unsubscribed: Vec::new(),
+        };
+        store.insert_account("admins", &admin).await.unwrap();
+        store.insert_problem("qbank", Problem {
+            id: "p1".to_string(),
+            tags: vec!["ohm".to_string()],
+            image: Some(mongodb::bson::Binary {
+                subtype: mongodb::bson::spec::BinarySubtype::Generic,
+                bytes: b"png".to_vec(),
+            }),
+        });
+
+        let mut qbanks = HashMap::new();
+        let entry = |id: &str, tag: &str| QBankEntry { id: id.to_string(), tags: vec![tag.to_string()], path: format!("{}.png", id) };
+        qbanks.insert("default".to_string(), vec![entry("p1", "ohm"), entry("p2", "kcl")]);
+        qbanks.insert("signals".to_string(), vec![entry("s1", "fourier")]);
+
+        Context {
+            store,
+            mailbox: Mailbox::start(),
+            config,
+            passwords,
+            hub: web::Data::new(LiveHub::new()),
+            qbanks,
+        }
+    }
+
+    /// A transport delivering to the mailbox.
+    pub fn mailer(&self) -> SmtpTransport {
+        SmtpTransport::builder_dangerous("127.0.0.1").port(self.mailbox.port).build()
+    }
+
+    /// Run the queued jobs, as the workers of the server would.
+    pub async fn run_jobs(&self) {
+        while jobs::run_next(self.store.as_ref(), &self.mailer(), &self.config, "test").await.unwrap() {}
+    }
+
+    pub async fn app(&self) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = actix_web::Error> {
+        let mailer = self.mailer();
+        let store: web::Data<dyn Store> = web::Data::from(self.store.clone() as Arc<dyn Store>);
+        test::init_service(
+            App::new()
+                .app_data(store)
+                .app_data(web::Data::new(mailer))
+                .app_data(web::Data::new(self.config.clone()))
+                .app_data(web::Data::new(self.qbanks.clone()))
+                .app_data(self.passwords.clone())
+                .app_data(self.hub.clone())
+                .configure(api::routes),
+        )
+        .await
+    }
+}
+
+/// Send a request, returning its status and JSON body (`null` when the body is not JSON).
+pub async fn call<S, B>(app: &S, req: test::TestRequest) -> (StatusCode, Value)
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let response = test::call_service(app, req.to_request()).await;
+    let status = response.status();
+    let body = test::read_body(response).await;
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+pub fn get(uri: &str, token: &str) -> test::TestRequest {
+    test::TestRequest::get().uri(uri).insert_header(("Authorization", format!("Bearer {}", token)))
+}
+
+pub fn post(uri: &str, token: &str, body: Value) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri(uri)
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .set_json(body)
+}
+
+pub fn anonymous(uri: &str, body: Value) -> test::TestRequest {
+    test::TestRequest::post().uri(uri).set_json(body)
+}
+
+/// Assert a successful response and return its body.
+pub async fn ok<S, B>(app: &S, req: test::TestRequest) -> Value
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let (status, body) = call(app, req).await;
+    assert_eq!(status, StatusCode::OK, "unexpected response {}", body);
+    body
+}
+
+/// The first run of `len` alphanumeric characters following `marker` in a mail body.
+pub fn code_after(body: &str, marker: &str, len: usize) -> String {
+    let start = body.find(marker).unwrap_or_else(|| panic!("{:?} not in {:?}", marker, body)) + marker.len();
+    body[start..].trim_start().chars().take(len).collect()
+}
+
+pub fn email_of(username: &str) -> String {
+    format!("{}@mails.tsinghua.edu.cn", username)
+}
+
+/// Register and activate a student through the API, returning a login token.
+pub async fn student<S, B>(ctx: &Context, app: &S, username: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let email = email_of(username);
+    ok(app, anonymous("/register", json!({ "username": username, "email": email, "password": PASSWORD }))).await;
+    let code = code_after(&ctx.mailbox.last_to(&email).body, "activation code is", 6);
+    ok(app, test::TestRequest::get().uri(&format!("/verify_email/{}?code={}", username, code))).await;
+    login(app, "/login", username, PASSWORD).await
+}
+
+pub async fn login<S, B>(app: &S, route: &str, username: &str, password: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let body = ok(app, anonymous(route, json!({ "username": username, "password": password }))).await;
+    body["token"].as_str().unwrap().to_string()
+}
+
+pub async fn root_token<S, B>(app: &S) -> String
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    login(app, "/login/admin", ADMIN, ADMIN_PASSWORD).await
+}
+
+/// Create a staff account with `staff_role`, returning a login token.
+pub async fn staff<S, B>(app: &S, root: &str, username: &str, staff_role: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let email = format!("{}@example.com", username);
+    ok(app, post("/register/admin", root, json!({ "username": username, "email": email, "password": PASSWORD }))).await;
+    ok(app, post("/admins/role", root, json!({ "username": username, "staff_role": staff_role }))).await;
+    login(app, "/login/admin", username, PASSWORD).await
+}
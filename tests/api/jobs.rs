@@ -0,0 +1,119 @@
+use actix_web::http::StatusCode;
+use mongodb::bson::{doc, DateTime};
+use mongodb::bson::oid::ObjectId;
+use serde_json::json;
+
+use ywt::jobs;
+use ywt::models::JobProgress;
+use ywt::repository::{AccountStore, JobStore};
+
+use crate::common::*;
+
+/// A moment every heartbeat so far is older than.
+fn later() -> DateTime {
+    DateTime::from_millis(DateTime::now().timestamp_millis() + 60_000)
+}
+
+#[actix_web::test]
+async fn queue_and_cancel_jobs() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    let ian = staff(&app, &root, "ian", "instructor").await;
+    let tess = staff(&app, &root, "tess", "ta").await;
+    let token = student(&ctx, &app, "zed").await;
+    ok(&app, post("/stats/conv", &token, json!({}))).await;
+
+    let cleared = ok(&app, post("/stats/clear", &ian, json!({}))).await["job_id"].as_str().unwrap().to_string();
+    let report = ok(&app, get("/send_email", &ian)).await["job_id"].as_str().unwrap().to_string();
+    let jobs = ok(&app, get("/jobs/list", &ian)).await["jobs"].clone();
+    assert_eq!(jobs.as_array().unwrap().len(), 2);
+    assert_eq!(jobs[0]["id"], report.as_str());
+    assert_eq!(jobs[0]["status"], "queued");
+    assert_eq!(jobs[0]["created_by"], "ian");
+    assert_eq!(jobs[1]["type"], "clear_stats");
+    // only the jobs of others need `jobs.manage`
+    assert_eq!(ok(&app, get("/jobs/list", &tess)).await["jobs"], json!([]));
+    let (status, _) = call(&app, get(&format!("/jobs/get/{}", report), &tess)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(ok(&app, get("/jobs/list?limit=1", &root)).await["jobs"].as_array().unwrap().len(), 1);
+    let (status, _) = call(&app, get("/jobs/list", &token)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let job = ok(&app, post("/jobs/cancel", &ian, json!({ "id": report }))).await;
+    assert_eq!(job["status"], "cancelled");
+    let (status, _) = call(&app, post("/jobs/cancel", &ian, json!({ "id": report }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let mails = ctx.mailbox.all().len();
+    ctx.run_jobs().await;
+    assert_eq!(ctx.mailbox.all().len(), mails);
+    let job = ok(&app, get(&format!("/jobs/get/{}", cleared), &root)).await;
+    assert_eq!(job["status"], "succeeded");
+    assert_eq!(job["attempts"], 1);
+    assert_eq!(job["progress"], json!({ "done": 1, "total": 1 }));
+    assert_eq!(job["result"], json!({ "cleared": 1 }));
+
+    // a running job stops at its next step
+    let report = ok(&app, get("/send_email", &ian)).await["job_id"].as_str().unwrap().to_string();
+    let claimed = ctx.store.claim_job("elsewhere").await.unwrap().unwrap();
+    assert_eq!(claimed.id.to_hex(), report);
+    let job = ok(&app, post("/jobs/cancel", &ian, json!({ "id": report }))).await;
+    assert_eq!(job["status"], "running");
+    assert_eq!(job["cancel_requested"], true);
+    assert!(!ctx.store.update_job_progress(&claimed.id, &JobProgress::default(), None).await.unwrap());
+    // its worker died before noticing, so recovery finishes it
+    assert_eq!(jobs::recover(ctx.store.as_ref(), later()).await.unwrap(), 0);
+    assert_eq!(ok(&app, get(&format!("/jobs/get/{}", report), &ian)).await["status"], "cancelled");
+
+    let actions: Vec<String> = ctx.store.audit_log().into_iter().map(|entry| entry.action).collect();
+    assert_eq!(actions.iter().filter(|action| *action == "jobs.cancel").count(), 2);
+}
+
+#[actix_web::test]
+async fn resume_interrupted_jobs() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    for username in ["amy", "bob"] {
+        let token = student(&ctx, &app, username).await;
+        ok(&app, post("/stats/conv", &token, json!({}))).await;
+    }
+    let mut ids: Vec<ObjectId> = Vec::new();
+    for username in ["amy", "bob"] {
+        ids.push(ctx.store.find_account_by_username("users", username).await.unwrap().unwrap().id);
+    }
+    ids.sort();
+    let first = ctx.store.find_account_in("users", &ids[0]).await.unwrap().unwrap();
+    let second = ctx.store.find_account_in("users", &ids[1]).await.unwrap().unwrap();
+    let (first_mails, second_mails) = (ctx.mailbox.count_to(&first.email), ctx.mailbox.count_to(&second.email));
+
+    // a worker emailed the first student, then the server went down
+    let report = ok(&app, get("/send_email", &root)).await["job_id"].as_str().unwrap().to_string();
+    let claimed = ctx.store.claim_job("crashed").await.unwrap().unwrap();
+    let progress = JobProgress { done: 1, total: 2, cursor: Some(ids[0]) };
+    let result = doc! { "sent": 1_i64, "failed": [] };
+    ctx.store.update_job_progress(&claimed.id, &progress, Some(&result)).await.unwrap();
+    // heartbeats within the window keep the job with its worker
+    assert_eq!(jobs::recover(ctx.store.as_ref(), DateTime::MIN).await.unwrap(), 0);
+    assert_eq!(jobs::recover(ctx.store.as_ref(), later()).await.unwrap(), 1);
+    ctx.run_jobs().await;
+
+    assert_eq!(ctx.mailbox.count_to(&first.email), first_mails);
+    assert_eq!(ctx.mailbox.count_to(&second.email), second_mails + 1);
+    let job = ok(&app, get(&format!("/jobs/get/{}", report), &root)).await;
+    assert_eq!(job["status"], "succeeded");
+    assert_eq!(job["attempts"], 2);
+    assert_eq!(job["progress"], json!({ "done": 2, "total": 2 }));
+    assert_eq!(job["result"], json!({ "sent": 2, "unsubscribed": 0, "failed": [] }));
+
+    // a job whose workers keep dying is given up
+    let report = ok(&app, get("/send_email", &root)).await["job_id"].as_str().unwrap().to_string();
+    for _ in 0..jobs::MAX_ATTEMPTS {
+        ctx.store.claim_job("crashed").await.unwrap().unwrap();
+        jobs::recover(ctx.store.as_ref(), later()).await.unwrap();
+    }
+    let job = ok(&app, get(&format!("/jobs/get/{}", report), &root)).await;
+    assert_eq!(job["status"], "failed");
+    assert_eq!(job["error"], "Interrupted too many times");
+    assert!(ctx.store.claim_job("test").await.unwrap().is_none());
+}
@@ -0,0 +1,180 @@
+use actix_web::http::StatusCode;
+use serde_json::json;
+
+use ywt::config::Config;
+use ywt::mail;
+
+use crate::common::*;
+
+/// Base64 Ed25519 secret key, for signing only in tests.
+const DKIM_KEY: &str = "AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA=";
+
+fn mail_config(mail: serde_json::Value) -> Config {
+    let mut config = config();
+    config.mail = serde_json::from_value(mail).unwrap();
+    config
+}
+
+#[actix_web::test]
+async fn senders_and_dkim_from_config() {
+    let config = mail_config(json!({
+        "from": "YWT Staff <staff@ywt.example.org>",
+        "reply_to": "help@ywt.example.org",
+        "senders": { "account": { "from": "YWT Accounts <accounts@ywt.example.org>" } },
+        "dkim": { "domain": "ywt.example.org", "selector": "mail", "algorithm": "ed25519", "private_key": DKIM_KEY }
+    }));
+    let ctx = Context::with_config(config).await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    student(&ctx, &app, "ann").await;
+
+    let mail = ctx.mailbox.last_to(&email_of("ann"));
+    assert_eq!(mail.header("From").unwrap(), "\"YWT Accounts\" <accounts@ywt.example.org>");
+    assert_eq!(mail.header("Reply-To").unwrap(), "help@ywt.example.org");
+    assert!(mail.header("Message-ID").unwrap().ends_with("@ywt.example.org>"));
+    let signature = mail.header("DKIM-Signature").unwrap();
+    assert!(signature.contains("a=ed25519-sha256"), "{}", signature);
+    assert!(signature.contains("d=ywt.example.org"), "{}", signature);
+    assert!(signature.contains("s=mail"), "{}", signature);
+
+    // the weekly report has no override and takes the defaults
+    ok(&app, post("/reports/run", &root, json!({}))).await;
+    ctx.run_jobs().await;
+    let mail = ctx.mailbox.last_to(&email_of("ann"));
+    assert_eq!(mail.header("From").unwrap(), "\"YWT Staff\" <staff@ywt.example.org>");
+    assert!(mail.header("DKIM-Signature").is_some());
+}
+
+#[actix_web::test]
+async fn staff_emails_thread_by_subject() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    student(&ctx, &app, "bob").await;
+
+    let send = |title: &str| post("/send_email/single", &root, json!({ "username": "bob", "title": title, "content": "See you." }));
+    ok(&app, send("Lab")).await;
+    let first = ctx.mailbox.last_to(&email_of("bob"));
+    assert_eq!(first.header("Reply-To").unwrap(), "root <root@example.com>");
+    assert!(first.header("In-Reply-To").is_none());
+    let first_id = first.header("Message-ID").unwrap();
+
+    ok(&app, send("Re: Lab")).await;
+    let second = ctx.mailbox.last_to(&email_of("bob"));
+    let second_id = second.header("Message-ID").unwrap();
+    assert_ne!(second_id, first_id);
+    assert_eq!(second.header("In-Reply-To").unwrap(), first_id);
+    assert_eq!(second.header("References").unwrap(), first_id);
+
+    ok(&app, send("Lab")).await;
+    let third = ctx.mailbox.last_to(&email_of("bob"));
+    assert_eq!(third.header("In-Reply-To").unwrap(), second_id);
+    assert_eq!(third.header("References").unwrap(), format!("{} {}", first_id, second_id));
+
+    // another subject starts another thread
+    ok(&app, send("Midterm")).await;
+    assert!(ctx.mailbox.last_to(&email_of("bob")).header("In-Reply-To").is_none());
+}
+
+#[test]
+fn prepare_checks_mail_config() {
+    let mut config = mail_config(json!({}));
+    mail::prepare(&mut config).unwrap();
+
+    for invalid in [
+        json!({ "from": "not an address" }),
+        json!({ "senders": { "newsletter": { "from": "news@example.com" } } }),
+        json!({ "senders": { "ta_messages": { "reply_to": "ta@" } } }),
+        json!({ "dkim": { "domain": "example.com", "selector": "mail" } }),
+        json!({ "dkim": { "domain": "example.com", "selector": "mail", "private_key": "not a key" } }),
+        json!({ "dkim": { "domain": "example.com", "selector": "mail", "algorithm": "dsa", "private_key": DKIM_KEY } }),
+        json!({ "dkim": { "domain": "example.com", "selector": "mail", "private_key_path": "/nonexistent/dkim.pem" } }),
+    ] {
+        let mut config = mail_config(invalid.clone());
+        assert!(mail::prepare(&mut config).is_err(), "{}", invalid);
+    }
+
+    let path = std::env::temp_dir().join(format!("ywt-dkim-{}.key", std::process::id()));
+    std::fs::write(&path, format!("{}\n", DKIM_KEY)).unwrap();
+    let mut config = mail_config(json!({
+        "dkim": { "domain": "example.com", "selector": "mail", "algorithm": "ed25519", "private_key_path": path }
+    }));
+    mail::prepare(&mut config).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // only the parsed key is kept
+    let dkim = config.mail.dkim.unwrap();
+    assert!(dkim.signer.is_some() && dkim.private_key.is_none());
+}
+
+#[actix_web::test]
+async fn email_history_and_resend() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    let ann = student(&ctx, &app, "ann").await;
+    ok(&app, post("/send_email/single", &root, json!({ "username": "ann", "title": "Lab", "content": "Bring your kit." }))).await;
+    let lab = ctx.mailbox.last_to(&email_of("ann"));
+    ok(&app, post("/reports/run", &root, json!({}))).await;
+    ctx.run_jobs().await;
+
+    let emails = ok(&app, get("/users/emails/ann", &root)).await["emails"].clone();
+    let templates: Vec<&str> = emails.as_array().unwrap().iter().map(|email| email["template"].as_str().unwrap()).collect();
+    assert_eq!(templates, vec!["weekly_report", "staff_message", "activation"]);
+    assert!(emails.as_array().unwrap().iter().all(|email| email["status"] == "sent" && email["email"] == email_of("ann")));
+    assert_eq!(emails[0]["sent_by"], json!(null));
+    assert_eq!(emails[1]["sent_by"], "root");
+    assert_eq!(emails[1]["kind"], "ta_messages");
+    assert_eq!(emails[1]["subject"], "Lab");
+    assert_eq!(emails[1]["body_digest"].as_str().unwrap().len(), 64);
+    assert_eq!(emails[2]["resendable"], false);
+    assert_eq!(ok(&app, get("/users/emails/ann?limit=1", &root)).await["emails"].as_array().unwrap().len(), 1);
+
+    let resend = |email_id: &str| post("/users/emails/resend", &root, json!({ "username": "ann", "email_id": email_id }));
+    let (status, _) = call(&app, resend(emails[2]["id"].as_str().unwrap())).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, resend("000000000000000000000000")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = call(&app, get("/users/emails/ann", &ann)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let resent = ok(&app, resend(emails[1]["id"].as_str().unwrap())).await;
+    assert_eq!(resent["resent_from"], emails[1]["id"]);
+    assert_eq!(resent["template"], "staff_message");
+    let mail = ctx.mailbox.last_to(&email_of("ann"));
+    assert_eq!(mail.subject, "Lab");
+    assert!(mail.body.starts_with("Bring your kit."), "{}", mail.body);
+    assert_eq!(mail.header("In-Reply-To"), lab.header("Message-ID"));
+    assert_eq!(ok(&app, get("/users/emails/ann", &root)).await["emails"][0]["id"], resent["id"]);
+    let entries = ok(&app, get("/audit/list?action=email.resend", &root)).await["entries"].clone();
+    assert_eq!(entries[0]["after"]["resent_from"], emails[1]["id"]);
+
+    // students who turned the kind off do not get it again
+    ok(&app, post("/profile/notifications", &ann, json!({ "weekly_report": false }))).await;
+    let (status, _) = call(&app, resend(emails[0]["id"].as_str().unwrap())).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn names_with_special_characters() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    let email = email_of("jo");
+
+    // a name that reads like another address must not change where the email goes
+    let username = "Jo, \"J\" <x@evil.io>";
+    ok(&app, anonymous("/register", json!({ "username": username, "email": email, "password": PASSWORD }))).await;
+    let mail = ctx.mailbox.last_to(&email);
+    let to = mail.header("To").unwrap();
+    assert!(to.ends_with(&format!("<{}>", email)), "{}", to);
+    assert!(!to.contains("<x@evil.io>"), "{}", to);
+    let code = code_after(&mail.body, "activation code is", 6);
+    let path = "Jo%2C%20%22J%22%20%3Cx%40evil.io%3E";
+    ok(&app, actix_web::test::TestRequest::get().uri(&format!("/verify_email/{}?code={}", path, code))).await;
+
+    ok(&app, post("/register/admin", &root, json!({ "username": "Dr. Lee, 李", "email": "lee@example.com", "password": PASSWORD }))).await;
+    let lee = login(&app, "/login/admin", "Dr. Lee, 李", PASSWORD).await;
+    ok(&app, post("/send_email/single", &lee, json!({ "username": username, "title": "Lab", "content": "See you." }))).await;
+    let reply_to = ctx.mailbox.last_to(&email).header("Reply-To").unwrap();
+    assert!(reply_to.ends_with("<lee@example.com>"), "{}", reply_to);
+}
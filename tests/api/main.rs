@@ -0,0 +1,9 @@
+mod common;
+
+mod accounts;
+mod campaigns;
+mod jobs;
+mod mail;
+mod reports;
+mod staff;
+mod stats;
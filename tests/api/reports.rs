@@ -0,0 +1,108 @@
+use actix_web::http::StatusCode;
+use chrono::{TimeZone, Utc};
+use mongodb::bson::DateTime;
+use serde_json::json;
+
+use ywt::jobs;
+use ywt::report::{self, ReportSchedule};
+use ywt::repository::ReportStore;
+
+use crate::common::*;
+
+#[actix_web::test]
+async fn schedule_weekly_reports() {
+    let mut config = config();
+    config.report.enabled = true;
+    let schedule = ReportSchedule::new(&config.report).unwrap();
+    // 08:00 on Mondays in Shanghai is 00:00 UTC
+    let slot = Utc.with_ymd_and_hms(2026, 10, 19, 0, 0, 0).unwrap();
+    assert_eq!(schedule.due(slot + chrono::Duration::minutes(30)), Some(slot));
+    assert_eq!(schedule.due(slot - chrono::Duration::minutes(1)), None);
+    // too late to catch up
+    assert_eq!(schedule.due(slot + chrono::Duration::hours(13)), None);
+    assert_eq!(schedule.next(slot), Some(slot + chrono::Duration::weeks(1)));
+    let mut invalid = config.report.clone();
+    invalid.schedule = "every monday".to_string();
+    assert!(ReportSchedule::new(&invalid).is_err());
+    let mut invalid = config.report.clone();
+    invalid.timezone = "Mars/Olympus".to_string();
+    assert!(ReportSchedule::new(&invalid).is_err());
+
+    let ctx = Context::with_config(config).await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    let token = student(&ctx, &app, "yan").await;
+    ok(&app, post("/stats/conv", &token, json!({}))).await;
+    let email = email_of("yan");
+    let before = ctx.mailbox.count_to(&email);
+
+    // a second tick, or a restarted server, finds the slot claimed
+    let now = slot + chrono::Duration::minutes(1);
+    report::run_due(ctx.store.as_ref(), &ctx.config, &schedule, now).await.unwrap();
+    report::run_due(ctx.store.as_ref(), &ctx.config, &schedule, now).await.unwrap();
+    ctx.run_jobs().await;
+    assert_eq!(ctx.mailbox.count_to(&email), before + 1);
+    assert!(ctx.mailbox.last_to(&email).body.contains("交谈 1 轮次"));
+
+    let history = ok(&app, get("/reports/runs", &root)).await;
+    assert_eq!(history["enabled"], true);
+    assert!(history["next_run"].is_string());
+    let runs = history["runs"].as_array().unwrap();
+    assert_eq!(runs.len(), 1);
+    assert_eq!(runs[0]["status"], "succeeded");
+    assert_eq!(runs[0]["sent"], 1);
+    assert_eq!(runs[0]["triggered_by"], json!(null));
+    assert!(runs[0]["scheduled_for"].is_string());
+    let runs = ok(&app, get("/courses/signals/reports/runs", &root)).await["runs"].clone();
+    assert_eq!(runs[0]["sent"], 0);
+
+    let run = ok(&app, post("/reports/run", &root, json!({}))).await;
+    assert_eq!(run["status"], "running");
+    assert_eq!(run["triggered_by"], "root");
+    assert_eq!(run["scheduled_for"], json!(null));
+    // one run at a time
+    let (status, _) = call(&app, post("/reports/run", &root, json!({}))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    ctx.run_jobs().await;
+    assert_eq!(ctx.mailbox.count_to(&email), before + 2);
+    let latest = ok(&app, get("/reports/runs?limit=1", &root)).await["runs"][0].clone();
+    assert_eq!(latest["id"], run["id"]);
+    assert_eq!(latest["status"], "succeeded");
+    let job = ok(&app, get(&format!("/jobs/get/{}", run["job_id"].as_str().unwrap()), &root)).await;
+    assert_eq!(job["type"], "weekly_report");
+    assert_eq!(job["progress"], json!({ "done": 1, "total": 1 }));
+    let tess = staff(&app, &root, "tess", "ta").await;
+    let (status, _) = call(&app, post("/reports/run", &tess, json!({}))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // cancelling the queued job ends the run
+    let run = ok(&app, post("/reports/run", &root, json!({}))).await;
+    ok(&app, post("/jobs/cancel", &root, json!({ "id": run["job_id"] }))).await;
+    let latest = ok(&app, get("/reports/runs?limit=1", &root)).await["runs"][0].clone();
+    assert_eq!(latest["status"], "cancelled");
+
+    // a run cut short by a shutdown before its job was queued blocks manual runs until recovered
+    let stuck = report::new_run("default", None, None);
+    assert!(ctx.store.claim_report_run(&stuck).await.unwrap());
+    let (status, _) = call(&app, post("/reports/run", &root, json!({}))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    // and the next scheduled report, which is claimed at the first tick after it
+    let next_week = now + chrono::Duration::weeks(1);
+    report::run_due(ctx.store.as_ref(), &ctx.config, &schedule, next_week).await.unwrap();
+    let latest = ok(&app, get("/reports/runs?limit=1", &root)).await["runs"][0].clone();
+    assert_eq!(latest["id"], stuck.id);
+    let later = DateTime::from_millis(DateTime::now().timestamp_millis() + 60_000);
+    jobs::recover(ctx.store.as_ref(), later).await.unwrap();
+    let runs = ok(&app, get("/reports/runs", &root)).await["runs"].clone();
+    assert_eq!(runs[0]["status"], "failed");
+    assert_eq!(runs[0]["error"], "Interrupted by a restart");
+    report::run_due(ctx.store.as_ref(), &ctx.config, &schedule, next_week).await.unwrap();
+    ctx.run_jobs().await;
+    let runs = ok(&app, get("/reports/runs", &root)).await["runs"].clone();
+    assert_eq!(runs.as_array().unwrap().iter().filter(|run| run["scheduled_for"].is_string()).count(), 2);
+    ok(&app, post("/reports/run", &root, json!({}))).await;
+    ctx.run_jobs().await;
+
+    let entries = ok(&app, get("/audit/list?action=report.run", &root)).await["entries"].clone();
+    assert_eq!(entries.as_array().unwrap().len(), 3);
+}
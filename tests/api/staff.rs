@@ -0,0 +1,267 @@
+use actix_web::http::StatusCode;
+use serde_json::{json, Value};
+
+use crate::common::*;
+
+fn usernames(list: &Value) -> Vec<String> {
+    list["usernames"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|name| name.as_str().unwrap().to_string())
+        .collect()
+}
+
+#[actix_web::test]
+async fn manage_staff_accounts() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+
+    let ivy = staff(&app, &root, "ivy", "instructor").await;
+    let (status, _) = call(&app, post("/register/admin", &root, json!({ "username": "ivy", "email": "ivy2@example.com", "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, post("/register/admin", &ivy, json!({ "username": "jack", "email": "jack@example.com", "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let admins = ok(&app, get("/admins/list", &ivy)).await["admins"].clone();
+    let entry = admins.as_array().unwrap().iter().find(|admin| admin["username"] == "ivy").unwrap().clone();
+    assert_eq!(entry["staff_role"], "instructor");
+    assert_eq!(entry["disabled"], false);
+    assert!(entry["created_by"].is_string());
+    let roles = ok(&app, get("/admins/roles", &ivy)).await;
+    assert_eq!(roles["roles"].as_array().unwrap().len(), 3);
+    let profile = ok(&app, get("/profile", &ivy)).await;
+    assert_eq!(profile["staff_role"], "instructor");
+    assert!(profile["permissions"].as_array().unwrap().contains(&json!("users.read")));
+
+    let (status, _) = call(&app, post("/admins/disable", &ivy, json!({ "username": "root", "disabled": true }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = call(&app, post("/admins/role", &root, json!({ "username": "ivy", "staff_role": "owner" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, post("/admins/role", &root, json!({ "username": "root", "staff_role": "ta" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    ok(&app, post("/admins/role", &root, json!({ "username": "ivy", "staff_role": "ta" }))).await;
+    assert_eq!(ok(&app, get("/profile", &ivy)).await["staff_role"], "ta");
+
+    let (status, _) = call(&app, post("/admins/courses", &root, json!({ "username": "ivy", "course_ids": ["missing"] }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    ok(&app, post("/admins/courses", &root, json!({ "username": "ivy", "course_ids": ["signals"] }))).await;
+    let admins = ok(&app, get("/admins/list", &root)).await["admins"].clone();
+    let entry = admins.as_array().unwrap().iter().find(|admin| admin["username"] == "ivy").unwrap().clone();
+    assert_eq!(entry["course_ids"], json!(["signals"]));
+    ok(&app, post("/admins/courses", &root, json!({ "username": "ivy", "course_ids": null }))).await;
+
+    ok(&app, post("/admins/disable", &root, json!({ "username": "ivy", "disabled": true }))).await;
+    let (status, _) = call(&app, anonymous("/login/admin", json!({ "username": "ivy", "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, get("/admins/list", &ivy)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    ok(&app, post("/admins/disable", &root, json!({ "username": "ivy", "disabled": false }))).await;
+    // the last super admin cannot be disabled
+    let (status, _) = call(&app, post("/admins/disable", &root, json!({ "username": "root", "disabled": true }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let new_password = "copper-kettle-51";
+    ok(&app, post("/admins/reset_password", &root, json!({ "username": "ivy", "new_password": new_password }))).await;
+    let (status, _) = call(&app, get("/profile", &ivy)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let body = ok(&app, anonymous("/login/admin", json!({ "username": "ivy", "password": new_password }))).await;
+    assert_eq!(body["must_change_password"], true);
+    let ivy = body["token"].as_str().unwrap().to_string();
+    let (status, _) = call(&app, get("/profile", &ivy)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    ok(&app, post("/modify/password", &ivy, json!({ "role": "admins", "current_password": new_password, "new_password": PASSWORD }))).await;
+    ok(&app, get("/profile", &ivy)).await;
+
+    ok(&app, post("/admins/delete", &root, json!({ "username": "ivy" }))).await;
+    let (status, _) = call(&app, post("/admins/delete", &root, json!({ "username": "ivy" }))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = call(&app, post("/admins/delete", &root, json!({ "username": "root" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let admins = ok(&app, get("/admins/list", &root)).await["admins"].clone();
+    assert_eq!(admins.as_array().unwrap().len(), 1);
+
+    let entries = ok(&app, get("/audit/list?action=admins.role", &root)).await["entries"].clone();
+    let entries = entries.as_array().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0]["actor"], "root");
+    assert_eq!(entries[0]["before"]["staff_role"], "instructor");
+    assert_eq!(entries[0]["after"]["staff_role"], "ta");
+    assert!(entries[0]["before"].get("password").is_none());
+}
+
+#[actix_web::test]
+async fn sections_scope_students() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    let ian = staff(&app, &root, "ian", "instructor").await;
+    let tess = staff(&app, &root, "tess", "ta").await;
+    let sam = student(&ctx, &app, "sam").await;
+    student(&ctx, &app, "sue").await;
+
+    let (status, _) = call(&app, post("/sections/create", &tess, json!({ "name": "A" }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = call(&app, post("/sections/create", &ian, json!({ "name": "" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let a = ok(&app, post("/sections/create", &ian, json!({ "name": "A" }))).await["id"].as_str().unwrap().to_string();
+    let b = ok(&app, post("/sections/create", &ian, json!({ "name": "B" }))).await["id"].as_str().unwrap().to_string();
+    let (status, _) = call(&app, post("/sections/create", &ian, json!({ "name": "A" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    // sections belong to one course
+    let (status, _) = call(&app, post("/courses/signals/sections/enroll", &ian, json!({ "username": "sam", "section_id": a }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    ok(&app, post("/sections/assign_ta", &ian, json!({ "section_id": a, "username": "tess", "assigned": true }))).await;
+    ok(&app, post("/sections/enroll", &ian, json!({ "username": "sam", "section_id": a }))).await;
+    ok(&app, post("/sections/enroll", &ian, json!({ "username": "sue", "section_id": a }))).await;
+    ok(&app, post("/sections/enroll", &ian, json!({ "username": "sue", "section_id": b }))).await;
+    let (status, _) = call(&app, post("/sections/enroll", &ian, json!({ "username": "sue", "section_id": "000000000000000000000000" }))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(ok(&app, get("/profile", &sam)).await["section"], "A");
+
+    let sections = ok(&app, get("/sections/list", &ian)).await["sections"].clone();
+    assert_eq!(sections.as_array().unwrap().len(), 2);
+    assert_eq!(sections[0]["tas"], json!(["tess"]));
+    assert_eq!(sections[0]["students"], 1);
+    assert_eq!(sections[1]["students"], 1);
+    let sections = ok(&app, get("/sections/list", &tess)).await["sections"].clone();
+    assert_eq!(sections.as_array().unwrap().len(), 1);
+
+    // TAs only see the students of their sections
+    assert_eq!(usernames(&ok(&app, get("/users/list", &ian)).await), vec!["sam", "sue"]);
+    assert_eq!(usernames(&ok(&app, get("/users/list", &tess)).await), vec!["sam"]);
+    assert_eq!(usernames(&ok(&app, get(&format!("/users/list?section_id={}", b), &ian)).await), vec!["sue"]);
+    let (status, _) = call(&app, post("/users/profile", &tess, json!({ "username": "sue", "student_id": "2024001" }))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    ok(&app, post("/users/profile", &tess, json!({ "username": "sam", "student_id": "2024001", "real_name": "Samuel Smith" }))).await;
+    assert_eq!(usernames(&ok(&app, get("/users/list?student_id=2024001", &ian)).await), vec!["sam"]);
+    assert_eq!(usernames(&ok(&app, get("/users/list?real_name=SMITH", &ian)).await), vec!["sam"]);
+    assert_eq!(usernames(&ok(&app, get("/users/list?real_name=.*", &ian)).await), Vec::<String>::new());
+
+    let stats = ok(&app, get("/users/stats/sam", &tess)).await;
+    assert_eq!(stats["conversation"], 0);
+    let (status, _) = call(&app, get("/users/stats/sue", &tess)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // suspensions lock students out of every course, so they are for super admins
+    let (status, _) = call(&app, post("/users/suspend", &ian, json!({ "username": "sam", "reason": "spam" }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = call(&app, post("/users/suspend", &root, json!({ "username": "sam", "reason": "spam", "duration_hours": 0 }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    ok(&app, post("/users/suspend", &root, json!({ "username": "sam", "reason": "spam", "duration_hours": 24 }))).await;
+    let (status, _) = call(&app, get("/profile", &sam)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = call(&app, anonymous("/login", json!({ "username": "sam", "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    ok(&app, post("/users/unsuspend", &root, json!({ "username": "sam" }))).await;
+    ok(&app, get("/profile", &sam)).await;
+
+    ok(&app, post("/users/require_password_change", &root, json!({ "username": "sam" }))).await;
+    let (status, _) = call(&app, get("/profile", &sam)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    ok(&app, post("/modify/password", &sam, json!({ "role": "users", "current_password": PASSWORD, "new_password": "velvet-orchard-23" }))).await;
+    ok(&app, get("/profile", &sam)).await;
+
+    let (status, _) = call(&app, post("/users/delete", &tess, json!({ "username": "sam" }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    ok(&app, post("/users/delete", &ian, json!({ "username": "sue" }))).await;
+    assert_eq!(usernames(&ok(&app, get("/users/list", &ian)).await), vec!["sam"]);
+
+    ok(&app, post("/sections/assign_ta", &ian, json!({ "section_id": a, "username": "tess", "assigned": false }))).await;
+    assert_eq!(ok(&app, get("/sections/list", &tess)).await["sections"], json!([]));
+    ok(&app, post("/sections/delete", &ian, json!({ "section_id": a }))).await;
+    assert_eq!(ok(&app, get("/profile", &sam)).await["section"], json!(null));
+    let (status, _) = call(&app, post("/sections/delete", &ian, json!({ "section_id": a }))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let actions: Vec<String> = ctx.store.audit_log().into_iter().map(|entry| entry.action).collect();
+    for action in ["sections.create", "sections.enroll", "users.profile", "users.suspend", "users.unsuspend", "users.delete", "sections.delete"] {
+        assert!(actions.iter().any(|a| a == action), "no {} entry", action);
+    }
+}
+
+#[actix_web::test]
+async fn course_staff_only_act_on_their_course() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    let sig = staff(&app, &root, "sig", "instructor").await;
+    ok(&app, post("/admins/courses", &root, json!({ "username": "sig", "course_ids": ["signals"] }))).await;
+    let kim = student(&ctx, &app, "kim").await;
+    ok(&app, post("/stats/conv", &kim, json!({}))).await;
+    ok(&app, post("/profile/courses/enroll", &kim, json!({ "course_id": "signals", "enrollment_key": "s1gnal" }))).await;
+    ok(&app, post("/courses/signals/stats/conv", &kim, json!({}))).await;
+    let lab = ok(&app, post("/courses/signals/sections/create", &sig, json!({ "name": "Lab" }))).await["id"].clone();
+    ok(&app, post("/courses/signals/sections/enroll", &sig, json!({ "username": "kim", "section_id": lab }))).await;
+
+    // deleting from one course keeps the account and the other course
+    ok(&app, post("/courses/signals/users/delete", &sig, json!({ "username": "kim" }))).await;
+    assert_eq!(usernames(&ok(&app, get("/courses/signals/users/list", &sig)).await), Vec::<String>::new());
+    let (status, _) = call(&app, get("/courses/signals/users/stats/kim", &root)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(usernames(&ok(&app, get("/users/list", &root)).await), vec!["kim"]);
+    assert_eq!(ok(&app, get("/users/stats/kim", &root)).await["conversation"], 1);
+    assert_eq!(ok(&app, get("/courses/signals/sections/list", &sig)).await["sections"][0]["students"], 0);
+    ok(&app, get("/profile", &kim)).await;
+
+    // whole accounts are only for super admins
+    for action in ["/courses/signals/users/suspend", "/courses/signals/users/purge"] {
+        let (status, _) = call(&app, post(action, &sig, json!({ "username": "kim", "reason": "spam" }))).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+    ok(&app, post("/users/purge", &root, json!({ "username": "kim" }))).await;
+    assert_eq!(usernames(&ok(&app, get("/users/list", &root)).await), Vec::<String>::new());
+    let (status, _) = call(&app, anonymous("/login", json!({ "username": "kim", "password": PASSWORD }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn send_emails_and_query_audit() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    let token = student(&ctx, &app, "tom").await;
+    let email = email_of("tom");
+    ok(&app, post("/stats", &token, json!({ "tag": ["ohm"] }))).await;
+    ok(&app, post("/stats/conv", &token, json!({}))).await;
+    ok(&app, post("/profile/courses/enroll", &token, json!({ "course_id": "signals", "enrollment_key": "s1gnal" }))).await;
+
+    ok(&app, get("/send_email", &root)).await;
+    ctx.run_jobs().await;
+    let mail = ctx.mailbox.last_to(&email);
+    assert_eq!(mail.subject, "YWT 答疑周报");
+    assert!(mail.body.contains("交谈 1 轮次，主要围绕 ohm"), "{}", mail.body);
+    ok(&app, get("/courses/signals/send_email", &root)).await;
+    ctx.run_jobs().await;
+    let mail = ctx.mailbox.last_to(&email);
+    assert_eq!(mail.subject, "Signals weekly");
+    assert!(mail.body.starts_with("Hi tom, 0 conversations about ."), "{}", mail.body);
+
+    // forwarding headers are ignored unless the peer is a trusted proxy
+    let send = post("/send_email/single", &root, json!({ "username": "tom", "title": "Office hours", "content": "See you at 3." }))
+        .peer_addr("198.51.100.2:4000".parse().unwrap())
+        .insert_header(("X-Forwarded-For", "203.0.113.7"));
+    ok(&app, send).await;
+    let mail = ctx.mailbox.last_to(&email);
+    assert_eq!(mail.subject, "Office hours");
+    assert!(mail.body.contains("See you at 3.") && mail.body.contains("root@example.com"));
+    let (status, _) = call(&app, post("/send_email/single", &token, json!({ "username": "tom", "title": "Hi", "content": "Hi" }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let entries = ok(&app, get("/audit/list?action=email.broadcast", &root)).await["entries"].clone();
+    assert_eq!(entries.as_array().unwrap().len(), 2);
+    assert_eq!(entries[0]["target"], "signals");
+    assert!(entries[1]["after"]["job"].is_string());
+    let entries = ok(&app, get("/audit/list?actor=root&limit=1", &root)).await["entries"].clone();
+    assert_eq!(entries.as_array().unwrap().len(), 1);
+    assert_eq!(entries[0]["action"], "email.send");
+    assert_eq!(entries[0]["ip"], "198.51.100.2");
+    let entries = ok(&app, get("/audit/list?since=2000-01-01T00:00:00Z&until=2000-01-02T00:00:00Z", &root)).await["entries"].clone();
+    assert_eq!(entries, json!([]));
+    let (status, _) = call(&app, get("/audit/list?since=yesterday", &root)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, get("/audit/list", &token)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
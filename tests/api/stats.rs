@@ -0,0 +1,185 @@
+use std::pin::pin;
+
+use actix_web::body::MessageBody;
+use actix_web::http::StatusCode;
+use actix_web::test;
+use serde_json::json;
+
+use crate::common::*;
+
+#[actix_web::test]
+async fn count_stats_and_batches() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let token = student(&ctx, &app, "uma").await;
+
+    // a tag repeated in one call counts once
+    ok(&app, post("/stats", &token, json!({ "tag": ["ohm", "ohm", "kcl"] }))).await;
+    ok(&app, post("/stats/conv", &token, json!({}))).await;
+    let (status, _) = call(&app, post("/stats", &token, json!({ "tag": ["$where"] }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let events = json!({ "events": [
+        { "id": "e1", "type": "conversation_started" },
+        { "id": "e2", "type": "message" },
+        { "id": "e3", "type": "tags_mentioned", "tags": ["ohm", "ohm"] },
+        { "id": "e4", "type": "problem_viewed", "problem_id": "p1" },
+        { "id": "e5", "type": "problem_viewed", "problem_id": "missing" },
+        { "id": "e6", "type": "unknown" },
+        { "id": "e1", "type": "conversation_started" },
+    ] });
+    let results = ok(&app, post("/stats/batch", &token, events.clone())).await["results"].clone();
+    let statuses: Vec<_> = results.as_array().unwrap().iter().map(|result| result["status"].clone()).collect();
+    assert_eq!(statuses, vec!["applied", "applied", "applied", "applied", "rejected", "rejected", "duplicate"]);
+    // a retried batch changes nothing
+    let results = ok(&app, post("/stats/batch", &token, events)).await["results"].clone();
+    assert_eq!(results[0]["status"], "duplicate");
+    let too_many: Vec<_> = (0..101).map(|i| json!({ "id": i.to_string(), "type": "message" })).collect();
+    let (status, _) = call(&app, post("/stats/batch", &token, json!({ "events": too_many }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let stats = ok(&app, get("/stats", &token)).await;
+    assert_eq!(stats["conversation"], 2);
+    assert_eq!(stats["messages"], 1);
+    assert_eq!(stats["tags"], json!([["kcl", 1], ["ohm", 2]]));
+    assert_eq!(stats["problems_viewed"], json!([["p1", 1]]));
+
+    // other courses need an enrollment and restrict their tags
+    let (status, _) = call(&app, post("/courses/signals/stats/conv", &token, json!({}))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    ok(&app, post("/profile/courses/enroll", &token, json!({ "course_id": "signals", "enrollment_key": "s1gnal" }))).await;
+    let (status, _) = call(&app, post("/courses/signals/stats", &token, json!({ "tag": ["ohm"] }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    ok(&app, post("/courses/signals/stats", &token, json!({ "tag": ["fourier"] }))).await;
+    assert_eq!(ok(&app, get("/courses/signals/stats", &token)).await["tags"], json!([["fourier", 1]]));
+
+    let root = root_token(&app).await;
+    let (status, _) = call(&app, post("/stats/clear", &token, json!({}))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let job_id = ok(&app, post("/stats/clear", &root, json!({}))).await["job_id"].clone();
+    ctx.run_jobs().await;
+    let job = ok(&app, get(&format!("/jobs/get/{}", job_id.as_str().unwrap()), &root)).await;
+    assert_eq!(job["status"], "succeeded");
+    assert_eq!(job["result"]["cleared"], 1);
+    let stats = ok(&app, get("/stats", &token)).await;
+    assert_eq!(stats["conversation"], 0);
+    assert_eq!(stats["tags"], json!([]));
+    assert_eq!(ok(&app, get("/courses/signals/stats", &token)).await["conversation"], 0);
+    let cleared = ctx.store.audit_log().into_iter().find(|entry| entry.action == "stats.clear").unwrap();
+    assert_eq!(cleared.after.unwrap().get_i64("students").unwrap(), 1);
+}
+
+#[actix_web::test]
+async fn repeated_tags_count_once() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let token = student(&ctx, &app, "vic").await;
+
+    ok(&app, post("/stats", &token, json!({ "tag": ["ohm", "ohm"] }))).await;
+    assert_eq!(ok(&app, get("/stats", &token)).await["tags"], json!([["ohm", 1]]));
+    let events = json!({ "events": [{ "id": "t1", "type": "tags_mentioned", "tags": ["ohm", "kcl", "ohm"] }] });
+    ok(&app, post("/stats/batch", &token, events)).await;
+    assert_eq!(ok(&app, get("/stats", &token)).await["tags"], json!([["kcl", 1], ["ohm", 2]]));
+}
+
+#[actix_web::test]
+async fn read_problems() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let token = student(&ctx, &app, "vic").await;
+
+    let problem = ok(&app, get("/problem/get/p1", &token)).await;
+    assert_eq!(problem["tags"], json!(["ohm"]));
+    assert_eq!(problem["image"], "cG5n");
+    let (status, _) = call(&app, get("/problem/get/p2", &token)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    assert_eq!(ok(&app, get("/problem/qbank", &token)).await.as_array().unwrap().len(), 2);
+    assert_eq!(ok(&app, get("/problem/tags", &token)).await["tags"], json!(["kcl", "ohm"]));
+    assert_eq!(ok(&app, get("/courses/signals/problem/tags", &token)).await["tags"], json!(["fourier", "laplace"]));
+    let (status, _) = call(&app, get("/courses/missing/problem/tags", &token)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = call(&app, get("/no/such/route", &token)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn services_use_api_keys() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    let token = student(&ctx, &app, "wes").await;
+    let wes = ok(&app, get("/profile", &token)).await["user_id"].as_str().unwrap().to_string();
+
+    let (status, _) = call(&app, post("/api_keys/create", &root, json!({ "name": "bot", "scopes": ["stats:delete"] }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, post("/api_keys/create", &root, json!({ "name": "bot", "scopes": ["stats:write"], "expires_in_days": 0 }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = call(&app, post("/api_keys/create", &token, json!({ "name": "bot", "scopes": ["stats:write"] }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let created = ok(&app, post("/api_keys/create", &root, json!({ "name": "bot", "scopes": ["stats:write", "problems:read"], "expires_in_days": 30 }))).await;
+    let key = created["key"].as_str().unwrap();
+
+    let service = |req: test::TestRequest| req.insert_header(("X-API-Key", key)).insert_header(("X-Act-As", wes.as_str()));
+    ok(&app, service(test::TestRequest::post().uri("/stats/conv"))).await;
+    ok(&app, test::TestRequest::get().uri("/problem/get/p1").insert_header(("X-API-Key", key))).await;
+    // the key lacks `stats:read`
+    let (status, _) = call(&app, service(test::TestRequest::get().uri("/stats"))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = call(&app, test::TestRequest::post().uri("/stats/conv").insert_header(("X-API-Key", key))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(ok(&app, get("/stats", &token)).await["conversation"], 1);
+
+    let keys = ok(&app, get("/api_keys/list", &root)).await["keys"].clone();
+    assert_eq!(keys[0]["name"], "bot");
+    assert!(keys[0]["last_used_at"].is_string());
+    assert!(keys[0]["expires_at"].is_string());
+    ok(&app, post("/api_keys/revoke", &root, json!({ "id": created["id"] }))).await;
+    let (status, _) = call(&app, service(test::TestRequest::post().uri("/stats/conv"))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = call(&app, post("/api_keys/revoke", &root, json!({ "id": "000000000000000000000000" }))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(ok(&app, get("/api_keys/list", &root)).await["keys"][0]["revoked"], true);
+}
+
+/// Read the next chunk of a streamed body as text.
+async fn next_chunk<B: MessageBody>(body: std::pin::Pin<&mut B>) -> String {
+    let mut body = body;
+    let chunk = futures::future::poll_fn(|cx| body.as_mut().poll_next(cx)).await;
+    match chunk {
+        Some(Ok(bytes)) => String::from_utf8(bytes.to_vec()).unwrap(),
+        _ => panic!("Stream ended"),
+    }
+}
+
+#[actix_web::test]
+async fn stream_live_events() {
+    let ctx = Context::new().await;
+    let app = ctx.app().await;
+    let root = root_token(&app).await;
+    let token = student(&ctx, &app, "xan").await;
+
+    let (status, _) = call(&app, get("/live", &token)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = call(&app, get("/live?section_id=bogus", &root)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let sig = staff(&app, &root, "sig", "instructor").await;
+    ok(&app, post("/admins/courses", &root, json!({ "username": "sig", "course_ids": ["signals"] }))).await;
+    let (status, _) = call(&app, get("/live", &sig)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let response = test::call_service(&app, get("/live", &root).to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut body = pin!(response.into_body());
+    assert_eq!(next_chunk(body.as_mut()).await, "retry: 3000\n\n");
+    ok(&app, post("/stats/conv", &token, json!({}))).await;
+    let event = next_chunk(body.as_mut()).await;
+    assert!(event.starts_with("id: ") && event.contains("event: conversation\n"), "{}", event);
+    assert!(event.contains(r#""username":"xan""#), "{}", event);
+
+    // a reconnecting dashboard gets what it missed
+    let response = test::call_service(&app, get("/live", &root).insert_header(("Last-Event-ID", "0")).to_request()).await;
+    let mut body = pin!(response.into_body());
+    next_chunk(body.as_mut()).await;
+    assert!(next_chunk(body.as_mut()).await.contains("event: registration\n"));
+}